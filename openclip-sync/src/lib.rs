@@ -0,0 +1,278 @@
+//! openclip-sync: a small HTTP server that keeps clips for openclip
+//! installations sharing a token. See `protocol` for the API.
+
+pub mod protocol;
+pub mod store;
+
+use std::io::Read;
+use std::net::SocketAddr;
+use std::path::Path;
+use std::sync::{Mutex, MutexGuard};
+
+use tiny_http::{Header, Method, Request, Response, ResponseBox, StatusCode};
+
+use protocol::{ErrorBody, NewUpload};
+use store::{Store, StoreError};
+
+/// Requests handled at once; a fixed pool, so a flood of connections can't
+/// run the server out of threads.
+pub const WORKERS: usize = 8;
+
+pub struct Server {
+    http: tiny_http::Server,
+    // Held only for the store call itself; request bodies are read before locking
+    store: Mutex<Store>,
+    tokens: Vec<String>,
+}
+
+impl Server {
+    /// Listens on `addr` (port 0 picks a free one) and keeps clips under `data`.
+    pub fn bind(addr: &str, data: &Path, tokens: Vec<String>) -> Result<Self, String> {
+        if tokens.iter().all(|t| t.trim().is_empty()) {
+            return Err("at least one token is required".to_string());
+        }
+        let store = Store::open(data).map_err(|e| format!("{}: {}", data.display(), e))?;
+        let http = tiny_http::Server::http(addr).map_err(|e| format!("{}: {}", addr, e))?;
+        Ok(Server { http, store: Mutex::new(store), tokens })
+    }
+
+    pub fn addr(&self) -> SocketAddr {
+        self.http.server_addr().to_ip().expect("bound to a TCP address")
+    }
+
+    /// Serves requests until the process exits on `WORKERS` threads, so a
+    /// slow upload or download doesn't hold up the other devices. Requests
+    /// beyond that wait for a free worker.
+    pub fn run(&self) {
+        std::thread::scope(|scope| {
+            for _ in 0..WORKERS {
+                scope.spawn(|| {
+                    while let Ok(request) = self.http.recv() {
+                        self.handle(request);
+                    }
+                });
+            }
+        });
+    }
+
+    fn store(&self) -> MutexGuard<'_, Store> {
+        self.store.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    fn handle(&self, mut request: Request) {
+        let response = if !self.authorized(&request) {
+            error(StatusCode(401), "missing or unknown token")
+                .with_header(header("WWW-Authenticate", "Bearer"))
+        } else {
+            self.route(&mut request).unwrap_or_else(|e| {
+                if matches!(e, StoreError::Io(_) | StoreError::Db(_)) {
+                    eprintln!("{} {}: {}", request.method(), request.url(), e);
+                }
+                store_error(e)
+            })
+        };
+        let _ = request.respond(response);
+    }
+
+    fn authorized(&self, request: &Request) -> bool {
+        let Some(given) = request
+            .headers()
+            .iter()
+            .find(|h| h.field.equiv("Authorization"))
+            .and_then(|h| h.value.as_str().strip_prefix("Bearer "))
+        else {
+            return false;
+        };
+        self.tokens.iter().any(|t| !t.is_empty() && same_token(t, given.trim()))
+    }
+
+    fn route(&self, request: &mut Request) -> store::Result<ResponseBox> {
+        let url = request.url().to_string();
+        let (path, query) = url.split_once('?').unwrap_or((&url, ""));
+        let segments: Vec<&str> = path.trim_matches('/').split('/').collect();
+        match (request.method().clone(), segments.as_slice()) {
+            (Method::Post, ["v1", "uploads"]) => {
+                let new: NewUpload = read_json(request)?;
+                Ok(json(StatusCode(200), &self.store().begin(&new)?))
+            }
+            (Method::Head, ["v1", "uploads", upload]) => {
+                let offset = self.store().offset(upload)?;
+                Ok(Response::empty(200).with_header(header(protocol::OFFSET_HEADER, &offset.to_string())).boxed())
+            }
+            (Method::Patch, ["v1", "uploads", upload]) => {
+                let offset = request_header(request, protocol::OFFSET_HEADER)
+                    .ok_or_else(|| StoreError::BadRequest(format!("{} header required", protocol::OFFSET_HEADER)))?;
+                // Buffer the chunk first so a slow client doesn't keep the store locked;
+                // one byte over the limit is enough for `append` to refuse it
+                let mut chunk = Vec::new();
+                request.as_reader().take(protocol::MAX_CHUNK_SIZE + 1).read_to_end(&mut chunk)?;
+                let status = self.store().append(upload, offset, &mut &chunk[..])?;
+                Ok(json(StatusCode(200), &status))
+            }
+            (Method::Get, ["v1", "changes"]) => {
+                let since = query_param(query, "since").unwrap_or(0);
+                let limit = query_param(query, "limit").unwrap_or(protocol::MAX_CHANGES as i64);
+                Ok(json(StatusCode(200), &self.store().changes(since, limit.max(0) as usize)?))
+            }
+            (Method::Delete, ["v1", "clips", id]) => {
+                let device = query_value(query, "device").unwrap_or_default();
+                self.store().delete(id, &device)?;
+                Ok(Response::empty(204).boxed())
+            }
+            (Method::Put, ["v1", "clips", id, "meta"]) => {
+                let device = query_value(query, "device").unwrap_or_default();
+                let mut meta = Vec::new();
+                request.as_reader().take(protocol::MAX_META_SIZE + 1).read_to_end(&mut meta)?;
+                let meta = String::from_utf8(meta).map_err(|_| StoreError::BadRequest("meta must be UTF-8".to_string()))?;
+                self.store().set_meta(id, &meta, &device)?;
+                Ok(Response::empty(204).boxed())
+            }
+            (Method::Get, ["v1", "keyring"]) => {
+                let (version, data) = self.store().keyring()?.ok_or(StoreError::NotFound)?;
+                Ok(Response::from_data(data)
+                    .with_header(header("Content-Type", "application/octet-stream"))
+                    .with_header(header(protocol::KEYRING_VERSION_HEADER, &version.to_string()))
+                    .boxed())
+            }
+            (Method::Put, ["v1", "keyring"]) => {
+                let replaces = request_header(request, protocol::KEYRING_VERSION_HEADER)
+                    .ok_or_else(|| StoreError::BadRequest(format!("{} header required", protocol::KEYRING_VERSION_HEADER)))?;
+                let mut data = Vec::new();
+                request.as_reader().take(protocol::MAX_KEYRING_SIZE + 1).read_to_end(&mut data)?;
+                let version = self.store().put_keyring(replaces, &data)?;
+                Ok(Response::empty(204).with_header(header(protocol::KEYRING_VERSION_HEADER, &version.to_string())).boxed())
+            }
+            (Method::Get, ["v1", "clips", id]) => {
+                let file = self.store().blob(id)?;
+                Ok(Response::from_file(file)
+                    .with_header(header("Content-Type", "application/octet-stream"))
+                    .boxed())
+            }
+            _ => Err(StoreError::NotFound),
+        }
+    }
+}
+
+fn read_json<T: serde::de::DeserializeOwned>(request: &mut Request) -> store::Result<T> {
+    let mut body = Vec::new();
+    request.as_reader().take(1 << 20).read_to_end(&mut body)?;
+    serde_json::from_slice(&body).map_err(|e| StoreError::BadRequest(e.to_string()))
+}
+
+fn query_value(query: &str, name: &str) -> Option<String> {
+    query
+        .split('&')
+        .filter_map(|pair| pair.split_once('='))
+        .find(|(key, _)| *key == name)
+        .map(|(_, value)| value.to_string())
+}
+
+fn query_param(query: &str, name: &str) -> Option<i64> {
+    query_value(query, name).and_then(|value| value.parse().ok())
+}
+
+fn request_header(request: &Request, name: &'static str) -> Option<u64> {
+    request
+        .headers()
+        .iter()
+        .find(|h| h.field.equiv(name))
+        .and_then(|h| h.value.as_str().trim().parse().ok())
+}
+
+fn header(name: &str, value: &str) -> Header {
+    Header::from_bytes(name.as_bytes(), value.as_bytes()).expect("valid header")
+}
+
+fn json<T: serde::Serialize>(status: StatusCode, body: &T) -> ResponseBox {
+    let text = serde_json::to_string(body).expect("wire types serialize");
+    Response::from_string(text)
+        .with_status_code(status)
+        .with_header(header("Content-Type", "application/json"))
+        .boxed()
+}
+
+fn error(status: StatusCode, message: &str) -> ResponseBox {
+    json(status, &ErrorBody { error: message.to_string() })
+}
+
+fn store_error(e: StoreError) -> ResponseBox {
+    let status = match &e {
+        StoreError::Io(_) | StoreError::Db(_) => 500,
+        StoreError::BadRequest(_) => 400,
+        StoreError::NotFound => 404,
+        StoreError::WrongOffset(_) | StoreError::KeyringChanged(_) => 409,
+        StoreError::TooLarge => 413,
+        StoreError::HashMismatch => 422,
+    };
+    let response = error(StatusCode(status), &e.to_string());
+    match e {
+        StoreError::WrongOffset(offset) => response.with_header(header(protocol::OFFSET_HEADER, &offset.to_string())),
+        StoreError::KeyringChanged(version) => {
+            response.with_header(header(protocol::KEYRING_VERSION_HEADER, &version.to_string()))
+        }
+        _ => response,
+    }
+}
+
+/// Compares without stopping at the first differing byte.
+fn same_token(expected: &str, given: &str) -> bool {
+    expected.len() == given.len() && expected.bytes().zip(given.bytes()).fold(0, |acc, (a, b)| acc | (a ^ b)) == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+    use std::net::TcpStream;
+    use std::time::Duration;
+
+    #[test]
+    fn a_stalled_request_does_not_block_others() {
+        let dir = tempfile::tempdir().unwrap();
+        let server = Server::bind("127.0.0.1:0", dir.path(), vec!["secret".to_string()]).unwrap();
+        let addr = server.addr();
+        std::thread::spawn(move || server.run());
+
+        // Promises a body it never sends
+        let mut stalled = TcpStream::connect(addr).unwrap();
+        write!(stalled, "PUT /v1/clips/aa/meta HTTP/1.1\r\nHost: x\r\nAuthorization: Bearer secret\r\nContent-Length: 100000\r\n\r\n{{").unwrap();
+        std::thread::sleep(Duration::from_millis(200));
+
+        let mut other = TcpStream::connect(addr).unwrap();
+        other.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        write!(other, "GET /v1/changes HTTP/1.1\r\nHost: x\r\nAuthorization: Bearer secret\r\nConnection: close\r\n\r\n").unwrap();
+        let mut response = String::new();
+        other.read_to_string(&mut response).unwrap();
+        assert!(response.starts_with("HTTP/1.1 200"), "{}", response);
+        drop(stalled);
+    }
+
+    #[test]
+    fn requests_beyond_the_workers_wait() {
+        let dir = tempfile::tempdir().unwrap();
+        let server = Server::bind("127.0.0.1:0", dir.path(), vec!["secret".to_string()]).unwrap();
+        let addr = server.addr();
+        std::thread::spawn(move || server.run());
+
+        let stalled: Vec<TcpStream> = (0..WORKERS)
+            .map(|_| {
+                let mut stream = TcpStream::connect(addr).unwrap();
+                write!(stream, "PUT /v1/clips/aa/meta HTTP/1.1\r\nHost: x\r\nAuthorization: Bearer secret\r\nContent-Length: 100000\r\n\r\n{{").unwrap();
+                stream
+            })
+            .collect();
+        std::thread::sleep(Duration::from_millis(200));
+
+        let mut other = TcpStream::connect(addr).unwrap();
+        write!(other, "GET /v1/changes HTTP/1.1\r\nHost: x\r\nAuthorization: Bearer secret\r\nConnection: close\r\n\r\n").unwrap();
+        other.set_read_timeout(Some(Duration::from_millis(300))).unwrap();
+        let mut response = String::new();
+        assert!(other.read_to_string(&mut response).is_err() && response.is_empty(), "{}", response);
+
+        // Answered as soon as a worker frees up
+        drop(stalled);
+        other.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        other.read_to_string(&mut response).unwrap();
+        assert!(response.starts_with("HTTP/1.1 200"), "{}", response);
+    }
+}
@@ -0,0 +1,68 @@
+use std::path::PathBuf;
+
+use openclip_sync::Server;
+
+const USAGE: &str = "usage: openclip-sync --token <token> [options]
+
+  --listen <addr>          address to listen on (default 127.0.0.1:8484)
+  --data <dir>             where clips are kept (default ./openclip-sync-data)
+  --token <token>          a bearer token clients must send; repeat for several
+  --tokens-file <path>     one token per line, # starts a comment
+
+Serve it behind a TLS-terminating reverse proxy when it's reachable beyond localhost.";
+
+struct Args {
+    listen: String,
+    data: PathBuf,
+    tokens: Vec<String>,
+}
+
+fn parse_args<I: Iterator<Item = String>>(mut args: I) -> Result<Args, String> {
+    let mut parsed = Args {
+        listen: "127.0.0.1:8484".to_string(),
+        data: PathBuf::from("openclip-sync-data"),
+        tokens: Vec::new(),
+    };
+    while let Some(arg) = args.next() {
+        if arg == "--help" || arg == "-h" {
+            return Err("openclip-sync clip server".to_string());
+        }
+        let mut value = || args.next().ok_or_else(|| format!("{} expects a value", arg));
+        match arg.as_str() {
+            "--listen" => parsed.listen = value()?,
+            "--data" => parsed.data = PathBuf::from(value()?),
+            "--token" => parsed.tokens.push(value()?),
+            "--tokens-file" => {
+                let path = value()?;
+                let text = std::fs::read_to_string(&path).map_err(|e| format!("{}: {}", path, e))?;
+                parsed.tokens.extend(
+                    text.lines()
+                        .map(str::trim)
+                        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+                        .map(str::to_string),
+                );
+            }
+            _ => return Err(format!("unknown argument '{}'", arg)),
+        }
+    }
+    Ok(parsed)
+}
+
+fn main() {
+    let args = match parse_args(std::env::args().skip(1)) {
+        Ok(args) => args,
+        Err(msg) => {
+            eprintln!("{}\n\n{}", msg, USAGE);
+            std::process::exit(2);
+        }
+    };
+    let server = match Server::bind(&args.listen, &args.data, args.tokens) {
+        Ok(server) => server,
+        Err(e) => {
+            eprintln!("openclip-sync: {}", e);
+            std::process::exit(1);
+        }
+    };
+    println!("openclip-sync listening on http://{}, data in {}", server.addr(), args.data.display());
+    server.run();
+}
@@ -0,0 +1,104 @@
+//! Wire types shared by the server and the openclip client.
+//!
+//! Every request carries `Authorization: Bearer <token>`. A clip is an opaque
+//! `meta` string plus one blob, both chosen by the client; the server only
+//! looks at the clip id, the sizes and the blob hash.
+//!
+//! ```text
+//! POST   /v1/uploads                     NewUpload -> UploadStatus
+//! HEAD   /v1/uploads/<upload>            -> Upload-Offset
+//! PATCH  /v1/uploads/<upload>            Upload-Offset + bytes -> UploadStatus
+//! GET    /v1/changes?since=<seq>         -> Changes
+//! GET    /v1/clips/<id>                  -> blob
+//! DELETE /v1/clips/<id>?device=<device>  -> 204, leaves a tombstone
+//! PUT    /v1/clips/<id>/meta?device=<d>  meta -> 204
+//! GET    /v1/keyring                     -> keyring + Keyring-Version
+//! PUT    /v1/keyring                     Keyring-Version + keyring -> Keyring-Version
+//! ```
+//!
+//! Uploads are resumable: posting the same clip again returns the upload
+//! already in progress and how much of it the server has, and a PATCH at the
+//! wrong offset is refused with 409 and the offset the server expects.
+//!
+//! Deleting a clip drops its blob and meta but keeps the id with a new `seq`,
+//! so every device reading the feed learns to drop its copy too. Uploading
+//! the clip again brings it back, and uploading different bytes for a stored
+//! clip replaces it.
+//!
+//! PUT on a clip's `meta` replaces the meta of a stored clip or a tombstone
+//! without touching the blob, and gives it a new `seq` like any other change.
+//! It's 404 for ids the server has never stored.
+//!
+//! The keyring is one opaque blob the clients use to share their encryption
+//! keys. A PUT names the version it replaces (0 for the first one) and is
+//! refused with 409 and the current version if another client got there first.
+
+use serde::{Deserialize, Serialize};
+
+pub const OFFSET_HEADER: &str = "Upload-Offset";
+pub const KEYRING_VERSION_HEADER: &str = "Keyring-Version";
+
+/// Largest blob the server accepts.
+pub const MAX_CLIP_SIZE: u64 = 256 << 20;
+/// Largest single PATCH body.
+pub const MAX_CHUNK_SIZE: u64 = 8 << 20;
+/// Largest keyring the server keeps.
+pub const MAX_KEYRING_SIZE: u64 = 64 << 10;
+/// Largest meta the server keeps.
+pub const MAX_META_SIZE: u64 = 256 << 10;
+/// Most changes returned by one `/v1/changes` call.
+pub const MAX_CHANGES: usize = 500;
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct NewUpload {
+    /// Clip id, the content hash as lowercase hex.
+    pub id: String,
+    pub size: u64,
+    /// blake3 of the whole blob, checked once the last byte arrives.
+    pub blob_hash: String,
+    pub meta: String,
+    /// Opaque id of the uploading device.
+    #[serde(default)]
+    pub device: String,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct UploadStatus {
+    /// None once the clip is stored.
+    pub upload: Option<String>,
+    pub offset: u64,
+    pub complete: bool,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Change {
+    pub seq: i64,
+    pub id: String,
+    pub size: u64,
+    pub meta: String,
+    /// Unix seconds of the upload or deletion.
+    pub uploaded_at: i64,
+    #[serde(default)]
+    pub device: String,
+    /// A tombstone: `size` is 0 and `meta` empty unless it was set after the deletion.
+    #[serde(default)]
+    pub deleted: bool,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Changes {
+    pub changes: Vec<Change>,
+    /// Pass as `since` on the next call.
+    pub next: i64,
+    pub more: bool,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct ErrorBody {
+    pub error: String,
+}
+
+/// Clip and upload ids are short lowercase hex, which also keeps them safe as file names.
+pub fn valid_id(id: &str) -> bool {
+    (1..=128).contains(&id.len()) && id.bytes().all(|b| b.is_ascii_digit() || (b'a'..=b'f').contains(&b))
+}
@@ -0,0 +1,523 @@
+//! Clips on disk: blobs as files under `blobs/`, partial uploads under
+//! `uploads/`, and an SQLite index with the change sequence.
+
+use rusqlite::{params, Connection, OptionalExtension};
+use std::fmt;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::protocol::{self, Change, Changes, NewUpload, UploadStatus};
+
+#[derive(Debug)]
+pub enum StoreError {
+    Io(io::Error),
+    Db(rusqlite::Error),
+    BadRequest(String),
+    NotFound,
+    /// The PATCH didn't start where the upload left off; carries the offset to resume from.
+    WrongOffset(u64),
+    /// The keyring changed since the client read it; carries the current version.
+    KeyringChanged(u64),
+    TooLarge,
+    HashMismatch,
+}
+
+impl fmt::Display for StoreError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            StoreError::Io(e) => write!(f, "{}", e),
+            StoreError::Db(e) => write!(f, "{}", e),
+            StoreError::BadRequest(msg) => write!(f, "{}", msg),
+            StoreError::NotFound => write!(f, "not found"),
+            StoreError::WrongOffset(offset) => write!(f, "upload is at offset {}", offset),
+            StoreError::KeyringChanged(version) => write!(f, "keyring is at version {}", version),
+            StoreError::TooLarge => write!(f, "clip is larger than {} bytes", protocol::MAX_CLIP_SIZE),
+            StoreError::HashMismatch => write!(f, "blob doesn't match blob_hash; upload discarded"),
+        }
+    }
+}
+
+impl From<io::Error> for StoreError {
+    fn from(e: io::Error) -> Self {
+        StoreError::Io(e)
+    }
+}
+
+impl From<rusqlite::Error> for StoreError {
+    fn from(e: rusqlite::Error) -> Self {
+        StoreError::Db(e)
+    }
+}
+
+pub type Result<T> = std::result::Result<T, StoreError>;
+
+pub struct Store {
+    dir: PathBuf,
+    conn: Connection,
+}
+
+struct Upload {
+    id: String,
+    size: u64,
+    blob_hash: String,
+    meta: String,
+    device: String,
+}
+
+/// Adds `column` to `table` unless it's already there.
+fn add_column_if_missing(conn: &Connection, table: &str, column: &str, decl: &str) -> rusqlite::Result<()> {
+    let mut stmt = conn.prepare(&format!("PRAGMA table_info({})", table))?;
+    let exists = stmt
+        .query_map([], |r| r.get::<_, String>(1))?
+        .collect::<rusqlite::Result<Vec<_>>>()?
+        .iter()
+        .any(|name| name == column);
+    if !exists {
+        conn.execute(&format!("ALTER TABLE {} ADD COLUMN {} {}", table, column, decl), [])?;
+    }
+    Ok(())
+}
+
+impl Store {
+    pub fn open(dir: &Path) -> Result<Self> {
+        fs::create_dir_all(dir.join("blobs"))?;
+        fs::create_dir_all(dir.join("uploads"))?;
+        let conn = Connection::open(dir.join("index.db"))?;
+        conn.execute_batch(
+            "CREATE TABLE IF NOT EXISTS clips (
+                id TEXT PRIMARY KEY,
+                seq INTEGER NOT NULL,
+                size INTEGER NOT NULL,
+                meta TEXT NOT NULL,
+                uploaded_at INTEGER NOT NULL
+            );
+            CREATE INDEX IF NOT EXISTS idx_clips_seq ON clips(seq);
+            CREATE TABLE IF NOT EXISTS uploads (
+                upload TEXT PRIMARY KEY,
+                id TEXT NOT NULL UNIQUE,
+                size INTEGER NOT NULL,
+                blob_hash TEXT NOT NULL,
+                meta TEXT NOT NULL
+            );
+            CREATE TABLE IF NOT EXISTS keyring (
+                version INTEGER NOT NULL,
+                data BLOB NOT NULL
+            );",
+        )?;
+        add_column_if_missing(&conn, "clips", "device", "TEXT NOT NULL DEFAULT ''")?;
+        add_column_if_missing(&conn, "clips", "deleted", "INTEGER NOT NULL DEFAULT 0")?;
+        add_column_if_missing(&conn, "clips", "blob_hash", "TEXT NOT NULL DEFAULT ''")?;
+        add_column_if_missing(&conn, "uploads", "device", "TEXT NOT NULL DEFAULT ''")?;
+        Ok(Store { dir: dir.to_path_buf(), conn })
+    }
+
+    fn blob_path(&self, id: &str) -> PathBuf {
+        self.dir.join("blobs").join(id)
+    }
+
+    fn part_path(&self, upload: &str) -> PathBuf {
+        self.dir.join("uploads").join(upload)
+    }
+
+    fn has_clip(&self, id: &str) -> Result<bool> {
+        let found = self
+            .conn
+            .query_row("SELECT 1 FROM clips WHERE id = ? AND deleted = 0", [id], |_| Ok(()))
+            .optional()?;
+        Ok(found.is_some())
+    }
+
+    fn upload(&self, upload: &str) -> Result<Upload> {
+        self.conn
+            .query_row(
+                "SELECT id, size, blob_hash, meta, device FROM uploads WHERE upload = ?",
+                [upload],
+                |r| {
+                    Ok(Upload { id: r.get(0)?, size: r.get(1)?, blob_hash: r.get(2)?, meta: r.get(3)?, device: r.get(4)? })
+                },
+            )
+            .optional()?
+            .ok_or(StoreError::NotFound)
+    }
+
+    /// Starts an upload, or picks up the one already running for the same clip.
+    /// New bytes for a stored clip replace it once they're complete.
+    pub fn begin(&self, new: &NewUpload) -> Result<UploadStatus> {
+        if !protocol::valid_id(&new.id) {
+            return Err(StoreError::BadRequest(format!("invalid clip id '{}'", new.id)));
+        }
+        if new.blob_hash.len() != 64 || !protocol::valid_id(&new.blob_hash) {
+            return Err(StoreError::BadRequest("blob_hash must be 64 hex digits".to_string()));
+        }
+        if new.size > protocol::MAX_CLIP_SIZE {
+            return Err(StoreError::TooLarge);
+        }
+        let stored = self
+            .conn
+            .query_row("SELECT 1 FROM clips WHERE id = ? AND deleted = 0 AND blob_hash = ?", [&new.id, &new.blob_hash], |_| Ok(()))
+            .optional()?;
+        if stored.is_some() {
+            return Ok(UploadStatus { upload: None, offset: new.size, complete: true });
+        }
+
+        let running: Option<(String, u64, String)> = self
+            .conn
+            .query_row("SELECT upload, size, blob_hash FROM uploads WHERE id = ?", [&new.id], |r| {
+                Ok((r.get(0)?, r.get(1)?, r.get(2)?))
+            })
+            .optional()?;
+        if let Some((upload, size, blob_hash)) = running {
+            if size == new.size && blob_hash == new.blob_hash {
+                let offset = self.offset(&upload)?;
+                return Ok(UploadStatus { upload: Some(upload), offset, complete: false });
+            }
+            // Same clip, different bytes: start over
+            self.discard(&upload)?;
+        }
+
+        let upload = new_upload_id(&new.id);
+        File::create(self.part_path(&upload))?;
+        self.conn.execute(
+            "INSERT INTO uploads (upload, id, size, blob_hash, meta, device) VALUES (?, ?, ?, ?, ?, ?)",
+            params![upload, new.id, new.size, new.blob_hash, new.meta, new.device],
+        )?;
+        if new.size == 0 {
+            return self.finish(&upload);
+        }
+        Ok(UploadStatus { upload: Some(upload), offset: 0, complete: false })
+    }
+
+    /// How many bytes of the upload have arrived.
+    pub fn offset(&self, upload: &str) -> Result<u64> {
+        if !protocol::valid_id(upload) {
+            return Err(StoreError::NotFound);
+        }
+        self.upload(upload)?;
+        Ok(fs::metadata(self.part_path(upload))?.len())
+    }
+
+    /// Appends `body` at `offset`. Whatever arrives before a dropped connection
+    /// is kept, so the client can resume from the offset the server reports.
+    pub fn append(&self, upload: &str, offset: u64, body: &mut dyn Read) -> Result<UploadStatus> {
+        let current = self.offset(upload)?;
+        if offset != current {
+            return Err(StoreError::WrongOffset(current));
+        }
+        let info = self.upload(upload)?;
+        let remaining = info.size - current;
+
+        let mut part = OpenOptions::new().append(true).open(self.part_path(upload))?;
+        let limit = remaining.min(protocol::MAX_CHUNK_SIZE);
+        let written = io::copy(&mut body.take(limit), &mut part)?;
+        // Anything past the limit is more than the upload declared
+        let mut extra = [0u8; 1];
+        if body.read(&mut extra)? > 0 {
+            part.set_len(current)?;
+            return Err(if written < remaining {
+                StoreError::BadRequest(format!("chunks are limited to {} bytes", protocol::MAX_CHUNK_SIZE))
+            } else {
+                StoreError::BadRequest("more data than the upload declared".to_string())
+            });
+        }
+        part.flush()?;
+
+        if current + written == info.size {
+            return self.finish(upload);
+        }
+        Ok(UploadStatus { upload: Some(upload.to_string()), offset: current + written, complete: false })
+    }
+
+    fn finish(&self, upload: &str) -> Result<UploadStatus> {
+        let info = self.upload(upload)?;
+        let part = self.part_path(upload);
+        let mut hasher = blake3::Hasher::new();
+        io::copy(&mut File::open(&part)?, &mut hasher)?;
+        if hasher.finalize().to_hex().as_str() != info.blob_hash {
+            self.discard(upload)?;
+            return Err(StoreError::HashMismatch);
+        }
+
+        fs::rename(&part, self.blob_path(&info.id))?;
+        let tx = self.conn.unchecked_transaction()?;
+        // Replaces a tombstone, bringing the clip back for everyone
+        tx.execute(
+            "INSERT INTO clips (id, seq, size, meta, uploaded_at, device, deleted, blob_hash)
+             VALUES (?1, (SELECT COALESCE(MAX(seq), 0) + 1 FROM clips), ?2, ?3, ?4, ?5, 0, ?6)
+             ON CONFLICT(id) DO UPDATE SET
+                seq = excluded.seq, size = excluded.size, meta = excluded.meta, uploaded_at = excluded.uploaded_at,
+                device = excluded.device, deleted = 0, blob_hash = excluded.blob_hash",
+            params![info.id, info.size, info.meta, unix_now(), info.device, info.blob_hash],
+        )?;
+        tx.execute("DELETE FROM uploads WHERE upload = ?", [upload])?;
+        tx.commit()?;
+        Ok(UploadStatus { upload: None, offset: info.size, complete: true })
+    }
+
+    fn discard(&self, upload: &str) -> Result<()> {
+        match fs::remove_file(self.part_path(upload)) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e.into()),
+            _ => {}
+        }
+        self.conn.execute("DELETE FROM uploads WHERE upload = ?", [upload])?;
+        Ok(())
+    }
+
+    /// Turns the clip into a tombstone. Deleting an unknown or already deleted clip does nothing.
+    pub fn delete(&self, id: &str, device: &str) -> Result<()> {
+        if !protocol::valid_id(id) || !self.has_clip(id)? {
+            return Ok(());
+        }
+        self.conn.execute(
+            "UPDATE clips SET seq = (SELECT MAX(seq) + 1 FROM clips), size = 0, meta = '', blob_hash = '',
+             uploaded_at = ?, device = ?, deleted = 1 WHERE id = ?",
+            params![unix_now(), device, id],
+        )?;
+        match fs::remove_file(self.blob_path(id)) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e.into()),
+            _ => Ok(()),
+        }
+    }
+
+    /// Replaces a clip's meta, or a tombstone's, without touching its blob,
+    /// and moves it to the end of the change feed.
+    pub fn set_meta(&self, id: &str, meta: &str, device: &str) -> Result<()> {
+        if meta.len() as u64 > protocol::MAX_META_SIZE {
+            return Err(StoreError::BadRequest(format!("meta is limited to {} bytes", protocol::MAX_META_SIZE)));
+        }
+        if !protocol::valid_id(id) {
+            return Err(StoreError::NotFound);
+        }
+        let updated = self.conn.execute(
+            "UPDATE clips SET seq = (SELECT MAX(seq) + 1 FROM clips), meta = ?, uploaded_at = ?, device = ? WHERE id = ?",
+            params![meta, unix_now(), device, id],
+        )?;
+        if updated == 0 {
+            return Err(StoreError::NotFound);
+        }
+        Ok(())
+    }
+
+    /// Clips stored or deleted after `since`, oldest first.
+    pub fn changes(&self, since: i64, limit: usize) -> Result<Changes> {
+        let limit = limit.clamp(1, protocol::MAX_CHANGES);
+        let mut stmt = self.conn.prepare(
+            "SELECT seq, id, size, meta, uploaded_at, device, deleted FROM clips WHERE seq > ? ORDER BY seq LIMIT ?",
+        )?;
+        // One extra row tells whether there's another page
+        let mut changes = stmt
+            .query_map(params![since, limit as i64 + 1], |r| {
+                Ok(Change {
+                    seq: r.get(0)?,
+                    id: r.get(1)?,
+                    size: r.get(2)?,
+                    meta: r.get(3)?,
+                    uploaded_at: r.get(4)?,
+                    device: r.get(5)?,
+                    deleted: r.get(6)?,
+                })
+            })?
+            .collect::<rusqlite::Result<Vec<_>>>()?;
+        let more = changes.len() > limit;
+        changes.truncate(limit);
+        let next = changes.last().map_or(since, |c| c.seq);
+        Ok(Changes { changes, next, more })
+    }
+
+    pub fn blob(&self, id: &str) -> Result<File> {
+        if !protocol::valid_id(id) || !self.has_clip(id)? {
+            return Err(StoreError::NotFound);
+        }
+        Ok(File::open(self.blob_path(id))?)
+    }
+
+    /// The keyring and its version, if a client has stored one.
+    pub fn keyring(&self) -> Result<Option<(u64, Vec<u8>)>> {
+        let keyring = self
+            .conn
+            .query_row("SELECT version, data FROM keyring", [], |r| Ok((r.get(0)?, r.get(1)?)))
+            .optional()?;
+        Ok(keyring)
+    }
+
+    /// Stores a new keyring if the current one is still at version `replaces`
+    /// (0 when there's none yet). Returns the new version.
+    pub fn put_keyring(&self, replaces: u64, data: &[u8]) -> Result<u64> {
+        if data.len() as u64 > protocol::MAX_KEYRING_SIZE {
+            return Err(StoreError::TooLarge);
+        }
+        let tx = self.conn.unchecked_transaction()?;
+        let current: u64 = tx.query_row("SELECT version FROM keyring", [], |r| r.get(0)).optional()?.unwrap_or(0);
+        if current != replaces {
+            return Err(StoreError::KeyringChanged(current));
+        }
+        tx.execute("DELETE FROM keyring", [])?;
+        tx.execute("INSERT INTO keyring (version, data) VALUES (?, ?)", params![current + 1, data])?;
+        tx.commit()?;
+        Ok(current + 1)
+    }
+}
+
+fn unix_now() -> i64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs() as i64)
+}
+
+fn new_upload_id(clip: &str) -> String {
+    static COUNTER: AtomicU64 = AtomicU64::new(0);
+    let nanos = SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |d| d.as_nanos());
+    let mut hasher = blake3::Hasher::new();
+    hasher.update(clip.as_bytes());
+    hasher.update(&nanos.to_le_bytes());
+    hasher.update(&COUNTER.fetch_add(1, Ordering::Relaxed).to_le_bytes());
+    hasher.finalize().to_hex()[..32].to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A store in a fresh directory, removed when the returned guard drops.
+    fn temp_store() -> (tempfile::TempDir, Store) {
+        let dir = tempfile::tempdir().unwrap();
+        let store = Store::open(dir.path()).unwrap();
+        (dir, store)
+    }
+
+    fn new_upload(id: &str, blob: &[u8]) -> NewUpload {
+        NewUpload {
+            id: id.to_string(),
+            size: blob.len() as u64,
+            blob_hash: blake3::hash(blob).to_hex().to_string(),
+            meta: "{}".to_string(),
+            device: "laptop".to_string(),
+        }
+    }
+
+    #[test]
+    fn uploads_resume() {
+        let (_dir, store) = temp_store();
+        let blob = b"0123456789";
+        let status = store.begin(&new_upload("aa", blob)).unwrap();
+        let upload = status.upload.unwrap();
+        assert_eq!(store.append(&upload, 0, &mut &blob[..4]).unwrap().offset, 4);
+
+        // Asking again picks up where the first attempt stopped
+        let again = store.begin(&new_upload("aa", blob)).unwrap();
+        assert_eq!((again.upload.as_deref(), again.offset), (Some(upload.as_str()), 4));
+        assert!(matches!(store.append(&upload, 2, &mut &blob[2..]), Err(StoreError::WrongOffset(4))));
+        assert!(store.append(&upload, 4, &mut &blob[4..]).unwrap().complete);
+
+        assert!(store.begin(&new_upload("aa", blob)).unwrap().complete);
+        let changes = store.changes(0, 10).unwrap();
+        assert_eq!(changes.changes.iter().map(|c| c.id.as_str()).collect::<Vec<_>>(), ["aa"]);
+        let mut stored = Vec::new();
+        store.blob("aa").unwrap().read_to_end(&mut stored).unwrap();
+        assert_eq!(stored, blob);
+    }
+
+    #[test]
+    fn bad_uploads() {
+        let (_dir, store) = temp_store();
+        assert!(matches!(store.begin(&new_upload("../etc", b"x")), Err(StoreError::BadRequest(_))));
+
+        let mut wrong = new_upload("bb", b"abc");
+        wrong.blob_hash = blake3::hash(b"abd").to_hex().to_string();
+        let upload = store.begin(&wrong).unwrap().upload.unwrap();
+        assert!(matches!(store.append(&upload, 0, &mut &b"abc"[..]), Err(StoreError::HashMismatch)));
+        assert!(matches!(store.offset(&upload), Err(StoreError::NotFound)));
+
+        let upload = store.begin(&new_upload("cc", b"abc")).unwrap().upload.unwrap();
+        assert!(matches!(store.append(&upload, 0, &mut &b"abcd"[..]), Err(StoreError::BadRequest(_))));
+        assert_eq!(store.offset(&upload).unwrap(), 0);
+        assert!(store.changes(0, 10).unwrap().changes.is_empty());
+    }
+
+    #[test]
+    fn new_bytes_replace_a_clip() {
+        let (_dir, store) = temp_store();
+        let upload = store.begin(&new_upload("ee", b"old key")).unwrap().upload.unwrap();
+        store.append(&upload, 0, &mut &b"old key"[..]).unwrap();
+        let first = store.changes(0, 10).unwrap().next;
+
+        let upload = store.begin(&new_upload("ee", b"new key")).unwrap().upload.unwrap();
+        assert!(store.append(&upload, 0, &mut &b"new key"[..]).unwrap().complete);
+        assert_eq!(store.changes(first, 10).unwrap().changes[0].id, "ee");
+        let mut stored = Vec::new();
+        store.blob("ee").unwrap().read_to_end(&mut stored).unwrap();
+        assert_eq!(stored, b"new key");
+    }
+
+    #[test]
+    fn keyring_versions() {
+        let (_dir, store) = temp_store();
+        assert_eq!(store.keyring().unwrap(), None);
+        assert_eq!(store.put_keyring(0, b"first").unwrap(), 1);
+        // A second device that also saw no keyring loses the race
+        assert!(matches!(store.put_keyring(0, b"second"), Err(StoreError::KeyringChanged(1))));
+        assert_eq!(store.put_keyring(1, b"rotated").unwrap(), 2);
+        assert_eq!(store.keyring().unwrap(), Some((2, b"rotated".to_vec())));
+    }
+
+    #[test]
+    fn tombstones() {
+        let (_dir, store) = temp_store();
+        let upload = store.begin(&new_upload("dd", b"gone")).unwrap().upload.unwrap();
+        store.append(&upload, 0, &mut &b"gone"[..]).unwrap();
+        let stored = store.changes(0, 10).unwrap().next;
+
+        store.delete("dd", "desktop").unwrap();
+        let changes = store.changes(stored, 10).unwrap();
+        let tombstone = &changes.changes[0];
+        assert!(tombstone.deleted && tombstone.meta.is_empty() && tombstone.device == "desktop");
+        assert!(matches!(store.blob("dd"), Err(StoreError::NotFound)));
+        // Deleting twice doesn't add another change
+        store.delete("dd", "desktop").unwrap();
+        assert!(store.changes(changes.next, 10).unwrap().changes.is_empty());
+
+        let upload = store.begin(&new_upload("dd", b"gone")).unwrap().upload.unwrap();
+        assert!(store.append(&upload, 0, &mut &b"gone"[..]).unwrap().complete);
+        let revived = store.changes(changes.next, 10).unwrap();
+        assert!(!revived.changes[0].deleted);
+        assert_eq!(revived.changes[0].device, "laptop");
+    }
+
+    #[test]
+    fn meta_updates() {
+        let (_dir, store) = temp_store();
+        assert!(matches!(store.set_meta("ff", "pinned", "desktop"), Err(StoreError::NotFound)));
+        let upload = store.begin(&new_upload("ff", b"kept")).unwrap().upload.unwrap();
+        store.append(&upload, 0, &mut &b"kept"[..]).unwrap();
+        let stored = store.changes(0, 10).unwrap().next;
+
+        store.set_meta("ff", "pinned", "desktop").unwrap();
+        let changes = store.changes(stored, 10).unwrap();
+        assert_eq!((changes.changes[0].meta.as_str(), changes.changes[0].device.as_str()), ("pinned", "desktop"));
+        let mut blob = Vec::new();
+        store.blob("ff").unwrap().read_to_end(&mut blob).unwrap();
+        assert_eq!(blob, b"kept");
+
+        // A tombstone can carry meta too, and stays a tombstone
+        store.delete("ff", "desktop").unwrap();
+        store.set_meta("ff", "deleted", "desktop").unwrap();
+        let tombstone = store.changes(changes.next, 10).unwrap().changes.remove(0);
+        assert!(tombstone.deleted && tombstone.meta == "deleted");
+        assert!(matches!(store.blob("ff"), Err(StoreError::NotFound)));
+    }
+
+    #[test]
+    fn changes_page() {
+        let (_dir, store) = temp_store();
+        for id in ["a1", "a2", "a3"] {
+            let upload = store.begin(&new_upload(id, id.as_bytes())).unwrap().upload.unwrap();
+            store.append(&upload, 0, &mut id.as_bytes()).unwrap();
+        }
+        let first = store.changes(0, 2).unwrap();
+        assert!(first.more);
+        let second = store.changes(first.next, 2).unwrap();
+        assert!(!second.more);
+        assert_eq!(second.changes[0].id, "a3");
+        assert_eq!(store.changes(second.next, 2).unwrap().next, second.next);
+    }
+}
@@ -1,26 +1,487 @@
 use eframe::egui;
-use std::collections::HashSet;
+use std::collections::{HashMap, HashSet};
+use std::net::SocketAddr;
+use std::path::PathBuf;
 use std::sync::Arc;
 use std::sync::atomic::{AtomicBool, Ordering};
 
-use crate::models::ClipSummary;
+use crate::models::{ClipSummary, ClipboardPayload, CloudClip, Device, FormatInfo, Peer, PushState};
+use crate::autosync::{self, SyncState, SyncTask};
 use crate::storage::Database;
-use crate::cloudstorage::CloudDatabase;
-use crate::{OpenClipboard, HWND, EmptyClipboard, GlobalAlloc, GMEM_MOVEABLE, GlobalLock, GlobalUnlock, SetClipboardData, HANDLE, CloseClipboard};
+use crate::cloudstorage::{Cleanup, CloudDatabase, CloudStats, DeviceUsage};
+use crate::config::{Config, CliOverrides};
+use crate::hotkeys::{self, HotkeyAction};
+use crate::query::{self, Query};
+use crate::imaging;
+use crate::inspect;
+use crate::formats::{self, RestoreMode};
+use crate::richtext::{self, BlockKind};
+use crate::files::{self, FileEntry};
+use crate::classify::{self, Kind, Language};
+use crate::highlight::{self, TokenKind};
+use crate::transform::{self, Action, Transform};
+use crate::lan::Nearby;
+use crate::lanshare::{self, LanState};
+
+const SEARCH_ID: &str = "history_search";
+/// Thumbnail textures kept across pages before the least recently shown are dropped.
+const THUMBNAIL_TEXTURES: usize = 300;
+
+/// What a key press in the history list asks for, applied after the frame is drawn.
+enum KeyCommand {
+    Restore(String, RestoreMode),
+    RestoreAndPaste(String),
+    Delete(String),
+}
+
+/// What the cloud browser asks for, applied after the frame is drawn.
+enum CloudCommand {
+    Import(String),
+    Restore(String, RestoreMode),
+    Delete(Vec<String>),
+    /// Count what a cleanup would delete and ask first.
+    PlanCleanup(Cleanup),
+    Task(SyncTask),
+    /// Give a clip that failed to upload another go.
+    Retry(String),
+    /// Revoke a device, moving the keyring to this new passphrase.
+    Revoke(String, String),
+}
+
+/// What the Nearby window asks for, applied after the frame is drawn.
+enum NearbyCommand {
+    /// Pair with a device using the code typed for it.
+    Pair(Nearby, String),
+    ShowCode,
+    HideCode,
+    /// Link the clipboard with this peer, or unlink it.
+    Link(Option<Peer>),
+    Forget(Peer),
+}
+
+/// Other openclips on the local network.
+struct NearbyWindow {
+    /// Pairing codes typed so far, by device id.
+    codes: HashMap<String, String>,
+}
+
+/// Clips in cloud.db, including the ones pulled from other devices.
+struct CloudBrowser {
+    clips: Vec<CloudClip>,
+    /// Hashes the local history already has.
+    in_history: HashSet<String>,
+    filter: String,
+    status: Option<String>,
+    stats: CloudStats,
+    cleanup_days: u32,
+    /// A bulk delete waiting for confirmation: its description and the clips it takes.
+    confirm: Option<(String, Vec<String>)>,
+    recovery_input: String,
+    /// The sync registry as of the last sync.
+    devices: Vec<Device>,
+    own_device: String,
+    /// A device waiting to be revoked, and the new passphrase typed so far.
+    revoke: Option<(String, String)>,
+}
+
+/// Full-size view of an image clip.
+struct ImageViewer {
+    hash: String,
+    texture: egui::TextureHandle,
+    zoom: f32,
+}
+
+/// Expanded, styled view of a clip's HTML or RTF.
+struct RichPreview {
+    hash: String,
+    blocks: Vec<richtext::Block>,
+}
+
+/// Full text of a code clip, highlighted once when opened.
+struct CodePreview {
+    hash: String,
+    text: String,
+    language: Option<Language>,
+    lines: Vec<Vec<highlight::Token>>,
+}
+
+impl CodePreview {
+    fn set_language(&mut self, language: Option<Language>) {
+        self.language = language;
+        self.lines = highlight::highlight(&self.text, language);
+    }
+}
+
+fn token_color(kind: TokenKind, dark: bool) -> Option<egui::Color32> {
+    use egui::Color32;
+    let (dark_color, light_color) = match kind {
+        TokenKind::Plain => return None,
+        TokenKind::Keyword => (Color32::from_rgb(86, 156, 214), Color32::from_rgb(0, 0, 200)),
+        TokenKind::Type => (Color32::from_rgb(78, 201, 176), Color32::from_rgb(38, 127, 153)),
+        TokenKind::String => (Color32::from_rgb(206, 145, 120), Color32::from_rgb(163, 21, 21)),
+        TokenKind::Number => (Color32::from_rgb(181, 206, 168), Color32::from_rgb(9, 134, 88)),
+        TokenKind::Comment => (Color32::from_rgb(106, 153, 85), Color32::from_rgb(0, 128, 0)),
+    };
+    Some(if dark { dark_color } else { light_color })
+}
+
+/// One numbered line of the code preview as a single monospace galley.
+fn code_line_job(ui: &egui::Ui, number: usize, width: usize, tokens: &[highlight::Token]) -> egui::text::LayoutJob {
+    let font = egui::TextStyle::Monospace.resolve(ui.style());
+    let visuals = ui.visuals();
+    let mut job = egui::text::LayoutJob::default();
+    job.append(
+        &format!("{:>width$}  ", number, width = width),
+        0.0,
+        egui::TextFormat::simple(font.clone(), visuals.weak_text_color()),
+    );
+    for token in tokens {
+        let color = token_color(token.kind, visuals.dark_mode).unwrap_or(visuals.text_color());
+        let mut format = egui::TextFormat::simple(font.clone(), color);
+        format.italics = token.kind == TokenKind::Comment;
+        job.append(&token.text, 0.0, format);
+    }
+    job
+}
+
+const HEX_ROWS_PER_PAGE: usize = 32;
+
+/// Format list and hex view for one clip.
+struct Inspector {
+    hash: String,
+    formats: Vec<FormatInfo>,
+    selected: Option<usize>,
+    data: Vec<u8>,
+    structure: Option<inspect::Fields>,
+    offset: usize,
+    goto: String,
+    search: String,
+    last_match: Option<usize>,
+    export_path: String,
+    status: Option<String>,
+}
+
+impl Inspector {
+    fn page_size() -> usize {
+        HEX_ROWS_PER_PAGE * inspect::BYTES_PER_ROW
+    }
+
+    fn jump_to(&mut self, offset: usize) {
+        let row_start = offset - offset % inspect::BYTES_PER_ROW;
+        self.offset = row_start.min(self.data.len().saturating_sub(1) / Self::page_size() * Self::page_size());
+    }
+}
+
+fn plural(n: usize, noun: &str) -> String {
+    format!("{} {}{}", n, noun, if n == 1 { "" } else { "s" })
+}
+
+/// "This device" for our own id, the registry's name or a short id for the others.
+fn device_label(device: &str, stats: &CloudStats) -> String {
+    match stats.devices.iter().find(|d| d.device == device) {
+        Some(usage) if usage.this_device => "this device".to_string(),
+        Some(DeviceUsage { name: Some(name), .. }) => name.clone(),
+        _ if device.is_empty() => "unknown device".to_string(),
+        _ => format!("device {}", &device[..8.min(device.len())]),
+    }
+}
+
+/// Icon for a clip that's in cloud.db but not on the server yet. `running`
+/// is whether the sync thread is busy, which is when pending clips go up.
+fn push_status(state: &PushState, running: bool) -> (egui::RichText, String) {
+    match state {
+        PushState::Uploaded => (egui::RichText::new("☁").color(egui::Color32::from_rgb(100, 160, 255)), "In the cloud".to_string()),
+        PushState::Pending if running => (egui::RichText::new("⟳").weak(), "Uploading".to_string()),
+        PushState::Pending => (egui::RichText::new("⏳").weak(), "Waiting to upload".to_string()),
+        PushState::Failed(e) => (egui::RichText::new("⚠").color(egui::Color32::from_rgb(220, 90, 70)), format!("Upload failed: {}", e)),
+    }
+}
+
+fn push_status_ui(ui: &mut egui::Ui, state: &PushState, running: bool) -> egui::Response {
+    let (icon, hover) = push_status(state, running);
+    ui.label(icon).on_hover_text(hover)
+}
+
+/// Usage, sync state and bulk cleanup for the cloud browser.
+fn cloud_manage_ui(ui: &mut egui::Ui, browser: &mut CloudBrowser, sync_enabled: bool) -> Option<CloudCommand> {
+    let mut command = None;
+    let stats = &browser.stats;
+    ui.label(format!("{} using {}", plural(stats.clips, "clip"), files::format_size(stats.bytes)));
+    if sync_enabled {
+        ui.weak(format!(
+            "Last sync: {}  ·  {} to upload, {} to delete",
+            stats.last_sync.as_deref().unwrap_or("never"),
+            stats.pending_uploads,
+            stats.pending_deletes
+        ));
+        if stats.failed_uploads > 0 {
+            ui.colored_label(
+                egui::Color32::from_rgb(220, 90, 70),
+                format!("{} failed to upload; retry them from the list", plural(stats.failed_uploads, "clip")),
+            );
+        }
+    }
+
+    egui::Grid::new("cloud_devices").num_columns(4).striped(true).show(ui, |ui| {
+        for usage in &stats.devices {
+            ui.label(device_label(&usage.device, stats));
+            ui.label(plural(usage.clips, "clip"));
+            ui.weak(files::format_size(usage.bytes));
+            if ui.small_button("Delete all").clicked() {
+                command = Some(CloudCommand::PlanCleanup(Cleanup::Device(usage.device.clone())));
+            }
+            ui.end_row();
+        }
+    });
+
+    ui.horizontal(|ui| {
+        if ui.button("Delete older than").clicked() {
+            command = Some(CloudCommand::PlanCleanup(Cleanup::OlderThanDays(browser.cleanup_days)));
+        }
+        ui.add(egui::DragValue::new(&mut browser.cleanup_days).clamp_range(1..=3650).suffix(" days"));
+        if ui.button("Delete everything").clicked() {
+            command = Some(CloudCommand::PlanCleanup(Cleanup::All));
+        }
+    });
+
+    let mut cancel = false;
+    if let Some((question, hashes)) = &browser.confirm {
+        ui.horizontal(|ui| {
+            ui.colored_label(ui.visuals().warn_fg_color, question);
+            if ui.button("Delete").clicked() {
+                command = Some(CloudCommand::Delete(hashes.clone()));
+            }
+            cancel = ui.button("Cancel").clicked();
+        });
+    }
+    if cancel {
+        browser.confirm = None;
+    }
+    command
+}
+
+/// The sync set's devices. Revoking one takes a new passphrase, since the
+/// revoked device knows the current one.
+fn devices_ui(ui: &mut egui::Ui, browser: &mut CloudBrowser) -> Option<CloudCommand> {
+    let mut command = None;
+    ui.label("Devices");
+    if browser.devices.is_empty() {
+        ui.weak("Devices join the registry on their first sync");
+    }
+    egui::Grid::new("sync_devices").num_columns(2).striped(true).show(ui, |ui| {
+        for device in &browser.devices {
+            let name = ui.label(&device.name);
+            name.on_hover_text(format!("Id {}", device.id));
+            if device.id == browser.own_device {
+                ui.weak("this device");
+            } else if device.revoked {
+                ui.colored_label(ui.visuals().warn_fg_color, "revoked");
+            } else if ui.small_button("Revoke…").on_hover_text("Cut it off from the sync set, e.g. if it was lost").clicked() {
+                browser.revoke = Some((device.id.clone(), String::new()));
+            }
+            ui.end_row();
+        }
+    });
+
+    let mut cancel = false;
+    if let Some((id, passphrase)) = &mut browser.revoke {
+        let name = browser.devices.iter().find(|d| d.id == *id).map_or(id.as_str(), |d| d.name.as_str());
+        ui.colored_label(
+            ui.visuals().warn_fg_color,
+            format!("{} will stop getting new clips. Choose a new sync passphrase; the other devices keep working and can be given it later.", name),
+        );
+        ui.horizontal(|ui| {
+            ui.add(egui::TextEdit::singleline(passphrase).password(true).hint_text("New passphrase"));
+            if ui.add_enabled(!passphrase.is_empty(), egui::Button::new("Revoke")).clicked() {
+                command = Some(CloudCommand::Revoke(id.clone(), std::mem::take(passphrase)));
+                cancel = true;
+            }
+            cancel |= ui.button("Cancel").clicked();
+        });
+    }
+    if cancel {
+        browser.revoke = None;
+    }
+    command
+}
+
+/// Recovery key and key rotation for the sync server's keyring.
+fn encryption_ui(ui: &mut egui::Ui, browser: &mut CloudBrowser, recovery_key: &mut Option<String>) -> Option<SyncTask> {
+    let mut task = None;
+    ui.horizontal(|ui| {
+        ui.label("Encryption");
+        if ui.button("Show recovery key").on_hover_text("Unlocks the keyring if the passphrase is ever lost").clicked() {
+            task = Some(SyncTask::ShowRecoveryKey);
+        }
+        if ui
+            .button("Rotate key")
+            .on_hover_text("Seals every clip with a new key; recovery keys shown before stop working")
+            .clicked()
+        {
+            task = Some(SyncTask::RotateKey);
+        }
+    });
+    let mut hide = false;
+    if let Some(key) = recovery_key.as_deref() {
+        ui.horizontal(|ui| {
+            ui.monospace(key);
+            if ui.small_button("Copy").clicked() {
+                // Written like a restore, so it stays out of the history
+                let data = formats::utf8_to_utf16(key);
+                crate::write_clipboard(&[ClipboardPayload {
+                    format_id: formats::CF_UNICODETEXT,
+                    format_name: "CF_UNICODETEXT".to_string(),
+                    data,
+                }]);
+            }
+            hide = ui.small_button("Hide").clicked();
+        });
+        ui.weak("Keep it somewhere safe, away from this machine.");
+    }
+    if hide {
+        *recovery_key = None;
+    }
+    ui.horizontal(|ui| {
+        ui.add(egui::TextEdit::singleline(&mut browser.recovery_input).hint_text("recovery key").desired_width(260.0));
+        let ready = !browser.recovery_input.trim().is_empty();
+        if ui
+            .add_enabled(ready, egui::Button::new("Recover"))
+            .on_hover_text("Locks the keyring with this device's passphrase; other devices then need it too")
+            .clicked()
+        {
+            task = Some(SyncTask::Recover(std::mem::take(&mut browser.recovery_input)));
+        }
+    });
+    task
+}
+
+/// Draws preview blocks with egui text styles; links open in the browser.
+fn rich_text_ui(ui: &mut egui::Ui, blocks: &[richtext::Block]) {
+    for block in blocks {
+        let spans = |ui: &mut egui::Ui, heading: Option<u8>| {
+            ui.spacing_mut().item_spacing.x = 0.0;
+            for span in &block.spans {
+                let mut text = egui::RichText::new(&span.text);
+                if let Some(level) = heading {
+                    text = text.size(24.0 - 2.0 * level.min(5) as f32).strong();
+                }
+                if span.style.bold { text = text.strong(); }
+                if span.style.italic { text = text.italics(); }
+                if span.style.underline { text = text.underline(); }
+                if span.style.strike { text = text.strikethrough(); }
+                if span.style.code { text = text.code(); }
+                match &span.style.link {
+                    Some(url) => { ui.hyperlink_to(text, url).on_hover_text(url); }
+                    None => { ui.label(text); }
+                }
+            }
+        };
+        match &block.kind {
+            BlockKind::Paragraph => { ui.horizontal_wrapped(|ui| spans(ui, None)); }
+            BlockKind::Heading(level) => { ui.horizontal_wrapped(|ui| spans(ui, Some(*level))); }
+            BlockKind::ListItem { marker, depth } => {
+                ui.horizontal_wrapped(|ui| {
+                    ui.add_space(16.0 * (*depth as f32 + 1.0));
+                    ui.label(format!("{} ", marker));
+                    spans(ui, None);
+                });
+            }
+            BlockKind::Quote => {
+                ui.horizontal(|ui| {
+                    ui.add_space(8.0);
+                    ui.separator();
+                    ui.horizontal_wrapped(|ui| spans(ui, None));
+                });
+            }
+            BlockKind::Code => {
+                let text: String = block.spans.iter().map(|s| s.text.as_str()).collect();
+                egui::Frame::group(ui.style())
+                    .fill(ui.visuals().code_bg_color)
+                    .show(ui, |ui| ui.label(egui::RichText::new(text).monospace()));
+            }
+        }
+    }
+}
+
+const FILE_ROWS_SHOWN: usize = 5;
+
+/// Icon, name and size per path, flagging files that are gone.
+fn file_list_ui(ui: &mut egui::Ui, entries: &[FileEntry]) {
+    for entry in entries.iter().take(FILE_ROWS_SHOWN) {
+        ui.horizontal(|ui| {
+            ui.label(entry.icon());
+            ui.label(entry.name()).on_hover_text(&entry.path);
+            if let Some(size) = entry.size {
+                ui.weak(files::format_size(size));
+            }
+            if !entry.exists {
+                let note = if entry.snapshotted { "restored from snapshot" } else { "can't be restored" };
+                ui.colored_label(egui::Color32::from_rgb(230, 160, 60), "⚠ missing")
+                    .on_hover_text(format!("{} no longer exists; {}", entry.path, note));
+            } else if entry.snapshotted {
+                ui.weak("snapshot");
+            }
+        });
+    }
+    if entries.len() > FILE_ROWS_SHOWN {
+        ui.weak(format!("… and {} more", entries.len() - FILE_ROWS_SHOWN));
+    }
+}
+
+fn rgba_texture(ctx: &egui::Context, name: String, img: &image::RgbaImage) -> egui::TextureHandle {
+    let size = [img.width() as usize, img.height() as usize];
+    let color = egui::ColorImage::from_rgba_unmultiplied(size, img.as_raw());
+    ctx.load_texture(name, color, egui::TextureOptions::LINEAR)
+}
 
 pub struct App {
     history: Vec<ClipSummary>,
+    // None marks a thumbnail that failed to load so it isn't retried every frame;
+    // the number is the last frame its clip was on the page, for eviction
+    thumbnails: HashMap<String, (Option<egui::TextureHandle>, u64)>,
+    thumbnail_frame: u64,
+    // Re-read on every refresh so the missing-file flags stay current
+    file_lists: HashMap<String, Vec<FileEntry>>,
+    viewer: Option<ImageViewer>,
+    inspector: Option<Inspector>,
+    preview: Option<RichPreview>,
+    code: Option<CodePreview>,
+    cloud_browser: Option<CloudBrowser>,
+    nearby: Option<NearbyWindow>,
+    selected: usize,
+    scroll_to_selected: bool,
     db_path: String,
     cloud_db_path: String,
+    db_key: String,
     current_page: i32,
     items_per_page: i32,
+    preview_length: usize,
     total_count: i32,
-    synced_hashes: HashSet<String>,
+    /// Where each clip in cloud.db stands with the server.
+    cloud_states: HashMap<String, PushState>,
+    sync_state: Arc<SyncState>,
+    /// The clip whose tags are being edited and the text typed so far.
+    tag_edit: Option<(String, String)>,
+    /// Same for the note.
+    note_edit: Option<(String, String)>,
     visible: Arc<AtomicBool>,
     last_visible: bool,
     last_focused: bool,
     has_ever_focused: bool,
     needs_refresh: Arc<AtomicBool>,
+    search: String,
+    query: Query,
+    query_error: Option<String>,
+    /// Restore a transform's result as soon as it's created.
+    transform_restore: bool,
+    transform_error: Option<String>,
+    focus_search: Arc<AtomicBool>,
+    config: Config,
+    config_path: PathBuf,
+    cli: CliOverrides,
+    config_changed: Arc<AtomicBool>,
+    settings_draft: Option<Config>,
+    settings_error: Option<String>,
 }
 
 impl App {
@@ -28,30 +489,253 @@ impl App {
         cc: &eframe::CreationContext<'_>,
         visible: Arc<AtomicBool>,
         needs_refresh: Arc<AtomicBool>,
+        focus_search: Arc<AtomicBool>,
+        config_changed: Arc<AtomicBool>,
+        config_path: PathBuf,
+        cli: CliOverrides,
     ) -> Self {
         let _ = crate::EGUI_CTX.set(cc.egui_ctx.clone());
+        let config = crate::current_config();
 
         let mut app = Self {
             history: Vec::new(),
-            db_path: "clipboard.db".to_string(),
-            cloud_db_path: "cloud.db".to_string(),
+            thumbnails: HashMap::new(),
+            thumbnail_frame: 0,
+            file_lists: HashMap::new(),
+            viewer: None,
+            inspector: None,
+            preview: None,
+            code: None,
+            cloud_browser: None,
+            nearby: None,
+            selected: 0,
+            scroll_to_selected: false,
+            db_path: config.database_path(),
+            cloud_db_path: config.cloud_database_path(),
+            db_key: config.storage.key.clone(),
             current_page: 0,
-            items_per_page: 20,
+            items_per_page: config.ui.items_per_page,
+            preview_length: config.ui.preview_length,
             total_count: 0,
-            synced_hashes: HashSet::new(),
+            cloud_states: HashMap::new(),
+            sync_state: crate::sync_state(),
+            tag_edit: None,
+            note_edit: None,
             visible,
             last_visible: true,
             last_focused: false,
             has_ever_focused: false,
             needs_refresh,
+            search: String::new(),
+            query: Query::default(),
+            query_error: None,
+            transform_restore: false,
+            transform_error: None,
+            focus_search,
+            config,
+            config_path,
+            cli,
+            config_changed,
+            settings_draft: None,
+            settings_error: None,
         };
         app.refresh_history();
         app
     }
 
+    fn apply_config(&mut self, config: Config) {
+        self.db_path = config.database_path();
+        self.cloud_db_path = config.cloud_database_path();
+        self.db_key = config.storage.key.clone();
+        self.items_per_page = config.ui.items_per_page;
+        self.preview_length = config.ui.preview_length;
+        if config.hotkeys != self.config.hotkeys {
+            // Validated on load, so this only fails if the file changed underneath us
+            if let Ok(bindings) = hotkeys::parse_bindings(&config.hotkeys) {
+                crate::reload_hotkeys(bindings);
+            }
+        }
+        let device_changed = config.sync.device_name != self.config.sync.device_name
+            || config.database_path() != self.config.database_path();
+        crate::set_config(config.clone());
+        if config.lan.enabled {
+            crate::start_lan(&config);
+        }
+        if device_changed {
+            autosync::register_this_device(&config);
+            // Passes the new name on to the registry
+            self.start_sync();
+        }
+        self.config = config;
+        self.refresh_history();
+    }
+
+    fn reload_config(&mut self) {
+        match Config::load(&self.config_path, &self.cli) {
+            Ok(config) => {
+                if config != self.config {
+                    println!("Reloaded {}", self.config_path.display());
+                    self.apply_config(config);
+                }
+                self.settings_error = None;
+            }
+            Err(e) => {
+                eprintln!("config reload failed: {}", e);
+                self.settings_error = Some(e.to_string());
+            }
+        }
+    }
+
+    fn save_settings(&mut self, draft: Config) {
+        match draft.save(&self.config_path, &self.cli) {
+            Ok(()) => {
+                self.settings_draft = None;
+                self.settings_error = None;
+                // The file watcher will notice the write; apply now so the UI doesn't lag a second
+                let mut effective = draft;
+                self.cli.apply(&mut effective);
+                self.apply_config(effective);
+            }
+            Err(e) => self.settings_error = Some(e.to_string()),
+        }
+    }
+
+    fn settings_window(&mut self, ctx: &egui::Context) {
+        let Some(mut draft) = self.settings_draft.take() else { return };
+        let mut open = true;
+        let mut save = false;
+        let mut cancel = false;
+
+        egui::Window::new("Settings")
+            .open(&mut open)
+            .collapsible(false)
+            .show(ctx, |ui| {
+                ui.weak(self.config_path.display().to_string());
+                let overridden = self.cli.overridden();
+                if !overridden.is_empty() {
+                    ui.weak(format!("Set on the command line, which wins over these: {}", overridden.join(", ")));
+                }
+                egui::Grid::new("settings_grid").num_columns(2).show(ui, |ui| {
+                    let mut database = draft.storage.database.to_string_lossy().into_owned();
+                    ui.label("History database");
+                    if ui.text_edit_singleline(&mut database).changed() {
+                        draft.storage.database = PathBuf::from(database);
+                    }
+                    ui.end_row();
+
+                    let mut cloud = draft.storage.cloud_database.to_string_lossy().into_owned();
+                    ui.label("Cloud database");
+                    if ui.text_edit_singleline(&mut cloud).changed() {
+                        draft.storage.cloud_database = PathBuf::from(cloud);
+                    }
+                    ui.end_row();
+
+                    ui.label("Database key");
+                    ui.add(egui::TextEdit::singleline(&mut draft.storage.key).password(true));
+                    ui.end_row();
+
+                    ui.label("Sync server");
+                    ui.add(egui::TextEdit::singleline(&mut draft.sync.server).hint_text("https://… (blank: local only)"));
+                    ui.end_row();
+
+                    ui.label("Sync token");
+                    ui.add(egui::TextEdit::singleline(&mut draft.sync.token).password(true));
+                    ui.end_row();
+
+                    let mut folder = draft.sync.folder.to_string_lossy().into_owned();
+                    ui.label("Sync folder");
+                    if ui.add(egui::TextEdit::singleline(&mut folder).hint_text("instead of a server: a Syncthing or shared folder")).changed() {
+                        draft.sync.folder = PathBuf::from(folder);
+                    }
+                    ui.end_row();
+
+                    ui.label("Sync passphrase");
+                    ui.add(egui::TextEdit::singleline(&mut draft.sync.passphrase).password(true))
+                        .on_hover_text("Encrypts clips before they leave this machine; use the same one on every device");
+                    ui.end_row();
+
+                    ui.label("Device name");
+                    ui.add(egui::TextEdit::singleline(&mut draft.sync.device_name).hint_text(self.config.sync.device_name()))
+                        .on_hover_text("Shown on clips copied here and in other devices' Cloud window");
+                    ui.end_row();
+
+                    ui.label("Nearby devices");
+                    ui.checkbox(&mut draft.lan.enabled, "pair with and send clips to devices on this network");
+                    ui.end_row();
+
+                    ui.label("Items per page");
+                    ui.add(egui::DragValue::new(&mut draft.ui.items_per_page).clamp_range(1..=500));
+                    ui.end_row();
+
+                    ui.label("Preview length");
+                    ui.add(egui::DragValue::new(&mut draft.ui.preview_length).clamp_range(1..=10_000));
+                    ui.end_row();
+
+                    ui.label("Window title");
+                    ui.text_edit_singleline(&mut draft.ui.window_title);
+                    ui.end_row();
+
+                    ui.label("Paste after restore");
+                    ui.checkbox(&mut draft.paste.auto_paste, "into the previously focused window");
+                    ui.end_row();
+
+                    ui.label("Copied files");
+                    ui.horizontal(|ui| {
+                        ui.checkbox(&mut draft.files.snapshot, "keep copies up to");
+                        ui.add_enabled(
+                            draft.files.snapshot,
+                            egui::DragValue::new(&mut draft.files.snapshot_max_kb).clamp_range(1..=65_536).suffix(" KB"),
+                        );
+                    });
+                    ui.end_row();
+                });
+                ui.weak("Window title changes, and turning nearby devices off, apply after a restart.");
+
+                egui::CollapsingHeader::new("Hotkeys").show(ui, |ui| {
+                    egui::Grid::new("hotkey_grid").num_columns(2).show(ui, |ui| {
+                        let transforms = draft.transforms.iter().map(|t| HotkeyAction::Transform(t.name.clone()));
+                        for action in HotkeyAction::all().into_iter().chain(transforms.collect::<Vec<_>>()) {
+                            let name = action.to_string();
+                            let mut combo = draft.hotkeys.get(&name).cloned().unwrap_or_default();
+                            ui.label(&name);
+                            if ui.add(egui::TextEdit::singleline(&mut combo).hint_text("unbound")).changed() {
+                                if combo.trim().is_empty() {
+                                    draft.hotkeys.remove(&name);
+                                } else {
+                                    draft.hotkeys.insert(name, combo);
+                                }
+                            }
+                            ui.end_row();
+                        }
+                    });
+                });
+
+                if let Some(err) = &self.settings_error {
+                    ui.colored_label(egui::Color32::from_rgb(230, 90, 90), err);
+                }
+
+                ui.horizontal(|ui| {
+                    if ui.button("Save").clicked() {
+                        save = true;
+                    }
+                    if ui.button("Cancel").clicked() {
+                        cancel = true;
+                    }
+                });
+            });
+
+        if save {
+            self.save_settings(draft);
+        } else if open && !cancel {
+            self.settings_draft = Some(draft);
+        } else {
+            self.settings_error = None;
+        }
+    }
+
     fn refresh_history(&mut self) {
-        if let Ok(db) = Database::new(&self.db_path, "pwd") {
-            self.total_count = db.get_total_count().unwrap_or(0);
+        if let Ok(db) = Database::new(&self.db_path, &self.db_key) {
+            self.total_count = db.get_total_count(&self.query).unwrap_or(0);
 
             let max_pages = ((self.total_count as f32 / self.items_per_page as f32).ceil() as i32).max(1);
             if self.current_page >= max_pages {
@@ -59,19 +743,711 @@ impl App {
             }
 
             let offset = self.current_page * self.items_per_page;
-            if let Ok(clips) = db.get_latest_clips(self.items_per_page, offset) {
+            if let Ok(clips) = db.get_latest_clips(&self.query, self.items_per_page, offset, self.preview_length) {
                 self.history = clips;
+                self.selected = self.selected.min(self.history.len().saturating_sub(1));
+            }
+            self.load_file_lists(&db);
+        }
+
+        if let Ok(cloud) = CloudDatabase::new(&self.cloud_db_path, &self.db_key) {
+            self.cloud_states = cloud.push_states().unwrap_or_default();
+        }
+        if self.cloud_browser.is_some() {
+            self.load_cloud_browser();
+        }
+    }
+
+    fn load_file_lists(&mut self, db: &Database) {
+        self.file_lists.clear();
+        for clip in self.history.iter().filter(|c| c.has_files && !c.is_sensitive) {
+            let Ok(payloads) = db.get_clip_payloads(&clip.hash) else { continue };
+            let Some(paths) = files::clip_paths(&payloads) else { continue };
+            let snapshotted: HashSet<String> = db.get_snapshot_paths(&clip.hash).unwrap_or_default().into_iter().collect();
+            self.file_lists.insert(clip.hash.clone(), files::describe(&paths, &snapshotted));
+        }
+    }
+
+    fn load_thumbnails(&mut self, ctx: &egui::Context) {
+        self.thumbnail_frame += 1;
+        let mut missing = Vec::new();
+        for clip in self.history.iter().filter(|c| c.has_thumbnail) {
+            match self.thumbnails.get_mut(&clip.hash) {
+                Some((_, shown)) => *shown = self.thumbnail_frame,
+                None => missing.push(clip.hash.clone()),
+            }
+        }
+        if missing.is_empty() {
+            return;
+        }
+        let Ok(db) = Database::new(&self.db_path, &self.db_key) else { return };
+        for hash in missing {
+            let texture = db
+                .get_thumbnail(&hash)
+                .ok()
+                .flatten()
+                .and_then(|png| imaging::decode_png(&png))
+                .map(|img| rgba_texture(ctx, format!("thumb-{}", hash), &img));
+            self.thumbnails.insert(hash, (texture, self.thumbnail_frame));
+        }
+        // Drop the textures shown longest ago; the current page is always newest
+        if self.thumbnails.len() > THUMBNAIL_TEXTURES {
+            let mut shown: Vec<u64> = self.thumbnails.values().map(|(_, frame)| *frame).collect();
+            shown.sort_unstable();
+            let cutoff = shown[shown.len() - THUMBNAIL_TEXTURES];
+            self.thumbnails.retain(|_, (_, frame)| *frame >= cutoff);
+        }
+    }
+
+    fn open_viewer(&mut self, ctx: &egui::Context, hash: &str) {
+        let Ok(db) = Database::new(&self.db_path, &self.db_key) else { return };
+        let image = db
+            .get_clip_payloads(hash)
+            .ok()
+            .and_then(|payloads| imaging::decode_clip_image(&payloads));
+        match image {
+            Some(img) => {
+                self.viewer = Some(ImageViewer {
+                    hash: hash.to_string(),
+                    texture: rgba_texture(ctx, format!("full-{}", hash), &img),
+                    zoom: 1.0,
+                });
+            }
+            None => eprintln!("open_viewer: {} has no decodable image", hash),
+        }
+    }
+
+    fn viewer_window(&mut self, ctx: &egui::Context) {
+        let Some(viewer) = &mut self.viewer else { return };
+        let mut open = true;
+        let size = viewer.texture.size_vec2();
+
+        egui::Window::new("Image")
+            .id(egui::Id::new("image_viewer"))
+            .open(&mut open)
+            .default_size([640.0, 480.0])
+            .resizable(true)
+            .show(ctx, |ui| {
+                ui.horizontal(|ui| {
+                    if ui.button("−").clicked() {
+                        viewer.zoom = (viewer.zoom / 1.25).max(0.05);
+                    }
+                    ui.label(format!("{:.0}%", viewer.zoom * 100.0));
+                    if ui.button("+").clicked() {
+                        viewer.zoom = (viewer.zoom * 1.25).min(16.0);
+                    }
+                    if ui.button("100%").clicked() {
+                        viewer.zoom = 1.0;
+                    }
+                    if ui.button("Fit").clicked() {
+                        let avail = ui.available_size();
+                        viewer.zoom = (avail.x / size.x).min(avail.y / size.y).clamp(0.05, 16.0);
+                    }
+                    ui.weak(format!("{}×{}  {}", size.x, size.y, &viewer.hash[..12.min(viewer.hash.len())]));
+                });
+                ui.separator();
+
+                // Ctrl+scroll (or pinch) zooms
+                let zoom_delta = ui.input(|i| i.zoom_delta());
+                if zoom_delta != 1.0 && ui.ui_contains_pointer() {
+                    viewer.zoom = (viewer.zoom * zoom_delta).clamp(0.05, 16.0);
+                }
+
+                egui::ScrollArea::both().show(ui, |ui| {
+                    ui.add(egui::Image::new((viewer.texture.id(), size * viewer.zoom)));
+                });
+            });
+
+        if !open {
+            self.viewer = None;
+        }
+    }
+
+    fn open_preview(&mut self, hash: &str) {
+        let Ok(db) = Database::new(&self.db_path, &self.db_key) else { return };
+        let blocks = db
+            .get_clip_payloads(hash)
+            .ok()
+            .and_then(|payloads| formats::rich_blocks(&payloads));
+        match blocks {
+            Some(blocks) => self.preview = Some(RichPreview { hash: hash.to_string(), blocks }),
+            None => eprintln!("open_preview: {} has no HTML or RTF", hash),
+        }
+    }
+
+    /// Returns a restore the user picked from the window.
+    fn preview_window(&mut self, ctx: &egui::Context) -> Option<(String, RestoreMode)> {
+        let preview = self.preview.as_ref()?;
+        let mut open = true;
+        let mut restore = None;
+
+        egui::Window::new("Preview")
+            .id(egui::Id::new("rich_preview"))
+            .open(&mut open)
+            .default_size([520.0, 420.0])
+            .resizable(true)
+            .show(ctx, |ui| {
+                ui.horizontal(|ui| {
+                    if ui.button("Restore").clicked() {
+                        restore = Some((preview.hash.clone(), RestoreMode::All));
+                    }
+                    if ui.button("Plain text only").clicked() {
+                        restore = Some((preview.hash.clone(), RestoreMode::PlainText));
+                    }
+                    if ui.button("Rich text only").clicked() {
+                        restore = Some((preview.hash.clone(), RestoreMode::RichText));
+                    }
+                });
+                ui.separator();
+                egui::ScrollArea::vertical().show(ui, |ui| rich_text_ui(ui, &preview.blocks));
+            });
+
+        if !open {
+            self.preview = None;
+        }
+        restore
+    }
+
+    /// The window title names the file, so it outranks the stored guess.
+    fn open_code(&mut self, hash: &str, title: &str, kind: Option<Kind>) {
+        let Ok(db) = Database::new(&self.db_path, &self.db_key) else { return };
+        let Some(text) = db.get_clip_payloads(hash).ok().and_then(|p| classify::clip_text(&p)) else {
+            eprintln!("open_code: {} has no text", hash);
+            return;
+        };
+        let language = classify::language_from_title(title)
+            .or(match kind {
+                Some(Kind::Code(lang)) => lang,
+                _ => None,
+            })
+            .or_else(|| classify::guess_language(&text));
+        let mut code = CodePreview { hash: hash.to_string(), text, language: None, lines: Vec::new() };
+        code.set_language(language);
+        self.code = Some(code);
+    }
+
+    /// Returns a restore the user picked from the window.
+    fn code_window(&mut self, ctx: &egui::Context) -> Option<(String, RestoreMode)> {
+        let code = self.code.as_mut()?;
+        let mut open = true;
+        let mut restore = None;
+
+        egui::Window::new("Code")
+            .id(egui::Id::new("code_preview"))
+            .open(&mut open)
+            .default_size([640.0, 480.0])
+            .resizable(true)
+            .show(ctx, |ui| {
+                ui.horizontal(|ui| {
+                    if ui.button("Restore").clicked() {
+                        restore = Some((code.hash.clone(), RestoreMode::PlainText));
+                    }
+                    let mut language = code.language;
+                    egui::ComboBox::from_id_source("code_language")
+                        .selected_text(language.map_or("plain", Language::name))
+                        .show_ui(ui, |ui| {
+                            ui.selectable_value(&mut language, None, "plain");
+                            for lang in Language::all() {
+                                ui.selectable_value(&mut language, Some(lang), lang.name());
+                            }
+                        });
+                    if language != code.language {
+                        code.set_language(language);
+                    }
+                    ui.weak(format!("{} lines", code.lines.len()));
+                });
+                ui.separator();
+
+                let width = code.lines.len().to_string().len();
+                let row_height = ui.text_style_height(&egui::TextStyle::Monospace);
+                ui.spacing_mut().item_spacing.y = 0.0;
+                egui::ScrollArea::both()
+                    .auto_shrink([false, false])
+                    .show_rows(ui, row_height, code.lines.len(), |ui, rows| {
+                        for n in rows {
+                            let job = code_line_job(ui, n + 1, width, &code.lines[n]);
+                            ui.add(egui::Label::new(job).wrap(false));
+                        }
+                    });
+            });
+
+        if !open {
+            self.code = None;
+        }
+        restore
+    }
+
+    /// Opens the cloud browser, or re-reads its list when it's already open.
+    fn load_cloud_browser(&mut self) {
+        let cloud = match CloudDatabase::new(&self.cloud_db_path, &self.db_key) {
+            Ok(cloud) => cloud,
+            Err(e) => { eprintln!("cloud browser: cloud.db open failed: {}", e); return; }
+        };
+        let clips = match cloud.list_clips(self.preview_length) {
+            Ok(clips) => clips,
+            Err(e) => { eprintln!("cloud browser: {}", e); return; }
+        };
+        let in_history = match Database::new(&self.db_path, &self.db_key) {
+            Ok(db) => clips.iter().filter(|c| db.has_clip(&c.hash).unwrap_or(false)).map(|c| c.hash.clone()).collect(),
+            Err(_) => HashSet::new(),
+        };
+        let stats = cloud.stats().unwrap_or_default();
+        let browser = self.cloud_browser.get_or_insert_with(|| CloudBrowser {
+            clips: Vec::new(),
+            in_history: HashSet::new(),
+            filter: String::new(),
+            status: None,
+            stats: CloudStats::default(),
+            cleanup_days: 30,
+            confirm: None,
+            recovery_input: String::new(),
+            devices: Vec::new(),
+            own_device: String::new(),
+            revoke: None,
+        });
+        browser.clips = clips;
+        browser.in_history = in_history;
+        browser.stats = stats;
+        browser.devices = cloud.devices().unwrap_or_default();
+        browser.own_device = cloud.device_id().unwrap_or_default();
+    }
+
+    fn cloud_window(&mut self, ctx: &egui::Context) -> Option<CloudCommand> {
+        let browser = self.cloud_browser.as_mut()?;
+        let mut open = true;
+        let mut command = None;
+        let sync_enabled = self.config.sync.enabled();
+        let running = self.sync_state.running.load(Ordering::Relaxed);
+        let mut recovery_key = self.sync_state.recovery_key.lock().unwrap();
+
+        egui::Window::new("Cloud")
+            .id(egui::Id::new("cloud_browser"))
+            .open(&mut open)
+            .default_size([560.0, 480.0])
+            .resizable(true)
+            .show(ctx, |ui| {
+                ui.horizontal(|ui| {
+                    ui.label("🔍");
+                    ui.text_edit_singleline(&mut browser.filter);
+                    ui.weak(format!("{} clips", browser.clips.len()));
+                });
+                if let Some(status) = &browser.status {
+                    ui.weak(status);
+                }
+                egui::CollapsingHeader::new("Manage").id_source("cloud_manage").show(ui, |ui| {
+                    if let Some(cleanup) = cloud_manage_ui(ui, browser, sync_enabled) {
+                        command = Some(cleanup);
+                    }
+                    if sync_enabled {
+                        ui.separator();
+                        if let Some(revoke) = devices_ui(ui, browser) {
+                            command = Some(revoke);
+                        }
+                        ui.separator();
+                        if let Some(task) = encryption_ui(ui, browser, &mut recovery_key) {
+                            command = Some(CloudCommand::Task(task));
+                        }
+                    }
+                });
+                ui.separator();
+
+                let filter = browser.filter.to_lowercase();
+                let matches = |clip: &&CloudClip| {
+                    filter.is_empty()
+                        || [&clip.preview, &clip.owner, &clip.fg_title].iter().any(|s| s.to_lowercase().contains(&filter))
+                };
+                egui::ScrollArea::vertical().auto_shrink([false, false]).show(ui, |ui| {
+                    for clip in browser.clips.iter().filter(matches) {
+                        ui.group(|ui| {
+                            ui.horizontal(|ui| {
+                                ui.weak(&clip.timestamp);
+                                ui.label(&clip.owner).on_hover_text(&clip.fg_title);
+                                if let Some(device) = &clip.device {
+                                    ui.weak(format!("💻 {}", device));
+                                }
+                                ui.weak(files::format_size(clip.size as u64));
+                                if sync_enabled {
+                                    push_status_ui(ui, &clip.push, running);
+                                    if matches!(clip.push, PushState::Failed(_)) && ui.small_button("Retry").clicked() {
+                                        command = Some(CloudCommand::Retry(clip.hash.clone()));
+                                    }
+                                }
+                            });
+                            ui.label(&clip.preview);
+                            ui.horizontal(|ui| {
+                                if ui.button("Restore").on_hover_text("Put it on the clipboard").clicked() {
+                                    command = Some(CloudCommand::Restore(clip.hash.clone(), RestoreMode::All));
+                                }
+                                if ui.button("Plain text").clicked() {
+                                    command = Some(CloudCommand::Restore(clip.hash.clone(), RestoreMode::PlainText));
+                                }
+                                if browser.in_history.contains(&clip.hash) {
+                                    ui.weak("in history");
+                                } else if ui.button("Import").on_hover_text("Copy it into the local history").clicked() {
+                                    command = Some(CloudCommand::Import(clip.hash.clone()));
+                                }
+                                if ui.button("🗑").on_hover_text("Delete from the cloud on every device").clicked() {
+                                    command = Some(CloudCommand::Delete(vec![clip.hash.clone()]));
+                                }
+                            });
+                        });
+                    }
+                });
+            });
+
+        if !open {
+            self.cloud_browser = None;
+        }
+        command
+    }
+
+    fn nearby_window(&mut self, ctx: &egui::Context, lan: &LanState) -> Option<NearbyCommand> {
+        let window = self.nearby.as_mut()?;
+        let mut open = true;
+        let mut command = None;
+        let peers = lan.node.peers();
+        let linked = lan.node.linked();
+        let nearby = lan.discovery.as_ref().map(|d| d.nearby()).unwrap_or_default();
+
+        egui::Window::new("Nearby")
+            .id(egui::Id::new("nearby"))
+            .open(&mut open)
+            .default_size([420.0, 360.0])
+            .show(ctx, |ui| {
+                if let Some(status) = lan.status.lock().unwrap().as_deref() {
+                    ui.weak(status);
+                }
+                ui.horizontal(|ui| match lan.node.shown_code() {
+                    Some(code) => {
+                        ui.label("Pairing code");
+                        ui.heading(format!("{} {}", &code[..3], &code[3..]));
+                        if ui.button("Cancel").clicked() {
+                            command = Some(NearbyCommand::HideCode);
+                        }
+                        // It runs out on its own
+                        ctx.request_repaint_after(std::time::Duration::from_secs(1));
+                    }
+                    None => {
+                        if ui.button("Show pairing code").on_hover_text("Let another device pair with this one").clicked() {
+                            command = Some(NearbyCommand::ShowCode);
+                        }
+                    }
+                });
+                ui.separator();
+
+                if nearby.is_empty() {
+                    ui.weak("No other openclip on this network yet");
+                }
+                for device in &nearby {
+                    ui.horizontal(|ui| {
+                        ui.label(format!("💻 {}", device.name)).on_hover_text(device.addr.to_string());
+                        if peers.iter().any(|p| p.id == device.id) {
+                            ui.weak("paired");
+                            return;
+                        }
+                        let code = window.codes.entry(device.id.clone()).or_default();
+                        ui.add(egui::TextEdit::singleline(code).hint_text("Code").desired_width(70.0));
+                        if ui.add_enabled(!code.trim().is_empty(), egui::Button::new("Pair"))
+                            .on_hover_text("Type the code that device shows")
+                            .clicked()
+                        {
+                            command = Some(NearbyCommand::Pair(device.clone(), code.clone()));
+                        }
+                    });
+                }
+
+                if !peers.is_empty() {
+                    ui.separator();
+                    ui.label("Paired");
+                    for peer in &peers {
+                        ui.horizontal(|ui| {
+                            ui.label(&peer.name);
+                            if !nearby.iter().any(|d| d.id == peer.id) {
+                                ui.weak("not around");
+                            }
+                            if linked.as_deref() == Some(peer.id.as_str()) {
+                                ui.label("🔗");
+                                if ui.small_button("Unlink").clicked() {
+                                    command = Some(NearbyCommand::Link(None));
+                                }
+                            } else if ui.small_button("Link clipboard")
+                                .on_hover_text("Copies here show up on its clipboard, and the other way round once it links back")
+                                .clicked()
+                            {
+                                command = Some(NearbyCommand::Link(Some(peer.clone())));
+                            }
+                            if ui.small_button("Forget").clicked() {
+                                command = Some(NearbyCommand::Forget(peer.clone()));
+                            }
+                        });
+                    }
+                }
+            });
+
+        if !open {
+            self.nearby = None;
+        }
+        command
+    }
+
+    /// Drops clips from cloud.db and leaves tombstones for the next sync to
+    /// pass on. Local history keeps its copies.
+    fn delete_from_cloud(&mut self, hashes: &[String]) {
+        let cloud = match CloudDatabase::new(&self.cloud_db_path, &self.db_key) {
+            Ok(cloud) => cloud,
+            Err(e) => { eprintln!("delete_from_cloud: cloud.db open failed: {}", e); return; }
+        };
+        let status = match cloud.delete_clips(hashes) {
+            Ok(removed) => format!("Deleted {} from the cloud", plural(removed, "clip")),
+            Err(e) => format!("Delete failed: {}", e),
+        };
+        if let Some(browser) = &mut self.cloud_browser {
+            browser.status = Some(status);
+            browser.confirm = None;
+        }
+        self.refresh_history();
+        self.start_sync();
+    }
+
+    fn plan_cleanup(&mut self, cleanup: Cleanup) {
+        let Ok(cloud) = CloudDatabase::new(&self.cloud_db_path, &self.db_key) else { return };
+        let Some(browser) = &mut self.cloud_browser else { return };
+        match cloud.cleanup_targets(&cleanup) {
+            Ok(hashes) if hashes.is_empty() => browser.status = Some("Nothing to delete".to_string()),
+            Ok(hashes) => {
+                let what = match &cleanup {
+                    Cleanup::OlderThanDays(days) => format!("clips older than {} days", days),
+                    Cleanup::Device(device) => format!("clips from {}", device_label(device, &browser.stats)),
+                    Cleanup::All => "every clip".to_string(),
+                };
+                browser.confirm = Some((format!("Delete {} ({}) from the cloud?", what, hashes.len()), hashes));
+            }
+            Err(e) => browser.status = Some(format!("Cleanup failed: {}", e)),
+        }
+    }
+
+    fn import_from_cloud(&mut self, hash: &str) {
+        let (Ok(cloud), Ok(db)) = (
+            CloudDatabase::new(&self.cloud_db_path, &self.db_key),
+            Database::new(&self.db_path, &self.db_key),
+        ) else {
+            return;
+        };
+        let status = match cloud.import_clip(hash, &db) {
+            Ok(true) => "Imported into history".to_string(),
+            Ok(false) => "Already in history".to_string(),
+            Err(e) => format!("Import failed: {}", e),
+        };
+        self.refresh_history();
+        if let Some(browser) = &mut self.cloud_browser {
+            browser.status = Some(status);
+        }
+    }
+
+    /// Writes a cloud clip to the clipboard without adding it to history.
+    fn restore_from_cloud(&mut self, hash: &str, mode: RestoreMode) {
+        let payloads = CloudDatabase::new(&self.cloud_db_path, &self.db_key).and_then(|cloud| cloud.get_clip(hash));
+        let status = match payloads {
+            Ok((_, payloads)) => {
+                let payloads = formats::for_restore(payloads, mode);
+                if payloads.is_empty() {
+                    format!("Nothing to restore as {:?}", mode)
+                } else if crate::write_clipboard(&payloads) {
+                    println!("Restored {} from the cloud", hash);
+                    if self.config.paste.auto_paste {
+                        self.hide();
+                        crate::paste_into_previous_window();
+                    }
+                    "Restored to the clipboard".to_string()
+                } else {
+                    "Couldn't open the clipboard".to_string()
+                }
             }
+            Err(e) => format!("Restore failed: {}", e),
+        };
+        if let Some(browser) = &mut self.cloud_browser {
+            browser.status = Some(status);
         }
+    }
 
-        if let Ok(cloud) = CloudDatabase::new(&self.cloud_db_path, "pwd") {
-            self.synced_hashes = cloud.get_synced_hashes().unwrap_or_default();
+    fn open_inspector(&mut self, hash: &str) {
+        let Ok(db) = Database::new(&self.db_path, &self.db_key) else { return };
+        match db.get_clip_formats(hash) {
+            Ok(formats) => {
+                self.inspector = Some(Inspector {
+                    hash: hash.to_string(),
+                    formats,
+                    selected: None,
+                    data: Vec::new(),
+                    structure: None,
+                    offset: 0,
+                    goto: String::new(),
+                    search: String::new(),
+                    last_match: None,
+                    export_path: String::new(),
+                    status: None,
+                });
+            }
+            Err(e) => eprintln!("open_inspector: {}", e),
+        }
+    }
+
+    fn select_format(&mut self, index: usize) {
+        let Some(inspector) = &mut self.inspector else { return };
+        let Some(format) = inspector.formats.get(index) else { return };
+        let Ok(db) = Database::new(&self.db_path, &self.db_key) else { return };
+        match db.get_format_data(&inspector.hash, format.format_id) {
+            Ok(data) => {
+                inspector.structure = inspect::decode_structure(format.format_id, &format.format_name, &data);
+                let safe_name: String = format
+                    .format_name
+                    .chars()
+                    .map(|c| if c.is_ascii_alphanumeric() || c == '_' || c == '-' { c } else { '_' })
+                    .collect();
+                inspector.export_path = crate::config::data_dir()
+                    .join(format!("{}_{}.bin", &inspector.hash[..12.min(inspector.hash.len())], safe_name))
+                    .to_string_lossy()
+                    .into_owned();
+                inspector.data = data;
+                inspector.selected = Some(index);
+                inspector.offset = 0;
+                inspector.last_match = None;
+                inspector.status = None;
+            }
+            Err(e) => inspector.status = Some(format!("load failed: {}", e)),
+        }
+    }
+
+    fn inspector_window(&mut self, ctx: &egui::Context) {
+        let Some(inspector) = &mut self.inspector else { return };
+        let mut open = true;
+        let mut pick: Option<usize> = None;
+
+        egui::Window::new("Inspector")
+            .id(egui::Id::new("inspector"))
+            .open(&mut open)
+            .default_size([720.0, 520.0])
+            .resizable(true)
+            .show(ctx, |ui| {
+                ui.weak(&inspector.hash);
+                egui::Grid::new("format_grid").striped(true).num_columns(3).show(ui, |ui| {
+                    ui.strong("format_id");
+                    ui.strong("format_name");
+                    ui.strong("size");
+                    ui.end_row();
+                    for (i, f) in inspector.formats.iter().enumerate() {
+                        let label = format!("{}", f.format_id);
+                        if ui.selectable_label(inspector.selected == Some(i), label).clicked() {
+                            pick = Some(i);
+                        }
+                        ui.label(&f.format_name);
+                        ui.label(format!("{} B", f.size));
+                        ui.end_row();
+                    }
+                });
+
+                if inspector.selected.is_none() {
+                    return;
+                }
+                ui.separator();
+
+                if let Some(fields) = &inspector.structure {
+                    egui::CollapsingHeader::new("Structure").default_open(true).show(ui, |ui| {
+                        egui::Grid::new("structure_grid").num_columns(2).show(ui, |ui| {
+                            for (name, value) in fields {
+                                ui.monospace(name);
+                                ui.label(value);
+                                ui.end_row();
+                            }
+                        });
+                    });
+                }
+
+                let page = Inspector::page_size();
+                ui.horizontal(|ui| {
+                    if ui.add_enabled(inspector.offset > 0, egui::Button::new("◀")).clicked() {
+                        inspector.offset = inspector.offset.saturating_sub(page);
+                    }
+                    let next = inspector.offset + page < inspector.data.len();
+                    if ui.add_enabled(next, egui::Button::new("▶")).clicked() {
+                        inspector.offset += page;
+                    }
+                    ui.label(format!(
+                        "{:#X}–{:#X} of {:#X}",
+                        inspector.offset,
+                        (inspector.offset + page).min(inspector.data.len()),
+                        inspector.data.len()
+                    ));
+
+                    let goto = ui.add(egui::TextEdit::singleline(&mut inspector.goto).hint_text("offset").desired_width(80.0));
+                    if ui.button("Go").clicked() || (goto.lost_focus() && ui.input(|i| i.key_pressed(egui::Key::Enter))) {
+                        match inspect::parse_offset(&inspector.goto) {
+                            Some(offset) if offset < inspector.data.len() => inspector.jump_to(offset),
+                            _ => inspector.status = Some(format!("bad offset '{}'", inspector.goto)),
+                        }
+                    }
+                });
+
+                ui.horizontal(|ui| {
+                    let search = ui.add(egui::TextEdit::singleline(&mut inspector.search).hint_text("4D 5A or \"text\"").desired_width(160.0));
+                    if ui.button("Find next").clicked() || (search.lost_focus() && ui.input(|i| i.key_pressed(egui::Key::Enter))) {
+                        match inspect::parse_needle(&inspector.search) {
+                            Ok(needle) => {
+                                let from = inspector.last_match.map(|m| m + 1).unwrap_or(0);
+                                match inspect::find_bytes(&inspector.data, &needle, from) {
+                                    Some(at) => {
+                                        inspector.last_match = Some(at);
+                                        inspector.jump_to(at);
+                                        inspector.status = Some(format!("found at {:#X}", at));
+                                    }
+                                    None => inspector.status = Some("not found".to_string()),
+                                }
+                            }
+                            Err(e) => inspector.status = Some(e),
+                        }
+                    }
+
+                    ui.separator();
+                    ui.add(egui::TextEdit::singleline(&mut inspector.export_path).desired_width(220.0));
+                    if ui.button("Export").clicked() {
+                        inspector.status = Some(match std::fs::write(&inspector.export_path, &inspector.data) {
+                            Ok(()) => format!("wrote {} bytes", inspector.data.len()),
+                            Err(e) => format!("export failed: {}", e),
+                        });
+                    }
+                });
+
+                if let Some(status) = &inspector.status {
+                    ui.weak(status);
+                }
+
+                egui::ScrollArea::vertical().id_source("hex_scroll").show(ui, |ui| {
+                    let end = (inspector.offset + page).min(inspector.data.len());
+                    for row in (inspector.offset..end).step_by(inspect::BYTES_PER_ROW) {
+                        let line = inspect::hex_line(&inspector.data, row);
+                        let hit = inspector
+                            .last_match
+                            .is_some_and(|m| m >= row && m < row + inspect::BYTES_PER_ROW);
+                        if hit {
+                            ui.label(egui::RichText::new(line).monospace().background_color(ui.visuals().selection.bg_fill));
+                        } else {
+                            ui.monospace(line);
+                        }
+                    }
+                });
+            });
+
+        if !open {
+            self.inspector = None;
+        } else if let Some(i) = pick {
+            self.select_format(i);
         }
     }
 
     fn clear_history(&mut self) {
-        if let Ok(db) = Database::new(&self.db_path, "pwd") {
+        if let Ok(db) = Database::new(&self.db_path, &self.db_key) {
             if db.clear_all_clips().is_ok() {
+                self.thumbnails.clear();
                 self.current_page = 0;
                 self.refresh_history();
             }
@@ -79,7 +1455,7 @@ impl App {
     }
 
     fn delete_single(&mut self, hash: &str) {
-        if let Ok(db) = Database::new(&self.db_path, "pwd") {
+        if let Ok(db) = Database::new(&self.db_path, &self.db_key) {
             if db.delete_clip_by_hash(hash).is_ok() {
                 self.refresh_history();
             }
@@ -87,54 +1463,267 @@ impl App {
     }
 
     fn push_to_cloud(&mut self, hash: &str) {
-        let source = match Database::new(&self.db_path, "pwd") {
+        let source = match Database::new(&self.db_path, &self.db_key) {
             Ok(db) => db,
             Err(e) => { eprintln!("push_to_cloud: clipboard.db open failed: {}", e); return; }
         };
-        let cloud = match CloudDatabase::new(&self.cloud_db_path, "pwd") {
+        let cloud = match CloudDatabase::new(&self.cloud_db_path, &self.db_key) {
             Ok(db) => db,
             Err(e) => { eprintln!("push_to_cloud: cloud.db open failed: {}", e); return; }
         };
         match cloud.copy_clip_from(hash, &source) {
             Ok(_) => {
                 println!("Pushed {} to cloud.db", hash);
-                self.synced_hashes.insert(hash.to_string());
+                self.cloud_states.entry(hash.to_string()).or_insert(PushState::Pending);
+                self.start_sync();
             }
             Err(e) => eprintln!("push_to_cloud failed: {}", e),
         }
     }
 
-    fn restore_clip(&self, hash: &str) {
-        let db = match Database::new(&self.db_path, "pwd") {
-            Ok(db) => db,
-            Err(_) => return,
-        };
-        let payloads = match db.get_clip_payloads(hash) {
-            Ok(p) => p,
-            Err(_) => return,
-        };
-        unsafe {
-            crate::set_restoring(true);
-            if OpenClipboard(HWND(0)).is_ok() {
-                let _ = EmptyClipboard();
-                for payload in payloads {
-                    if let Ok(hglobal) = GlobalAlloc(GMEM_MOVEABLE, payload.data.len()) {
-                        let ptr = GlobalLock(hglobal);
-                        if !ptr.is_null() {
-                            std::ptr::copy_nonoverlapping(
-                                payload.data.as_ptr(),
-                                ptr as *mut u8,
-                                payload.data.len(),
-                            );
-                            let _ = GlobalUnlock(hglobal);
-                            let _ = SetClipboardData(payload.format_id, HANDLE(hglobal.0 as isize));
-                        }
+    /// Asks the sync thread to upload pending cloud clips and pull other
+    /// devices'. It does nothing unless a `[sync]` server is configured.
+    fn start_sync(&self) {
+        crate::request_sync(SyncTask::Sync);
+    }
+
+    /// Resets a clip's failed upload attempts so the next sync tries again.
+    fn retry_push(&mut self, hash: &str) {
+        match CloudDatabase::new(&self.cloud_db_path, &self.db_key).and_then(|cloud| cloud.retry_push(hash)) {
+            Ok(()) => {
+                self.cloud_states.insert(hash.to_string(), PushState::Pending);
+                self.start_sync();
+            }
+            Err(e) => eprintln!("retry_push failed: {}", e),
+        }
+    }
+
+    fn restore_clip(&self, hash: &str, mode: RestoreMode) {
+        if crate::restore_hash(hash, mode) {
+            println!("Restored {}", hash);
+            if self.config.paste.auto_paste {
+                self.hide();
+                crate::paste_into_previous_window();
+            }
+        }
+    }
+
+    fn transform_clip(&mut self, hash: &str, action: &Action) {
+        let Ok(db) = Database::new(&self.db_path, &self.db_key) else { return };
+        match transform::derive(&db, hash, action) {
+            Ok(derived) => {
+                self.transform_error = None;
+                self.refresh_history();
+                if self.transform_restore {
+                    self.restore_clip(&derived, RestoreMode::All);
+                }
+            }
+            Err(e) => self.transform_error = Some(format!("{}: {}", action.label(), e)),
+        }
+    }
+
+    fn set_pin(&mut self, hash: &str, slot: Option<u8>) {
+        if let Ok(db) = Database::new(&self.db_path, &self.db_key) {
+            match db.set_pin_slot(hash, slot) {
+                Ok(()) => {
+                    autosync::share_metadata(&db, hash);
+                    autosync::push_if_matching(&db, hash);
+                    self.refresh_history();
+                }
+                Err(e) => eprintln!("set_pin failed: {}", e),
+            }
+        }
+    }
+
+    fn set_tags(&mut self, hash: &str, text: &str) {
+        if let Ok(db) = Database::new(&self.db_path, &self.db_key) {
+            match db.set_tags(hash, text) {
+                Ok(()) => {
+                    autosync::share_metadata(&db, hash);
+                    autosync::push_if_matching(&db, hash);
+                    self.refresh_history();
+                }
+                Err(e) => eprintln!("set_tags failed: {}", e),
+            }
+        }
+    }
+
+    fn set_note(&mut self, hash: &str, note: &str) {
+        if let Ok(db) = Database::new(&self.db_path, &self.db_key) {
+            match db.set_note(hash, note) {
+                Ok(()) => {
+                    autosync::share_metadata(&db, hash);
+                    self.refresh_history();
+                }
+                Err(e) => eprintln!("set_note failed: {}", e),
+            }
+        }
+    }
+
+    fn restore_and_paste(&self, hash: &str) {
+        if crate::restore_hash(hash, RestoreMode::All) {
+            self.hide();
+            crate::paste_into_previous_window();
+        }
+    }
+
+    fn total_pages(&self) -> i32 {
+        ((self.total_count as f32 / self.items_per_page as f32).ceil() as i32).max(1)
+    }
+
+    fn select(&mut self, index: usize) {
+        self.selected = index;
+        self.scroll_to_selected = true;
+    }
+
+    fn move_selection(&mut self, down: bool) {
+        if down {
+            if self.selected + 1 < self.history.len() {
+                self.select(self.selected + 1);
+            } else if self.current_page + 1 < self.total_pages() {
+                self.current_page += 1;
+                self.refresh_history();
+                self.select(0);
+            }
+        } else if self.selected > 0 {
+            self.select(self.selected - 1);
+        } else if self.current_page > 0 {
+            self.current_page -= 1;
+            self.refresh_history();
+            self.select(self.history.len().saturating_sub(1));
+        }
+    }
+
+    fn change_page(&mut self, forward: bool) {
+        if forward && self.current_page + 1 < self.total_pages() {
+            self.current_page += 1;
+        } else if !forward && self.current_page > 0 {
+            self.current_page -= 1;
+        } else {
+            return;
+        }
+        self.refresh_history();
+        self.select(0);
+    }
+
+    /// Arrow keys or j/k move the selection, Enter restores, Shift+Enter restores and
+    /// pastes, Ctrl+Enter restores as plain text, Delete removes and 1–9 pick a row directly. Any other typing goes to
+    /// the search box. While the search box has focus, only the keys that can't
+    /// edit text (arrows, Enter, Escape, page keys) are taken.
+    fn handle_keys(&mut self, ctx: &egui::Context) -> Option<KeyCommand> {
+        let search_id = egui::Id::new(SEARCH_ID);
+        let (search_focused, other_focused) = ctx.memory(|m| {
+            let search = m.has_focus(search_id);
+            (search, m.focused().is_some() && !search)
+        });
+        if other_focused {
+            return None;
+        }
+
+        let mut command = None;
+        let mut typed = String::new();
+        let (down, up, page_down, page_up, paste, plain, enter, escape, delete) = ctx.input_mut(|i| {
+            let keys = (
+                i.consume_key(egui::Modifiers::NONE, egui::Key::ArrowDown),
+                i.consume_key(egui::Modifiers::NONE, egui::Key::ArrowUp),
+                i.consume_key(egui::Modifiers::NONE, egui::Key::PageDown),
+                i.consume_key(egui::Modifiers::NONE, egui::Key::PageUp),
+                i.consume_key(egui::Modifiers::SHIFT, egui::Key::Enter),
+                i.consume_key(egui::Modifiers::COMMAND, egui::Key::Enter),
+                i.consume_key(egui::Modifiers::NONE, egui::Key::Enter),
+                i.consume_key(egui::Modifiers::NONE, egui::Key::Escape),
+                !search_focused && i.consume_key(egui::Modifiers::NONE, egui::Key::Delete),
+            );
+            if !search_focused {
+                // Take the text out of the event queue so the search box, focused
+                // below, doesn't also receive it this frame
+                i.events.retain(|e| match e {
+                    egui::Event::Text(t) => {
+                        typed.push_str(t);
+                        false
+                    }
+                    _ => true,
+                });
+            }
+            keys
+        });
+
+        let mut nav_down = down;
+        let mut nav_up = up;
+        let mut search_text = String::new();
+        for c in typed.chars() {
+            match c {
+                'j' if search_text.is_empty() => nav_down = true,
+                'k' if search_text.is_empty() => nav_up = true,
+                '1'..='9' if search_text.is_empty() => {
+                    let index = c as usize - '1' as usize;
+                    if let Some(hash) = self.history.get(index).map(|c| c.hash.clone()) {
+                        self.select(index);
+                        command = Some(KeyCommand::Restore(hash, RestoreMode::All));
                     }
                 }
-                let _ = CloseClipboard();
-                println!("Restored {}", hash);
+                c => search_text.push(c),
             }
-            crate::set_restoring(false);
+        }
+
+        if !search_text.is_empty() {
+            self.search.push_str(&search_text);
+            ctx.memory_mut(|m| m.request_focus(search_id));
+            // Put the caret after what was just typed rather than at the start
+            if let Some(mut state) = egui::TextEdit::load_state(ctx, search_id) {
+                let end = egui::text::CCursor::new(self.search.chars().count());
+                state.cursor.set_char_range(Some(egui::text::CCursorRange::one(end)));
+                state.store(ctx, search_id);
+            }
+            self.apply_search();
+        }
+
+        if nav_down {
+            self.move_selection(true);
+        }
+        if nav_up {
+            self.move_selection(false);
+        }
+        if page_down {
+            self.change_page(true);
+        }
+        if page_up {
+            self.change_page(false);
+        }
+        if escape {
+            if search_focused {
+                ctx.memory_mut(|m| m.surrender_focus(search_id));
+            } else {
+                self.hide();
+            }
+        }
+
+        let selected = self.history.get(self.selected).map(|c| c.hash.clone());
+        if let Some(hash) = selected {
+            if paste {
+                command = Some(KeyCommand::RestoreAndPaste(hash));
+            } else if plain {
+                command = Some(KeyCommand::Restore(hash, RestoreMode::PlainText));
+            } else if enter {
+                command = Some(KeyCommand::Restore(hash, RestoreMode::All));
+            } else if delete {
+                command = Some(KeyCommand::Delete(hash));
+            }
+        }
+        command
+    }
+
+    fn apply_search(&mut self) {
+        match query::parse(&self.search) {
+            Ok(query) => {
+                self.query = query;
+                self.query_error = None;
+                self.current_page = 0;
+                self.selected = 0;
+                self.refresh_history();
+            }
+            // Keep showing the last valid results while the user is mid-edit
+            Err(e) => self.query_error = Some(e),
         }
     }
 
@@ -145,6 +1734,9 @@ impl App {
 
 impl eframe::App for App {
     fn update(&mut self, ctx: &egui::Context, _frame: &mut eframe::Frame) {
+        if self.config_changed.swap(false, Ordering::Relaxed) {
+            self.reload_config();
+        }
         if self.needs_refresh.swap(false, Ordering::Relaxed) {
             self.refresh_history();
         }
@@ -164,9 +1756,40 @@ impl eframe::App for App {
         }
         self.last_focused = focused;
 
-        let mut restore_hash: Option<String> = None;
+        let mut restore_hash: Option<(String, RestoreMode)> = None;
         let mut delete_hash: Option<String> = None;
         let mut cloud_hash: Option<String> = None;
+        let mut sync_now = false;
+        let mut open_cloud = false;
+        let mut unsync_hash: Option<String> = None;
+        let mut pin_change: Option<(String, Option<u8>)> = None;
+        let mut tag_change: Option<(String, String)> = None;
+        let mut note_change: Option<(String, String)> = None;
+        let mut retry_hash: Option<String> = None;
+        let sync_enabled = self.config.sync.enabled();
+        let syncing = self.sync_state.running.load(Ordering::Relaxed);
+        let mut paste_hash: Option<String> = None;
+        let mut view_hash: Option<String> = None;
+        let mut inspect_hash: Option<String> = None;
+        let mut preview_hash: Option<String> = None;
+        let mut code_clip: Option<(String, String, Option<Kind>)> = None;
+        let mut transform_request: Option<(String, Action)> = None;
+        let mut open_nearby = false;
+        let mut send_request: Option<(Peer, SocketAddr, String)> = None;
+        let mut offer_answer: Option<(u64, bool)> = None;
+        let lan = crate::lan();
+        let reachable = lan.as_deref().map(lanshare::reachable).unwrap_or_default();
+
+        self.load_thumbnails(ctx);
+
+        if self.settings_draft.is_none() {
+            match self.handle_keys(ctx) {
+                Some(KeyCommand::Restore(hash, mode)) => restore_hash = Some((hash, mode)),
+                Some(KeyCommand::RestoreAndPaste(hash)) => paste_hash = Some(hash),
+                Some(KeyCommand::Delete(hash)) => delete_hash = Some(hash),
+                None => {}
+            }
+        }
 
         egui::CentralPanel::default().show(ctx, |ui| {
             ui.horizontal(|ui| {
@@ -178,7 +1801,78 @@ impl eframe::App for App {
                     self.needs_refresh.store(true, Ordering::Relaxed);
                     self.clear_history();
                 }
+                if ui.button("Settings").clicked() && self.settings_draft.is_none() {
+                    // The file alone: values given on the command line don't belong in it
+                    match Config::read(&self.config_path) {
+                        Ok(file) => self.settings_draft = Some(file),
+                        Err(e) => self.settings_error = Some(e.to_string()),
+                    }
+                }
+                if ui.button("☁ Cloud").on_hover_text("Browse clips in the cloud").clicked() {
+                    open_cloud = true;
+                }
+                if let Some(lan) = &lan {
+                    if ui.button("📡 Nearby").on_hover_text("Pair with and send to devices on this network").clicked() {
+                        open_nearby = true;
+                    }
+                    if let Some(peer) = lan.node.linked().and_then(|id| lan.node.peers().into_iter().find(|p| p.id == id)) {
+                        ui.weak(format!("🔗 {}", peer.name)).on_hover_text("Clipboard linked");
+                    }
+                }
+                if sync_enabled {
+                    if ui.add_enabled(!syncing, egui::Button::new("⟳ Sync")).clicked() {
+                        sync_now = true;
+                    }
+                    if syncing {
+                        ui.spinner();
+                    } else if let Some(status) = self.sync_state.status.lock().unwrap().as_deref() {
+                        ui.weak(status);
+                    }
+                }
+            });
+
+            ui.horizontal(|ui| {
+                ui.label("🔍");
+                let search = ui.add(
+                    egui::TextEdit::singleline(&mut self.search)
+                        .id(egui::Id::new(SEARCH_ID))
+                        .hint_text("Search, e.g. app:chrome type:image after:2026-10-01 is:pinned size:>1mb")
+                        .desired_width(f32::INFINITY),
+                );
+                if self.focus_search.swap(false, Ordering::Relaxed) {
+                    search.request_focus();
+                }
+                if search.changed() {
+                    self.apply_search();
+                }
             });
+            if let Some(err) = &self.query_error {
+                ui.colored_label(egui::Color32::from_rgb(230, 90, 90), err);
+            }
+            if let Some(err) = &self.transform_error {
+                ui.colored_label(egui::Color32::from_rgb(230, 90, 90), err);
+            }
+
+            for err in crate::hotkey_errors() {
+                ui.colored_label(egui::Color32::from_rgb(230, 90, 90), format!("Hotkey {}", err));
+            }
+            if let Some(lan) = &lan {
+                for offer in lan.offers.lock().unwrap().iter() {
+                    ui.horizontal(|ui| {
+                        let what = match classify::clip_text(&offer.clip.payloads) {
+                            Some(text) => format!("\"{}\"", text.chars().take(self.preview_length).collect::<String>()),
+                            None => format!("a clip from {}", offer.clip.owner),
+                        };
+                        ui.label(format!("📡 {} sent {}", offer.from.name, what));
+                        if ui.button("Accept").on_hover_text("Put it on the clipboard and in the history").clicked() {
+                            offer_answer = Some((offer.id, true));
+                        }
+                        if ui.button("Decline").clicked() {
+                            offer_answer = Some((offer.id, false));
+                        }
+                    });
+                }
+            }
 
             ui.separator();
 
@@ -186,32 +1880,180 @@ impl eframe::App for App {
                 .id_source("clip_scroll")
                 .max_height(ui.available_height() - 40.0)
                 .show(ui, |ui| {
-                    for clip in &self.history {
-                        ui.group(|ui| {
+                    for (i, clip) in self.history.iter().enumerate() {
+                        let is_selected = i == self.selected;
+                        let mut frame = egui::Frame::group(ui.style());
+                        if is_selected {
+                            frame = frame
+                                .stroke(ui.visuals().selection.stroke)
+                                .fill(ui.visuals().selection.bg_fill.linear_multiply(0.2));
+                        }
+                        let row = frame.show(ui, |ui| {
                             ui.horizontal(|ui| {
+                                if i < 9 {
+                                    ui.weak(format!("{}", i + 1));
+                                }
+                                if let Some(kind) = clip.kind.as_deref().and_then(Kind::parse) {
+                                    ui.label(kind.icon()).on_hover_text(clip.kind.as_deref().unwrap_or_default());
+                                }
                                 ui.label(egui::RichText::new(&clip.owner).strong());
                                 ui.label(egui::RichText::new(&clip.fg_title).strong());
                                 ui.label(&clip.timestamp);
+                                if let Some(device) = &clip.device {
+                                    ui.weak(format!("💻 {}", device)).on_hover_text("Copied on another device");
+                                }
+                                if clip.is_sensitive {
+                                    ui.label("🔒").on_hover_text("Secure copy: kept out of the system clipboard and search");
+                                }
+                                if let Some(parent) = &clip.parent_hash {
+                                    ui.weak("↳").on_hover_text(format!("Transformed from {}", &parent[..12.min(parent.len())]));
+                                }
+
+                                let pin_label = match clip.pin_slot {
+                                    Some(slot) => format!("📌{}", slot),
+                                    None => "📌".to_string(),
+                                };
+                                ui.menu_button(pin_label, |ui| {
+                                    for slot in 1..=9u8 {
+                                        if ui.button(format!("Slot {}", slot)).clicked() {
+                                            pin_change = Some((clip.hash.clone(), Some(slot)));
+                                            ui.close_menu();
+                                        }
+                                    }
+                                    if clip.pin_slot.is_some() && ui.button("Unpin").clicked() {
+                                        pin_change = Some((clip.hash.clone(), None));
+                                        ui.close_menu();
+                                    }
+                                });
+
+                                ui.menu_button("🏷", |ui| {
+                                    if self.tag_edit.as_ref().is_none_or(|(hash, _)| *hash != clip.hash) {
+                                        self.tag_edit = Some((clip.hash.clone(), clip.tags.join(" ")));
+                                    }
+                                    let Some((_, draft)) = &mut self.tag_edit else { return };
+                                    let edit = ui.add(egui::TextEdit::singleline(draft).hint_text("share, work").desired_width(160.0));
+                                    edit.request_focus();
+                                    if ui.button("Save").clicked() || (edit.lost_focus() && ui.input(|i| i.key_pressed(egui::Key::Enter))) {
+                                        tag_change = Some((clip.hash.clone(), draft.clone()));
+                                        ui.close_menu();
+                                    }
+                                })
+                                .response
+                                .on_hover_text("Tags");
+                                for tag in &clip.tags {
+                                    ui.weak(format!("#{}", tag));
+                                }
+                                ui.menu_button("📝", |ui| {
+                                    if self.note_edit.as_ref().is_none_or(|(hash, _)| *hash != clip.hash) {
+                                        self.note_edit = Some((clip.hash.clone(), clip.note.clone().unwrap_or_default()));
+                                    }
+                                    let Some((_, draft)) = &mut self.note_edit else { return };
+                                    ui.add(egui::TextEdit::multiline(draft).hint_text("Note").desired_width(220.0).desired_rows(3))
+                                        .request_focus();
+                                    if ui.button("Save").clicked() {
+                                        note_change = Some((clip.hash.clone(), draft.clone()));
+                                        ui.close_menu();
+                                    }
+                                })
+                                .response
+                                .on_hover_text("Note");
 
-                                if self.synced_hashes.contains(&clip.hash) {
-                                    ui.label(egui::RichText::new("☁").color(egui::Color32::from_rgb(100, 160, 255)));
+                                if let Some(state) = self.cloud_states.get(&clip.hash) {
+                                    // Without a server, cloud.db is all there is
+                                    let state = if sync_enabled { state } else { &PushState::Uploaded };
+                                    let (icon, hover) = push_status(state, syncing);
+                                    ui.menu_button(icon, |ui| {
+                                        if matches!(state, PushState::Failed(_)) && ui.button("Retry").clicked() {
+                                            retry_hash = Some(clip.hash.clone());
+                                            ui.close_menu();
+                                        }
+                                        if ui.button("Unsync").on_hover_text("Delete from the cloud on every device").clicked() {
+                                            unsync_hash = Some(clip.hash.clone());
+                                            ui.close_menu();
+                                        }
+                                    })
+                                    .response
+                                    .on_hover_text(hover);
                                 } else if ui.small_button("⬆ Cloud").clicked() {
                                     cloud_hash = Some(clip.hash.clone());
                                 }
+                                if !clip.is_sensitive && !reachable.is_empty() {
+                                    ui.menu_button("📡", |ui| {
+                                        for (peer, addr) in &reachable {
+                                            if ui.button(&peer.name).clicked() {
+                                                send_request = Some((peer.clone(), *addr, clip.hash.clone()));
+                                                ui.close_menu();
+                                            }
+                                        }
+                                    })
+                                    .response
+                                    .on_hover_text("Send to a nearby device");
+                                }
                             });
 
-                            ui.label(&clip.preview);
+                            match (self.thumbnails.get(&clip.hash), self.file_lists.get(&clip.hash)) {
+                                (Some((Some(texture), _)), _) => {
+                                    let thumb = egui::ImageButton::new((texture.id(), texture.size_vec2()));
+                                    if ui.add(thumb).on_hover_text("Open full size").clicked() {
+                                        view_hash = Some(clip.hash.clone());
+                                    }
+                                }
+                                (_, Some(entries)) => file_list_ui(ui, entries),
+                                _ => {
+                                    ui.label(&clip.preview);
+                                }
+                            }
+                            if let Some(note) = &clip.note {
+                                ui.label(egui::RichText::new(format!("📝 {}", note)).italics().weak());
+                            }
 
                             ui.horizontal(|ui| {
                                 if ui.button("Restore").clicked() {
-                                    restore_hash = Some(clip.hash.clone());
+                                    restore_hash = Some((clip.hash.clone(), RestoreMode::All));
+                                }
+                                if !clip.has_thumbnail
+                                    && ui.button("Plain").on_hover_text("Restore as plain text only").clicked()
+                                {
+                                    restore_hash = Some((clip.hash.clone(), RestoreMode::PlainText));
+                                }
+                                if clip.has_rich && !clip.is_sensitive && ui.button("Preview").clicked() {
+                                    preview_hash = Some(clip.hash.clone());
+                                }
+                                let kind = clip.kind.as_deref().and_then(Kind::parse);
+                                if matches!(kind, Some(Kind::Code(_))) && ui.button("Code").clicked() {
+                                    code_clip = Some((clip.hash.clone(), clip.fg_title.clone(), kind));
+                                }
+                                if !clip.is_sensitive && !clip.has_thumbnail && !clip.has_files {
+                                    ui.menu_button("Transform", |ui| {
+                                        ui.checkbox(&mut self.transform_restore, "Restore result");
+                                        ui.separator();
+                                        let builtin = Transform::all().map(Action::Builtin);
+                                        let user = self.config.transforms.iter().cloned().map(Action::User);
+                                        for (i, action) in builtin.into_iter().chain(user).enumerate() {
+                                            if i == Transform::all().len() {
+                                                ui.separator();
+                                            }
+                                            if ui.button(action.label()).clicked() {
+                                                transform_request = Some((clip.hash.clone(), action));
+                                                ui.close_menu();
+                                            }
+                                        }
+                                    });
                                 }
                                 if ui.button("Delete").clicked() {
                                     delete_hash = Some(clip.hash.clone());
                                 }
+                                if !clip.is_sensitive && ui.button("Inspect").clicked() {
+                                    inspect_hash = Some(clip.hash.clone());
+                                }
                             });
-                        });
+                        }).response;
+
+                        if is_selected && self.scroll_to_selected {
+                            row.scroll_to_me(None);
+                        }
                     }
+                    self.scroll_to_selected = false;
                 });
 
             ui.separator();
@@ -223,7 +2065,7 @@ impl eframe::App for App {
                     self.refresh_history();
                 }
 
-                let total_pages = ((self.total_count as f32 / self.items_per_page as f32).ceil() as i32).max(1);
+                let total_pages = self.total_pages();
                 ui.label(format!(
                     "{} of {}",
                     if self.total_count == 0 { 0 } else { self.current_page + 1 },
@@ -240,8 +2082,81 @@ impl eframe::App for App {
             });
         });
 
-        if let Some(hash) = restore_hash {
-            self.restore_clip(&hash);
+        self.settings_window(ctx);
+        if let Some(hash) = view_hash {
+            self.open_viewer(ctx, &hash);
+        }
+        self.viewer_window(ctx);
+        if let Some(hash) = inspect_hash {
+            self.open_inspector(&hash);
+        }
+        self.inspector_window(ctx);
+        if let Some(hash) = preview_hash {
+            self.open_preview(&hash);
+        }
+        if let Some(picked) = self.preview_window(ctx) {
+            restore_hash = Some(picked);
+        }
+        if let Some((hash, title, kind)) = code_clip {
+            self.open_code(&hash, &title, kind);
+        }
+        if let Some(picked) = self.code_window(ctx) {
+            restore_hash = Some(picked);
+        }
+        if open_cloud {
+            self.load_cloud_browser();
+        }
+        match self.cloud_window(ctx) {
+            Some(CloudCommand::Import(hash)) => self.import_from_cloud(&hash),
+            Some(CloudCommand::Restore(hash, mode)) => self.restore_from_cloud(&hash, mode),
+            Some(CloudCommand::Delete(hashes)) => self.delete_from_cloud(&hashes),
+            Some(CloudCommand::PlanCleanup(cleanup)) => self.plan_cleanup(cleanup),
+            Some(CloudCommand::Task(task)) => crate::request_sync(task),
+            Some(CloudCommand::Revoke(device, passphrase)) => {
+                crate::request_sync(SyncTask::Revoke { device, passphrase, config_path: self.config_path.clone() });
+            }
+            Some(CloudCommand::Retry(hash)) => {
+                self.retry_push(&hash);
+                self.load_cloud_browser();
+            }
+            None => {}
+        }
+
+        if open_nearby {
+            self.nearby.get_or_insert_with(|| NearbyWindow { codes: HashMap::new() });
+        }
+        if let Some(lan) = lan {
+            match self.nearby_window(ctx, &lan) {
+                Some(NearbyCommand::Pair(device, code)) => {
+                    if let Some(window) = &mut self.nearby {
+                        window.codes.remove(&device.id);
+                    }
+                    lanshare::pair(lan.clone(), device, code);
+                }
+                Some(NearbyCommand::ShowCode) => {
+                    lan.node.show_code();
+                }
+                Some(NearbyCommand::HideCode) => lan.node.hide_code(),
+                Some(NearbyCommand::Link(peer)) => lanshare::link(&lan, peer.as_ref()),
+                Some(NearbyCommand::Forget(peer)) => lanshare::forget(&lan, &peer),
+                None => {}
+            }
+            if let Some((peer, addr, hash)) = send_request {
+                lanshare::send(lan.clone(), peer, addr, hash);
+            }
+            if let Some((id, accept)) = offer_answer {
+                lanshare::answer(&lan, id, accept);
+            }
+        }
+
+        if let Some((hash, action)) = transform_request {
+            self.transform_clip(&hash, &action);
+        }
+        if let Some((hash, mode)) = restore_hash {
+            self.restore_clip(&hash, mode);
+        }
+        if let Some(hash) = paste_hash {
+            self.restore_and_paste(&hash);
         }
         if let Some(hash) = delete_hash {
             self.needs_refresh.store(true, Ordering::Relaxed);
@@ -250,5 +2165,25 @@ impl eframe::App for App {
         if let Some(hash) = cloud_hash {
             self.push_to_cloud(&hash);
         }
+        if let Some(hash) = unsync_hash {
+            self.delete_from_cloud(&[hash]);
+        }
+        if sync_now {
+            self.start_sync();
+        }
+        if let Some((hash, slot)) = pin_change {
+            self.set_pin(&hash, slot);
+        }
+        if let Some((hash, text)) = tag_change {
+            self.tag_edit = None;
+            self.set_tags(&hash, &text);
+        }
+        if let Some((hash, note)) = note_change {
+            self.note_edit = None;
+            self.set_note(&hash, &note);
+        }
+        if let Some(hash) = retry_hash {
+            self.retry_push(&hash);
+        }
     }
 }
\ No newline at end of file
@@ -0,0 +1,236 @@
+//! Background sync. One thread runs sync and keyring tasks in turn, backs off
+//! while the server is unreachable and looks for other devices' clips now and
+//! then. Clips reach its queue (the not-yet-uploaded rows of cloud.db, which
+//! outlive restarts) from the ⬆ Cloud button and from `[[sync.push]]` rules.
+
+use std::collections::VecDeque;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{channel, RecvTimeoutError, Sender};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use crate::cloudstorage::{self, CloudDatabase, HttpRemote, Remote, SyncError};
+use crate::config::Config;
+use crate::folderlog::FolderRemote;
+use crate::storage::Database;
+
+/// How often to pull when nothing else asks for a sync.
+const POLL_INTERVAL: Duration = Duration::from_secs(5 * 60);
+/// First wait after the server turns out to be unreachable; doubles up to `MAX_RETRY`.
+const FIRST_RETRY: Duration = Duration::from_secs(15);
+const MAX_RETRY: Duration = Duration::from_secs(10 * 60);
+
+pub enum SyncTask {
+    Sync,
+    RotateKey,
+    ShowRecoveryKey,
+    /// Re-locks the keyring with this device's passphrase using a recovery key.
+    Recover(String),
+    /// Cuts a device off and moves the keyring to a new passphrase, which is
+    /// saved to the config file at `config_path`.
+    Revoke { device: String, passphrase: String, config_path: PathBuf },
+}
+
+/// What the UI shows about the sync thread.
+#[derive(Debug, Default)]
+pub struct SyncState {
+    pub running: AtomicBool,
+    /// Outcome of the last task, shown next to the Sync button.
+    pub status: Mutex<Option<String>>,
+    /// Shown in the cloud window after the user asks for it.
+    pub recovery_key: Mutex<Option<String>>,
+}
+
+/// Starts the sync thread, which syncs once straight away.
+pub fn spawn(state: Arc<SyncState>) -> Sender<SyncTask> {
+    let (tx, rx) = channel();
+    std::thread::spawn(move || {
+        let mut queue = VecDeque::from([SyncTask::Sync]);
+        let mut retry: Option<Duration> = None;
+        loop {
+            if queue.is_empty() {
+                match rx.recv_timeout(retry.unwrap_or(POLL_INTERVAL)) {
+                    Ok(task) => queue.push_back(task),
+                    Err(RecvTimeoutError::Timeout) => queue.push_back(SyncTask::Sync),
+                    Err(RecvTimeoutError::Disconnected) => return,
+                }
+            }
+            // Syncs asked for while busy collapse into one
+            while let Ok(task) = rx.try_recv() {
+                if !matches!(task, SyncTask::Sync) || !queue.iter().any(|t| matches!(t, SyncTask::Sync)) {
+                    queue.push_back(task);
+                }
+            }
+            let task = queue.pop_front().expect("queue isn't empty");
+            let config = crate::current_config();
+            if !config.sync.enabled() {
+                retry = None;
+                continue;
+            }
+
+            state.running.store(true, Ordering::SeqCst);
+            refresh_ui();
+            let result = run(&config, task, &state);
+            state.running.store(false, Ordering::SeqCst);
+            let message = match result {
+                Ok(message) => {
+                    retry = None;
+                    message
+                }
+                Err(SyncError::Offline(e)) => {
+                    let wait = retry.map_or(FIRST_RETRY, |r| (r * 2).min(MAX_RETRY));
+                    retry = Some(wait);
+                    eprintln!("sync: server unreachable, retrying in {}s: {}", wait.as_secs(), e);
+                    format!("Offline; retrying in {}", describe_wait(wait))
+                }
+                Err(e) => {
+                    eprintln!("sync failed: {}", e);
+                    retry = None;
+                    e.to_string()
+                }
+            };
+            *state.status.lock().unwrap() = Some(message);
+            refresh_ui();
+        }
+    });
+    tx
+}
+
+/// Key changes are followed by a sync so clips get sealed with the new key straight away.
+fn run(config: &Config, task: SyncTask, state: &SyncState) -> Result<String, SyncError> {
+    let mut passphrase = config.sync.passphrase.clone();
+    let cloud = CloudDatabase::new(&config.cloud_database_path(), &config.storage.key)?;
+    cloud.set_device_name(&config.sync.device_name())?;
+    let remote = open_remote(config, &cloud)?;
+    let remote = remote.as_ref();
+    let prefix = match task {
+        SyncTask::Sync => String::new(),
+        SyncTask::RotateKey => format!("Now on key {}. ", cloud.rotate_key(remote, &passphrase)?),
+        SyncTask::ShowRecoveryKey => {
+            *state.recovery_key.lock().unwrap() = Some(cloud.recovery_key(remote, &passphrase)?);
+            return Ok("Recovery key unlocked".to_string());
+        }
+        SyncTask::Recover(code) => {
+            cloudstorage::recover(remote, &code, &passphrase)?;
+            "Keyring recovered. ".to_string()
+        }
+        SyncTask::Revoke { device, passphrase: new_passphrase, config_path } => {
+            if !cloud.revoke_device(remote, &passphrase, &device, &new_passphrase)? {
+                return Ok("That device was already revoked".to_string());
+            }
+            passphrase = new_passphrase;
+            match save_passphrase(&config_path, &passphrase) {
+                Ok(()) => "Device revoked; the new passphrase is saved. ".to_string(),
+                Err(e) => {
+                    eprintln!("revoke: saving the new passphrase failed: {}", e);
+                    "Device revoked, but the new passphrase couldn't be saved; enter it in Settings. ".to_string()
+                }
+            }
+        }
+    };
+    let report = cloud.sync(remote, &passphrase)?;
+    register_this_device(config);
+    apply_synced_metadata(config, &cloud);
+    Ok(format!("{}Synced: {}", prefix, report))
+}
+
+/// The server, or the shared folder standing in for one.
+fn open_remote(config: &Config, cloud: &CloudDatabase) -> Result<Box<dyn Remote>, SyncError> {
+    if config.sync.folder.as_os_str().is_empty() {
+        return Ok(Box::new(HttpRemote::new(&config.sync.server, &config.sync.token)));
+    }
+    let remote = FolderRemote::open(&config.sync.folder, &config.folder_index_path(), &cloud.device_id()?)?;
+    Ok(Box::new(remote))
+}
+
+/// Writes a new sync passphrase to the config file, and to the live config
+/// so the rest of this task already uses it.
+fn save_passphrase(config_path: &std::path::Path, passphrase: &str) -> Result<(), crate::config::ConfigError> {
+    let mut current = crate::current_config();
+    current.sync.passphrase = passphrase.to_string();
+    crate::set_config(current);
+    let mut file = Config::read(config_path)?;
+    file.sync.passphrase = passphrase.to_string();
+    file.save(config_path, crate::cli_overrides())
+}
+
+/// Tells the history database which device new captures come from and what
+/// the registry calls the others.
+pub fn register_this_device(config: &Config) {
+    let registered = CloudDatabase::new(&config.cloud_database_path(), &config.storage.key).and_then(|cloud| {
+        let db = Database::new(&config.database_path(), &config.storage.key)?;
+        db.set_this_device(&cloud.device_id()?, &config.sync.device_name())?;
+        db.save_devices(&cloud.devices()?)
+    });
+    if let Err(e) = registered {
+        eprintln!("register_this_device: {}", e);
+    }
+}
+
+/// Pins, tags and notes other devices set on clips in the history.
+fn apply_synced_metadata(config: &Config, cloud: &CloudDatabase) {
+    let applied = Database::new(&config.database_path(), &config.storage.key).and_then(|db| cloud.apply_states(&db));
+    if let Err(e) = applied {
+        eprintln!("apply_synced_metadata: {}", e);
+    }
+}
+
+fn describe_wait(wait: Duration) -> String {
+    match wait.as_secs() {
+        secs if secs < 60 => format!("{}s", secs),
+        secs => format!("{} min", secs / 60),
+    }
+}
+
+pub fn refresh_ui() {
+    if let Some(flag) = crate::NEEDS_REFRESH.get() {
+        flag.store(true, Ordering::Relaxed);
+    }
+    if let Some(ctx) = crate::EGUI_CTX.get() {
+        ctx.request_repaint();
+    }
+}
+
+/// Queues `hash` for upload when a `[[sync.push]]` rule matches it. Called
+/// after a clip is captured, pinned or tagged.
+pub fn push_if_matching(db: &Database, hash: &str) {
+    let config = crate::current_config();
+    if config.sync.push.is_empty() {
+        return;
+    }
+    let facts = match db.get_clip_facts(hash) {
+        Ok(facts) => facts,
+        Err(e) => {
+            eprintln!("auto-push {}: {}", hash, e);
+            return;
+        }
+    };
+    if !config.sync.push.iter().any(|rule| rule.matches(&facts)) {
+        return;
+    }
+    let queued = CloudDatabase::new(&config.cloud_database_path(), &config.storage.key)
+        .and_then(|cloud| cloud.copy_clip_from(hash, db));
+    match queued {
+        Ok(true) => {
+            println!("Auto-pushed {}", hash);
+            crate::request_sync(SyncTask::Sync);
+            refresh_ui();
+        }
+        Ok(false) => {}
+        Err(e) => eprintln!("auto-push {}: {}", hash, e),
+    }
+}
+
+/// Passes a history clip's new pin, tags or note on to the other devices,
+/// if the clip is in the cloud.
+pub fn share_metadata(db: &Database, hash: &str) {
+    let config = crate::current_config();
+    let recorded = CloudDatabase::new(&config.cloud_database_path(), &config.storage.key)
+        .and_then(|cloud| cloud.record_metadata(hash, db));
+    match recorded {
+        Ok(true) => crate::request_sync(SyncTask::Sync),
+        Ok(false) => {}
+        Err(e) => eprintln!("share_metadata {}: {}", hash, e),
+    }
+}
@@ -0,0 +1,530 @@
+//! Capture-time guess at what a clip holds, stored in `clips.kind` as e.g.
+//! `url` or `code:rust` and searchable with `kind:`.
+
+use std::fmt;
+
+use crate::files;
+use crate::imaging::is_image_format;
+use crate::models::ClipboardPayload;
+use crate::storage::decode_text;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Language {
+    Rust,
+    Python,
+    JavaScript,
+    TypeScript,
+    Java,
+    CSharp,
+    Cpp,
+    C,
+    Go,
+    Ruby,
+    Php,
+    Css,
+}
+
+impl Language {
+    pub fn all() -> [Language; 12] {
+        use Language::*;
+        [Rust, Python, JavaScript, TypeScript, Java, CSharp, Cpp, C, Go, Ruby, Php, Css]
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Language::Rust => "rust",
+            Language::Python => "python",
+            Language::JavaScript => "javascript",
+            Language::TypeScript => "typescript",
+            Language::Java => "java",
+            Language::CSharp => "csharp",
+            Language::Cpp => "cpp",
+            Language::C => "c",
+            Language::Go => "go",
+            Language::Ruby => "ruby",
+            Language::Php => "php",
+            Language::Css => "css",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        Language::all().into_iter().find(|l| l.name() == name)
+    }
+
+    pub fn from_extension(ext: &str) -> Option<Self> {
+        Some(match ext.to_ascii_lowercase().as_str() {
+            "rs" => Language::Rust,
+            "py" | "pyw" | "pyi" => Language::Python,
+            "js" | "mjs" | "cjs" | "jsx" => Language::JavaScript,
+            "ts" | "mts" | "cts" | "tsx" => Language::TypeScript,
+            "java" => Language::Java,
+            "cs" => Language::CSharp,
+            "cpp" | "cc" | "cxx" | "hpp" | "hh" | "hxx" => Language::Cpp,
+            "c" | "h" => Language::C,
+            "go" => Language::Go,
+            "rb" => Language::Ruby,
+            "php" => Language::Php,
+            "css" | "scss" | "less" => Language::Css,
+            _ => return None,
+        })
+    }
+
+    /// Substrings that are rare outside this language, with their weight.
+    fn markers(self) -> &'static [(&'static str, i32)] {
+        match self {
+            Language::Rust => &[
+                ("fn ", 2), ("let mut ", 3), ("impl ", 2), ("pub fn", 3), ("::", 1), ("-> ", 1),
+                ("&self", 3), ("match ", 1), ("use std", 3), ("#[derive", 3), ("Option<", 2), ("println!", 3),
+            ],
+            Language::Python => &[
+                ("def ", 2), ("import ", 1), ("self.", 1), ("elif ", 3), ("print(", 1), ("__init__", 3),
+                ("from ", 1), (" in range(", 3), ("None", 1), ("):\n", 2), ("lambda ", 1),
+            ],
+            Language::JavaScript => &[
+                ("function ", 2), ("const ", 1), ("=> ", 1), ("console.log", 3), ("document.", 2),
+                ("require(", 2), ("===", 2), ("let ", 1), ("undefined", 2),
+            ],
+            Language::TypeScript => &[
+                ("interface ", 1), (": string", 3), (": number", 3), ("export type ", 3), (": boolean", 3),
+                ("readonly ", 1), ("as const", 2),
+            ],
+            Language::Java => &[
+                ("public class ", 3), ("System.out", 3), ("private ", 1), ("public static void", 3),
+                ("extends ", 1), ("@Override", 3), ("import java", 3), ("new ", 1),
+            ],
+            Language::CSharp => &[
+                ("using System", 3), ("namespace ", 2), ("Console.Write", 3), ("public ", 1),
+                ("{ get;", 3), ("var ", 1), ("async Task", 3),
+            ],
+            Language::Cpp => &[
+                ("std::", 3), ("#include <", 2), ("cout <<", 3), ("template<", 3), ("nullptr", 3),
+                ("::", 1), ("auto ", 1),
+            ],
+            Language::C => &[
+                ("#include <stdio.h>", 4), ("printf(", 2), ("malloc(", 3), ("int main(", 2), ("->", 1),
+                ("#define ", 2), ("NULL", 1),
+            ],
+            Language::Go => &[
+                ("func ", 2), ("package ", 2), (":= ", 2), ("fmt.", 3), ("go func", 3), ("chan ", 2),
+                ("defer ", 3), ("err != nil", 4),
+            ],
+            Language::Ruby => &[
+                ("def ", 1), ("end\n", 2), ("puts ", 3), ("require '", 2), (".each do", 3), ("attr_", 3),
+                ("elsif ", 3),
+            ],
+            Language::Php => &[("<?php", 5), ("$this->", 3), ("echo ", 1), ("function ", 1), ("=> $", 2)],
+            Language::Css => &[
+                ("px;", 2), ("color:", 2), ("margin:", 2), ("padding:", 2), ("display:", 2), ("@media", 3),
+                ("font-size:", 2),
+            ],
+        }
+    }
+}
+
+/// Best-scoring language for `text`, if any scores enough to be more than noise.
+pub fn guess_language(text: &str) -> Option<Language> {
+    Language::all()
+        .into_iter()
+        .map(|lang| {
+            let score: i32 = lang
+                .markers()
+                .iter()
+                .filter(|(m, _)| text.contains(m))
+                .map(|(_, w)| w)
+                .sum();
+            (score, lang)
+        })
+        .filter(|(score, _)| *score >= 4)
+        // Earlier languages win ties, so TypeScript needs its own markers to beat JavaScript
+        .min_by_key(|(score, _)| -score)
+        .map(|(_, lang)| lang)
+}
+
+/// Language of the file an editor's window title names, e.g. "main.rs - VS Code"
+/// or "● App.tsx — project". Words without a known extension are ignored.
+pub fn language_from_title(title: &str) -> Option<Language> {
+    title
+        .split(|c: char| c.is_whitespace() || "[]()|\"'●•—–*".contains(c))
+        .filter_map(|word| word.rsplit_once('.'))
+        .find_map(|(stem, ext)| (!stem.is_empty()).then(|| Language::from_extension(ext)).flatten())
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Kind {
+    Url,
+    Email,
+    Phone,
+    Path,
+    Color,
+    Json,
+    Xml,
+    Sql,
+    Code(Option<Language>),
+    Shell,
+    Number,
+    Uuid,
+    Image,
+    Text,
+}
+
+impl Kind {
+    pub const NAMES: [&'static str; 14] = [
+        "url", "email", "phone", "path", "color", "json", "xml", "sql", "code", "shell", "number", "uuid",
+        "image", "text",
+    ];
+
+    pub fn icon(self) -> &'static str {
+        match self {
+            Kind::Url => "🔗",
+            Kind::Email => "✉",
+            Kind::Phone => "☎",
+            Kind::Path => "📁",
+            Kind::Color => "🎨",
+            Kind::Json => "{}",
+            Kind::Xml => "</>",
+            Kind::Sql => "🗄",
+            Kind::Code(_) => "💻",
+            Kind::Shell => "$_",
+            Kind::Number => "#",
+            Kind::Uuid => "🆔",
+            Kind::Image => "🖼",
+            Kind::Text => "📝",
+        }
+    }
+
+    pub fn parse(stored: &str) -> Option<Self> {
+        let (base, lang) = match stored.split_once(':') {
+            Some((base, lang)) => (base, Some(lang)),
+            None => (stored, None),
+        };
+        Some(match base {
+            "url" => Kind::Url,
+            "email" => Kind::Email,
+            "phone" => Kind::Phone,
+            "path" => Kind::Path,
+            "color" => Kind::Color,
+            "json" => Kind::Json,
+            "xml" => Kind::Xml,
+            "sql" => Kind::Sql,
+            "code" => Kind::Code(lang.and_then(Language::from_name)),
+            "shell" => Kind::Shell,
+            "number" => Kind::Number,
+            "uuid" => Kind::Uuid,
+            "image" => Kind::Image,
+            "text" => Kind::Text,
+            _ => return None,
+        })
+    }
+}
+
+impl fmt::Display for Kind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            Kind::Url => "url",
+            Kind::Email => "email",
+            Kind::Phone => "phone",
+            Kind::Path => "path",
+            Kind::Color => "color",
+            Kind::Json => "json",
+            Kind::Xml => "xml",
+            Kind::Sql => "sql",
+            Kind::Code(Some(lang)) => return write!(f, "code:{}", lang.name()),
+            Kind::Code(None) => "code",
+            Kind::Shell => "shell",
+            Kind::Number => "number",
+            Kind::Uuid => "uuid",
+            Kind::Image => "image",
+            Kind::Text => "text",
+        };
+        f.write_str(name)
+    }
+}
+
+/// The clip's plain text: CF_UNICODETEXT, then CF_TEXT, then `text/plain`.
+pub fn clip_text(payloads: &[ClipboardPayload]) -> Option<String> {
+    payloads
+        .iter()
+        .find(|p| p.format_id == 13)
+        .or_else(|| payloads.iter().find(|p| p.format_id == 1))
+        .map(|p| decode_text(&p.data))
+        .or_else(|| {
+            payloads
+                .iter()
+                .find(|p| p.format_name.starts_with("text/plain"))
+                .map(|p| String::from_utf8_lossy(&p.data).into_owned())
+        })
+}
+
+/// Classifies a captured clip; None when it has nothing to go on. A source
+/// file named in the window title decides the language of code, and turns
+/// text that looked like nothing in particular into code.
+pub fn classify(payloads: &[ClipboardPayload], title: &str) -> Option<Kind> {
+    if payloads.iter().any(is_image_format) {
+        return Some(Kind::Image);
+    }
+    if files::clip_paths(payloads).is_some_and(|p| !p.is_empty()) {
+        return Some(Kind::Path);
+    }
+    let kind = classify_text(&clip_text(payloads)?)?;
+    match (kind, language_from_title(title)) {
+        (Kind::Code(_) | Kind::Text, Some(lang)) => Some(Kind::Code(Some(lang))),
+        _ => Some(kind),
+    }
+}
+
+/// Whether the check applies to this text's shape, the test, and the kind it yields.
+type Check = (bool, fn(&str) -> bool, Kind);
+
+pub fn classify_text(text: &str) -> Option<Kind> {
+    let text = text.trim();
+    if text.is_empty() {
+        return None;
+    }
+    let single_line = !text.contains('\n');
+    let single_token = single_line && !text.contains(char::is_whitespace);
+
+    let checks: [Check; 9] = [
+        (single_token, is_uuid, Kind::Uuid),
+        (single_token, is_url, Kind::Url),
+        (single_token, is_email, Kind::Email),
+        (single_line, is_color, Kind::Color),
+        (single_line, is_phone, Kind::Phone),
+        (single_line, is_number, Kind::Number),
+        (single_line, is_path, Kind::Path),
+        (true, is_json, Kind::Json),
+        (true, is_xml, Kind::Xml),
+    ];
+    if let Some((_, _, kind)) = checks.iter().find(|(applies, check, _)| *applies && check(text)) {
+        return Some(*kind);
+    }
+    if is_sql(text) {
+        return Some(Kind::Sql);
+    }
+    if is_shell(text) {
+        return Some(Kind::Shell);
+    }
+    if let Some(lang) = guess_language(text) {
+        return Some(Kind::Code(Some(lang)));
+    }
+    Some(Kind::Text)
+}
+
+fn is_uuid(s: &str) -> bool {
+    let s = s.strip_prefix('{').and_then(|s| s.strip_suffix('}')).unwrap_or(s);
+    let groups: Vec<&str> = s.split('-').collect();
+    groups.len() == 5
+        && groups.iter().map(|g| g.len()).eq([8, 4, 4, 4, 12])
+        && groups.iter().all(|g| g.chars().all(|c| c.is_ascii_hexdigit()))
+}
+
+fn is_url(s: &str) -> bool {
+    let lower = s.to_ascii_lowercase();
+    let rest = ["https://", "http://", "ftp://", "file://", "ws://", "wss://"]
+        .iter()
+        .find_map(|scheme| lower.strip_prefix(scheme))
+        .or_else(|| lower.strip_prefix("www.").filter(|r| r.contains('.')));
+    rest.is_some_and(|r| !r.is_empty())
+}
+
+fn is_email(s: &str) -> bool {
+    let s = s.strip_prefix("mailto:").unwrap_or(s);
+    let Some((local, domain)) = s.split_once('@') else { return false };
+    !local.is_empty()
+        && local.chars().all(|c| c.is_alphanumeric() || "._%+-".contains(c))
+        && domain.contains('.')
+        && !domain.starts_with('.')
+        && !domain.ends_with('.')
+        && domain.chars().all(|c| c.is_alphanumeric() || c == '.' || c == '-')
+}
+
+fn is_color(s: &str) -> bool {
+    if let Some(hex) = s.strip_prefix('#') {
+        return matches!(hex.len(), 3 | 4 | 6 | 8) && hex.chars().all(|c| c.is_ascii_hexdigit());
+    }
+    let lower = s.to_ascii_lowercase();
+    ["rgb(", "rgba(", "hsl(", "hsla("].iter().any(|f| lower.starts_with(f))
+        && lower.ends_with(')')
+        && lower[lower.find('(').unwrap_or(0) + 1..lower.len() - 1]
+            .chars()
+            .all(|c| c.is_ascii_digit() || " ,.%/".contains(c))
+}
+
+/// `2026-10-18` would otherwise pass for both a phone number and arithmetic.
+fn is_date(s: &str) -> bool {
+    s.len() == 10 && s.as_bytes()[4] == b'-' && s.as_bytes()[7] == b'-'
+}
+
+fn is_phone(s: &str) -> bool {
+    let digits = s.chars().filter(|c| c.is_ascii_digit()).count();
+    (7..=15).contains(&digits)
+        && !is_date(s)
+        && s.chars().all(|c| c.is_ascii_digit() || "+-() .".contains(c))
+        && s.contains(['-', '(', ' ', '+'])
+        && s.rfind('+').is_none_or(|at| at == 0)
+}
+
+/// A number or an arithmetic expression.
+fn is_number(s: &str) -> bool {
+    !is_date(s)
+        && s.chars().any(|c| c.is_ascii_digit())
+        && s.chars().all(|c| c.is_ascii_digit() || " +-*/^%().,_eE".contains(c))
+        && !s.ends_with(['+', '-', '*', '/', '^'])
+}
+
+fn is_path(s: &str) -> bool {
+    let b = s.as_bytes();
+    let drive = b.len() >= 3 && b[0].is_ascii_alphabetic() && b[1] == b':' && (b[2] == b'\\' || b[2] == b'/');
+    let unc = s.starts_with("\\\\") && s.len() > 2;
+    let unix = (s.starts_with('/') || s.starts_with("~/") || s.starts_with("./") || s.starts_with("../"))
+        && s[1..].contains('/')
+        && !s.contains("//");
+    drive || unc || unix
+}
+
+fn is_json(s: &str) -> bool {
+    (s.starts_with('{') && s.ends_with('}') || s.starts_with('[') && s.ends_with(']'))
+        && serde_json::from_str::<serde_json::Value>(s).is_ok()
+}
+
+fn is_xml(s: &str) -> bool {
+    if !(s.starts_with('<') && s.ends_with('>')) {
+        return false;
+    }
+    if s.starts_with("<?xml") || s.starts_with("<!DOCTYPE") || s.starts_with("<!doctype") {
+        return true;
+    }
+    // The root element has to be closed by the last tag
+    let name: String = s[1..].chars().take_while(|c| c.is_alphanumeric() || ":-_.".contains(*c)).collect();
+    !name.is_empty() && (s.ends_with(&format!("</{}>", name)) || s.ends_with("/>") && !s[1..].contains('<'))
+}
+
+fn is_sql(s: &str) -> bool {
+    let upper = s.to_ascii_uppercase();
+    let first = upper.split_whitespace().next().unwrap_or("");
+    let follow: &[&str] = match first {
+        // Prose starts with "select" too, so ask for something only queries have
+        "SELECT" if upper.contains([',', '*', '=', ';']) || upper.contains(" WHERE ") => &[" FROM ", "\nFROM "],
+        "INSERT" => &[" INTO "],
+        "UPDATE" => &[" SET "],
+        "DELETE" => &[" FROM "],
+        "CREATE" | "DROP" | "ALTER" => &[" TABLE ", " INDEX ", " VIEW ", " DATABASE "],
+        "WITH" => &[" AS (", " AS("],
+        _ => return false,
+    };
+    follow.iter().any(|f| upper.contains(f))
+}
+
+/// Commands that rarely start a line of prose. Anything else needs a prompt.
+const SHELL_COMMANDS: &[&str] = &[
+    "git", "cargo", "npm", "npx", "yarn", "pnpm", "pip", "pip3", "sudo", "apt", "apt-get", "brew", "choco",
+    "winget", "docker", "kubectl", "curl", "wget", "ssh", "scp", "rm", "cp", "mv", "mkdir", "chmod", "chown",
+    "tar", "ls", "cd", "grep", "rustup", "dotnet", "systemctl", "journalctl", "ipconfig", "pwsh",
+];
+
+fn is_shell(s: &str) -> bool {
+    let lines: Vec<&str> = s.lines().map(str::trim).filter(|l| !l.is_empty()).collect();
+    if lines.len() > 5 {
+        return false;
+    }
+    lines.iter().all(|line| {
+        if line.starts_with("$ ") || line.starts_with("PS> ") || line.starts_with("PS ") && line.contains("> ") {
+            return true;
+        }
+        let first = line.split_whitespace().next().unwrap_or("");
+        SHELL_COMMANDS.contains(&first)
+            // PowerShell cmdlets
+            || first.split_once('-').is_some_and(|(verb, noun)| {
+                ["Get", "Set", "New", "Remove", "Invoke", "Start", "Stop"].contains(&verb) && !noun.is_empty()
+            })
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn kind(text: &str) -> Option<String> {
+        classify_text(text).map(|k| k.to_string())
+    }
+
+    #[test]
+    fn single_values() {
+        assert_eq!(kind("https://example.com/a?b=c").as_deref(), Some("url"));
+        assert_eq!(kind("www.example.org").as_deref(), Some("url"));
+        assert_eq!(kind("someone.else+tag@mail.example.com").as_deref(), Some("email"));
+        assert_eq!(kind("+1 (555) 010-9999").as_deref(), Some("phone"));
+        assert_eq!(kind("555-0100 99").as_deref(), Some("phone"));
+        assert_eq!(kind(r"C:\Users\me\notes.txt").as_deref(), Some("path"));
+        assert_eq!(kind("/usr/local/bin").as_deref(), Some("path"));
+        assert_eq!(kind("#1e90ff").as_deref(), Some("color"));
+        assert_eq!(kind("rgba(30, 144, 255, 0.5)").as_deref(), Some("color"));
+        assert_eq!(kind("3.14159").as_deref(), Some("number"));
+        assert_eq!(kind("(12 + 4) * 2.5").as_deref(), Some("number"));
+        assert_eq!(kind("1234567").as_deref(), Some("number"));
+        assert_eq!(kind("{6F9619FF-8B86-D011-B42D-00C04FC964FF}").as_deref(), Some("uuid"));
+        assert_eq!(kind("  "), None);
+    }
+
+    #[test]
+    fn structured_text() {
+        assert_eq!(kind("{\"a\": [1, 2, {\"b\": null}]}").as_deref(), Some("json"));
+        assert_eq!(kind("{not json}").as_deref(), Some("text"));
+        assert_eq!(kind("<?xml version=\"1.0\"?><a/>").as_deref(), Some("xml"));
+        assert_eq!(kind("<note><to>Tove</to></note>").as_deref(), Some("xml"));
+        assert_eq!(kind("SELECT id, name\nFROM users WHERE id = 1").as_deref(), Some("sql"));
+        assert_eq!(kind("select * from logs").as_deref(), Some("sql"));
+        assert_eq!(kind("select the best option from the list").as_deref(), Some("text"));
+        assert_eq!(kind("2026-10-18").as_deref(), Some("text"));
+        assert_eq!(kind("update: meeting moved").as_deref(), Some("text"));
+        assert_eq!(kind("$ git commit -m 'x'\n$ git push").as_deref(), Some("shell"));
+        assert_eq!(kind("Get-ChildItem -Recurse").as_deref(), Some("shell"));
+        assert_eq!(kind("Hello there, how are you?").as_deref(), Some("text"));
+    }
+
+    #[test]
+    fn code_languages() {
+        assert_eq!(kind("fn main() {\n    let mut x = 1;\n    println!(\"{}\", x);\n}").as_deref(), Some("code:rust"));
+        assert_eq!(kind("def f(x):\n    return [i for i in range(x)]\n").as_deref(), Some("code:python"));
+        assert_eq!(kind("if err != nil {\n    return fmt.Errorf(\"x\")\n}").as_deref(), Some("code:go"));
+        assert_eq!(kind("const x = (a) => a === 1;\nconsole.log(x(1));").as_deref(), Some("code:javascript"));
+        assert_eq!(kind(".btn {\n  color: red;\n  padding: 4px;\n}").as_deref(), Some("code:css"));
+    }
+
+    #[test]
+    fn stored_names_round_trip() {
+        for stored in ["url", "code", "code:rust", "image", "text"] {
+            assert_eq!(Kind::parse(stored).unwrap().to_string(), stored);
+        }
+        assert_eq!(Kind::parse("nope"), None);
+        assert!(Kind::NAMES.iter().all(|n| Kind::parse(n).is_some()));
+    }
+
+    #[test]
+    fn clips() {
+        let text = |s: &str| ClipboardPayload {
+            format_id: 13,
+            format_name: "CF_UNICODETEXT".into(),
+            data: s.encode_utf16().flat_map(u16::to_le_bytes).collect(),
+        };
+        assert_eq!(classify(&[text("https://x.test")], ""), Some(Kind::Url));
+        let dib = ClipboardPayload { format_id: 8, format_name: "CF_DIB".into(), data: vec![] };
+        assert_eq!(classify(&[dib, text("ignored")], ""), Some(Kind::Image));
+        assert_eq!(classify(&[], ""), None);
+
+        // The title outranks the content guess, but never turns a URL into code
+        let snippet = text("const x = (a) => a === 1;\nconsole.log(x(1));");
+        assert_eq!(classify(&[snippet], "app.ts - openclip - Visual Studio Code"), Some(Kind::Code(Some(Language::TypeScript))));
+        assert_eq!(classify(&[text("x = y")], "● util.py — editor"), Some(Kind::Code(Some(Language::Python))));
+        assert_eq!(classify(&[text("https://x.test")], "main.rs"), Some(Kind::Url));
+    }
+
+    #[test]
+    fn titles() {
+        assert_eq!(language_from_title("main.rs - openclip - Visual Studio Code"), Some(Language::Rust));
+        assert_eq!(language_from_title("[Running] Program.cs (Debugging)"), Some(Language::CSharp));
+        assert_eq!(language_from_title("*scratch.c - Notepad++"), Some(Language::C));
+        assert_eq!(language_from_title("example.com - Google Chrome"), None);
+        assert_eq!(language_from_title(".rs"), None);
+        assert_eq!(language_from_title(""), None);
+    }
+}
@@ -0,0 +1,609 @@
+//! Client-side encryption for synced clips.
+//!
+//! The server keeps a keyring it can't read: a random master key sealed
+//! under a key derived from the sync passphrase with Argon2id, and under the
+//! master key the data keys that seal clip blobs and metadata, plus the id
+//! key that turns content hashes into opaque clip ids. The master key doubles
+//! as the recovery key. None of this involves the local database key.
+//!
+//! The master key is also sealed to the X25519 public key of every device in
+//! the registry, so devices stay in after the passphrase changes. Revoking a
+//! device drops its copy and replaces the master, data and id keys.
+
+use argon2::{Algorithm, Argon2, Params, Version};
+use chacha20poly1305::aead::rand_core::RngCore;
+use chacha20poly1305::aead::{Aead, AeadCore, KeyInit, OsRng, Payload};
+use chacha20poly1305::{XChaCha20Poly1305, XNonce};
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use x25519_dalek::{EphemeralSecret, PublicKey, StaticSecret};
+
+use crate::models::Device;
+
+const SEALED_MAGIC: &[u8; 4] = b"OCE1";
+const NONCE_LEN: usize = 24;
+
+/// Argon2id memory (KiB), passes and lanes for new keyrings.
+#[cfg(not(test))]
+const KDF_COST: (u32, u32, u32) = (64 * 1024, 3, 1);
+#[cfg(test)]
+const KDF_COST: (u32, u32, u32) = (64, 1, 1);
+
+/// The most a stored keyring can ask of Argon2id, so a hostile or broken
+/// server can't have unlocking eat all the memory or run for hours. Costs
+/// above these derive a different key, which reads as a wrong passphrase.
+#[cfg(not(test))]
+const MAX_KDF_COST: (u32, u32, u32) = (256 * 1024, 10, 4);
+#[cfg(test)]
+const MAX_KDF_COST: (u32, u32, u32) = (256, 2, 2);
+
+#[derive(Debug, PartialEq)]
+pub enum CryptoError {
+    WrongPassphrase,
+    BadRecoveryKey,
+    /// Sealed with a data key this keyring doesn't have (any more).
+    UnknownKey(u32),
+    Damaged,
+    Revoked,
+    /// Unlocked with this device's key, so the keyring can't be locked again
+    /// until the passphrase another device set is entered here too.
+    PassphraseChanged,
+}
+
+impl fmt::Display for CryptoError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CryptoError::WrongPassphrase => write!(f, "the sync passphrase doesn't unlock the server's keyring"),
+            CryptoError::BadRecoveryKey => write!(f, "that recovery key doesn't unlock the server's keyring"),
+            CryptoError::UnknownKey(id) => write!(f, "sealed with unknown key {}", id),
+            CryptoError::Damaged => write!(f, "encrypted data is damaged"),
+            CryptoError::Revoked => write!(f, "this device was revoked from the sync set"),
+            CryptoError::PassphraseChanged => write!(f, "the sync passphrase was changed on another device; enter the new one in Settings"),
+        }
+    }
+}
+
+/// The keyring as stored on the server. Every field is either public
+/// (salt, costs, key ids) or sealed.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Keyring {
+    salt: String,
+    m_cost: u32,
+    t_cost: u32,
+    p_cost: u32,
+    /// The master key, sealed under the passphrase key.
+    master: String,
+    /// The id key, sealed under the master key.
+    id_key: String,
+    keys: Vec<WrappedKey>,
+    current: u32,
+    /// The device registry, sealed under the master key.
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    devices: String,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    device_keys: Vec<DeviceWrappedKey>,
+    /// Revoked device ids, in the clear so a revoked device can tell.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    revoked: Vec<String>,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct WrappedKey {
+    id: u32,
+    /// Sealed under the master key.
+    key: String,
+}
+
+/// The master key sealed to one device: X25519 between a one-off key and
+/// the device's public key.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct DeviceWrappedKey {
+    device: String,
+    ephemeral: String,
+    master: String,
+}
+
+/// An unlocked keyring.
+pub struct SyncKeys {
+    master: [u8; 32],
+    id_key: [u8; 32],
+    /// Data keys by id; the newest is `current`.
+    keys: Vec<(u32, [u8; 32])>,
+    current: u32,
+    devices: Vec<Device>,
+    /// False when only this device's key opened the keyring: the passphrase
+    /// changed elsewhere, and locking with the old one would undo that.
+    passphrase_ok: bool,
+}
+
+/// This installation's long-term key. The secret never leaves cloud.db.
+pub struct DeviceKey {
+    pub id: String,
+    secret: StaticSecret,
+}
+
+impl Keyring {
+    /// A keyring with fresh keys, locked with `passphrase`.
+    pub fn create(passphrase: &str) -> (Keyring, SyncKeys) {
+        let keys = SyncKeys {
+            master: random_key(),
+            id_key: random_key(),
+            keys: vec![(1, random_key())],
+            current: 1,
+            devices: Vec::new(),
+            passphrase_ok: true,
+        };
+        (keys.locked(passphrase), keys)
+    }
+
+    pub fn unlock(&self, passphrase: &str) -> Result<SyncKeys, CryptoError> {
+        let salt = from_hex(&self.salt).ok_or(CryptoError::Damaged)?;
+        let cost = (self.m_cost.min(MAX_KDF_COST.0), self.t_cost.min(MAX_KDF_COST.1), self.p_cost.min(MAX_KDF_COST.2));
+        let kek = derive_key(passphrase, &salt, cost)?;
+        let master = open_key(&kek, &self.master, b"master").map_err(|_| CryptoError::WrongPassphrase)?;
+        self.open_with(master, true)
+    }
+
+    /// Unlocks as `device`: with the passphrase, or failing that with the
+    /// device's own key if it's registered.
+    pub fn unlock_as(&self, passphrase: &str, device: &DeviceKey) -> Result<SyncKeys, CryptoError> {
+        if self.revoked.contains(&device.id) {
+            return Err(CryptoError::Revoked);
+        }
+        match self.unlock(passphrase) {
+            Err(CryptoError::WrongPassphrase) => {
+                let wrapped = self.device_keys.iter().find(|k| k.device == device.id).ok_or(CryptoError::WrongPassphrase)?;
+                let ephemeral: [u8; 32] = from_hex(&wrapped.ephemeral).and_then(|k| k.try_into().ok()).ok_or(CryptoError::Damaged)?;
+                let shared = device.secret.diffie_hellman(&PublicKey::from(ephemeral));
+                let kek = device_kek(shared.as_bytes(), &ephemeral, PublicKey::from(&device.secret).as_bytes());
+                let master = open_key(&kek, &wrapped.master, device.id.as_bytes()).map_err(|_| CryptoError::WrongPassphrase)?;
+                self.open_with(master, false)
+            }
+            unlocked => unlocked,
+        }
+    }
+
+    /// Unlocks with the recovery key instead of the passphrase.
+    pub fn recover(&self, recovery_key: &str) -> Result<SyncKeys, CryptoError> {
+        let master = parse_recovery_key(recovery_key)?;
+        self.open_with(master, true).map_err(|_| CryptoError::BadRecoveryKey)
+    }
+
+    fn open_with(&self, master: [u8; 32], passphrase_ok: bool) -> Result<SyncKeys, CryptoError> {
+        let id_key = open_key(&master, &self.id_key, b"id")?;
+        let keys = self
+            .keys
+            .iter()
+            .map(|k| Ok((k.id, open_key(&master, &k.key, &k.id.to_le_bytes())?)))
+            .collect::<Result<Vec<_>, CryptoError>>()?;
+        if !keys.iter().any(|(id, _)| *id == self.current) {
+            return Err(CryptoError::Damaged);
+        }
+        let devices = match self.devices.as_str() {
+            "" => Vec::new(),
+            sealed => {
+                let json = open_with(&master, b"devices", &from_hex(sealed).ok_or(CryptoError::Damaged)?)?;
+                serde_json::from_slice(&json).map_err(|_| CryptoError::Damaged)?
+            }
+        };
+        Ok(SyncKeys { master, id_key, keys, current: self.current, devices, passphrase_ok })
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        serde_json::to_vec(self).expect("keyring serializes")
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Keyring, CryptoError> {
+        serde_json::from_slice(bytes).map_err(|_| CryptoError::Damaged)
+    }
+}
+
+impl SyncKeys {
+    /// Seals the keys under `passphrase`, with a fresh salt, and to every
+    /// device that isn't revoked.
+    pub fn lock(&self, passphrase: &str) -> Result<Keyring, CryptoError> {
+        if !self.passphrase_ok {
+            return Err(CryptoError::PassphraseChanged);
+        }
+        Ok(self.locked(passphrase))
+    }
+
+    fn locked(&self, passphrase: &str) -> Keyring {
+        let mut salt = [0u8; 16];
+        OsRng.fill_bytes(&mut salt);
+        let kek = derive_key(passphrase, &salt, KDF_COST).expect("KDF_COST is valid");
+        Keyring {
+            salt: to_hex(&salt),
+            m_cost: KDF_COST.0,
+            t_cost: KDF_COST.1,
+            p_cost: KDF_COST.2,
+            master: to_hex(&seal_with(&kek, b"master", &self.master)),
+            id_key: to_hex(&seal_with(&self.master, b"id", &self.id_key)),
+            keys: self
+                .keys
+                .iter()
+                .map(|(id, key)| WrappedKey { id: *id, key: to_hex(&seal_with(&self.master, &id.to_le_bytes(), key)) })
+                .collect(),
+            current: self.current,
+            devices: if self.devices.is_empty() {
+                String::new()
+            } else {
+                to_hex(&seal_with(&self.master, b"devices", &serde_json::to_vec(&self.devices).expect("devices serialize")))
+            },
+            device_keys: self
+                .devices
+                .iter()
+                .filter(|d| !d.revoked)
+                .filter_map(|d| Some(seal_to_device(&self.master, &d.id, from_hex(&d.public_key)?.try_into().ok()?)))
+                .collect(),
+            revoked: self.devices.iter().filter(|d| d.revoked).map(|d| d.id.clone()).collect(),
+        }
+    }
+
+    pub fn passphrase_ok(&self) -> bool {
+        self.passphrase_ok
+    }
+
+    pub fn devices(&self) -> &[Device] {
+        &self.devices
+    }
+
+    /// Adds `device` to the registry under `name`, or renames it. Returns
+    /// whether the keyring needs storing again.
+    pub fn register(&mut self, device: &DeviceKey, name: &str) -> bool {
+        let public_key = device.public_key();
+        match self.devices.iter_mut().find(|d| d.id == device.id) {
+            Some(known) if known.name == name && known.public_key == public_key => false,
+            Some(known) => {
+                known.name = name.to_string();
+                known.public_key = public_key;
+                true
+            }
+            None => {
+                self.devices.push(Device { id: device.id.clone(), name: name.to_string(), public_key, revoked: false });
+                true
+            }
+        }
+    }
+
+    /// Cuts a device off: it loses its copy of the master key, and new keys
+    /// it never saw take over. A new id key as well means it can't tell which
+    /// clips are stored any more; everything has to go up again under new
+    /// ids. It can still read what was sealed before. Returns false for a
+    /// device that isn't registered or already revoked.
+    pub fn revoke(&mut self, id: &str) -> bool {
+        match self.devices.iter_mut().find(|d| d.id == id && !d.revoked) {
+            Some(device) => {
+                device.revoked = true;
+                self.rotate();
+                self.id_key = random_key();
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Starts a new data key for everything sealed from now on, under a new
+    /// master key, so the old recovery key stops working. Older data keys
+    /// stay readable until `retire_old_keys`.
+    pub fn rotate(&mut self) {
+        self.master = random_key();
+        self.current = self.keys.iter().map(|(id, _)| *id).max().unwrap_or(0) + 1;
+        self.keys.push((self.current, random_key()));
+    }
+
+    /// Drops every data key but the current one. Returns whether there were any.
+    pub fn retire_old_keys(&mut self) -> bool {
+        let before = self.keys.len();
+        self.keys.retain(|(id, _)| *id == self.current);
+        self.keys.len() < before
+    }
+
+    pub fn current_key(&self) -> u32 {
+        self.current
+    }
+
+    /// Names the current data key without giving it away. Tied to the id
+    /// key, so the same key number in another keyring reads differently.
+    pub fn key_fingerprint(&self) -> String {
+        let key = self.keys.iter().find(|(id, _)| *id == self.current).map(|(_, key)| key).expect("current key exists");
+        self.fingerprint(key)
+    }
+
+    /// Whether clips sealed with the key behind `fingerprint` still open here.
+    pub fn has_key(&self, fingerprint: &str) -> bool {
+        self.keys.iter().any(|(_, key)| self.fingerprint(key) == fingerprint)
+    }
+
+    fn fingerprint(&self, key: &[u8; 32]) -> String {
+        blake3::keyed_hash(&self.id_key, key).to_hex()[..16].to_string()
+    }
+
+    /// The master key with a checksum, in groups of five base32 letters.
+    pub fn recovery_key(&self) -> String {
+        let mut bytes = self.master.to_vec();
+        bytes.extend_from_slice(&blake3::hash(&self.master).as_bytes()[..2]);
+        let code = base32(&bytes);
+        code.as_bytes().chunks(5).map(|c| std::str::from_utf8(c).unwrap()).collect::<Vec<_>>().join("-")
+    }
+
+    /// The id a clip is stored under, so the server never sees content hashes.
+    pub fn remote_id(&self, content_hash: &str) -> String {
+        blake3::keyed_hash(&self.id_key, content_hash.as_bytes()).to_hex().to_string()
+    }
+
+    /// Encrypts with the current data key. `aad` isn't stored but has to match when opening.
+    pub fn seal(&self, aad: &[u8], plaintext: &[u8]) -> Vec<u8> {
+        let key = self.keys.iter().find(|(id, _)| *id == self.current).map(|(_, key)| key).expect("current key exists");
+        let mut sealed = SEALED_MAGIC.to_vec();
+        sealed.extend_from_slice(&self.current.to_le_bytes());
+        let header = sealed.clone();
+        sealed.extend(seal_with(key, &[&header[..], aad].concat(), plaintext));
+        sealed
+    }
+
+    pub fn open(&self, aad: &[u8], sealed: &[u8]) -> Result<Vec<u8>, CryptoError> {
+        let rest = sealed.strip_prefix(SEALED_MAGIC).ok_or(CryptoError::Damaged)?;
+        if rest.len() < 4 {
+            return Err(CryptoError::Damaged);
+        }
+        let (id, body) = rest.split_at(4);
+        let id = u32::from_le_bytes(id.try_into().unwrap());
+        let key = self.keys.iter().find(|(k, _)| *k == id).map(|(_, key)| key).ok_or(CryptoError::UnknownKey(id))?;
+        open_with(key, &[&sealed[..8], aad].concat(), body)
+    }
+}
+
+impl DeviceKey {
+    pub fn generate(id: &str) -> DeviceKey {
+        DeviceKey { id: id.to_string(), secret: StaticSecret::random_from_rng(OsRng) }
+    }
+
+    pub fn from_secret(id: &str, secret_hex: &str) -> Option<DeviceKey> {
+        let secret: [u8; 32] = from_hex(secret_hex)?.try_into().ok()?;
+        Some(DeviceKey { id: id.to_string(), secret: StaticSecret::from(secret) })
+    }
+
+    pub fn secret_hex(&self) -> String {
+        to_hex(self.secret.as_bytes())
+    }
+
+    pub fn public_key(&self) -> String {
+        to_hex(PublicKey::from(&self.secret).as_bytes())
+    }
+
+    /// X25519 with another device's public key.
+    pub fn agree(&self, public_key: [u8; 32]) -> [u8; 32] {
+        self.secret.diffie_hellman(&PublicKey::from(public_key)).to_bytes()
+    }
+}
+
+fn seal_to_device(master: &[u8; 32], device: &str, public_key: [u8; 32]) -> DeviceWrappedKey {
+    let ephemeral = EphemeralSecret::random_from_rng(OsRng);
+    let ephemeral_public = PublicKey::from(&ephemeral);
+    let shared = ephemeral.diffie_hellman(&PublicKey::from(public_key));
+    let kek = device_kek(shared.as_bytes(), ephemeral_public.as_bytes(), &public_key);
+    DeviceWrappedKey {
+        device: device.to_string(),
+        ephemeral: to_hex(ephemeral_public.as_bytes()),
+        master: to_hex(&seal_with(&kek, device.as_bytes(), master)),
+    }
+}
+
+fn device_kek(shared: &[u8; 32], ephemeral: &[u8; 32], public_key: &[u8; 32]) -> [u8; 32] {
+    blake3::derive_key("openclip keyring device key v1", &[&shared[..], ephemeral, public_key].concat())
+}
+
+fn random_key() -> [u8; 32] {
+    let mut key = [0u8; 32];
+    OsRng.fill_bytes(&mut key);
+    key
+}
+
+fn derive_key(passphrase: &str, salt: &[u8], (m_cost, t_cost, p_cost): (u32, u32, u32)) -> Result<[u8; 32], CryptoError> {
+    let params = Params::new(m_cost, t_cost, p_cost, Some(32)).map_err(|_| CryptoError::Damaged)?;
+    let mut key = [0u8; 32];
+    Argon2::new(Algorithm::Argon2id, Version::V0x13, params)
+        .hash_password_into(passphrase.as_bytes(), salt, &mut key)
+        .map_err(|_| CryptoError::Damaged)?;
+    Ok(key)
+}
+
+/// XChaCha20-Poly1305 with a random nonce in front of the ciphertext.
+pub fn seal_with(key: &[u8; 32], aad: &[u8], plaintext: &[u8]) -> Vec<u8> {
+    let cipher = XChaCha20Poly1305::new(key.into());
+    let nonce = XChaCha20Poly1305::generate_nonce(&mut OsRng);
+    let mut sealed = nonce.to_vec();
+    sealed.extend(cipher.encrypt(&nonce, Payload { msg: plaintext, aad }).expect("in-memory encryption"));
+    sealed
+}
+
+pub fn open_with(key: &[u8; 32], aad: &[u8], sealed: &[u8]) -> Result<Vec<u8>, CryptoError> {
+    if sealed.len() < NONCE_LEN {
+        return Err(CryptoError::Damaged);
+    }
+    let (nonce, ciphertext) = sealed.split_at(NONCE_LEN);
+    XChaCha20Poly1305::new(key.into())
+        .decrypt(XNonce::from_slice(nonce), Payload { msg: ciphertext, aad })
+        .map_err(|_| CryptoError::Damaged)
+}
+
+fn open_key(key: &[u8; 32], sealed_hex: &str, aad: &[u8]) -> Result<[u8; 32], CryptoError> {
+    let sealed = from_hex(sealed_hex).ok_or(CryptoError::Damaged)?;
+    open_with(key, aad, &sealed)?.try_into().map_err(|_| CryptoError::Damaged)
+}
+
+fn parse_recovery_key(code: &str) -> Result<[u8; 32], CryptoError> {
+    let letters: String = code.chars().filter(|c| !c.is_whitespace() && *c != '-').collect();
+    let bytes = unbase32(&letters.to_ascii_uppercase()).ok_or(CryptoError::BadRecoveryKey)?;
+    if bytes.len() != 34 {
+        return Err(CryptoError::BadRecoveryKey);
+    }
+    let master: [u8; 32] = bytes[..32].try_into().unwrap();
+    if blake3::hash(&master).as_bytes()[..2] != bytes[32..] {
+        return Err(CryptoError::BadRecoveryKey);
+    }
+    Ok(master)
+}
+
+const BASE32: &[u8; 32] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZ234567";
+
+fn base32(bytes: &[u8]) -> String {
+    let (mut out, mut buffer, mut bits) = (String::new(), 0u32, 0);
+    for &b in bytes {
+        buffer = (buffer << 8) | b as u32;
+        bits += 8;
+        while bits >= 5 {
+            bits -= 5;
+            out.push(BASE32[((buffer >> bits) & 31) as usize] as char);
+        }
+    }
+    if bits > 0 {
+        out.push(BASE32[((buffer << (5 - bits)) & 31) as usize] as char);
+    }
+    out
+}
+
+fn unbase32(text: &str) -> Option<Vec<u8>> {
+    let (mut out, mut buffer, mut bits) = (Vec::new(), 0u32, 0);
+    for c in text.bytes() {
+        let value = BASE32.iter().position(|&b| b == c)? as u32;
+        buffer = (buffer << 5) | value;
+        bits += 5;
+        if bits >= 8 {
+            bits -= 8;
+            out.push((buffer >> bits) as u8);
+        }
+    }
+    Some(out)
+}
+
+pub fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+pub fn from_hex(text: &str) -> Option<Vec<u8>> {
+    if !text.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..text.len()).step_by(2).map(|i| u8::from_str_radix(text.get(i..i + 2)?, 16).ok()).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn seals_and_opens() {
+        let (_, keys) = Keyring::create("correct horse");
+        let sealed = keys.seal(b"clip", b"secret text");
+        assert!(!sealed.windows(6).any(|w| w == b"secret"));
+        assert_eq!(keys.open(b"clip", &sealed).unwrap(), b"secret text");
+        assert_eq!(keys.open(b"other clip", &sealed), Err(CryptoError::Damaged));
+        let mut tampered = sealed.clone();
+        *tampered.last_mut().unwrap() ^= 1;
+        assert_eq!(keys.open(b"clip", &tampered), Err(CryptoError::Damaged));
+    }
+
+    #[test]
+    fn passphrase_and_recovery_key() {
+        let (keyring, keys) = Keyring::create("correct horse");
+        let keyring = Keyring::from_bytes(&keyring.to_bytes()).unwrap();
+        let sealed = keys.seal(b"", b"hello");
+        assert_eq!(keyring.unlock("correct horse").unwrap().open(b"", &sealed).unwrap(), b"hello");
+        assert_eq!(keyring.unlock("wrong horse").err(), Some(CryptoError::WrongPassphrase));
+
+        let code = keys.recovery_key();
+        assert_eq!(code.len(), 55 + 10);
+        let recovered = keyring.recover(&code.to_lowercase().replace('-', " ")).unwrap();
+        assert_eq!(recovered.open(b"", &sealed).unwrap(), b"hello");
+        let typo = code.replacen(|c: char| c.is_ascii_alphabetic(), "7", 1);
+        assert_eq!(keyring.recover(&typo).err(), Some(CryptoError::BadRecoveryKey));
+
+        // A new passphrase for the same keys
+        let relocked = recovered.lock("battery staple").unwrap();
+        assert_eq!(relocked.unlock("battery staple").unwrap().remote_id("aa"), keys.remote_id("aa"));
+    }
+
+    #[test]
+    fn rotation() {
+        let (_, mut keys) = Keyring::create("pass");
+        let old_code = keys.recovery_key();
+        let before = keys.seal(b"", b"before");
+        let id = keys.remote_id("aa");
+        let old_key = keys.key_fingerprint();
+
+        keys.rotate();
+        assert_eq!(keys.current_key(), 2);
+        let keyring = keys.lock("pass").unwrap();
+        assert_eq!(keyring.recover(&old_code).err(), Some(CryptoError::BadRecoveryKey));
+        let unlocked = keyring.unlock("pass").unwrap();
+        assert_eq!(unlocked.open(b"", &before).unwrap(), b"before");
+        assert_eq!(unlocked.remote_id("aa"), id);
+        assert_eq!(unlocked.open(b"", &unlocked.seal(b"", b"after")).unwrap(), b"after");
+
+        assert!(unlocked.has_key(&old_key) && unlocked.key_fingerprint() != old_key);
+        assert!(keys.retire_old_keys());
+        assert!(!keys.retire_old_keys());
+        assert!(!keys.has_key(&old_key));
+        // Another keyring's first key isn't this one's
+        assert!(!keys.has_key(&Keyring::create("pass").1.key_fingerprint()));
+        assert_eq!(keys.open(b"", &before), Err(CryptoError::UnknownKey(1)));
+    }
+
+    #[test]
+    fn devices_and_revocation() {
+        let (_, mut keys) = Keyring::create("pass");
+        let (laptop, desktop) = (DeviceKey::generate("laptop"), DeviceKey::generate("desktop"));
+        assert!(keys.register(&laptop, "Laptop"));
+        assert!(keys.register(&desktop, "Desktop"));
+        assert!(!keys.register(&desktop, "Desktop"));
+        let keyring = keys.lock("pass").unwrap();
+
+        // A registered device gets in without the passphrase, but can't lock
+        let unlocked = keyring.unlock_as("new pass", &laptop).unwrap();
+        assert_eq!(unlocked.devices().iter().map(|d| d.name.as_str()).collect::<Vec<_>>(), ["Laptop", "Desktop"]);
+        assert_eq!(unlocked.lock("new pass").err(), Some(CryptoError::PassphraseChanged));
+        let stranger = DeviceKey::generate("stranger");
+        assert_eq!(keyring.unlock_as("new pass", &stranger).err(), Some(CryptoError::WrongPassphrase));
+
+        let old_id = keys.remote_id("aa");
+        assert!(keys.revoke("laptop"));
+        assert!(!keys.revoke("laptop"));
+        let keyring = keys.lock("new pass").unwrap();
+        assert_eq!(keyring.unlock_as("pass", &laptop).err(), Some(CryptoError::Revoked));
+        let desktop_keys = keyring.unlock_as("pass", &desktop).unwrap();
+        assert_eq!(desktop_keys.current_key(), 2);
+        assert_ne!(desktop_keys.remote_id("aa"), old_id);
+        assert_eq!(desktop_keys.remote_id("aa"), keys.remote_id("aa"));
+        assert!(desktop_keys.devices()[0].revoked);
+
+        let reloaded = DeviceKey::from_secret("desktop", &desktop.secret_hex()).unwrap();
+        assert_eq!(reloaded.public_key(), desktop.public_key());
+    }
+
+    #[test]
+    fn stored_costs_are_capped() {
+        let (_, keys) = Keyring::create("pass");
+        let mut keyring = keys.lock("pass").unwrap();
+        // Sealed under the capped costs, but asking for far more
+        let kek = derive_key("pass", &from_hex(&keyring.salt).unwrap(), MAX_KDF_COST).unwrap();
+        keyring.master = to_hex(&seal_with(&kek, b"master", &keys.master));
+        (keyring.m_cost, keyring.t_cost, keyring.p_cost) = (u32::MAX, u32::MAX, u32::MAX);
+        assert_eq!(keyring.unlock("pass").unwrap().remote_id("aa"), keys.remote_id("aa"));
+
+        keyring.p_cost = 0;
+        assert_eq!(keyring.unlock("pass").err(), Some(CryptoError::Damaged));
+    }
+
+    #[test]
+    fn encodings() {
+        assert_eq!(base32(b"foobar"), "MZXW6YTBOI");
+        assert_eq!(unbase32("MZXW6YTBOI").unwrap(), b"foobar");
+        assert_eq!(from_hex(&to_hex(&[0, 15, 255])).unwrap(), [0, 15, 255]);
+        assert_eq!(from_hex("abc"), None);
+    }
+}
@@ -1,7 +1,23 @@
-use rusqlite::{params, Connection, Result};
-use std::collections::HashSet;
-use crate::storage::Database;
+use rusqlite::{params, Connection, OptionalExtension, Result};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fmt;
+use std::io::Read;
+use std::time::Duration;
 
+use openclip_sync::protocol::{self, Change, Changes, ErrorBody, NewUpload, UploadStatus};
+
+use crate::classify;
+use crate::cloudcrypto::{self, CryptoError, DeviceKey, Keyring, SyncKeys};
+use crate::crdt::{ClipState, Clock};
+use crate::formats;
+use crate::imaging;
+use crate::models::{ClipboardPayload, CloudClip, Device, Peer, PushState};
+use crate::storage::{self, Database};
+
+/// Clips pushed to the cloud. Without a `[sync]` server or folder this file
+/// is the cloud; with one it mirrors the server, tracking what still has to
+/// go up and how far the server's change feed has been read.
 pub struct CloudDatabase {
     conn: Connection,
 }
@@ -17,12 +33,434 @@ fn apply_cipher_pragmas(conn: &Connection, password: &str) -> Result<()> {
     Ok(())
 }
 
+#[derive(Debug)]
+pub enum SyncError {
+    Db(rusqlite::Error),
+    /// The server couldn't be reached; worth retrying later.
+    Offline(String),
+    Unauthorized,
+    Server(u16, String),
+    Corrupt(String),
+    Crypto(CryptoError),
+}
+
+impl fmt::Display for SyncError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SyncError::Db(e) => write!(f, "cloud.db: {}", e),
+            SyncError::Offline(e) => write!(f, "sync server unreachable: {}", e),
+            SyncError::Unauthorized => write!(f, "sync server rejected the token"),
+            SyncError::Server(status, msg) => write!(f, "sync server error {}: {}", status, msg),
+            SyncError::Corrupt(msg) => write!(f, "bad data from sync server: {}", msg),
+            SyncError::Crypto(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl From<rusqlite::Error> for SyncError {
+    fn from(e: rusqlite::Error) -> Self {
+        SyncError::Db(e)
+    }
+}
+
+impl From<CryptoError> for SyncError {
+    fn from(e: CryptoError) -> Self {
+        SyncError::Crypto(e)
+    }
+}
+
+/// Everything about a clip besides its payloads.
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct ClipMeta {
+    pub owner: String,
+    pub title: String,
+    pub exe: String,
+    pub timestamp: String,
+}
+
+/// What gets sealed into the protocol's `meta`: the clip's metadata, its
+/// content hash, which the server only sees as a keyed hash, and the
+/// sender's replica of its pin, tags, note and deletion.
+#[derive(Serialize, Deserialize)]
+struct SealedMeta {
+    hash: String,
+    /// The device the clip was copied on, which isn't always the uploader.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    device: Option<String>,
+    #[serde(flatten)]
+    meta: ClipMeta,
+    #[serde(default)]
+    state: ClipState,
+}
+
+#[derive(Debug, Default, PartialEq)]
+pub struct SyncReport {
+    pub pushed: usize,
+    pub pulled: usize,
+    /// Clips whose pin, tags, note or deletion went up without their payloads.
+    pub updated: usize,
+    /// Clips dropped here because another device deleted them, plus this device's deletions sent up.
+    pub deleted: usize,
+    /// Clips the server refused or sent back damaged. Refused uploads are
+    /// retried on the next few syncs.
+    pub failed: usize,
+    /// Only this device's key opened the keyring.
+    pub passphrase_changed: bool,
+}
+
+impl fmt::Display for SyncReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} pushed, {} pulled", self.pushed, self.pulled)?;
+        if self.updated > 0 {
+            write!(f, ", {} updated", self.updated)?;
+        }
+        if self.deleted > 0 {
+            write!(f, ", {} deleted", self.deleted)?;
+        }
+        if self.failed > 0 {
+            write!(f, ", {} failed", self.failed)?;
+        }
+        if self.passphrase_changed {
+            write!(f, ". {}", CryptoError::PassphraseChanged)?;
+        }
+        Ok(())
+    }
+}
+
+/// What's in cloud.db, for the management panel.
+#[derive(Debug, Default, PartialEq)]
+pub struct CloudStats {
+    pub clips: usize,
+    pub bytes: u64,
+    /// Most clips first.
+    pub devices: Vec<DeviceUsage>,
+    pub pending_uploads: usize,
+    /// Uploads given up on after `MAX_PUSH_ATTEMPTS`.
+    pub failed_uploads: usize,
+    pub pending_deletes: usize,
+    /// Local time of the last sync that finished.
+    pub last_sync: Option<String>,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct DeviceUsage {
+    pub device: String,
+    /// From the registry, once a sync has brought it in.
+    pub name: Option<String>,
+    pub this_device: bool,
+    pub clips: usize,
+    pub bytes: u64,
+}
+
+/// A bulk delete from the management panel.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Cleanup {
+    OlderThanDays(u32),
+    Device(String),
+    All,
+}
+
+/// Where synced clips are kept beyond this machine.
+pub trait Remote {
+    /// Stores a clip, resuming an earlier partial upload of the same one.
+    fn upload(&self, id: &str, meta: &str, blob: &[u8], device: &str) -> std::result::Result<(), SyncError>;
+    /// Clips stored or deleted after sequence number `since`.
+    fn changes(&self, since: i64) -> std::result::Result<Changes, SyncError>;
+    fn download(&self, id: &str) -> std::result::Result<Vec<u8>, SyncError>;
+    /// Leaves a tombstone so the other devices drop the clip too.
+    fn delete(&self, id: &str, device: &str) -> std::result::Result<(), SyncError>;
+    /// Replaces the meta of a stored clip or a tombstone, keeping the blob.
+    /// An id the remote never stored is `Server(404, _)`.
+    fn put_meta(&self, id: &str, meta: &str, device: &str) -> std::result::Result<(), SyncError>;
+    /// The shared keyring and its version, None until a device stores one.
+    fn keyring(&self) -> std::result::Result<Option<(u64, Vec<u8>)>, SyncError>;
+    /// Replaces the keyring if it's still at version `replaces` (0 for none)
+    /// and returns the new version. Losing that race is `Server(409, _)`.
+    fn put_keyring(&self, replaces: u64, keyring: &[u8]) -> std::result::Result<u64, SyncError>;
+}
+
+/// An openclip-sync server.
+pub struct HttpRemote {
+    base: String,
+    token: String,
+    agent: ureq::Agent,
+    chunk_size: usize,
+}
+
+/// Times a chunk is re-sent after the connection drops before giving up until the next sync.
+const CHUNK_RETRIES: u32 = 3;
+
+impl HttpRemote {
+    pub fn new(server: &str, token: &str) -> Self {
+        let agent = ureq::AgentBuilder::new()
+            .timeout_connect(Duration::from_secs(5))
+            .timeout_read(Duration::from_secs(30))
+            .timeout_write(Duration::from_secs(30))
+            .build();
+        HttpRemote {
+            base: server.trim_end_matches('/').to_string(),
+            token: token.to_string(),
+            agent,
+            chunk_size: 1 << 20,
+        }
+    }
+
+    fn request(&self, method: &str, path: &str) -> ureq::Request {
+        self.agent
+            .request(method, &format!("{}{}", self.base, path))
+            .set("Authorization", &format!("Bearer {}", self.token))
+    }
+
+    fn start_upload(&self, new: &NewUpload) -> std::result::Result<UploadStatus, SyncError> {
+        let response = self.request("POST", "/v1/uploads").send_json(new).map_err(http_error)?;
+        response.into_json().map_err(|e| SyncError::Corrupt(e.to_string()))
+    }
+
+    /// Sends `chunk` at `offset`. A 409 isn't an error: it carries the offset to continue from.
+    fn send_chunk(&self, upload: &str, offset: u64, chunk: &[u8]) -> std::result::Result<UploadStatus, SyncError> {
+        let sent = self
+            .request("PATCH", &format!("/v1/uploads/{}", upload))
+            .set(protocol::OFFSET_HEADER, &offset.to_string())
+            .send_bytes(chunk);
+        match sent {
+            Ok(response) => response.into_json().map_err(|e| SyncError::Corrupt(e.to_string())),
+            Err(ureq::Error::Status(409, response)) => Ok(UploadStatus {
+                upload: Some(upload.to_string()),
+                offset: offset_header(&response)?,
+                complete: false,
+            }),
+            Err(e) => Err(http_error(e)),
+        }
+    }
+
+    fn server_offset(&self, upload: &str) -> std::result::Result<u64, SyncError> {
+        let response = self.request("HEAD", &format!("/v1/uploads/{}", upload)).call().map_err(http_error)?;
+        offset_header(&response)
+    }
+}
+
+impl Remote for HttpRemote {
+    fn upload(&self, id: &str, meta: &str, blob: &[u8], device: &str) -> std::result::Result<(), SyncError> {
+        let new = NewUpload {
+            id: id.to_string(),
+            size: blob.len() as u64,
+            blob_hash: blake3::hash(blob).to_hex().to_string(),
+            meta: meta.to_string(),
+            device: device.to_string(),
+        };
+        let mut status = self.start_upload(&new)?;
+        let mut retries = 0;
+        while !status.complete {
+            let upload = status.upload.clone().ok_or_else(|| SyncError::Corrupt("no upload id".to_string()))?;
+            let offset = status.offset.min(new.size) as usize;
+            let end = (offset + self.chunk_size).min(blob.len());
+            match self.send_chunk(&upload, offset as u64, &blob[offset..end]) {
+                Ok(next) => {
+                    status = next;
+                    retries = 0;
+                }
+                Err(SyncError::Offline(_)) if retries < CHUNK_RETRIES => {
+                    // Part of the chunk may have landed; ask before sending more
+                    retries += 1;
+                    status.offset = self.server_offset(&upload)?;
+                }
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(())
+    }
+
+    fn changes(&self, since: i64) -> std::result::Result<Changes, SyncError> {
+        let response = self.request("GET", &format!("/v1/changes?since={}", since)).call().map_err(http_error)?;
+        response.into_json().map_err(|e| SyncError::Corrupt(e.to_string()))
+    }
+
+    fn download(&self, id: &str) -> std::result::Result<Vec<u8>, SyncError> {
+        let response = self.request("GET", &format!("/v1/clips/{}", id)).call().map_err(http_error)?;
+        let mut blob = Vec::new();
+        response
+            .into_reader()
+            .take(protocol::MAX_CLIP_SIZE + 1)
+            .read_to_end(&mut blob)
+            .map_err(|e| SyncError::Offline(e.to_string()))?;
+        Ok(blob)
+    }
+
+    fn delete(&self, id: &str, device: &str) -> std::result::Result<(), SyncError> {
+        self.request("DELETE", &format!("/v1/clips/{}?device={}", id, device)).call().map_err(http_error)?;
+        Ok(())
+    }
+
+    fn put_meta(&self, id: &str, meta: &str, device: &str) -> std::result::Result<(), SyncError> {
+        self.request("PUT", &format!("/v1/clips/{}/meta?device={}", id, device)).send_string(meta).map_err(http_error)?;
+        Ok(())
+    }
+
+    fn keyring(&self) -> std::result::Result<Option<(u64, Vec<u8>)>, SyncError> {
+        let response = match self.request("GET", "/v1/keyring").call() {
+            Ok(response) => response,
+            Err(ureq::Error::Status(404, _)) => return Ok(None),
+            Err(e) => return Err(http_error(e)),
+        };
+        let version = version_header(&response)?;
+        let mut keyring = Vec::new();
+        response
+            .into_reader()
+            .take(protocol::MAX_KEYRING_SIZE + 1)
+            .read_to_end(&mut keyring)
+            .map_err(|e| SyncError::Offline(e.to_string()))?;
+        Ok(Some((version, keyring)))
+    }
+
+    fn put_keyring(&self, replaces: u64, keyring: &[u8]) -> std::result::Result<u64, SyncError> {
+        let response = self
+            .request("PUT", "/v1/keyring")
+            .set(protocol::KEYRING_VERSION_HEADER, &replaces.to_string())
+            .send_bytes(keyring)
+            .map_err(http_error)?;
+        version_header(&response)
+    }
+}
+
+fn http_error(e: ureq::Error) -> SyncError {
+    match e {
+        ureq::Error::Status(401, _) => SyncError::Unauthorized,
+        ureq::Error::Status(status, response) => {
+            let reason = response.status_text().to_string();
+            let message = response.into_json::<ErrorBody>().map_or(reason, |body| body.error);
+            SyncError::Server(status, message)
+        }
+        ureq::Error::Transport(t) => SyncError::Offline(t.to_string()),
+    }
+}
+
+fn offset_header(response: &ureq::Response) -> std::result::Result<u64, SyncError> {
+    response
+        .header(protocol::OFFSET_HEADER)
+        .and_then(|v| v.trim().parse().ok())
+        .ok_or_else(|| SyncError::Corrupt(format!("missing {} header", protocol::OFFSET_HEADER)))
+}
+
+fn push_state(uploaded: Option<bool>, attempts: Option<i64>, error: Option<String>) -> PushState {
+    match (uploaded.unwrap_or(false), attempts.unwrap_or(0), error) {
+        (true, _, _) => PushState::Uploaded,
+        (false, attempts, Some(error)) if attempts >= MAX_PUSH_ATTEMPTS => PushState::Failed(error),
+        _ => PushState::Pending,
+    }
+}
+
+fn version_header(response: &ureq::Response) -> std::result::Result<u64, SyncError> {
+    response
+        .header(protocol::KEYRING_VERSION_HEADER)
+        .and_then(|v| v.trim().parse().ok())
+        .ok_or_else(|| SyncError::Corrupt(format!("missing {} header", protocol::KEYRING_VERSION_HEADER)))
+}
+
+/// Fetches and unlocks the server's keyring, creating one if this is the
+/// first device to sync. Returns it with its version.
+fn unlock(remote: &dyn Remote, passphrase: &str, device: &DeviceKey) -> std::result::Result<(u64, SyncKeys), SyncError> {
+    loop {
+        if let Some((version, keyring)) = remote.keyring()? {
+            return Ok((version, Keyring::from_bytes(&keyring)?.unlock_as(passphrase, device)?));
+        }
+        let (keyring, keys) = Keyring::create(passphrase);
+        match remote.put_keyring(0, &keyring.to_bytes()) {
+            Ok(version) => return Ok((version, keys)),
+            // Another device created one first; use theirs
+            Err(SyncError::Server(409, _)) => continue,
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+/// Unlocks the server's keyring with a recovery key and locks it with
+/// `passphrase` instead, which every device then has to use.
+pub fn recover(remote: &dyn Remote, recovery_key: &str, passphrase: &str) -> std::result::Result<(), SyncError> {
+    let (version, keyring) = remote.keyring()?.ok_or(CryptoError::BadRecoveryKey)?;
+    let keys = Keyring::from_bytes(&keyring)?.recover(recovery_key)?;
+    remote.put_keyring(version, &keys.lock(passphrase)?.to_bytes())?;
+    Ok(())
+}
+
+fn seal_meta(keys: &SyncKeys, id: &str, meta: &SealedMeta) -> String {
+    let json = serde_json::to_vec(meta).expect("meta serializes");
+    cloudcrypto::to_hex(&keys.seal(format!("meta:{}", id).as_bytes(), &json))
+}
+
+fn open_meta(keys: &SyncKeys, id: &str, sealed: &str) -> std::result::Result<SealedMeta, SyncError> {
+    let sealed = cloudcrypto::from_hex(sealed).ok_or_else(|| SyncError::Corrupt("meta isn't sealed".to_string()))?;
+    let json = keys.open(format!("meta:{}", id).as_bytes(), &sealed)?;
+    let meta: SealedMeta = serde_json::from_slice(&json).map_err(|e| SyncError::Corrupt(e.to_string()))?;
+    // Stops the server from passing off one clip's meta as another's
+    if keys.remote_id(&meta.hash) != id {
+        return Err(SyncError::Corrupt("meta belongs to another clip".to_string()));
+    }
+    Ok(meta)
+}
+
+/// Gives a history clip the pin, tags and note from its cloud state.
+fn apply_state(target: &Database, hash: &str, state: &ClipState) -> Result<()> {
+    target.set_pin_slot(hash, state.pin.value)?;
+    target.set_tags(hash, &state.tags.tags().join(" "))?;
+    target.set_note(hash, &state.note.value)
+}
+
+/// Syncs an upload is tried on before it's marked failed and left for the user to retry.
+pub const MAX_PUSH_ATTEMPTS: i64 = 5;
+
+const BLOB_MAGIC: &[u8; 4] = b"OCP1";
+
+/// Packs payloads as `OCP1` followed by, per format, its id (u32), name
+/// length (u32), name, data length (u64) and data, all little-endian.
+pub fn encode_payloads(payloads: &[ClipboardPayload]) -> Vec<u8> {
+    let size: usize = payloads.iter().map(|p| 16 + p.format_name.len() + p.data.len()).sum();
+    let mut blob = Vec::with_capacity(4 + size);
+    blob.extend_from_slice(BLOB_MAGIC);
+    for p in payloads {
+        blob.extend_from_slice(&p.format_id.to_le_bytes());
+        blob.extend_from_slice(&(p.format_name.len() as u32).to_le_bytes());
+        blob.extend_from_slice(p.format_name.as_bytes());
+        blob.extend_from_slice(&(p.data.len() as u64).to_le_bytes());
+        blob.extend_from_slice(&p.data);
+    }
+    blob
+}
+
+pub fn decode_payloads(blob: &[u8]) -> std::result::Result<Vec<ClipboardPayload>, SyncError> {
+    let corrupt = || SyncError::Corrupt("truncated clip blob".to_string());
+    let mut rest = blob.strip_prefix(BLOB_MAGIC).ok_or_else(|| SyncError::Corrupt("not a clip blob".to_string()))?;
+    let mut take = |n: usize| -> std::result::Result<&[u8], SyncError> {
+        if rest.len() < n {
+            return Err(corrupt());
+        }
+        let (head, tail) = rest.split_at(n);
+        rest = tail;
+        Ok(head)
+    };
+    let mut payloads = Vec::new();
+    loop {
+        let Ok(id) = take(4) else { break };
+        let format_id = u32::from_le_bytes(id.try_into().unwrap());
+        let name_len = u32::from_le_bytes(take(4)?.try_into().unwrap()) as usize;
+        let format_name = String::from_utf8(take(name_len)?.to_vec()).map_err(|_| corrupt())?;
+        let data_len = u64::from_le_bytes(take(8)?.try_into().unwrap());
+        let data = take(usize::try_from(data_len).map_err(|_| corrupt())?)?.to_vec();
+        payloads.push(ClipboardPayload { format_id, format_name, data });
+    }
+    // A few bytes too short for another format id
+    if !rest.is_empty() {
+        return Err(corrupt());
+    }
+    Ok(payloads)
+}
+
 impl CloudDatabase {
     pub fn new(path: &str, password: &str) -> Result<Self> {
         let conn = Connection::open(path)?;
         apply_cipher_pragmas(&conn, password)?;
         let db = CloudDatabase { conn };
         db.create_tables()?;
+        db.migrate()?;
         Ok(db)
     }
 
@@ -50,28 +488,295 @@ impl CloudDatabase {
             )",
             [],
         )?;
+        // The registry as of the last sync
+        self.conn.execute(
+            "CREATE TABLE IF NOT EXISTS devices (
+                id TEXT PRIMARY KEY,
+                name TEXT,
+                public_key TEXT,
+                revoked INTEGER DEFAULT 0
+            )",
+            [],
+        )?;
+        self.conn.execute(
+            "CREATE TABLE IF NOT EXISTS sync_state (
+                key TEXT PRIMARY KEY,
+                value TEXT
+            )",
+            [],
+        )?;
+        // Each clip's replica of its pin, tags, note and deletion, kept after
+        // the clip is deleted. `dirty` ones have edits the server hasn't got;
+        // `unapplied` ones have edits from elsewhere the history hasn't got.
+        self.conn.execute(
+            "CREATE TABLE IF NOT EXISTS clip_states (
+                content_hash TEXT PRIMARY KEY,
+                state TEXT NOT NULL,
+                dirty INTEGER DEFAULT 0,
+                unapplied INTEGER DEFAULT 0
+            )",
+            [],
+        )?;
+        // Devices paired for sending clips over the local network
+        self.conn.execute(
+            "CREATE TABLE IF NOT EXISTS peers (
+                id TEXT PRIMARY KEY,
+                name TEXT,
+                public_key TEXT
+            )",
+            [],
+        )?;
+        // Deletions made here that the server hasn't heard about yet
+        self.conn.execute(
+            "CREATE TABLE IF NOT EXISTS tombstones (
+                content_hash TEXT PRIMARY KEY,
+                deleted_at DATETIME DEFAULT CURRENT_TIMESTAMP
+            )",
+            [],
+        )?;
         Ok(())
     }
 
-    pub fn copy_clip_from(&self, hash: &str, source: &Database) -> Result<()> {
+    fn migrate(&self) -> Result<()> {
+        // Clips pushed before there was a server still have to go up
+        storage::add_column_if_missing(&self.conn, "clips", "uploaded", "INTEGER DEFAULT 0")?;
+        storage::add_column_if_missing(&self.conn, "clips", "push_attempts", "INTEGER DEFAULT 0")?;
+        storage::add_column_if_missing(&self.conn, "clips", "push_error", "TEXT")?;
+        storage::add_column_if_missing(&self.conn, "peers", "linked", "INTEGER DEFAULT 0")?;
+        // The data key each upload was sealed with, from `SyncKeys::key_fingerprint`
+        storage::add_column_if_missing(&self.conn, "clips", "sealed_with", "TEXT")?;
+        if storage::add_column_if_missing(&self.conn, "clips", "device", "TEXT")? {
+            // Everything already here was pushed from this machine
+            self.conn.execute("UPDATE clips SET device = ?", [self.device_id()?])?;
+        }
+        Ok(())
+    }
+
+    /// This installation's id, created on first use. Sent with uploads and
+    /// deletions so clips can be counted per device.
+    pub fn device_id(&self) -> Result<String> {
+        if let Some(id) = self.sync_value("device_id")? {
+            return Ok(id);
+        }
+        let mut hasher = blake3::Hasher::new();
+        hasher.update(&chrono::Utc::now().timestamp_nanos_opt().unwrap_or_default().to_le_bytes());
+        hasher.update(&std::process::id().to_le_bytes());
+        hasher.update(std::env::var("COMPUTERNAME").or_else(|_| std::env::var("HOSTNAME")).unwrap_or_default().as_bytes());
+        let id = hasher.finalize().to_hex()[..16].to_string();
+        self.set_sync_value("device_id", &id)?;
+        Ok(id)
+    }
+
+    /// This installation's id and key pair, created on first use.
+    pub fn device_key(&self) -> Result<DeviceKey> {
+        let id = self.device_id()?;
+        if let Some(key) = self.sync_value("device_secret")?.and_then(|secret| DeviceKey::from_secret(&id, &secret)) {
+            return Ok(key);
+        }
+        let key = DeviceKey::generate(&id);
+        self.set_sync_value("device_secret", &key.secret_hex())?;
+        Ok(key)
+    }
+
+    /// The name the next sync registers this device under.
+    pub fn set_device_name(&self, name: &str) -> Result<()> {
+        self.set_sync_value("device_name", name)
+    }
+
+    /// Every device in the registry, revoked ones included.
+    pub fn devices(&self) -> Result<Vec<Device>> {
+        let mut stmt = self.conn.prepare("SELECT id, name, public_key, revoked FROM devices ORDER BY revoked, name")?;
+        let devices = stmt
+            .query_map([], |r| Ok(Device { id: r.get(0)?, name: r.get(1)?, public_key: r.get(2)?, revoked: r.get(3)? }))?
+            .collect::<Result<Vec<_>>>()?;
+        Ok(devices)
+    }
+
+    /// Devices paired over the local network.
+    pub fn peers(&self) -> Result<Vec<Peer>> {
+        let mut stmt = self.conn.prepare("SELECT id, name, public_key FROM peers ORDER BY name")?;
+        let peers = stmt
+            .query_map([], |r| Ok(Peer { id: r.get(0)?, name: r.get(1)?, public_key: r.get(2)? }))?
+            .collect::<Result<Vec<_>>>()?;
+        Ok(peers)
+    }
+
+    /// Adds a peer, or replaces its name and key after pairing again.
+    pub fn save_peer(&self, peer: &Peer) -> Result<()> {
+        self.conn.execute(
+            "INSERT OR REPLACE INTO peers (id, name, public_key) VALUES (?, ?, ?)",
+            params![peer.id, peer.name, peer.public_key],
+        )?;
+        Ok(())
+    }
+
+    /// The peer the clipboard is linked with.
+    pub fn linked_peer(&self) -> Result<Option<String>> {
+        self.conn.query_row("SELECT id FROM peers WHERE linked = 1", [], |r| r.get(0)).optional()
+    }
+
+    pub fn set_linked_peer(&self, id: Option<&str>) -> Result<()> {
+        self.conn.execute("UPDATE peers SET linked = (id IS ?)", [id])?;
+        Ok(())
+    }
+
+    pub fn forget_peer(&self, id: &str) -> Result<()> {
+        self.conn.execute("DELETE FROM peers WHERE id = ?", [id])?;
+        Ok(())
+    }
+
+    fn save_devices(&self, devices: &[Device]) -> Result<()> {
+        let tx = self.conn.unchecked_transaction()?;
+        tx.execute("DELETE FROM devices", [])?;
+        for d in devices {
+            tx.execute(
+                "INSERT INTO devices (id, name, public_key, revoked) VALUES (?, ?, ?, ?)",
+                params![d.id, d.name, d.public_key, d.revoked],
+            )?;
+        }
+        tx.commit()
+    }
+
+    /// Queues a history clip for the cloud. Returns false if it's already there.
+    pub fn copy_clip_from(&self, hash: &str, source: &Database) -> Result<bool> {
+        if self.has_clip(hash)? {
+            return Ok(false);
+        }
+
+        let (owner, title, exe) = source.get_clip_meta(hash)?;
+        let device = match source.get_clip_device(hash)? {
+            Some(device) => device,
+            None => self.device_id()?,
+        };
+        let mut payloads = source.get_clip_payloads(hash)?;
+        // Add the MIME forms so machines without Win32 formats can read the clip
+        let extras = formats::mime_extras(&payloads);
+        payloads.extend(extras);
+        let meta = ClipMeta { owner, title, exe, timestamp: chrono::Utc::now().format("%Y-%m-%d %H:%M:%S").to_string() };
+        // Pushing a clip that was deleted earlier brings it back
+        self.conn.execute("DELETE FROM tombstones WHERE content_hash = ?", [hash])?;
+        self.insert_clip(hash, &meta, payloads, false, &device)?;
+        self.edit_state(hash, |state, clock| state.set_deleted(false, clock))?;
+        self.record_metadata(hash, source)?;
+        Ok(true)
+    }
+
+    /// Records the history's pin, tags and note for a cloud clip as edits
+    /// made on this device, for the next sync to pass on. Returns false if
+    /// the clip isn't in the cloud or nothing changed.
+    pub fn record_metadata(&self, hash: &str, source: &Database) -> Result<bool> {
+        if !self.has_clip(hash)? {
+            return Ok(false);
+        }
+        let (pin, tags, note) = source.get_clip_metadata(hash)?;
+        self.edit_state(hash, |state, clock| {
+            state.set_pin(pin, clock);
+            state.set_tags(&tags, clock);
+            state.set_note(&note, clock);
+        })
+    }
+
+    /// Gives the history the pin, tags and note other devices set, for the
+    /// clips it has. Returns how many clips changed.
+    pub fn apply_states(&self, target: &Database) -> Result<usize> {
+        let mut applied = 0;
+        for hash in self.hashes("SELECT content_hash FROM clip_states WHERE unapplied = 1")? {
+            if let Some(state) = self.clip_state(&hash)? {
+                if target.has_clip(&hash)? {
+                    apply_state(target, &hash, &state)?;
+                    applied += 1;
+                }
+            }
+            self.conn.execute("UPDATE clip_states SET unapplied = 0 WHERE content_hash = ?", [&hash])?;
+        }
+        Ok(applied)
+    }
+
+    fn clip_state(&self, hash: &str) -> Result<Option<ClipState>> {
+        let json: Option<String> =
+            self.conn.query_row("SELECT state FROM clip_states WHERE content_hash = ?", [hash], |r| r.get(0)).optional()?;
+        Ok(json.and_then(|json| match serde_json::from_str(&json) {
+            Ok(state) => Some(state),
+            Err(e) => {
+                eprintln!("cloud.db: bad state for {}: {}", hash, e);
+                None
+            }
+        }))
+    }
+
+    fn save_state(&self, hash: &str, state: &ClipState, dirty: bool, unapplied: bool) -> Result<()> {
+        let json = serde_json::to_string(state).expect("clip state serializes");
+        self.conn.execute(
+            "INSERT INTO clip_states (content_hash, state, dirty, unapplied) VALUES (?, ?, ?, ?)
+             ON CONFLICT(content_hash) DO UPDATE SET
+                state = excluded.state, dirty = excluded.dirty, unapplied = unapplied OR excluded.unapplied",
+            params![hash, json, dirty, unapplied],
+        )?;
+        Ok(())
+    }
+
+    /// This device's Lamport clock for clip states.
+    fn clock(&self) -> Result<Clock> {
+        let time = self.sync_value("clock")?.and_then(|v| v.parse().ok()).unwrap_or(0);
+        Ok(Clock::new(&self.device_id()?, time))
+    }
+
+    /// Never moves the clock back, even if another connection ticked it meanwhile.
+    fn save_clock(&self, clock: &Clock) -> Result<()> {
+        self.conn.execute(
+            "INSERT INTO sync_state (key, value) VALUES ('clock', ?1)
+             ON CONFLICT(key) DO UPDATE SET value = max(CAST(value AS INTEGER), ?1)",
+            [clock.time as i64],
+        )?;
+        Ok(())
+    }
+
+    /// Edits a clip's state on this device. Returns whether anything changed.
+    fn edit_state(&self, hash: &str, edit: impl FnOnce(&mut ClipState, &mut Clock)) -> Result<bool> {
+        let before = self.clip_state(hash)?.unwrap_or_default();
+        let mut clock = self.clock()?;
+        clock.observe(&before);
+        let mut state = before.clone();
+        edit(&mut state, &mut clock);
+        if state == before {
+            return Ok(false);
+        }
+        self.save_state(hash, &state, true, false)?;
+        self.save_clock(&clock)?;
+        Ok(true)
+    }
+
+    /// Merges in another device's replica and returns the result. It's left
+    /// dirty when this device knows something `remote` doesn't.
+    fn merge_state(&self, hash: &str, remote: &ClipState) -> Result<ClipState> {
+        let local = self.clip_state(hash)?.unwrap_or_default();
+        let mut merged = local.clone();
+        merged.merge(remote);
+        if merged != local {
+            self.save_state(hash, &merged, merged != *remote, !merged.same_view(&local))?;
+            let mut clock = self.clock()?;
+            clock.observe(&merged);
+            self.save_clock(&clock)?;
+        }
+        Ok(merged)
+    }
+
+    fn has_clip(&self, hash: &str) -> Result<bool> {
         let exists: u32 = self.conn.query_row(
             "SELECT COUNT(1) FROM clips WHERE content_hash = ?",
             [hash],
             |r| r.get(0),
         )?;
-        if exists > 0 {
-            return Ok(());
-        }
-
-        let (owner, fg_title, exe_path) = source.get_clip_meta(hash)?;
-        let payloads = source.get_clip_payloads(hash)?;
+        Ok(exists > 0)
+    }
 
+    fn insert_clip(&self, hash: &str, meta: &ClipMeta, payloads: Vec<ClipboardPayload>, uploaded: bool, device: &str) -> Result<()> {
         let tx = self.conn.unchecked_transaction()?;
 
         tx.execute(
-            "INSERT INTO clips (owner_process_name, foreground_window_title, exe_path, content_hash)
-             VALUES (?, ?, ?, ?)",
-            params![owner, fg_title, exe_path, hash],
+            "INSERT INTO clips (owner_process_name, foreground_window_title, exe_path, content_hash, timestamp, uploaded, device)
+             VALUES (?, ?, ?, ?, ?, ?, ?)",
+            params![meta.owner, meta.title, meta.exe, hash, meta.timestamp, uploaded, device],
         )?;
         let clip_id = tx.last_insert_rowid();
 
@@ -86,11 +791,983 @@ impl CloudDatabase {
         Ok(())
     }
 
-    pub fn get_synced_hashes(&self) -> Result<HashSet<String>> {
-        let mut stmt = self.conn.prepare("SELECT content_hash FROM clips")?;
-        let hashes = stmt
-            .query_map([], |row| row.get(0))?
-            .collect::<Result<HashSet<String>, _>>()?;
+    /// Every clip in cloud.db and how its upload is going.
+    pub fn push_states(&self) -> Result<HashMap<String, PushState>> {
+        let mut stmt = self.conn.prepare("SELECT content_hash, uploaded, push_attempts, push_error FROM clips")?;
+        let states = stmt
+            .query_map([], |r| Ok((r.get(0)?, push_state(r.get(1)?, r.get(2)?, r.get(3)?))))?
+            .collect::<Result<HashMap<String, PushState>, _>>()?;
+        Ok(states)
+    }
+
+    /// Puts a failed upload back in the queue.
+    pub fn retry_push(&self, hash: &str) -> Result<()> {
+        self.conn.execute("UPDATE clips SET push_attempts = 0, push_error = NULL WHERE content_hash = ?", [hash])?;
+        Ok(())
+    }
+
+    /// Queues every clip to be uploaded again.
+    fn requeue_all(&self) -> Result<()> {
+        self.conn.execute("UPDATE clips SET uploaded = 0, push_attempts = 0, push_error = NULL", [])?;
+        Ok(())
+    }
+
+    /// Newest first, with the same kind of text preview as the history list.
+    pub fn list_clips(&self, preview_len: usize) -> Result<Vec<CloudClip>> {
+        let mut stmt = self.conn.prepare(
+            "SELECT content_hash, timestamp, owner_process_name, foreground_window_title, uploaded, push_attempts, push_error,
+             (SELECT SUM(length(data)) FROM formats WHERE clip_id = clips.id),
+             (SELECT format_id FROM formats WHERE clip_id = clips.id
+                     AND (format_id IN (13, 1) OR format_name = 'text/plain')
+                     ORDER BY format_id = 0, id LIMIT 1) as text_format,
+             (SELECT data FROM formats WHERE clip_id = clips.id
+                     AND (format_id IN (13, 1) OR format_name = 'text/plain')
+                     ORDER BY format_id = 0, id LIMIT 1) as text,
+             (SELECT group_concat(format_name, ', ') FROM formats WHERE clip_id = clips.id AND format_id != 0),
+             coalesce((SELECT name FROM devices WHERE devices.id = clips.device), device)
+             FROM clips ORDER BY timestamp DESC, id DESC",
+        )?;
+        let clips = stmt
+            .query_map([], |r| {
+                let text = match (r.get::<_, Option<u32>>(8)?, r.get::<_, Option<Vec<u8>>>(9)?) {
+                    (Some(0), Some(bytes)) => Some(String::from_utf8_lossy(&bytes).into_owned()),
+                    (Some(_), Some(bytes)) => Some(storage::decode_text(&bytes)),
+                    _ => None,
+                };
+                let preview = match text {
+                    Some(text) => text.chars().take(preview_len).collect(),
+                    None => format!("[ {} ]", r.get::<_, Option<String>>(10)?.unwrap_or_else(|| "empty".to_string())),
+                };
+                Ok(CloudClip {
+                    hash: r.get(0)?,
+                    timestamp: r.get::<_, Option<String>>(1)?.unwrap_or_default(),
+                    owner: r.get::<_, Option<String>>(2)?.unwrap_or_default(),
+                    fg_title: r.get::<_, Option<String>>(3)?.unwrap_or_default(),
+                    push: push_state(r.get(4)?, r.get(5)?, r.get(6)?),
+                    size: r.get::<_, Option<i64>>(7)?.unwrap_or(0) as usize,
+                    device: r.get(11)?,
+                    preview,
+                })
+            })?
+            .collect::<Result<Vec<_>>>()?;
+        Ok(clips)
+    }
+
+    /// Copies a cloud clip into the history database, classified and with a
+    /// thumbnail like a fresh capture. Returns false if history already has it.
+    pub fn import_clip(&self, hash: &str, target: &Database) -> Result<bool> {
+        let (meta, payloads) = self.get_clip(hash)?;
+        let kind = classify::classify(&payloads, &meta.title);
+        let thumbnail = imaging::thumbnail_for(&payloads);
+        if !target.save_snapshot(&meta.owner, &meta.title, &meta.exe, hash, &payloads, false)? {
+            return Ok(false);
+        }
+        if let Some(kind) = kind {
+            target.set_kind(hash, &kind.to_string())?;
+        }
+        if let Some(thumb) = thumbnail {
+            target.save_thumbnail(hash, &thumb)?;
+        }
+        if let Some(device) = self.clip_device(hash)? {
+            target.set_device(hash, &device)?;
+        }
+        if let Some(state) = self.clip_state(hash)? {
+            apply_state(target, hash, &state)?;
+        }
+        Ok(true)
+    }
+
+    pub fn get_clip(&self, hash: &str) -> Result<(ClipMeta, Vec<ClipboardPayload>)> {
+        let (clip_id, meta) = self.clip_meta(hash)?.ok_or(rusqlite::Error::QueryReturnedNoRows)?;
+        let mut stmt = self.conn.prepare("SELECT format_id, format_name, data FROM formats WHERE clip_id = ? ORDER BY id")?;
+        let payloads = stmt
+            .query_map([clip_id], |r| Ok(ClipboardPayload { format_id: r.get(0)?, format_name: r.get(1)?, data: r.get(2)? }))?
+            .collect::<Result<Vec<_>>>()?;
+        Ok((meta, payloads))
+    }
+
+    fn clip_meta(&self, hash: &str) -> Result<Option<(i64, ClipMeta)>> {
+        self.conn.query_row(
+            "SELECT id, owner_process_name, foreground_window_title, exe_path, timestamp
+             FROM clips WHERE content_hash = ? LIMIT 1",
+            [hash],
+            |r| {
+                let meta = ClipMeta {
+                    owner: r.get::<_, Option<String>>(1)?.unwrap_or_default(),
+                    title: r.get::<_, Option<String>>(2)?.unwrap_or_default(),
+                    exe: r.get::<_, Option<String>>(3)?.unwrap_or_default(),
+                    timestamp: r.get::<_, Option<String>>(4)?.unwrap_or_default(),
+                };
+                Ok((r.get(0)?, meta))
+            },
+        )
+        .optional()
+    }
+
+    fn clip_device(&self, hash: &str) -> Result<Option<String>> {
+        let device = self.conn.query_row("SELECT device FROM clips WHERE content_hash = ? LIMIT 1", [hash], |r| r.get(0)).optional()?;
+        Ok(device.flatten())
+    }
+
+    fn sync_value(&self, key: &str) -> Result<Option<String>> {
+        self.conn
+            .query_row("SELECT value FROM sync_state WHERE key = ?", [key], |r| r.get(0))
+            .optional()
+    }
+
+    fn set_sync_value(&self, key: &str, value: &str) -> Result<()> {
+        self.conn.execute(
+            "INSERT INTO sync_state (key, value) VALUES (?, ?) ON CONFLICT(key) DO UPDATE SET value = excluded.value",
+            params![key, value],
+        )?;
+        Ok(())
+    }
+
+    /// Removes clips from the cloud. They're remembered as tombstones until
+    /// the next sync tells the server, which passes the deletion on to the
+    /// other devices. Returns how many clips were removed.
+    pub fn delete_clips(&self, hashes: &[String]) -> Result<usize> {
+        let tx = self.conn.unchecked_transaction()?;
+        let mut removed = 0;
+        for hash in hashes {
+            removed += tx.execute("DELETE FROM clips WHERE content_hash = ?", [hash])?;
+            tx.execute("INSERT OR IGNORE INTO tombstones (content_hash) VALUES (?)", [hash])?;
+            self.edit_state(hash, |state, clock| state.set_deleted(true, clock))?;
+        }
+        tx.commit()?;
+        Ok(removed)
+    }
+
+    /// The clips a bulk cleanup would delete.
+    pub fn cleanup_targets(&self, cleanup: &Cleanup) -> Result<Vec<String>> {
+        let (condition, arg) = match cleanup {
+            Cleanup::OlderThanDays(days) => ("timestamp < datetime('now', ?)", format!("-{} days", days)),
+            Cleanup::Device(device) => ("device = ?", device.clone()),
+            Cleanup::All => ("? IS NOT NULL", String::new()),
+        };
+        let mut stmt = self.conn.prepare(&format!("SELECT DISTINCT content_hash FROM clips WHERE {}", condition))?;
+        let hashes = stmt.query_map([arg], |r| r.get(0))?.collect::<Result<Vec<_>>>()?;
         Ok(hashes)
     }
-}
\ No newline at end of file
+
+    pub fn stats(&self) -> Result<CloudStats> {
+        let own = self.device_id()?;
+        let mut stmt = self.conn.prepare(
+            "SELECT COALESCE(device, ''), COUNT(1), COALESCE(SUM((SELECT SUM(length(data)) FROM formats WHERE clip_id = clips.id)), 0),
+             (SELECT name FROM devices WHERE devices.id = clips.device)
+             FROM clips GROUP BY 1 ORDER BY 2 DESC, 1",
+        )?;
+        let devices = stmt
+            .query_map([], |r| {
+                let device: String = r.get(0)?;
+                Ok(DeviceUsage {
+                    this_device: device == own,
+                    device,
+                    name: r.get(3)?,
+                    clips: r.get::<_, i64>(1)? as usize,
+                    bytes: r.get::<_, i64>(2)? as u64,
+                })
+            })?
+            .collect::<Result<Vec<_>>>()?;
+        let count = |sql: &str| -> Result<usize> { self.conn.query_row(sql, [], |r| r.get::<_, i64>(0)).map(|n| n as usize) };
+        Ok(CloudStats {
+            clips: devices.iter().map(|d| d.clips).sum(),
+            bytes: devices.iter().map(|d| d.bytes).sum(),
+            devices,
+            pending_uploads: count(&format!(
+                "SELECT COUNT(1) FROM clips WHERE (uploaded = 0 OR uploaded IS NULL) AND coalesce(push_attempts, 0) < {}",
+                MAX_PUSH_ATTEMPTS
+            ))?,
+            failed_uploads: count(&format!("SELECT COUNT(1) FROM clips WHERE uploaded = 0 AND push_attempts >= {}", MAX_PUSH_ATTEMPTS))?,
+            pending_deletes: count("SELECT COUNT(1) FROM tombstones")?,
+            last_sync: self.sync_value("last_sync")?,
+        })
+    }
+
+    /// Pulls what other devices stored, changed or deleted since the last
+    /// sync, then sends this device's deletions, the clips the remote hasn't
+    /// got yet and edits to the ones it has, all sealed with the keys
+    /// `passphrase` unlocks. Each clip's pin, tags, note and deletion are
+    /// merged with the other devices' (see `crdt`), so a deletion wins over a
+    /// clip uploaded before it was pulled and a push after it wins back.
+    /// Losing the connection stops the sync; a clip the server refuses is
+    /// skipped and tried again next time.
+    pub fn sync(&self, remote: &dyn Remote, passphrase: &str) -> std::result::Result<SyncReport, SyncError> {
+        let mut report = SyncReport::default();
+        let device_key = self.device_key()?;
+        let device = device_key.id.clone();
+        let (mut version, mut keys) = unlock(remote, passphrase, &device_key)?;
+        report.passphrase_changed = !keys.passphrase_ok();
+
+        // Join the registry, or tell it about a new name. Losing a race with
+        // another device, or not knowing the passphrase, waits for the next sync.
+        let name = self.sync_value("device_name")?.unwrap_or_else(|| format!("device {}", device));
+        if keys.register(&device_key, &name) {
+            if let Ok(keyring) = keys.lock(passphrase) {
+                match remote.put_keyring(version, &keyring.to_bytes()) {
+                    Ok(new_version) => version = new_version,
+                    Err(SyncError::Server(409, _)) => {}
+                    Err(e) => return Err(e),
+                }
+            }
+        }
+        self.save_devices(keys.devices())?;
+
+        if self.sync_value("sealed")?.is_none() {
+            // Clips pushed before encryption sit on the server in the clear
+            // under their content hash; take them down and push them sealed.
+            for hash in self.hashes("SELECT content_hash FROM clips WHERE uploaded = 1")? {
+                remote.delete(&hash, &device)?;
+            }
+            self.requeue_all()?;
+            self.set_sync_value("sealed", "1")?;
+        }
+
+        // Devices that each made a keyring while apart, as with a shared
+        // folder that hadn't synced yet, end up with the other one's. What
+        // went up under the keyring that lost can't be read anywhere else.
+        let sealed_with = self.hashes("SELECT DISTINCT sealed_with FROM clips WHERE uploaded = 1 AND sealed_with IS NOT NULL")?;
+        if sealed_with.iter().any(|fingerprint| !keys.has_key(fingerprint)) {
+            self.requeue_all()?;
+        }
+
+        let local: HashMap<String, String> = self
+            .hashes("SELECT content_hash FROM clips")?
+            .into_iter()
+            .map(|hash| (keys.remote_id(&hash), hash))
+            .collect();
+        let mut since: i64 = self.sync_value("cursor")?.and_then(|v| v.parse().ok()).unwrap_or(0);
+        loop {
+            let page = remote.changes(since)?;
+            for change in &page.changes {
+                if !protocol::valid_id(&change.id) {
+                    continue;
+                }
+                match self.apply_change(remote, &keys, change, local.get(&change.id), &mut report) {
+                    Ok(()) => {}
+                    Err(e @ (SyncError::Corrupt(_) | SyncError::Crypto(_))) => {
+                        eprintln!("sync: skipping {}: {}", change.id, e);
+                        report.failed += 1;
+                    }
+                    Err(e) => return Err(e),
+                }
+            }
+            since = page.next;
+            self.set_sync_value("cursor", &since.to_string())?;
+            if !page.more {
+                break;
+            }
+        }
+
+        for hash in self.hashes("SELECT content_hash FROM tombstones")? {
+            match remote.delete(&keys.remote_id(&hash), &device) {
+                Ok(()) => {
+                    self.conn.execute("DELETE FROM tombstones WHERE content_hash = ?", [&hash])?;
+                    report.deleted += 1;
+                }
+                Err(e @ SyncError::Server(..)) => {
+                    eprintln!("sync: deleting {} failed: {}", hash, e);
+                    report.failed += 1;
+                }
+                Err(e) => return Err(e),
+            }
+        }
+
+        let queued = format!(
+            "SELECT content_hash FROM clips WHERE (uploaded = 0 OR uploaded IS NULL) AND coalesce(push_attempts, 0) < {}",
+            MAX_PUSH_ATTEMPTS
+        );
+        for hash in self.hashes(&queued)? {
+            let (meta, payloads) = self.get_clip(&hash)?;
+            let id = keys.remote_id(&hash);
+            let state = self.clip_state(&hash)?.unwrap_or_default();
+            let meta = seal_meta(&keys, &id, &SealedMeta { hash: hash.clone(), device: self.clip_device(&hash)?, meta, state });
+            let blob = keys.seal(id.as_bytes(), &encode_payloads(&payloads));
+            match remote.upload(&id, &meta, &blob, &device) {
+                Ok(()) => {
+                    self.conn.execute(
+                        "UPDATE clips SET uploaded = 1, sealed_with = ?, push_attempts = 0, push_error = NULL WHERE content_hash = ?",
+                        params![keys.key_fingerprint(), hash],
+                    )?;
+                    self.conn.execute("UPDATE clip_states SET dirty = 0 WHERE content_hash = ?", [&hash])?;
+                    report.pushed += 1;
+                }
+                Err(e @ (SyncError::Server(..) | SyncError::Corrupt(_))) => {
+                    eprintln!("sync: pushing {} failed: {}", hash, e);
+                    self.conn.execute(
+                        "UPDATE clips SET push_attempts = coalesce(push_attempts, 0) + 1, push_error = ? WHERE content_hash = ?",
+                        params![e.to_string(), hash],
+                    )?;
+                    report.failed += 1;
+                }
+                Err(e) => return Err(e),
+            }
+        }
+
+        // Edits to clips the server already has, or had, go up on their own.
+        // Clips still waiting to upload take theirs along.
+        let edited = "SELECT content_hash FROM clip_states WHERE dirty = 1
+             AND content_hash NOT IN (SELECT content_hash FROM clips WHERE uploaded = 0 OR uploaded IS NULL)";
+        for hash in self.hashes(edited)? {
+            let id = keys.remote_id(&hash);
+            let state = self.clip_state(&hash)?.unwrap_or_default();
+            let meta = self.clip_meta(&hash)?.map(|(_, meta)| meta).unwrap_or_default();
+            let meta = seal_meta(&keys, &id, &SealedMeta { hash: hash.clone(), device: self.clip_device(&hash)?, meta, state });
+            match remote.put_meta(&id, &meta, &device) {
+                Ok(()) => report.updated += 1,
+                // Never got to the server, so there's nobody to tell
+                Err(SyncError::Server(404, _)) => {}
+                Err(e @ SyncError::Server(..)) => {
+                    eprintln!("sync: updating {} failed: {}", hash, e);
+                    report.failed += 1;
+                    continue;
+                }
+                Err(e) => return Err(e),
+            }
+            self.conn.execute("UPDATE clip_states SET dirty = 0 WHERE content_hash = ?", [&hash])?;
+        }
+
+        // After a rotation, once everything is sealed with the new key the
+        // old ones can go. Another device rotating meanwhile wins; retry later.
+        if report.failed == 0 && keys.retire_old_keys() {
+            if let Ok(keyring) = keys.lock(passphrase) {
+                match remote.put_keyring(version, &keyring.to_bytes()) {
+                    Ok(_) | Err(SyncError::Server(409, _)) => {}
+                    Err(e) => return Err(e),
+                }
+            }
+        }
+
+        self.set_sync_value("last_sync", &chrono::Local::now().format("%Y-%m-%d %H:%M").to_string())?;
+        Ok(report)
+    }
+
+    /// Starts a new encryption key and master key. Every clip is queued to be
+    /// sealed again with the new key on the next sync, after which the old
+    /// key is dropped. Returns the new key's number.
+    pub fn rotate_key(&self, remote: &dyn Remote, passphrase: &str) -> std::result::Result<u32, SyncError> {
+        let (version, mut keys) = unlock(remote, passphrase, &self.device_key()?)?;
+        keys.rotate();
+        remote.put_keyring(version, &keys.lock(passphrase)?.to_bytes())?;
+        self.requeue_all()?;
+        Ok(keys.current_key())
+    }
+
+    /// The recovery key for the server's keyring, to unlock it if the passphrase is lost.
+    pub fn recovery_key(&self, remote: &dyn Remote, passphrase: &str) -> std::result::Result<String, SyncError> {
+        Ok(unlock(remote, passphrase, &self.device_key()?)?.1.recovery_key())
+    }
+
+    /// Cuts `device` off from the sync set. The keyring moves to new keys
+    /// under `new_passphrase`, which the revoked device doesn't know; the
+    /// other registered devices keep their access until they're given it.
+    /// The revocation changes the ids clips are stored under, so the copies
+    /// under the old ids are taken down and every clip is queued to go up
+    /// again, sealed with the new key. Returns false if the device wasn't
+    /// registered or was already revoked.
+    pub fn revoke_device(&self, remote: &dyn Remote, passphrase: &str, device: &str, new_passphrase: &str) -> std::result::Result<bool, SyncError> {
+        let device_key = self.device_key()?;
+        let (version, mut keys) = unlock(remote, passphrase, &device_key)?;
+        let old_ids: Vec<String> = self.hashes("SELECT content_hash FROM clips WHERE uploaded = 1")?.iter().map(|hash| keys.remote_id(hash)).collect();
+        if !keys.revoke(device) {
+            return Ok(false);
+        }
+        remote.put_keyring(version, &keys.lock(new_passphrase)?.to_bytes())?;
+        self.save_devices(keys.devices())?;
+        self.requeue_all()?;
+        for id in old_ids {
+            remote.delete(&id, &device_key.id)?;
+        }
+        Ok(true)
+    }
+
+    fn hashes(&self, sql: &str) -> Result<Vec<String>> {
+        let mut stmt = self.conn.prepare(sql)?;
+        let hashes = stmt.query_map([], |r| r.get(0))?.collect::<Result<Vec<_>>>()?;
+        Ok(hashes)
+    }
+
+    /// Brings one entry of the change feed into cloud.db. `known` is the
+    /// content hash when this device has the clip.
+    fn apply_change(&self, remote: &dyn Remote, keys: &SyncKeys, change: &Change, known: Option<&String>, report: &mut SyncReport) -> std::result::Result<(), SyncError> {
+        if change.meta.is_empty() {
+            // A tombstone without a state, either left by an older version or
+            // not yet followed by its state: the deletion wins for now
+            if let Some(hash) = known {
+                report.deleted += self.conn.execute("DELETE FROM clips WHERE content_hash = ?", [hash])?.min(1);
+            }
+            return Ok(());
+        }
+        let sealed = open_meta(keys, &change.id, &change.meta)?;
+        let state = self.merge_state(&sealed.hash, &sealed.state)?;
+        let here = self.has_clip(&sealed.hash)?;
+        match (state.is_deleted(), change.deleted) {
+            (true, deleted) => {
+                report.deleted += self.conn.execute("DELETE FROM clips WHERE content_hash = ?", [&sealed.hash])?.min(1);
+                if !deleted {
+                    // Uploaded by a device that hadn't seen the deletion; take it down again
+                    self.conn.execute("INSERT OR IGNORE INTO tombstones (content_hash) VALUES (?)", [&sealed.hash])?;
+                }
+            }
+            // Pushed again here after the deletion the server has; send it back up
+            (false, true) if here => {
+                self.conn.execute("UPDATE clips SET uploaded = 0, push_attempts = 0 WHERE content_hash = ?", [&sealed.hash])?;
+            }
+            (false, true) => {}
+            (false, false) if here => {}
+            (false, false) => {
+                let origin = sealed.device.clone().unwrap_or_else(|| change.device.clone());
+                self.pull_clip(remote, keys, &change.id, sealed, &origin)?;
+                report.pulled += 1;
+            }
+        }
+        Ok(())
+    }
+
+    fn pull_clip(&self, remote: &dyn Remote, keys: &SyncKeys, id: &str, sealed: SealedMeta, device: &str) -> std::result::Result<(), SyncError> {
+        let payloads = decode_payloads(&keys.open(id.as_bytes(), &remote.download(id)?)?)?;
+        // The content hash is the hash of the primary format, so it vouches for the payloads
+        match payloads.first() {
+            Some(first) if blake3::hash(&first.data).to_hex().as_str() == sealed.hash => {}
+            _ => return Err(SyncError::Corrupt("payloads don't match the clip hash".to_string())),
+        }
+        self.insert_clip(&sealed.hash, &sealed.meta, payloads, true, device)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::folderlog::FolderRemote;
+    use openclip_sync::Server;
+    use std::collections::HashSet;
+
+    /// Starts a server on a free loopback port and returns its URL.
+    fn start_server(dir: &std::path::Path) -> String {
+        let server = Server::bind("127.0.0.1:0", &dir.join("server"), vec!["secret".to_string()]).unwrap();
+        let url = format!("http://{}", server.addr());
+        std::thread::spawn(move || server.run());
+        url
+    }
+
+    fn cloud(dir: &std::path::Path, name: &str) -> CloudDatabase {
+        CloudDatabase::new(&dir.join(name).to_string_lossy(), "pwd").unwrap()
+    }
+
+    fn clip(text: &str) -> (String, Vec<ClipboardPayload>) {
+        let payloads = vec![
+            ClipboardPayload { format_id: 1, format_name: "CF_TEXT".to_string(), data: text.as_bytes().to_vec() },
+            ClipboardPayload { format_id: 0xC0A0, format_name: "HTML Format".to_string(), data: vec![0, 255, 7] },
+        ];
+        (blake3::hash(text.as_bytes()).to_hex().to_string(), payloads)
+    }
+
+    fn meta(owner: &str) -> ClipMeta {
+        ClipMeta { owner: owner.to_string(), title: "Notes".to_string(), exe: String::new(), timestamp: "2026-10-18 09:00:00".to_string() }
+    }
+
+    #[test]
+    fn blobs_round_trip() {
+        let (_, payloads) = clip("hello");
+        let decoded = decode_payloads(&encode_payloads(&payloads)).unwrap();
+        assert_eq!(decoded.len(), 2);
+        assert_eq!((decoded[1].format_id, decoded[1].format_name.as_str(), decoded[1].data.as_slice()), (0xC0A0, "HTML Format", &[0, 255, 7][..]));
+        let blob = encode_payloads(&payloads);
+        assert!(decode_payloads(&blob[..blob.len() - 1]).is_err());
+        for extra in 1..4 {
+            let mut trailing = blob.clone();
+            trailing.extend(std::iter::repeat_n(0, extra));
+            assert!(matches!(decode_payloads(&trailing), Err(SyncError::Corrupt(_))), "{} trailing bytes", extra);
+        }
+        assert!(decode_payloads(b"nope").is_err());
+    }
+
+    #[test]
+    fn syncs_between_devices() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path();
+        let url = start_server(dir);
+        let remote = HttpRemote::new(&url, "secret");
+        let (laptop, desktop) = (cloud(dir, "laptop.db"), cloud(dir, "desktop.db"));
+
+        let (hash, payloads) = clip("from the laptop");
+        laptop.insert_clip(&hash, &meta("notepad.exe"), payloads, false, "laptop").unwrap();
+        assert_eq!(laptop.sync(&remote, "pass").unwrap(), SyncReport { pushed: 1, ..SyncReport::default() });
+        // Nothing left to do once it's up
+        assert_eq!(laptop.sync(&remote, "pass").unwrap(), SyncReport::default());
+
+        let (other, payloads) = clip("from the desktop");
+        desktop.insert_clip(&other, &meta("code.exe"), payloads, false, "desktop").unwrap();
+        assert_eq!(desktop.sync(&remote, "pass").unwrap(), SyncReport { pushed: 1, pulled: 1, ..SyncReport::default() });
+        let (pulled_meta, pulled) = desktop.get_clip(&hash).unwrap();
+        assert_eq!(pulled_meta, meta("notepad.exe"));
+        assert_eq!(pulled[0].data, b"from the laptop");
+        assert_eq!(pulled[1].data, [0, 255, 7]);
+
+        assert_eq!(laptop.sync(&remote, "pass").unwrap().pulled, 1);
+        assert_eq!(laptop.push_states().unwrap().into_keys().collect::<HashSet<_>>(), desktop.push_states().unwrap().into_keys().collect::<HashSet<_>>());
+
+        let intruder = HttpRemote::new(&url, "guess");
+        assert!(matches!(intruder.changes(0), Err(SyncError::Unauthorized)));
+    }
+
+    #[test]
+    fn deletions_reach_other_devices() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path();
+        let url = start_server(dir);
+        let remote = HttpRemote::new(&url, "secret");
+        let (laptop, desktop) = (cloud(dir, "laptop.db"), cloud(dir, "desktop.db"));
+
+        let (kept, payloads) = clip("kept");
+        laptop.insert_clip(&kept, &meta("notepad.exe"), payloads, false, "laptop").unwrap();
+        let (dropped, payloads) = clip("dropped");
+        laptop.insert_clip(&dropped, &meta("notepad.exe"), payloads, false, "laptop").unwrap();
+        laptop.sync(&remote, "pass").unwrap();
+        assert_eq!(desktop.sync(&remote, "pass").unwrap().pulled, 2);
+
+        assert_eq!(desktop.delete_clips(std::slice::from_ref(&dropped)).unwrap(), 1);
+        assert_eq!(desktop.stats().unwrap().pending_deletes, 1);
+        assert_eq!(desktop.sync(&remote, "pass").unwrap().deleted, 1);
+        assert_eq!(desktop.stats().unwrap().pending_deletes, 0);
+        assert_eq!(laptop.sync(&remote, "pass").unwrap().deleted, 1);
+        assert_eq!(laptop.push_states().unwrap().into_keys().collect::<HashSet<_>>(), HashSet::from([kept.clone()]));
+
+        // A deletion made before pulling the upload keeps the clip from coming back
+        let (late, payloads) = clip("late");
+        laptop.insert_clip(&late, &meta("notepad.exe"), payloads, false, "laptop").unwrap();
+        laptop.sync(&remote, "pass").unwrap();
+        desktop.delete_clips(std::slice::from_ref(&late)).unwrap();
+        let report = desktop.sync(&remote, "pass").unwrap();
+        assert_eq!((report.pulled, report.deleted), (0, 1));
+        assert_eq!(laptop.sync(&remote, "pass").unwrap().deleted, 1);
+        assert_eq!(desktop.push_states().unwrap().into_keys().collect::<HashSet<_>>(), HashSet::from([kept]));
+    }
+
+    #[test]
+    fn stats_and_cleanup() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path();
+        let cloud = cloud(dir, "cloud.db");
+        let own = cloud.device_id().unwrap();
+        assert_eq!(cloud.device_id().unwrap(), own);
+
+        for (text, device) in [("a", own.as_str()), ("bb", "desktop"), ("ccc", "desktop")] {
+            let (hash, payloads) = clip(text);
+            cloud.insert_clip(&hash, &meta("x"), payloads, device == "desktop", device).unwrap();
+        }
+        let (old, payloads) = clip("old");
+        let mut old_meta = meta("x");
+        old_meta.timestamp = "2020-01-01 00:00:00".to_string();
+        cloud.insert_clip(&old, &old_meta, payloads, true, "desktop").unwrap();
+
+        let stats = cloud.stats().unwrap();
+        assert_eq!((stats.clips, stats.pending_uploads, stats.last_sync.as_deref()), (4, 1, None));
+        let usage: Vec<(&str, bool, usize)> = stats.devices.iter().map(|d| (d.device.as_str(), d.this_device, d.clips)).collect();
+        assert_eq!(usage, [("desktop", false, 3), (own.as_str(), true, 1)]);
+        // 3 bytes of HTML each, plus the text
+        assert_eq!(stats.bytes, 4 * 3 + 1 + 2 + 3 + 3);
+
+        assert_eq!(cloud.cleanup_targets(&Cleanup::OlderThanDays(30)).unwrap(), std::slice::from_ref(&old));
+        assert_eq!(cloud.cleanup_targets(&Cleanup::Device("desktop".to_string())).unwrap().len(), 3);
+        let all = cloud.cleanup_targets(&Cleanup::All).unwrap();
+        assert_eq!(cloud.delete_clips(&all).unwrap(), 4);
+        assert_eq!(cloud.stats().unwrap().pending_deletes, 4);
+    }
+
+    #[test]
+    fn lists_and_imports() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path();
+        let cloud = cloud(dir, "cloud.db");
+        let (hash, payloads) = clip("pulled from elsewhere");
+        cloud.insert_clip(&hash, &meta("notepad.exe"), payloads, true, "laptop").unwrap();
+        let linux = vec![ClipboardPayload { format_id: 0, format_name: "text/plain".to_string(), data: "héllo".as_bytes().to_vec() }];
+        let linux_hash = blake3::hash("héllo".as_bytes()).to_hex().to_string();
+        cloud.insert_clip(&linux_hash, &meta("gedit"), linux, true, "desktop").unwrap();
+
+        let listed = cloud.list_clips(8).unwrap();
+        let previews: Vec<&str> = listed.iter().map(|c| c.preview.as_str()).collect();
+        assert_eq!(previews, ["héllo", "pulled f"]);
+        assert_eq!(listed[1].size, "pulled from elsewhere".len() + 3);
+
+        let history = Database::new(&dir.join("clipboard.db").to_string_lossy(), "pwd").unwrap();
+        assert!(cloud.import_clip(&hash, &history).unwrap());
+        assert!(!cloud.import_clip(&hash, &history).unwrap());
+        assert_eq!(history.get_clip_meta(&hash).unwrap().0, "notepad.exe");
+        assert_eq!(history.get_clip_payloads(&hash).unwrap().len(), 2);
+    }
+
+    #[test]
+    fn uploads_resume_after_a_drop() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path();
+        let url = start_server(dir);
+        let mut remote = HttpRemote::new(&url, "secret");
+        remote.chunk_size = 4;
+
+        let (hash, payloads) = clip("resumable upload over a flaky link");
+        let blob = encode_payloads(&payloads);
+        let new = NewUpload {
+            id: hash.clone(),
+            size: blob.len() as u64,
+            blob_hash: blake3::hash(&blob).to_hex().to_string(),
+            meta: "{}".to_string(),
+            device: "laptop".to_string(),
+        };
+        // The first attempt gets one chunk through before the link goes
+        let upload = remote.start_upload(&new).unwrap().upload.unwrap();
+        remote.send_chunk(&upload, 0, &blob[..4]).unwrap();
+        // A stale offset is answered with where the server actually is
+        assert_eq!(remote.send_chunk(&upload, 0, &blob[..4]).unwrap().offset, 4);
+
+        let resumed = remote.start_upload(&new).unwrap();
+        assert_eq!((resumed.upload, resumed.offset), (Some(upload), 4));
+        remote.upload(&hash, "{}", &blob, "laptop").unwrap();
+        assert_eq!(remote.download(&hash).unwrap(), blob);
+        assert_eq!(remote.changes(0).unwrap().changes.len(), 1);
+    }
+
+    /// Every file under `dir`, concatenated.
+    fn all_bytes(dir: &std::path::Path) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        for entry in std::fs::read_dir(dir).unwrap() {
+            let path = entry.unwrap().path();
+            if path.is_dir() {
+                bytes.extend(all_bytes(&path));
+            } else {
+                bytes.extend(std::fs::read(&path).unwrap());
+            }
+        }
+        bytes
+    }
+
+    #[test]
+    fn server_only_sees_sealed_clips() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path();
+        let url = start_server(dir);
+        let remote = HttpRemote::new(&url, "secret");
+        let laptop = cloud(dir, "laptop.db");
+        let (hash, payloads) = clip("my bank password hint");
+        laptop.insert_clip(&hash, &meta("keepass.exe"), payloads, false, "laptop").unwrap();
+        laptop.sync(&remote, "pass").unwrap();
+
+        let stored = all_bytes(&dir.join("server"));
+        for secret in ["my bank password hint", "keepass.exe", "Notes", hash.as_str()] {
+            assert!(!stored.windows(secret.len()).any(|w| w == secret.as_bytes()), "server has {}", secret);
+        }
+        let change = &remote.changes(0).unwrap().changes[0];
+        assert_ne!(change.id, hash);
+
+        // Another device needs the same passphrase
+        let desktop = cloud(dir, "desktop.db");
+        assert!(matches!(desktop.sync(&remote, "guess"), Err(SyncError::Crypto(CryptoError::WrongPassphrase))));
+        assert_eq!(desktop.sync(&remote, "pass").unwrap().pulled, 1);
+        assert_eq!(desktop.get_clip(&hash).unwrap().0, meta("keepass.exe"));
+    }
+
+    #[test]
+    fn rotation_and_recovery() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path();
+        let url = start_server(dir);
+        let remote = HttpRemote::new(&url, "secret");
+        let (laptop, desktop) = (cloud(dir, "laptop.db"), cloud(dir, "desktop.db"));
+        let (hash, payloads) = clip("sealed twice");
+        laptop.insert_clip(&hash, &meta("notepad.exe"), payloads, false, "laptop").unwrap();
+        laptop.sync(&remote, "pass").unwrap();
+        let old_code = laptop.recovery_key(&remote, "pass").unwrap();
+
+        assert_eq!(laptop.rotate_key(&remote, "pass").unwrap(), 2);
+        assert_eq!(laptop.sync(&remote, "pass").unwrap().pushed, 1);
+        // Everything is on the new key, so the old one was dropped
+        let (_, mut keys) = unlock(&remote, "pass", &laptop.device_key().unwrap()).unwrap();
+        assert!(!keys.retire_old_keys());
+        assert_eq!(desktop.sync(&remote, "pass").unwrap().pulled, 1);
+
+        assert!(matches!(recover(&remote, &old_code, "new pass"), Err(SyncError::Crypto(CryptoError::BadRecoveryKey))));
+        recover(&remote, &laptop.recovery_key(&remote, "pass").unwrap(), "new pass").unwrap();
+        // Registered devices still get in, but are told to catch up
+        assert!(laptop.sync(&remote, "pass").unwrap().passphrase_changed);
+        assert!(matches!(cloud(dir, "new.db").sync(&remote, "pass"), Err(SyncError::Crypto(CryptoError::WrongPassphrase))));
+        assert_eq!(laptop.sync(&remote, "new pass").unwrap(), SyncReport::default());
+    }
+
+    #[test]
+    fn device_registry_and_revocation() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path();
+        let url = start_server(dir);
+        let remote = HttpRemote::new(&url, "secret");
+        let (laptop, desktop, phone) = (cloud(dir, "laptop.db"), cloud(dir, "desktop.db"), cloud(dir, "phone.db"));
+        for (db, name) in [(&laptop, "Laptop"), (&desktop, "Desktop"), (&phone, "Phone")] {
+            db.set_device_name(name).unwrap();
+        }
+        let (hash, payloads) = clip("copied on the laptop");
+        let laptop_id = laptop.device_id().unwrap();
+        laptop.insert_clip(&hash, &meta("notepad.exe"), payloads, false, &laptop_id).unwrap();
+        laptop.sync(&remote, "pass").unwrap();
+        phone.sync(&remote, "pass").unwrap();
+        desktop.sync(&remote, "pass").unwrap();
+        let names: Vec<String> = desktop.devices().unwrap().into_iter().map(|d| d.name).collect();
+        assert_eq!(names, ["Desktop", "Laptop", "Phone"]);
+
+        // Provenance survives the trip and the import into history
+        assert_eq!(desktop.list_clips(10).unwrap()[0].device.as_deref(), Some("Laptop"));
+        let history = Database::new(&dir.join("clipboard.db").to_string_lossy(), "pwd").unwrap();
+        desktop.import_clip(&hash, &history).unwrap();
+        assert_eq!(history.get_clip_device(&hash).unwrap(), Some(laptop_id.clone()));
+
+        let live = |remote: &HttpRemote| -> Vec<String> { remote.changes(0).unwrap().changes.into_iter().filter(|c| !c.deleted).map(|c| c.id).collect() };
+        let old_ids = live(&remote);
+        assert!(desktop.revoke_device(&remote, "pass", &laptop_id, "new pass").unwrap());
+        assert!(!desktop.revoke_device(&remote, "new pass", &laptop_id, "newer pass").unwrap());
+        // The laptop can no longer look clips up by the ids it knows
+        assert!(live(&remote).is_empty());
+        assert!(matches!(laptop.sync(&remote, "pass"), Err(SyncError::Crypto(CryptoError::Revoked))));
+        assert!(matches!(laptop.sync(&remote, "new pass"), Err(SyncError::Crypto(CryptoError::Revoked))));
+        // The phone hasn't been told the new passphrase yet but keeps syncing
+        let report = phone.sync(&remote, "pass").unwrap();
+        assert!(report.passphrase_changed);
+        assert!(phone.devices().unwrap().iter().any(|d| d.id == laptop_id && d.revoked));
+        let report = desktop.sync(&remote, "new pass").unwrap();
+        assert!(!report.passphrase_changed);
+        assert_eq!((report.pushed, report.failed), (1, 0));
+        let new_ids = live(&remote);
+        assert_eq!(new_ids.len(), 1);
+        assert!(!old_ids.contains(&new_ids[0]));
+        // The other devices see the same clip under its new id
+        let report = phone.sync(&remote, "pass").unwrap();
+        assert_eq!((report.pulled, report.failed), (0, 0));
+        assert_eq!(phone.list_clips(10).unwrap().len(), 1);
+    }
+
+    #[test]
+    fn clips_keep_the_hashed_format_first() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path();
+        let url = start_server(dir);
+        let remote = HttpRemote::new(&url, "secret");
+        let (laptop, desktop) = (cloud(dir, "laptop.db"), cloud(dir, "desktop.db"));
+        let history = Database::new(&dir.join("history.db").to_string_lossy(), "pwd").unwrap();
+
+        // Windows lists CF_UNICODETEXT before the CF_TEXT it synthesizes
+        let text: Vec<u8> = "plain text".encode_utf16().flat_map(u16::to_le_bytes).collect();
+        let hash = blake3::hash(&text).to_hex().to_string();
+        let payloads = vec![
+            ClipboardPayload { format_id: 13, format_name: "CF_UNICODETEXT".to_string(), data: text },
+            ClipboardPayload { format_id: 1, format_name: "CF_TEXT".to_string(), data: b"plain text".to_vec() },
+        ];
+        history.save_snapshot("notepad.exe", "Notes", "", &hash, &payloads, false).unwrap();
+        assert!(laptop.copy_clip_from(&hash, &history).unwrap());
+        assert_eq!(laptop.sync(&remote, "pass").unwrap().pushed, 1);
+
+        let report = desktop.sync(&remote, "pass").unwrap();
+        assert_eq!((report.pulled, report.failed), (1, 0));
+        assert_eq!(desktop.get_clip(&hash).unwrap().1[0].format_id, 13);
+    }
+
+    #[test]
+    fn metadata_merges_across_devices() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path();
+        let url = start_server(dir);
+        let remote = HttpRemote::new(&url, "secret");
+        let (laptop, desktop, phone) = (cloud(dir, "laptop.db"), cloud(dir, "desktop.db"), cloud(dir, "phone.db"));
+        let history = |name: &str| Database::new(&dir.join(name).to_string_lossy(), "pwd").unwrap();
+        let (laptop_history, desktop_history) = (history("laptop-history.db"), history("desktop-history.db"));
+
+        let (hash, payloads) = clip("meeting notes");
+        laptop_history.save_snapshot("notepad.exe", "Notes", "", &hash, &payloads, false).unwrap();
+        laptop_history.set_pin_slot(&hash, Some(2)).unwrap();
+        assert!(laptop.copy_clip_from(&hash, &laptop_history).unwrap());
+        laptop.sync(&remote, "pass").unwrap();
+        desktop.sync(&remote, "pass").unwrap();
+        phone.sync(&remote, "pass").unwrap();
+        desktop.import_clip(&hash, &desktop_history).unwrap();
+        assert_eq!(desktop_history.get_clip_metadata(&hash).unwrap(), (Some(2), vec![], String::new()));
+
+        // Tagged on the desktop and annotated on the laptop at the same time
+        desktop_history.set_tags(&hash, "share").unwrap();
+        assert!(desktop.record_metadata(&hash, &desktop_history).unwrap());
+        laptop_history.set_note(&hash, "for friday").unwrap();
+        assert!(laptop.record_metadata(&hash, &laptop_history).unwrap());
+        assert!(!laptop.record_metadata(&hash, &laptop_history).unwrap());
+        assert_eq!(desktop.sync(&remote, "pass").unwrap().updated, 1);
+        // The laptop's edit wasn't in what it pulled, so the merge goes back up
+        assert_eq!(laptop.sync(&remote, "pass").unwrap(), SyncReport { updated: 1, ..SyncReport::default() });
+        desktop.sync(&remote, "pass").unwrap();
+        for (db, history) in [(&laptop, &laptop_history), (&desktop, &desktop_history)] {
+            db.apply_states(history).unwrap();
+            assert_eq!(history.get_clip_metadata(&hash).unwrap(), (Some(2), vec!["share".to_string()], "for friday".to_string()));
+        }
+
+        // Deleted on the phone, which never saw the edits, then pushed again
+        phone.delete_clips(std::slice::from_ref(&hash)).unwrap();
+        phone.sync(&remote, "pass").unwrap();
+        assert_eq!(laptop.sync(&remote, "pass").unwrap().deleted, 1);
+        assert!(laptop.copy_clip_from(&hash, &laptop_history).unwrap());
+        assert_eq!(laptop.sync(&remote, "pass").unwrap().pushed, 1);
+        assert_eq!(phone.sync(&remote, "pass").unwrap().pulled, 1);
+        let state = phone.clip_state(&hash).unwrap().unwrap();
+        assert!(!state.is_deleted());
+        assert_eq!((state.tags.tags(), state.note.value), (vec!["share".to_string()], "for friday".to_string()));
+        assert_eq!(desktop.sync(&remote, "pass").unwrap().deleted, 0);
+        assert!(desktop.has_clip(&hash).unwrap());
+    }
+
+    /// Passes everything through but refuses uploads, like a server that's out of space.
+    struct Refusing<'a>(&'a HttpRemote);
+
+    impl Remote for Refusing<'_> {
+        fn upload(&self, _: &str, _: &str, _: &[u8], _: &str) -> std::result::Result<(), SyncError> {
+            Err(SyncError::Server(507, "full".to_string()))
+        }
+        fn changes(&self, since: i64) -> std::result::Result<Changes, SyncError> {
+            self.0.changes(since)
+        }
+        fn download(&self, id: &str) -> std::result::Result<Vec<u8>, SyncError> {
+            self.0.download(id)
+        }
+        fn delete(&self, id: &str, device: &str) -> std::result::Result<(), SyncError> {
+            self.0.delete(id, device)
+        }
+        fn put_meta(&self, id: &str, meta: &str, device: &str) -> std::result::Result<(), SyncError> {
+            self.0.put_meta(id, meta, device)
+        }
+        fn keyring(&self) -> std::result::Result<Option<(u64, Vec<u8>)>, SyncError> {
+            self.0.keyring()
+        }
+        fn put_keyring(&self, replaces: u64, keyring: &[u8]) -> std::result::Result<u64, SyncError> {
+            self.0.put_keyring(replaces, keyring)
+        }
+    }
+
+    #[test]
+    fn failed_uploads_wait_for_retry() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path();
+        let url = start_server(dir);
+        let remote = HttpRemote::new(&url, "secret");
+        let laptop = cloud(dir, "laptop.db");
+        let (hash, payloads) = clip("stuck");
+        laptop.insert_clip(&hash, &meta("notepad.exe"), payloads, false, "laptop").unwrap();
+
+        // Offline syncs don't count as attempts
+        let offline = HttpRemote::new("http://127.0.0.1:1", "secret");
+        assert!(matches!(laptop.sync(&offline, "pass"), Err(SyncError::Offline(_))));
+        assert_eq!(laptop.push_states().unwrap()[&hash], PushState::Pending);
+
+        for _ in 0..MAX_PUSH_ATTEMPTS {
+            assert_eq!(laptop.sync(&Refusing(&remote), "pass").unwrap().failed, 1);
+        }
+        assert!(matches!(&laptop.push_states().unwrap()[&hash], PushState::Failed(e) if e.contains("full")));
+        let stats = laptop.stats().unwrap();
+        assert_eq!((stats.pending_uploads, stats.failed_uploads), (0, 1));
+        // Given up on until the user asks again
+        assert_eq!(laptop.sync(&remote, "pass").unwrap(), SyncReport::default());
+
+        laptop.retry_push(&hash).unwrap();
+        assert_eq!(laptop.push_states().unwrap()[&hash], PushState::Pending);
+        assert_eq!(laptop.sync(&remote, "pass").unwrap().pushed, 1);
+        assert_eq!(laptop.push_states().unwrap()[&hash], PushState::Uploaded);
+    }
+
+    /// Copies the files `from` has more of over to `to`, like a sync tool.
+    /// Log segments only ever grow, so the longer copy is the newer one.
+    fn mirror(from: &std::path::Path, to: &std::path::Path) {
+        for entry in std::fs::read_dir(from).unwrap() {
+            let path = entry.unwrap().path();
+            let target = to.join(path.file_name().unwrap());
+            if path.is_dir() {
+                std::fs::create_dir_all(&target).unwrap();
+                mirror(&path, &target);
+            } else if std::fs::metadata(&target).map_or(true, |m| m.len() < path.metadata().unwrap().len()) {
+                std::fs::copy(&path, &target).unwrap();
+            }
+        }
+    }
+
+    #[test]
+    fn syncs_through_a_shared_folder() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path();
+        let (laptop, desktop) = (cloud(dir, "laptop.db"), cloud(dir, "desktop.db"));
+        // Each device has its own copy of the folder
+        let (laptop_folder, desktop_folder) = (dir.join("laptop-sync"), dir.join("desktop-sync"));
+        let remote = |folder: &std::path::Path, db: &CloudDatabase| {
+            std::fs::create_dir_all(folder).unwrap();
+            FolderRemote::open(folder, &folder.with_extension("index"), &db.device_id().unwrap()).unwrap()
+        };
+        let (laptop_remote, desktop_remote) = (remote(&laptop_folder, &laptop), remote(&desktop_folder, &desktop));
+
+        let (hash, payloads) = clip("from the laptop");
+        laptop.insert_clip(&hash, &meta("notepad.exe"), payloads, false, "laptop").unwrap();
+        assert_eq!(laptop.sync(&laptop_remote, "pass").unwrap().pushed, 1);
+        mirror(&laptop_folder, &desktop_folder);
+
+        let (other, payloads) = clip("from the desktop");
+        desktop.insert_clip(&other, &meta("code.exe"), payloads, false, "desktop").unwrap();
+        assert_eq!(desktop.sync(&desktop_remote, "pass").unwrap(), SyncReport { pushed: 1, pulled: 1, ..SyncReport::default() });
+        let (pulled_meta, pulled) = desktop.get_clip(&hash).unwrap();
+        assert_eq!((pulled_meta, pulled[0].data.as_slice()), (meta("notepad.exe"), &b"from the laptop"[..]));
+        mirror(&desktop_folder, &laptop_folder);
+        assert_eq!(laptop.sync(&laptop_remote, "pass").unwrap().pulled, 1);
+        assert_eq!(laptop.devices().unwrap().len(), 2);
+
+        laptop.delete_clips(std::slice::from_ref(&other)).unwrap();
+        assert_eq!(laptop.sync(&laptop_remote, "pass").unwrap().deleted, 1);
+        mirror(&laptop_folder, &desktop_folder);
+        assert_eq!(desktop.sync(&desktop_remote, "pass").unwrap().deleted, 1);
+        assert_eq!(desktop.push_states().unwrap().into_keys().collect::<HashSet<_>>(), HashSet::from([hash.clone()]));
+
+        let stored = all_bytes(&desktop_folder);
+        for secret in ["from the laptop", "from the desktop", "notepad.exe", hash.as_str()] {
+            assert!(!stored.windows(secret.len()).any(|w| w == secret.as_bytes()), "folder has {}", secret);
+        }
+    }
+
+    #[test]
+    fn folders_that_start_apart_settle_on_one_keyring() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path();
+        let (laptop, desktop) = (cloud(dir, "laptop.db"), cloud(dir, "desktop.db"));
+        let (laptop_folder, desktop_folder) = (dir.join("laptop-sync"), dir.join("desktop-sync"));
+        let remote = |folder: &std::path::Path, db: &CloudDatabase| {
+            std::fs::create_dir_all(folder).unwrap();
+            FolderRemote::open(folder, &folder.with_extension("index"), &db.device_id().unwrap()).unwrap()
+        };
+        let (laptop_remote, desktop_remote) = (remote(&laptop_folder, &laptop), remote(&desktop_folder, &desktop));
+
+        // Each makes a keyring of its own and pushes under it
+        let (hash, payloads) = clip("from the laptop");
+        laptop.insert_clip(&hash, &meta("notepad.exe"), payloads, false, "laptop").unwrap();
+        assert_eq!(laptop.sync(&laptop_remote, "pass").unwrap().pushed, 1);
+        let (other, payloads) = clip("from the desktop");
+        desktop.insert_clip(&other, &meta("code.exe"), payloads, false, "desktop").unwrap();
+        assert_eq!(desktop.sync(&desktop_remote, "pass").unwrap().pushed, 1);
+        mirror(&laptop_folder, &desktop_folder);
+        mirror(&desktop_folder, &laptop_folder);
+
+        // The larger device id's keyring wins, so the other device's clip goes up again
+        let ((winner, winner_remote, winner_folder), (loser, loser_remote, loser_folder)) =
+            if laptop.device_id().unwrap() > desktop.device_id().unwrap() {
+                ((&laptop, &laptop_remote, &laptop_folder), (&desktop, &desktop_remote, &desktop_folder))
+            } else {
+                ((&desktop, &desktop_remote, &desktop_folder), (&laptop, &laptop_remote, &laptop_folder))
+            };
+        let report = loser.sync(loser_remote, "pass").unwrap();
+        assert_eq!((report.pulled, report.pushed), (1, 1));
+        mirror(loser_folder, winner_folder);
+        let report = winner.sync(winner_remote, "pass").unwrap();
+        // The copy sealed under the lost keyring can't be opened and is skipped
+        assert_eq!((report.pulled, report.pushed, report.failed), (1, 0, 1));
+        for db in [&laptop, &desktop] {
+            assert_eq!(db.push_states().unwrap().into_keys().collect::<HashSet<_>>(), HashSet::from([hash.clone(), other.clone()]));
+        }
+        mirror(winner_folder, loser_folder);
+        assert_eq!(loser.sync(loser_remote, "pass").unwrap(), SyncReport::default());
+    }
+}
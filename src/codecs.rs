@@ -0,0 +1,449 @@
+//! Per-format conversion between clipboard handles and the bytes we store.
+//!
+//! Most formats are HGLOBAL memory blocks and are stored verbatim. CF_BITMAP,
+//! CF_ENHMETAFILE and CF_PALETTE hand out GDI object handles instead, so they
+//! are serialized into a portable form on capture (a packed DIB, the EMF
+//! records and a LOGPALETTE respectively) and turned back into real handles on
+//! restore. The byte-level half of each codec is platform-independent.
+
+use std::fmt;
+
+use windows::Win32::Foundation::{HANDLE, HGLOBAL, HWND};
+use windows::Win32::Graphics::Gdi::{
+    CreateDIBitmap, CreatePalette, GetDC, GetDIBits, GetEnhMetaFileBits, GetObjectW,
+    GetPaletteEntries, ReleaseDC, SetEnhMetaFileBits, BITMAP, BITMAPINFO, BITMAPINFOHEADER,
+    BI_RGB, CBM_INIT, DIB_RGB_COLORS, HBITMAP, HENHMETAFILE, HGDIOBJ, HPALETTE, LOGPALETTE,
+    PALETTEENTRY,
+};
+use windows::Win32::System::Memory::{GlobalAlloc, GlobalLock, GlobalSize, GlobalUnlock, GMEM_MOVEABLE};
+
+use crate::imaging::{dib_pixel_offset, CF_BITMAP};
+
+pub const CF_METAFILEPICT: u32 = 3;
+pub const CF_PALETTE: u32 = 9;
+pub const CF_ENHMETAFILE: u32 = 14;
+pub const CF_OWNERDISPLAY: u32 = 0x0080;
+pub const CF_DSPBITMAP: u32 = 0x0082;
+pub const CF_DSPMETAFILEPICT: u32 = 0x0083;
+pub const CF_DSPENHMETAFILE: u32 = 0x008E;
+
+const EMR_HEADER: u32 = 1;
+const ENHMETA_SIGNATURE: u32 = 0x464D_4520;
+const PALETTE_VERSION: u16 = 0x300;
+
+/// How the handle behind a clipboard format has to be read and rebuilt.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum HandleKind {
+    Global,
+    Bitmap,
+    EnhMetafile,
+    Palette,
+    /// Handles that only mean something inside the owning process
+    /// (owner-display, metafile pictures wrapping an HMETAFILE).
+    Unsupported,
+}
+
+pub fn handle_kind(format: u32) -> HandleKind {
+    match format {
+        CF_BITMAP | CF_DSPBITMAP => HandleKind::Bitmap,
+        CF_ENHMETAFILE | CF_DSPENHMETAFILE => HandleKind::EnhMetafile,
+        CF_PALETTE => HandleKind::Palette,
+        CF_OWNERDISPLAY | CF_METAFILEPICT | CF_DSPMETAFILEPICT => HandleKind::Unsupported,
+        _ => HandleKind::Global,
+    }
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum CodecError {
+    Truncated { needed: usize, got: usize },
+    BadHeader(&'static str),
+}
+
+impl fmt::Display for CodecError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CodecError::Truncated { needed, got } => write!(f, "truncated: need {} bytes, got {}", needed, got),
+            CodecError::BadHeader(what) => write!(f, "bad header: {}", what),
+        }
+    }
+}
+
+fn u16_at(data: &[u8], at: usize) -> Result<u16, CodecError> {
+    data.get(at..at + 2)
+        .map(|b| u16::from_le_bytes([b[0], b[1]]))
+        .ok_or(CodecError::Truncated { needed: at + 2, got: data.len() })
+}
+
+fn u32_at(data: &[u8], at: usize) -> Result<u32, CodecError> {
+    data.get(at..at + 4)
+        .map(|b| u32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+        .ok_or(CodecError::Truncated { needed: at + 4, got: data.len() })
+}
+
+/// Layout facts about a packed DIB, checked against its length.
+#[derive(Debug, PartialEq, Eq)]
+pub struct DibLayout {
+    pub width: i32,
+    pub height: i32,
+    pub bit_count: u16,
+    pub pixel_offset: usize,
+}
+
+/// Packs 32bpp bottom-up BGRA rows (as GetDIBits returns them) into a DIB.
+pub fn encode_bitmap(width: i32, height: i32, pixels: &[u8]) -> Result<Vec<u8>, CodecError> {
+    if width <= 0 || height <= 0 {
+        return Err(CodecError::BadHeader("bitmap dimensions must be positive"));
+    }
+    let needed = width as usize * height as usize * 4;
+    if pixels.len() < needed {
+        return Err(CodecError::Truncated { needed, got: pixels.len() });
+    }
+    let mut dib = Vec::with_capacity(40 + needed);
+    dib.extend_from_slice(&40u32.to_le_bytes());
+    dib.extend_from_slice(&width.to_le_bytes());
+    dib.extend_from_slice(&height.to_le_bytes());
+    dib.extend_from_slice(&1u16.to_le_bytes());
+    dib.extend_from_slice(&32u16.to_le_bytes());
+    dib.extend_from_slice(&0u32.to_le_bytes()); // BI_RGB
+    dib.extend_from_slice(&(needed as u32).to_le_bytes());
+    dib.extend_from_slice(&[0; 16]); // resolution and color counts
+    dib.extend_from_slice(&pixels[..needed]);
+    Ok(dib)
+}
+
+/// Validates a stored DIB before it is handed to CreateDIBitmap.
+pub fn decode_bitmap(dib: &[u8]) -> Result<DibLayout, CodecError> {
+    let header_size = u32_at(dib, 0)? as usize;
+    if header_size < 40 {
+        return Err(CodecError::BadHeader("BITMAPINFOHEADER too small"));
+    }
+    let width = u32_at(dib, 4)? as i32;
+    let height = u32_at(dib, 8)? as i32;
+    let planes = u16_at(dib, 12)?;
+    let bit_count = u16_at(dib, 14)?;
+    let compression = u32_at(dib, 16)?;
+    if width <= 0 || height == 0 {
+        return Err(CodecError::BadHeader("bitmap dimensions"));
+    }
+    if planes != 1 {
+        return Err(CodecError::BadHeader("biPlanes must be 1"));
+    }
+    if !matches!(bit_count, 1 | 4 | 8 | 16 | 24 | 32) {
+        return Err(CodecError::BadHeader("unsupported biBitCount"));
+    }
+    let pixel_offset = dib_pixel_offset(dib).ok_or(CodecError::BadHeader("color table overruns data"))?;
+
+    // Compressed bitmaps carry their own size; uncompressed ones must fill every row
+    if compression == 0 {
+        let stride = (width as usize * bit_count as usize).div_ceil(32) * 4;
+        let needed = pixel_offset + stride * height.unsigned_abs() as usize;
+        if dib.len() < needed {
+            return Err(CodecError::Truncated { needed, got: dib.len() });
+        }
+    }
+    Ok(DibLayout { width, height, bit_count, pixel_offset })
+}
+
+/// Enhanced metafiles are stored as their record stream; this checks that the
+/// first record is a well-formed EMR_HEADER describing exactly `data`.
+pub fn decode_emf(data: &[u8]) -> Result<(), CodecError> {
+    if u32_at(data, 0)? != EMR_HEADER {
+        return Err(CodecError::BadHeader("first record is not EMR_HEADER"));
+    }
+    if u32_at(data, 40)? != ENHMETA_SIGNATURE {
+        return Err(CodecError::BadHeader("missing ' EMF' signature"));
+    }
+    let total = u32_at(data, 48)? as usize;
+    if total != data.len() {
+        return Err(CodecError::Truncated { needed: total, got: data.len() });
+    }
+    Ok(())
+}
+
+/// Serializes palette entries (red, green, blue, flags) as a LOGPALETTE.
+pub fn encode_palette(entries: &[[u8; 4]]) -> Vec<u8> {
+    let mut data = Vec::with_capacity(4 + entries.len() * 4);
+    data.extend_from_slice(&PALETTE_VERSION.to_le_bytes());
+    data.extend_from_slice(&(entries.len() as u16).to_le_bytes());
+    for entry in entries {
+        data.extend_from_slice(entry);
+    }
+    data
+}
+
+pub fn decode_palette(data: &[u8]) -> Result<Vec<[u8; 4]>, CodecError> {
+    if u16_at(data, 0)? != PALETTE_VERSION {
+        return Err(CodecError::BadHeader("palVersion must be 0x300"));
+    }
+    let count = u16_at(data, 2)? as usize;
+    if count == 0 {
+        return Err(CodecError::BadHeader("empty palette"));
+    }
+    let needed = 4 + count * 4;
+    if data.len() < needed {
+        return Err(CodecError::Truncated { needed, got: data.len() });
+    }
+    Ok(data[4..needed]
+        .chunks_exact(4)
+        .map(|c| [c[0], c[1], c[2], c[3]])
+        .collect())
+}
+
+/// Reads the clipboard handle for `format` into storable bytes. None means the
+/// format can't be captured meaningfully and should be skipped.
+///
+/// # Safety
+/// The clipboard must be open and `handle` must come from `GetClipboardData(format)`.
+pub unsafe fn read_handle(format: u32, handle: HANDLE) -> Option<Vec<u8>> {
+    match handle_kind(format) {
+        HandleKind::Global => {
+            let hglobal = HGLOBAL(handle.0 as *mut _);
+            let size = GlobalSize(hglobal);
+            let ptr = GlobalLock(hglobal);
+            if ptr.is_null() {
+                return None;
+            }
+            let data = (size > 0).then(|| std::slice::from_raw_parts(ptr as *const u8, size).to_vec());
+            let _ = GlobalUnlock(hglobal);
+            data
+        }
+        HandleKind::Bitmap => read_bitmap(HBITMAP(handle.0)),
+        HandleKind::EnhMetafile => {
+            let hemf = HENHMETAFILE(handle.0);
+            let size = GetEnhMetaFileBits(hemf, None) as usize;
+            if size == 0 {
+                return None;
+            }
+            let mut data = vec![0u8; size];
+            GetEnhMetaFileBits(hemf, Some(&mut data));
+            decode_emf(&data).ok().map(|_| data)
+        }
+        HandleKind::Palette => {
+            let hpal = HPALETTE(handle.0);
+            let count = GetPaletteEntries(hpal, 0, None) as usize;
+            if count == 0 {
+                return None;
+            }
+            let mut entries = vec![PALETTEENTRY::default(); count];
+            GetPaletteEntries(hpal, 0, Some(&mut entries));
+            let entries: Vec<[u8; 4]> = entries
+                .iter()
+                .map(|e| [e.peRed, e.peGreen, e.peBlue, e.peFlags])
+                .collect();
+            Some(encode_palette(&entries))
+        }
+        HandleKind::Unsupported => None,
+    }
+}
+
+unsafe fn read_bitmap(hbm: HBITMAP) -> Option<Vec<u8>> {
+    let mut bm = BITMAP::default();
+    let got = GetObjectW(
+        HGDIOBJ(hbm.0),
+        std::mem::size_of::<BITMAP>() as i32,
+        Some(&mut bm as *mut _ as *mut _),
+    );
+    if got == 0 || bm.bmWidth <= 0 || bm.bmHeight <= 0 {
+        return None;
+    }
+
+    let mut info = BITMAPINFO {
+        bmiHeader: BITMAPINFOHEADER {
+            biSize: std::mem::size_of::<BITMAPINFOHEADER>() as u32,
+            biWidth: bm.bmWidth,
+            biHeight: bm.bmHeight,
+            biPlanes: 1,
+            biBitCount: 32,
+            biCompression: BI_RGB.0,
+            ..Default::default()
+        },
+        ..Default::default()
+    };
+    let mut pixels = vec![0u8; bm.bmWidth as usize * bm.bmHeight as usize * 4];
+    let hdc = GetDC(HWND(0));
+    let lines = GetDIBits(
+        hdc,
+        hbm,
+        0,
+        bm.bmHeight as u32,
+        Some(pixels.as_mut_ptr() as *mut _),
+        &mut info,
+        DIB_RGB_COLORS,
+    );
+    ReleaseDC(HWND(0), hdc);
+    if lines <= 0 {
+        return None;
+    }
+    encode_bitmap(bm.bmWidth, bm.bmHeight, &pixels).ok()
+}
+
+/// Rebuilds a handle SetClipboardData will accept for `format`.
+///
+/// # Safety
+/// Ownership of the returned handle passes to the clipboard on a successful
+/// SetClipboardData; the caller must not free it.
+pub unsafe fn write_handle(format: u32, data: &[u8]) -> Result<HANDLE, CodecError> {
+    match handle_kind(format) {
+        HandleKind::Global => {
+            let hglobal = GlobalAlloc(GMEM_MOVEABLE, data.len())
+                .map_err(|_| CodecError::BadHeader("GlobalAlloc failed"))?;
+            let ptr = GlobalLock(hglobal);
+            if ptr.is_null() {
+                return Err(CodecError::BadHeader("GlobalLock failed"));
+            }
+            std::ptr::copy_nonoverlapping(data.as_ptr(), ptr as *mut u8, data.len());
+            let _ = GlobalUnlock(hglobal);
+            Ok(HANDLE(hglobal.0 as isize))
+        }
+        HandleKind::Bitmap => {
+            let layout = decode_bitmap(data)?;
+            // CreateDIBitmap reads the header through typed pointers; copy out of
+            // the byte buffer so alignment is guaranteed
+            let mut aligned = vec![0u32; layout.pixel_offset.div_ceil(4)];
+            std::ptr::copy_nonoverlapping(data.as_ptr(), aligned.as_mut_ptr() as *mut u8, layout.pixel_offset);
+            let info = aligned.as_ptr() as *const BITMAPINFO;
+            let hdc = GetDC(HWND(0));
+            let hbm = CreateDIBitmap(
+                hdc,
+                Some(&(*info).bmiHeader),
+                CBM_INIT as u32,
+                Some(data[layout.pixel_offset..].as_ptr() as *const _),
+                Some(info),
+                DIB_RGB_COLORS,
+            );
+            ReleaseDC(HWND(0), hdc);
+            if hbm.is_invalid() {
+                return Err(CodecError::BadHeader("CreateDIBitmap failed"));
+            }
+            Ok(HANDLE(hbm.0))
+        }
+        HandleKind::EnhMetafile => {
+            decode_emf(data)?;
+            let hemf = SetEnhMetaFileBits(data);
+            if hemf.is_invalid() {
+                return Err(CodecError::BadHeader("SetEnhMetaFileBits failed"));
+            }
+            Ok(HANDLE(hemf.0))
+        }
+        HandleKind::Palette => {
+            let entries = decode_palette(data)?;
+            let mut aligned = vec![0u32; 1 + entries.len()];
+            std::ptr::copy_nonoverlapping(data.as_ptr(), aligned.as_mut_ptr() as *mut u8, 4 + entries.len() * 4);
+            let hpal = CreatePalette(aligned.as_ptr() as *const LOGPALETTE);
+            if hpal.is_invalid() {
+                return Err(CodecError::BadHeader("CreatePalette failed"));
+            }
+            Ok(HANDLE(hpal.0))
+        }
+        HandleKind::Unsupported => Err(CodecError::BadHeader("format cannot be restored")),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn emf_header(total: u32) -> Vec<u8> {
+        let mut data = vec![0u8; total as usize];
+        data[0..4].copy_from_slice(&EMR_HEADER.to_le_bytes());
+        data[4..8].copy_from_slice(&88u32.to_le_bytes());
+        data[40..44].copy_from_slice(&ENHMETA_SIGNATURE.to_le_bytes());
+        data[48..52].copy_from_slice(&total.to_le_bytes());
+        data
+    }
+
+    #[test]
+    fn handle_kinds() {
+        assert_eq!(handle_kind(CF_BITMAP), HandleKind::Bitmap);
+        assert_eq!(handle_kind(CF_ENHMETAFILE), HandleKind::EnhMetafile);
+        assert_eq!(handle_kind(CF_PALETTE), HandleKind::Palette);
+        assert_eq!(handle_kind(CF_METAFILEPICT), HandleKind::Unsupported);
+        assert_eq!(handle_kind(13), HandleKind::Global);
+        assert_eq!(handle_kind(0xC0FF), HandleKind::Global);
+    }
+
+    #[test]
+    fn bitmap_round_trip() {
+        let pixels: Vec<u8> = (0..3 * 2 * 4).map(|i| i as u8).collect();
+        let dib = encode_bitmap(3, 2, &pixels).unwrap();
+        let layout = decode_bitmap(&dib).unwrap();
+        assert_eq!(
+            layout,
+            DibLayout { width: 3, height: 2, bit_count: 32, pixel_offset: 40 }
+        );
+        assert_eq!(&dib[layout.pixel_offset..], &pixels[..]);
+    }
+
+    #[test]
+    fn bitmap_rejects_bad_input() {
+        assert_eq!(
+            encode_bitmap(2, 2, &[0; 15]),
+            Err(CodecError::Truncated { needed: 16, got: 15 })
+        );
+        assert!(encode_bitmap(0, 2, &[]).is_err());
+
+        let dib = encode_bitmap(2, 2, &[0; 16]).unwrap();
+        assert_eq!(
+            decode_bitmap(&dib[..dib.len() - 1]),
+            Err(CodecError::Truncated { needed: 56, got: 55 })
+        );
+        let mut bad_planes = dib.clone();
+        bad_planes[12] = 2;
+        assert!(matches!(decode_bitmap(&bad_planes), Err(CodecError::BadHeader(_))));
+        assert!(decode_bitmap(&dib[..20]).is_err());
+    }
+
+    #[test]
+    fn bitmap_accepts_top_down_and_padded_rows() {
+        // 3px wide at 24bpp pads each row from 9 to 12 bytes
+        let mut dib = encode_bitmap(3, 2, &[0; 24]).unwrap();
+        dib[8..12].copy_from_slice(&(-2i32).to_le_bytes());
+        dib[14..16].copy_from_slice(&24u16.to_le_bytes());
+        dib.truncate(40 + 24);
+        let layout = decode_bitmap(&dib).unwrap();
+        assert_eq!(layout.height, -2);
+        assert_eq!(layout.bit_count, 24);
+        assert!(decode_bitmap(&dib[..40 + 23]).is_err());
+    }
+
+    #[test]
+    fn emf_header_validation() {
+        assert_eq!(decode_emf(&emf_header(108)), Ok(()));
+
+        let mut wrong_size = emf_header(108);
+        wrong_size.truncate(100);
+        assert_eq!(
+            decode_emf(&wrong_size),
+            Err(CodecError::Truncated { needed: 108, got: 100 })
+        );
+
+        let mut no_signature = emf_header(108);
+        no_signature[40] = 0;
+        assert!(matches!(decode_emf(&no_signature), Err(CodecError::BadHeader(_))));
+
+        let mut not_header = emf_header(108);
+        not_header[0] = 2;
+        assert!(matches!(decode_emf(&not_header), Err(CodecError::BadHeader(_))));
+    }
+
+    #[test]
+    fn palette_round_trip() {
+        let entries = vec![[255, 0, 0, 0], [0, 128, 255, 1], [7, 7, 7, 0]];
+        let data = encode_palette(&entries);
+        assert_eq!(&data[..4], &[0x00, 0x03, 3, 0]);
+        assert_eq!(decode_palette(&data), Ok(entries));
+    }
+
+    #[test]
+    fn palette_rejects_bad_input() {
+        let data = encode_palette(&[[1, 2, 3, 0], [4, 5, 6, 0]]);
+        assert_eq!(
+            decode_palette(&data[..10]),
+            Err(CodecError::Truncated { needed: 12, got: 10 })
+        );
+        assert!(decode_palette(&encode_palette(&[])).is_err());
+        let mut wrong_version = data;
+        wrong_version[1] = 4;
+        assert!(matches!(decode_palette(&wrong_version), Err(CodecError::BadHeader(_))));
+    }
+}
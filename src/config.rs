@@ -0,0 +1,871 @@
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fmt;
+use std::path::{Path, PathBuf};
+
+use crate::hotkeys::{self, HotkeyAction};
+use crate::models::ClipFacts;
+use crate::query;
+use crate::transform::Transform;
+
+/// Top-level layout of `config.toml`.
+///
+/// ```toml
+/// [storage]
+/// database = "clipboard.db"        # relative paths resolve against the data dir
+/// cloud_database = "cloud.db"
+/// key = "pwd"                      # SQLCipher passphrase for both databases
+///
+/// [ui]
+/// window_title = "Clip"
+/// items_per_page = 20              # 1..=500
+/// preview_length = 80              # characters, 1..=10000
+///
+/// [hotkeys]                        # action = combo, see `HotkeyAction::from_name`
+/// show_history = "Ctrl+Alt+C"
+/// open_search = "Ctrl+Alt+F"
+/// paste_previous = "Ctrl+Alt+V"
+/// paste_plain = "Ctrl+Alt+Shift+V"
+/// paste_pinned_1 = "Ctrl+Alt+1"    # paste_pinned_1 ..= paste_pinned_9
+/// secure_copy = "Ctrl+Alt+S"
+/// clear_clipboard = "Ctrl+Alt+X"
+/// "transform:trim" = "Ctrl+Alt+T"  # built-in or [[transforms]] name; pastes the result
+///
+/// [paste]
+/// auto_paste = false               # paste into the previous window after a restore
+/// delay_ms = 50                    # wait before re-activating that window
+///
+/// [paste.overrides]                # process name = paste combo, for apps that don't take Ctrl+V
+/// "WindowsTerminal.exe" = "Ctrl+Shift+V"
+///
+/// [files]
+/// snapshot = false                 # keep copies of small copied files in the database
+/// snapshot_max_kb = 1024           # per file, 1..=65536
+///
+/// [[transforms]]                   # clip text on stdin, result on stdout
+/// name = "strip_tracking"
+/// command = ["python", "strip_tracking.py"]
+/// timeout_ms = 5000                # 1..=60000
+///
+/// [[transforms]]                   # sandboxed WebAssembly, see `scripts`
+/// name = "rot13"
+/// wasm = "rot13.wasm"              # relative paths resolve against the config dir
+/// format = "text/plain"            # clip format handed to the module
+///
+/// [[rules]]                        # applied to each new clip; every condition is optional
+/// app = "chrome.exe"               # owner process, case-insensitive
+/// title = "GitHub"                 # window title contains
+/// kind = "url"                     # same values as the `kind:` search filter
+/// transform = "strip_tracking"
+/// replace = true                   # put the result on the clipboard, not just in history
+///
+/// [sync]                           # an openclip-sync server; leave out to keep cloud.db local
+/// server = "https://clips.example.net"
+/// token = "..."                    # one of the server's --token values
+/// # folder = 'D:\Sync\openclip'    # or instead of server and token, a folder Syncthing,
+///                                  # Nextcloud or a network share keeps the same everywhere
+/// passphrase = "..."               # encrypts clips before upload; the same on every device
+/// device_name = "laptop-2"         # shown on clips copied here; defaults to the computer name
+///
+/// [[sync.push]]                    # pushes matching clips automatically; sensitive clips never match
+/// pinned = true                    # every condition is optional; an empty rule pushes everything
+///
+/// [[sync.push]]
+/// app = "code.exe"                 # also title, kind and tag, as in [[rules]]
+///
+/// [[sync.push]]
+/// tag = "share"
+///
+/// [lan]                            # send clips to paired devices on the local network
+/// enabled = true
+/// port = 0                         # TCP port to listen on; 0 picks a free one
+///
+/// [[lan.link]]                     # what a linked clipboard shares; no rules shares every copy
+/// app = "code.exe"                 # same conditions as [[sync.push]]; sensitive clips never match
+/// ```
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub storage: StorageConfig,
+    pub ui: UiConfig,
+    pub hotkeys: BTreeMap<String, String>,
+    pub paste: PasteConfig,
+    pub files: FilesConfig,
+    pub transforms: Vec<TransformConfig>,
+    pub rules: Vec<RuleConfig>,
+    pub sync: SyncConfig,
+    pub lan: LanConfig,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct StorageConfig {
+    pub database: PathBuf,
+    pub cloud_database: PathBuf,
+    pub key: String,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct UiConfig {
+    pub window_title: String,
+    pub items_per_page: i32,
+    pub preview_length: usize,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct PasteConfig {
+    pub auto_paste: bool,
+    pub delay_ms: u64,
+    pub overrides: BTreeMap<String, String>,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct FilesConfig {
+    pub snapshot: bool,
+    pub snapshot_max_kb: u64,
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SyncConfig {
+    /// Base URL of the server; empty keeps the cloud in cloud.db.
+    pub server: String,
+    pub token: String,
+    /// A shared folder to sync through instead of a server; see `folderlog`.
+    #[serde(skip_serializing_if = "path_is_empty")]
+    pub folder: PathBuf,
+    /// Unlocks the keys clips are encrypted with. Unrelated to `storage.key`.
+    pub passphrase: String,
+    /// What other devices call this one; the computer name when empty.
+    #[serde(skip_serializing_if = "String::is_empty")]
+    pub device_name: String,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub push: Vec<PushRuleConfig>,
+}
+
+/// Found over mDNS; see `lan`.
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LanConfig {
+    pub enabled: bool,
+    pub port: u16,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub link: Vec<PushRuleConfig>,
+}
+
+/// Pushes a clip to the cloud when it's captured, pinned or tagged, or to a
+/// linked clipboard when it's copied, and meets every condition given.
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct PushRuleConfig {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub app: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub title: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub kind: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tag: Option<String>,
+    pub pinned: bool,
+}
+
+/// A user transform: exactly one of `command` and `wasm`.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct TransformConfig {
+    pub name: String,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub command: Vec<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub wasm: Option<PathBuf>,
+    pub format: String,
+    pub timeout_ms: u64,
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RuleConfig {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub app: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub title: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub kind: Option<String>,
+    pub transform: String,
+    pub replace: bool,
+}
+
+impl Default for TransformConfig {
+    fn default() -> Self {
+        Self {
+            name: String::new(),
+            command: Vec::new(),
+            wasm: None,
+            format: "text/plain".to_string(),
+            timeout_ms: 5000,
+        }
+    }
+}
+
+impl TransformConfig {
+    /// The module path with relative entries anchored in the config dir.
+    pub fn wasm_path(&self) -> Option<PathBuf> {
+        self.wasm.as_ref().map(|path| if path.is_absolute() { path.clone() } else { config_dir().join(path) })
+    }
+}
+
+/// The `app`, `title` and `kind` conditions shared by `[[rules]]` and `[[sync.push]]`.
+struct SourceConditions<'a> {
+    app: &'a Option<String>,
+    title: &'a Option<String>,
+    kind: &'a Option<String>,
+}
+
+impl SourceConditions<'_> {
+    fn matches(&self, owner: &str, title: &str, kind: Option<&str>) -> bool {
+        self.app.as_ref().is_none_or(|app| app.eq_ignore_ascii_case(owner))
+            && self.title.as_ref().is_none_or(|t| title.to_lowercase().contains(&t.to_lowercase()))
+            && self.kind.as_ref().is_none_or(|pattern| {
+                let Ok(pattern) = query::parse_kind(pattern) else { return false };
+                kind.is_some_and(|kind| kind == pattern || kind.starts_with(&format!("{}:", pattern)))
+            })
+    }
+}
+
+impl RuleConfig {
+    /// Whether a clip from `owner`/`title`, classified as `kind`, triggers this rule.
+    pub fn matches(&self, owner: &str, title: &str, kind: Option<&str>) -> bool {
+        SourceConditions { app: &self.app, title: &self.title, kind: &self.kind }.matches(owner, title, kind)
+    }
+}
+
+impl PushRuleConfig {
+    pub fn matches(&self, clip: &ClipFacts) -> bool {
+        let source = SourceConditions { app: &self.app, title: &self.title, kind: &self.kind };
+        !clip.sensitive
+            && source.matches(&clip.owner, &clip.title, clip.kind.as_deref())
+            && self.tag.as_ref().is_none_or(|tag| clip.tags.iter().any(|t| t.eq_ignore_ascii_case(tag.trim_start_matches('#'))))
+            && (!self.pinned || clip.pinned)
+    }
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            storage: StorageConfig::default(),
+            ui: UiConfig::default(),
+            hotkeys: hotkeys::default_bindings(),
+            paste: PasteConfig::default(),
+            files: FilesConfig::default(),
+            transforms: Vec::new(),
+            rules: Vec::new(),
+            sync: SyncConfig::default(),
+            lan: LanConfig::default(),
+        }
+    }
+}
+
+impl Default for FilesConfig {
+    fn default() -> Self {
+        Self { snapshot: false, snapshot_max_kb: 1024 }
+    }
+}
+
+impl Default for PasteConfig {
+    fn default() -> Self {
+        let terminals = ["WindowsTerminal.exe", "alacritty.exe", "wezterm-gui.exe"];
+        Self {
+            auto_paste: false,
+            delay_ms: 50,
+            overrides: terminals
+                .iter()
+                .map(|t| (t.to_string(), "Ctrl+Shift+V".to_string()))
+                .collect(),
+        }
+    }
+}
+
+impl SyncConfig {
+    pub fn enabled(&self) -> bool {
+        !self.server.trim().is_empty() || !path_is_empty(&self.folder)
+    }
+
+    pub fn device_name(&self) -> String {
+        match self.device_name.trim() {
+            "" => std::env::var("COMPUTERNAME")
+                .or_else(|_| std::env::var("HOSTNAME"))
+                .unwrap_or_else(|_| "This device".to_string()),
+            name => name.to_string(),
+        }
+    }
+}
+
+impl PasteConfig {
+    /// The combo that pastes into `process`, matched case-insensitively.
+    pub fn combo_for(&self, process: &str) -> (u32, u32) {
+        self.overrides
+            .iter()
+            .find(|(name, _)| name.eq_ignore_ascii_case(process))
+            .and_then(|(_, combo)| hotkeys::parse_hotkey(combo).ok())
+            .unwrap_or((hotkeys::MOD_CONTROL, 'V' as u32))
+    }
+}
+
+impl Default for StorageConfig {
+    fn default() -> Self {
+        Self {
+            database: PathBuf::from("clipboard.db"),
+            cloud_database: PathBuf::from("cloud.db"),
+            key: "pwd".to_string(),
+        }
+    }
+}
+
+impl Default for UiConfig {
+    fn default() -> Self {
+        Self {
+            window_title: "Clip".to_string(),
+            items_per_page: 20,
+            preview_length: 80,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum ConfigError {
+    Io(PathBuf, std::io::Error),
+    Parse(PathBuf, toml::de::Error),
+    Invalid(String),
+    Usage(String),
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConfigError::Io(path, e) => write!(f, "{}: {}", path.display(), e),
+            ConfigError::Parse(path, e) => write!(f, "{}: {}", path.display(), e),
+            ConfigError::Invalid(msg) => write!(f, "invalid config: {}", msg),
+            ConfigError::Usage(msg) => write!(f, "{}\n\n{}", msg, USAGE),
+        }
+    }
+}
+
+pub const USAGE: &str = "usage: openclip [options]
+
+  --config <path>          config file (default: <config dir>/openclip/config.toml)
+  --db <path>              local history database
+  --cloud-db <path>        cloud database
+  --key <passphrase>       database passphrase
+  --items-per-page <n>     history page size
+  --preview-length <n>     preview length in characters
+  --hotkey <combo>         show-history hotkey, e.g. Ctrl+Alt+C
+  --window-title <title>   main window title";
+
+/// Per-user config directory: `%APPDATA%\openclip` on Windows,
+/// `$XDG_CONFIG_HOME/openclip` (or `~/.config/openclip`) elsewhere.
+pub fn config_dir() -> PathBuf {
+    if cfg!(windows) {
+        if let Some(dir) = std::env::var_os("APPDATA") {
+            return PathBuf::from(dir).join("openclip");
+        }
+    }
+    if let Some(dir) = std::env::var_os("XDG_CONFIG_HOME") {
+        return PathBuf::from(dir).join("openclip");
+    }
+    home_dir().join(".config").join("openclip")
+}
+
+/// Per-user data directory: `%LOCALAPPDATA%\openclip` on Windows,
+/// `$XDG_DATA_HOME/openclip` (or `~/.local/share/openclip`) elsewhere.
+pub fn data_dir() -> PathBuf {
+    if cfg!(windows) {
+        if let Some(dir) = std::env::var_os("LOCALAPPDATA").or_else(|| std::env::var_os("APPDATA")) {
+            return PathBuf::from(dir).join("openclip");
+        }
+    }
+    if let Some(dir) = std::env::var_os("XDG_DATA_HOME") {
+        return PathBuf::from(dir).join("openclip");
+    }
+    home_dir().join(".local").join("share").join("openclip")
+}
+
+fn home_dir() -> PathBuf {
+    std::env::var_os("HOME")
+        .or_else(|| std::env::var_os("USERPROFILE"))
+        .map(PathBuf::from)
+        .unwrap_or_else(|| PathBuf::from("."))
+}
+
+/// Values given on the command line. They win over the file and survive reloads.
+#[derive(Clone, Debug, Default)]
+pub struct CliOverrides {
+    pub config_path: Option<PathBuf>,
+    database: Option<PathBuf>,
+    cloud_database: Option<PathBuf>,
+    key: Option<String>,
+    items_per_page: Option<i32>,
+    preview_length: Option<usize>,
+    hotkey: Option<String>,
+    window_title: Option<String>,
+}
+
+impl CliOverrides {
+    pub fn parse<I: Iterator<Item = String>>(mut args: I) -> Result<Self, ConfigError> {
+        let mut cli = CliOverrides::default();
+        while let Some(arg) = args.next() {
+            if arg == "--help" || arg == "-h" {
+                return Err(ConfigError::Usage("openclip clipboard history".to_string()));
+            }
+            let mut value = || {
+                args.next().ok_or_else(|| ConfigError::Usage(format!("{} expects a value", arg)))
+            };
+            match arg.as_str() {
+                "--config" => cli.config_path = Some(PathBuf::from(value()?)),
+                "--db" => cli.database = Some(PathBuf::from(value()?)),
+                "--cloud-db" => cli.cloud_database = Some(PathBuf::from(value()?)),
+                "--key" => cli.key = Some(value()?),
+                "--items-per-page" => cli.items_per_page = Some(parse_number(&arg, &value()?)?),
+                "--preview-length" => cli.preview_length = Some(parse_number(&arg, &value()?)?),
+                "--hotkey" => cli.hotkey = Some(value()?),
+                "--window-title" => cli.window_title = Some(value()?),
+                _ => return Err(ConfigError::Usage(format!("unknown argument '{}'", arg))),
+            }
+        }
+        Ok(cli)
+    }
+
+    pub fn apply(&self, config: &mut Config) {
+        if let Some(v) = &self.database { config.storage.database = v.clone(); }
+        if let Some(v) = &self.cloud_database { config.storage.cloud_database = v.clone(); }
+        if let Some(v) = &self.key { config.storage.key = v.clone(); }
+        if let Some(v) = self.items_per_page { config.ui.items_per_page = v; }
+        if let Some(v) = self.preview_length { config.ui.preview_length = v; }
+        if let Some(v) = &self.hotkey { config.hotkeys.insert("show_history".to_string(), v.clone()); }
+        if let Some(v) = &self.window_title { config.ui.window_title = v.clone(); }
+    }
+
+    /// The config keys the command line sets, which win over the file.
+    pub fn overridden(&self) -> Vec<&'static str> {
+        [
+            (self.database.is_some(), "storage.database"),
+            (self.cloud_database.is_some(), "storage.cloud_database"),
+            (self.key.is_some(), "storage.key"),
+            (self.items_per_page.is_some(), "ui.items_per_page"),
+            (self.preview_length.is_some(), "ui.preview_length"),
+            (self.hotkey.is_some(), "hotkeys.show_history"),
+            (self.window_title.is_some(), "ui.window_title"),
+        ]
+        .into_iter()
+        .filter_map(|(set, key)| set.then_some(key))
+        .collect()
+    }
+
+    pub fn config_path(&self) -> PathBuf {
+        self.config_path
+            .clone()
+            .unwrap_or_else(|| config_dir().join("config.toml"))
+    }
+}
+
+fn parse_number<T: std::str::FromStr>(arg: &str, value: &str) -> Result<T, ConfigError> {
+    value
+        .parse()
+        .map_err(|_| ConfigError::Usage(format!("{} expects a number, got '{}'", arg, value)))
+}
+
+impl Config {
+    /// Reads `path`, falling back to defaults when the file does not exist.
+    /// This is the file layer, without the command line; Settings edits and
+    /// saves it.
+    pub fn read(path: &Path) -> Result<Self, ConfigError> {
+        let mut config: Config = match std::fs::read_to_string(path) {
+            Ok(text) => toml::from_str(&text).map_err(|e| ConfigError::Parse(path.to_path_buf(), e))?,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Config::default(),
+            Err(e) => return Err(ConfigError::Io(path.to_path_buf(), e)),
+        };
+        if let Some(combo) = config.hotkeys.remove("toggle") {
+            config.hotkeys.entry("show_history".to_string()).or_insert(combo);
+        }
+        Ok(config)
+    }
+
+    /// Reads `path`, then layers `cli` on top and validates the result.
+    pub fn load(path: &Path, cli: &CliOverrides) -> Result<Self, ConfigError> {
+        let mut config = Self::read(path)?;
+        cli.apply(&mut config);
+        config.validate()?;
+        Ok(config)
+    }
+
+    /// Writes this file layer to `path` once it's valid with `cli` on top.
+    /// Values that only came from the command line stay out of the file.
+    pub fn save(&self, path: &Path, cli: &CliOverrides) -> Result<(), ConfigError> {
+        let mut effective = self.clone();
+        cli.apply(&mut effective);
+        effective.validate()?;
+        let text = toml::to_string_pretty(self)
+            .map_err(|e| ConfigError::Invalid(e.to_string()))?;
+        if let Some(dir) = path.parent() {
+            std::fs::create_dir_all(dir).map_err(|e| ConfigError::Io(dir.to_path_buf(), e))?;
+        }
+        std::fs::write(path, text).map_err(|e| ConfigError::Io(path.to_path_buf(), e))
+    }
+
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        if self.storage.database.as_os_str().is_empty() {
+            return Err(ConfigError::Invalid("storage.database must not be empty".to_string()));
+        }
+        if self.storage.cloud_database.as_os_str().is_empty() {
+            return Err(ConfigError::Invalid("storage.cloud_database must not be empty".to_string()));
+        }
+        if self.storage.key.is_empty() {
+            return Err(ConfigError::Invalid("storage.key must not be empty".to_string()));
+        }
+        if self.ui.window_title.trim().is_empty() {
+            return Err(ConfigError::Invalid("ui.window_title must not be empty".to_string()));
+        }
+        if !(1..=500).contains(&self.ui.items_per_page) {
+            return Err(ConfigError::Invalid(format!(
+                "ui.items_per_page must be between 1 and 500, got {}",
+                self.ui.items_per_page
+            )));
+        }
+        if !(1..=10_000).contains(&self.ui.preview_length) {
+            return Err(ConfigError::Invalid(format!(
+                "ui.preview_length must be between 1 and 10000, got {}",
+                self.ui.preview_length
+            )));
+        }
+        self.validate_transforms()?;
+        let bindings = hotkeys::parse_bindings(&self.hotkeys)
+            .map_err(|e| ConfigError::Invalid(format!("hotkeys: {}", e)))?;
+        for binding in bindings {
+            if let HotkeyAction::Transform(name) = &binding.action {
+                if !self.has_transform(name) {
+                    return Err(ConfigError::Invalid(format!("hotkeys: {} names an unknown transform", binding.action)));
+                }
+            }
+        }
+        if self.paste.delay_ms > 5_000 {
+            return Err(ConfigError::Invalid(format!(
+                "paste.delay_ms must be at most 5000, got {}",
+                self.paste.delay_ms
+            )));
+        }
+        for (process, combo) in &self.paste.overrides {
+            hotkeys::parse_hotkey(combo)
+                .map_err(|e| ConfigError::Invalid(format!("paste.overrides.{}: {}", process, e)))?;
+        }
+        if !(1..=65_536).contains(&self.files.snapshot_max_kb) {
+            return Err(ConfigError::Invalid(format!(
+                "files.snapshot_max_kb must be between 1 and 65536, got {}",
+                self.files.snapshot_max_kb
+            )));
+        }
+        if !path_is_empty(&self.sync.folder) {
+            if !self.sync.server.trim().is_empty() {
+                return Err(ConfigError::Invalid("sync.folder and sync.server can't both be set".to_string()));
+            }
+            if !self.sync.folder.is_absolute() {
+                return Err(ConfigError::Invalid(format!(
+                    "sync.folder must be an absolute path, got '{}'",
+                    self.sync.folder.display()
+                )));
+            }
+        } else if self.sync.enabled() {
+            if !self.sync.server.starts_with("http://") && !self.sync.server.starts_with("https://") {
+                return Err(ConfigError::Invalid(format!(
+                    "sync.server must be an http:// or https:// URL, got '{}'",
+                    self.sync.server
+                )));
+            }
+            if self.sync.token.trim().is_empty() {
+                return Err(ConfigError::Invalid("sync.token must not be empty when sync.server is set".to_string()));
+            }
+        }
+        if self.sync.enabled() {
+            if self.sync.passphrase.is_empty() {
+                return Err(ConfigError::Invalid("sync.passphrase must not be empty when syncing".to_string()));
+            }
+            if self.sync.passphrase == self.storage.key {
+                return Err(ConfigError::Invalid("sync.passphrase must differ from storage.key".to_string()));
+            }
+        }
+        Ok(())
+    }
+
+    fn validate_transforms(&self) -> Result<(), ConfigError> {
+        for (i, t) in self.transforms.iter().enumerate() {
+            let invalid = |msg: &str| Err(ConfigError::Invalid(format!("transforms[{}]: {}", i, msg)));
+            if t.name.trim().is_empty() {
+                return invalid("name must not be empty");
+            }
+            if Transform::from_name(&t.name).is_some() {
+                return invalid(&format!("'{}' is a built-in transform", t.name));
+            }
+            if self.transforms[..i].iter().any(|other| other.name == t.name) {
+                return invalid(&format!("'{}' is declared twice", t.name));
+            }
+            match (t.command.is_empty(), &t.wasm) {
+                (true, None) => return invalid("needs a command or a wasm module"),
+                (false, Some(_)) => return invalid("takes a command or a wasm module, not both"),
+                _ => {}
+            }
+            if !(1..=60_000).contains(&t.timeout_ms) {
+                return invalid(&format!("timeout_ms must be between 1 and 60000, got {}", t.timeout_ms));
+            }
+        }
+        for (i, rule) in self.rules.iter().enumerate() {
+            if !self.has_transform(&rule.transform) {
+                return Err(ConfigError::Invalid(format!(
+                    "rules[{}]: unknown transform '{}'",
+                    i, rule.transform
+                )));
+            }
+            if let Some(kind) = &rule.kind {
+                query::parse_kind(kind).map_err(|e| ConfigError::Invalid(format!("rules[{}]: {}", i, e)))?;
+            }
+        }
+        for (i, rule) in self.sync.push.iter().enumerate() {
+            if let Some(kind) = &rule.kind {
+                query::parse_kind(kind).map_err(|e| ConfigError::Invalid(format!("sync.push[{}]: {}", i, e)))?;
+            }
+        }
+        for (i, rule) in self.lan.link.iter().enumerate() {
+            if let Some(kind) = &rule.kind {
+                query::parse_kind(kind).map_err(|e| ConfigError::Invalid(format!("lan.link[{}]: {}", i, e)))?;
+            }
+        }
+        Ok(())
+    }
+
+    /// A built-in transform or one declared under `[[transforms]]`.
+    pub fn has_transform(&self, name: &str) -> bool {
+        Transform::from_name(name).is_some() || self.transforms.iter().any(|t| t.name == name)
+    }
+
+    /// Database paths with relative entries anchored in the data dir, so the
+    /// working directory the app was started from doesn't matter.
+    pub fn database_path(&self) -> String {
+        resolve_data_path(&self.storage.database)
+    }
+
+    pub fn cloud_database_path(&self) -> String {
+        resolve_data_path(&self.storage.cloud_database)
+    }
+
+    /// What's been read from `sync.folder`, next to cloud.db.
+    pub fn folder_index_path(&self) -> PathBuf {
+        Path::new(&self.cloud_database_path()).with_extension("folder.db")
+    }
+}
+
+fn path_is_empty(path: &Path) -> bool {
+    path.as_os_str().is_empty()
+}
+
+fn resolve_data_path(path: &Path) -> String {
+    let full = if path.is_absolute() {
+        path.to_path_buf()
+    } else {
+        let dir = data_dir();
+        let _ = std::fs::create_dir_all(&dir);
+        dir.join(path)
+    };
+    full.to_string_lossy().into_owned()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(text: &str) -> Config {
+        toml::from_str(text).unwrap()
+    }
+
+    fn invalid(text: &str) -> String {
+        match parse(text).validate() {
+            Err(ConfigError::Invalid(msg)) => msg,
+            other => panic!("expected an invalid config, got {:?}", other),
+        }
+    }
+
+    fn cli(args: &[&str]) -> CliOverrides {
+        CliOverrides::parse(args.iter().map(|a| a.to_string())).unwrap()
+    }
+
+    #[test]
+    fn defaults() {
+        let config = parse("");
+        assert_eq!(config, Config::default());
+        config.validate().unwrap();
+        assert_eq!(config.hotkeys["show_history"], "Ctrl+Alt+C");
+        assert_eq!(config.paste.combo_for("windowsterminal.exe"), (hotkeys::MOD_CONTROL | hotkeys::MOD_SHIFT, 'V' as u32));
+        // Restoring only puts the clip on the clipboard unless asked to paste too
+        assert!(!config.paste.auto_paste);
+        assert!(!config.sync.enabled());
+    }
+
+    #[test]
+    fn parses_every_section() {
+        let config = parse(
+            r#"
+            [storage]
+            key = "secret"
+            [ui]
+            items_per_page = 50
+            [hotkeys]
+            "transform:rot13" = "Ctrl+Alt+R"
+            [[transforms]]
+            name = "rot13"
+            wasm = "rot13.wasm"
+            [[rules]]
+            app = "chrome.exe"
+            kind = "url"
+            transform = "trim"
+            [sync]
+            folder = "/srv/sync"
+            passphrase = "words"
+            [[sync.push]]
+            tag = "share"
+            [lan]
+            enabled = true
+            [[lan.link]]
+            pinned = true
+            "#,
+        );
+        config.validate().unwrap();
+        assert_eq!((config.storage.key.as_str(), config.ui.items_per_page, config.ui.preview_length), ("secret", 50, 80));
+        // A table in the file replaces the defaults rather than adding to them
+        assert_eq!(config.hotkeys.len(), 1);
+        assert_eq!(config.transforms[0].wasm_path(), Some(config_dir().join("rot13.wasm")));
+        assert_eq!(config.transforms[0].timeout_ms, 5000);
+        assert!(config.rules[0].matches("Chrome.exe", "", Some("url")));
+        assert!(!config.rules[0].matches("chrome.exe", "", Some("email")));
+        assert!(config.sync.enabled());
+        assert_eq!(config.sync.push[0].tag.as_deref(), Some("share"));
+        assert!(config.lan.enabled && config.lan.link[0].pinned);
+    }
+
+    #[test]
+    fn unknown_keys_are_rejected() {
+        assert!(toml::from_str::<Config>("[ui]\nitems_per_pag = 3").is_err());
+        assert!(toml::from_str::<Config>("[clipboard]").is_err());
+        assert!(toml::from_str::<Config>("[ui]\nitems_per_page = \"many\"").is_err());
+    }
+
+    #[test]
+    fn validation() {
+        assert!(invalid("[storage]\nkey = \"\"").contains("storage.key"));
+        assert!(invalid("[ui]\nitems_per_page = 0").contains("items_per_page"));
+        assert!(invalid("[ui]\npreview_length = 10001").contains("preview_length"));
+        assert!(invalid("[hotkeys]\nshow_history = \"Ctrl+Alt+C\"\nopen_search = \"ctrl+alt+c\"").contains("bound to both"));
+        assert!(invalid("[hotkeys]\nwarp = \"Ctrl+W\"").contains("unknown hotkey action"));
+        assert!(invalid("[hotkeys]\n\"transform:nope\" = \"Ctrl+N\"").contains("unknown transform"));
+        assert!(invalid("[[transforms]]\nname = \"x\"").contains("needs a command"));
+        assert!(invalid("[[transforms]]\nname = \"x\"\ncommand = [\"x\"]\ntimeout_ms = 0").contains("timeout_ms"));
+        assert!(invalid("[[rules]]\ntransform = \"nope\"").contains("unknown transform"));
+        assert!(invalid("[[rules]]\ntransform = \"trim\"\nkind = \"gif\"").contains("rules[0]"));
+        assert!(invalid("[paste]\ndelay_ms = 9000").contains("delay_ms"));
+        assert!(invalid("[files]\nsnapshot_max_kb = 0").contains("snapshot_max_kb"));
+        assert!(invalid("[sync]\nserver = \"clips.example.net\"\ntoken = \"t\"\npassphrase = \"p\"").contains("http"));
+        assert!(invalid("[sync]\nserver = \"https://clips.example.net\"\npassphrase = \"p\"").contains("sync.token"));
+        assert!(invalid("[sync]\nserver = \"https://clips.example.net\"\ntoken = \"t\"").contains("sync.passphrase"));
+        assert!(invalid("[sync]\nfolder = \"/srv/sync\"\npassphrase = \"pwd\"").contains("differ"));
+        assert!(invalid("[sync]\nfolder = \"/srv/sync\"\nserver = \"https://x\"\ntoken = \"t\"\npassphrase = \"p\"").contains("both"));
+        assert!(invalid("[sync]\nfolder = \"sync\"\npassphrase = \"p\"").contains("absolute"));
+        assert!(invalid("[[lan.link]]\nkind = \"gif\"").contains("lan.link[0]"));
+    }
+
+    #[test]
+    fn command_line() {
+        let overrides = cli(&["--db", "other.db", "--key", "from-cli", "--items-per-page", "7"]);
+        assert_eq!(overrides.overridden(), ["storage.database", "storage.key", "ui.items_per_page"]);
+        let mut config = Config::default();
+        overrides.apply(&mut config);
+        assert_eq!((config.storage.database.as_path(), config.storage.key.as_str(), config.ui.items_per_page), (Path::new("other.db"), "from-cli", 7));
+
+        let usage = |args: &[&str]| matches!(CliOverrides::parse(args.iter().map(|a| a.to_string())), Err(ConfigError::Usage(_)));
+        assert!(usage(&["--items-per-page", "many"]));
+        assert!(usage(&["--key"]));
+        assert!(usage(&["--verbose"]));
+        assert!(cli(&[]).overridden().is_empty());
+    }
+
+    #[test]
+    fn push_rules() {
+        let facts = |sensitive: bool| ClipFacts {
+            owner: "Code.exe".to_string(),
+            title: "main.rs - openclip".to_string(),
+            kind: Some("code:rust".to_string()),
+            pinned: true,
+            sensitive,
+            tags: vec!["share".to_string(), "work".to_string()],
+        };
+        let (clip, secret) = (facts(false), facts(true));
+        let rule = |text: &str| -> PushRuleConfig { parse(&format!("[[sync.push]]\n{}", text)).sync.push.remove(0) };
+
+        // An empty rule is everything except secure copies
+        let everything = PushRuleConfig::default();
+        assert!(everything.matches(&clip));
+        assert!(!everything.matches(&secret));
+        let all = rule("app = \"code.exe\"\ntitle = \"MAIN.RS\"\nkind = \"code\"\ntag = \"#share\"\npinned = true");
+        assert!(all.matches(&clip));
+        assert!(!all.matches(&secret));
+
+        // The tag matches with or without '#', in any case
+        assert!(rule("tag = \"SHARE\"").matches(&clip));
+        assert!(rule("tag = \"#work\"").matches(&clip));
+        assert!(!rule("tag = \"#private\"").matches(&clip));
+        let mut unpinned = facts(false);
+        unpinned.pinned = false;
+        assert!(!rule("pinned = true").matches(&unpinned));
+        assert!(rule("pinned = false").matches(&unpinned));
+
+        // Every condition has to hold
+        for text in [
+            "tag = \"share\"\napp = \"notepad.exe\"",
+            "tag = \"share\"\npinned = true\ntitle = \"readme\"",
+            "app = \"code.exe\"\nkind = \"url\"",
+            "app = \"code.exe\"\ntag = \"other\"",
+        ] {
+            assert!(!rule(text).matches(&clip), "{}", text);
+        }
+        assert!(!rule("app = \"code.exe\"\npinned = true").matches(&unpinned));
+    }
+
+    #[test]
+    fn file_and_command_line_layers() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("config.toml");
+        // No file yet means defaults
+        assert_eq!(Config::load(&path, &CliOverrides::default()).unwrap(), Config::default());
+
+        std::fs::write(&path, "[hotkeys]\ntoggle = \"Ctrl+Alt+H\"\n[ui]\nwindow_title = \"From file\"").unwrap();
+        let overrides = cli(&["--key", "from-cli", "--window-title", "From cli"]);
+        let effective = Config::load(&path, &overrides).unwrap();
+        assert_eq!((effective.storage.key.as_str(), effective.ui.window_title.as_str()), ("from-cli", "From cli"));
+        // The old name of show_history still works
+        assert_eq!(effective.hotkeys["show_history"], "Ctrl+Alt+H");
+
+        // Settings edits the file layer, so the command line stays out of the file
+        let mut file = Config::read(&path).unwrap();
+        file.ui.items_per_page = 30;
+        file.save(&path, &overrides).unwrap();
+        let saved = std::fs::read_to_string(&path).unwrap();
+        assert!(!saved.contains("from-cli") && !saved.contains("From cli"));
+        assert_eq!(Config::read(&path).unwrap().ui.window_title, "From file");
+        assert_eq!(Config::load(&path, &overrides).unwrap().ui.items_per_page, 30);
+
+        // What gets saved has to be valid with the command line on top
+        file.sync.passphrase = "from-cli".to_string();
+        file.sync.folder = PathBuf::from(if cfg!(windows) { r"C:\sync" } else { "/sync" });
+        assert!(file.save(&path, &overrides).is_err());
+        assert!(Config::load(&dir.path().join("missing").join("config.toml"), &overrides).is_ok());
+        std::fs::write(&path, "[ui\n").unwrap();
+        assert!(matches!(Config::load(&path, &overrides), Err(ConfigError::Parse(..))));
+    }
+}
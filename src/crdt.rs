@@ -0,0 +1,325 @@
+//! Clip metadata that merges without conflicts. Every device keeps its own
+//! replica of a clip's pin, tags, note and deleted flag and edits it freely;
+//! replicas merge in any order, any number of times, to the same state.
+//!
+//! Edits are stamped with a Lamport clock plus the device id. A register keeps
+//! the edit with the highest stamp, so an edit made after seeing another one
+//! always wins and concurrent ones are settled the same way on every device.
+//! Tags are an add-wins set: removing a tag takes back only the adds this
+//! replica had seen, so a concurrent add elsewhere survives.
+
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeSet;
+
+/// When and where an edit was made. Ordered by clock, then device.
+#[derive(Clone, Debug, Default, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+pub struct Stamp {
+    pub clock: u64,
+    pub device: String,
+}
+
+/// A device's Lamport clock.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Clock {
+    pub time: u64,
+    pub device: String,
+}
+
+impl Clock {
+    pub fn new(device: &str, time: u64) -> Self {
+        Clock { time, device: device.to_string() }
+    }
+
+    /// Stamps a new edit.
+    pub fn tick(&mut self) -> Stamp {
+        self.time += 1;
+        Stamp { clock: self.time, device: self.device.clone() }
+    }
+
+    /// Moves past every edit in `state`, so later edits here win over them.
+    pub fn observe(&mut self, state: &ClipState) {
+        self.time = self.time.max(state.clock());
+    }
+}
+
+/// A last-writer-wins value. The default stamp means it was never set.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Register<T> {
+    pub stamp: Stamp,
+    pub value: T,
+}
+
+impl<T: Clone + Ord> Register<T> {
+    fn set(&mut self, value: T, stamp: Stamp) {
+        self.merge(&Register { stamp, value });
+    }
+
+    /// Keeps the later edit. Equal stamps only come from replicas that
+    /// disagree about the same edit, which the value settles.
+    pub fn merge(&mut self, other: &Self) {
+        if (&other.stamp, &other.value) > (&self.stamp, &self.value) {
+            *self = other.clone();
+        }
+    }
+}
+
+/// An observed-remove set of tags.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TagSet {
+    /// Adds that haven't been removed, by the stamp that made them.
+    adds: BTreeSet<(Stamp, String)>,
+    /// Stamps of every add that was removed, kept so the add stays removed
+    /// when a replica that hasn't heard yet merges it back in.
+    removed: BTreeSet<Stamp>,
+}
+
+impl TagSet {
+    pub fn tags(&self) -> Vec<String> {
+        let tags: BTreeSet<&String> = self.adds.iter().map(|(_, tag)| tag).collect();
+        tags.into_iter().cloned().collect()
+    }
+
+    fn add(&mut self, tag: &str, stamp: Stamp) {
+        self.adds.insert((stamp, tag.to_string()));
+    }
+
+    fn remove(&mut self, tag: &str) {
+        let seen: Vec<(Stamp, String)> = self.adds.iter().filter(|(_, t)| t == tag).cloned().collect();
+        for add in seen {
+            self.adds.remove(&add);
+            self.removed.insert(add.0);
+        }
+    }
+
+    pub fn merge(&mut self, other: &Self) {
+        self.removed.extend(other.removed.iter().cloned());
+        self.adds.extend(other.adds.iter().cloned());
+        let removed = &self.removed;
+        self.adds.retain(|(stamp, _)| !removed.contains(stamp));
+    }
+
+    fn clock(&self) -> u64 {
+        let adds = self.adds.iter().map(|(stamp, _)| stamp.clock);
+        adds.chain(self.removed.iter().map(|stamp| stamp.clock)).max().unwrap_or(0)
+    }
+}
+
+/// Everything about a cloud clip that devices can change after it's pushed.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ClipState {
+    pub pin: Register<Option<u8>>,
+    pub tags: TagSet,
+    pub note: Register<String>,
+    pub deleted: Register<bool>,
+}
+
+impl ClipState {
+    pub fn merge(&mut self, other: &ClipState) {
+        self.pin.merge(&other.pin);
+        self.tags.merge(&other.tags);
+        self.note.merge(&other.note);
+        self.deleted.merge(&other.deleted);
+    }
+
+    /// The highest clock of any edit in here.
+    pub fn clock(&self) -> u64 {
+        [self.pin.stamp.clock, self.note.stamp.clock, self.deleted.stamp.clock, self.tags.clock()]
+            .into_iter()
+            .max()
+            .unwrap_or(0)
+    }
+
+    pub fn is_deleted(&self) -> bool {
+        self.deleted.value
+    }
+
+    /// Whether the parts the history shows (pin, tags and note) differ.
+    pub fn same_view(&self, other: &ClipState) -> bool {
+        self.pin.value == other.pin.value && self.tags.tags() == other.tags.tags() && self.note.value == other.note.value
+    }
+
+    pub fn set_pin(&mut self, pin: Option<u8>, clock: &mut Clock) {
+        if self.pin.value != pin {
+            self.pin.set(pin, clock.tick());
+        }
+    }
+
+    pub fn set_note(&mut self, note: &str, clock: &mut Clock) {
+        if self.note.value != note {
+            self.note.set(note.to_string(), clock.tick());
+        }
+    }
+
+    pub fn set_deleted(&mut self, deleted: bool, clock: &mut Clock) {
+        if self.deleted.value != deleted {
+            self.deleted.set(deleted, clock.tick());
+        }
+    }
+
+    /// Adds and removes tags until the set is `tags`.
+    pub fn set_tags(&mut self, tags: &[String], clock: &mut Clock) {
+        let current = self.tags.tags();
+        for tag in current.iter().filter(|t| !tags.contains(t)) {
+            self.tags.remove(tag);
+        }
+        for tag in tags.iter().filter(|t| !current.contains(t)) {
+            self.tags.add(tag, clock.tick());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use proptest::prelude::*;
+
+    const DEVICES: usize = 3;
+
+    #[derive(Clone, Debug)]
+    enum Edit {
+        Pin(Option<u8>),
+        Tags(Vec<String>),
+        Note(String),
+        Delete(bool),
+        /// Merges in another replica's state, as a sync would.
+        Pull(usize),
+    }
+
+    fn edit() -> impl Strategy<Value = Edit> {
+        prop_oneof![
+            proptest::option::of(1u8..4).prop_map(Edit::Pin),
+            proptest::sample::subsequence(vec!["a", "b", "c"], 0..=3)
+                .prop_map(|tags| Edit::Tags(tags.into_iter().map(str::to_string).collect())),
+            "[xy]{0,2}".prop_map(Edit::Note),
+            any::<bool>().prop_map(Edit::Delete),
+            (0..DEVICES).prop_map(Edit::Pull),
+        ]
+    }
+
+    /// Replays `edits` (each on one of the devices) and returns every replica.
+    fn replay(edits: &[(usize, Edit)]) -> Vec<(Clock, ClipState)> {
+        let mut replicas: Vec<(Clock, ClipState)> =
+            (0..DEVICES).map(|d| (Clock::new(&format!("dev{}", d), 0), ClipState::default())).collect();
+        for (device, edit) in edits {
+            let other = match edit {
+                Edit::Pull(from) => Some(replicas[*from].1.clone()),
+                _ => None,
+            };
+            let (clock, state) = &mut replicas[*device];
+            match edit {
+                Edit::Pin(pin) => state.set_pin(*pin, clock),
+                Edit::Tags(tags) => state.set_tags(tags, clock),
+                Edit::Note(note) => state.set_note(note, clock),
+                Edit::Delete(deleted) => state.set_deleted(*deleted, clock),
+                Edit::Pull(_) => {
+                    let other = other.expect("pulled state");
+                    state.merge(&other);
+                    clock.observe(&other);
+                }
+            }
+        }
+        replicas
+    }
+
+    fn merged(a: &ClipState, b: &ClipState) -> ClipState {
+        let mut out = a.clone();
+        out.merge(b);
+        out
+    }
+
+    fn history() -> impl Strategy<Value = Vec<(usize, Edit)>> {
+        proptest::collection::vec((0..DEVICES, edit()), 0..40)
+    }
+
+    proptest! {
+        #[test]
+        fn merge_is_a_join(edits in history()) {
+            let states: Vec<ClipState> = replay(&edits).into_iter().map(|(_, s)| s).collect();
+            let (a, b, c) = (&states[0], &states[1], &states[2]);
+            prop_assert_eq!(merged(a, b), merged(b, a));
+            prop_assert_eq!(merged(&merged(a, b), c), merged(a, &merged(b, c)));
+            prop_assert_eq!(merged(a, a), a.clone());
+            // Merging in something already seen changes nothing
+            prop_assert_eq!(merged(&merged(a, b), b), merged(a, b));
+        }
+
+        #[test]
+        fn divergent_replicas_converge(edits in history(), order in Just((0..DEVICES).collect::<Vec<_>>()).prop_shuffle()) {
+            let replicas = replay(&edits);
+            let states: Vec<ClipState> = replicas.iter().map(|(_, s)| s.clone()).collect();
+            // Everyone syncing with everyone, in any order, ends in one state
+            let forward = states.iter().fold(ClipState::default(), |acc, s| merged(&acc, s));
+            let shuffled = order.iter().fold(ClipState::default(), |acc, &i| merged(&acc, &states[i]));
+            prop_assert_eq!(&forward, &shuffled);
+            for state in &states {
+                prop_assert_eq!(&merged(state, &forward), &forward);
+            }
+            // Survives the trip through the sealed meta
+            let json = serde_json::to_string(&forward).unwrap();
+            prop_assert_eq!(serde_json::from_str::<ClipState>(&json).unwrap(), forward);
+        }
+
+        #[test]
+        fn an_edit_after_a_full_sync_wins(edits in history(), device in 0..DEVICES, pin in proptest::option::of(1u8..4), note in "[xyz]{1,3}") {
+            let mut replicas = replay(&edits);
+            let everything = replicas.iter().fold(ClipState::default(), |acc, (_, s)| merged(&acc, s));
+            let (clock, state) = &mut replicas[device];
+            state.merge(&everything);
+            clock.observe(&everything);
+            state.set_pin(pin, clock);
+            state.set_note(&note, clock);
+            state.set_tags(&["z".to_string()], clock);
+            let edited = state.clone();
+
+            for (_, other) in &replicas {
+                let result = merged(other, &edited);
+                prop_assert_eq!(result.pin.value, pin);
+                prop_assert_eq!(&result.note.value, &note);
+                prop_assert_eq!(result.tags.tags(), ["z"]);
+            }
+        }
+    }
+
+    #[test]
+    fn concurrent_add_beats_remove() {
+        let (mut laptop, mut desktop) = (Clock::new("laptop", 0), Clock::new("desktop", 0));
+        let mut a = ClipState::default();
+        a.set_tags(&["work".to_string()], &mut laptop);
+        let mut b = a.clone();
+        desktop.observe(&a);
+
+        // The laptop drops the tag while the desktop adds it again
+        a.set_tags(&[], &mut laptop);
+        b.set_tags(&[], &mut desktop);
+        b.set_tags(&["work".to_string()], &mut desktop);
+        assert_eq!(merged(&a, &b).tags.tags(), ["work"]);
+        assert_eq!(merged(&b, &a).tags.tags(), ["work"]);
+
+        // A removal that saw both adds sticks
+        let mut both = merged(&a, &b);
+        laptop.observe(&both);
+        both.set_tags(&[], &mut laptop);
+        assert!(merged(&b, &both).tags.tags().is_empty());
+    }
+
+    #[test]
+    fn pin_tags_and_delete_from_three_devices() {
+        let (mut one, mut two, mut three) = (Clock::new("one", 0), Clock::new("two", 0), Clock::new("three", 0));
+        let base = ClipState::default();
+        let (mut a, mut b, mut c) = (base.clone(), base.clone(), base);
+        a.set_pin(Some(2), &mut one);
+        b.set_tags(&["share".to_string()], &mut two);
+        c.set_note("for later", &mut three);
+        c.set_deleted(true, &mut three);
+
+        let all = merged(&merged(&a, &b), &c);
+        assert_eq!((all.pin.value, all.tags.tags(), all.note.value.as_str(), all.is_deleted()), (Some(2), vec!["share".to_string()], "for later", true));
+        // Pushing it again after seeing the deletion brings it back
+        let mut revived = all.clone();
+        one.observe(&all);
+        revived.set_deleted(false, &mut one);
+        assert!(!merged(&all, &revived).is_deleted());
+        assert!(merged(&all, &revived).same_view(&all));
+    }
+}
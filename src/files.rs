@@ -0,0 +1,240 @@
+//! File-list clips: the paths behind CF_HDROP or `text/uri-list`, what they
+//! look like on disk now, and optional snapshots of small files so the clip
+//! can be restored after the originals move.
+
+use std::collections::HashSet;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use crate::formats::{self, CF_HDROP};
+use crate::inspect::dropfiles_paths;
+use crate::models::ClipboardPayload;
+
+pub const URI_LIST: &str = "text/uri-list";
+
+/// One path of a file-list clip as it stands on disk right now.
+pub struct FileEntry {
+    pub path: String,
+    pub size: Option<u64>,
+    pub is_dir: bool,
+    pub exists: bool,
+    /// A copy of the contents is kept in the store.
+    pub snapshotted: bool,
+}
+
+impl FileEntry {
+    pub fn name(&self) -> &str {
+        self.path.rsplit(['\\', '/']).find(|s| !s.is_empty()).unwrap_or(&self.path)
+    }
+
+    pub fn icon(&self) -> &'static str {
+        if self.is_dir {
+            return "📁";
+        }
+        let ext = self.name().rsplit_once('.').map(|(_, e)| e.to_ascii_lowercase()).unwrap_or_default();
+        match ext.as_str() {
+            "png" | "jpg" | "jpeg" | "gif" | "bmp" | "webp" | "svg" | "ico" => "🖼",
+            "mp3" | "wav" | "flac" | "ogg" | "m4a" => "🎵",
+            "mp4" | "mkv" | "avi" | "mov" | "webm" => "🎞",
+            "zip" | "7z" | "rar" | "tar" | "gz" | "xz" => "📦",
+            "exe" | "msi" | "bat" | "cmd" | "ps1" | "dll" => "⚙",
+            "pdf" => "📕",
+            "txt" | "md" | "log" | "csv" | "json" | "toml" | "xml" | "rs" | "py" | "js" | "ts" | "c" | "h" | "cpp" => "📝",
+            _ => "📄",
+        }
+    }
+}
+
+/// Paths named by the clip's CF_HDROP, falling back to its uri-list.
+pub fn clip_paths(payloads: &[ClipboardPayload]) -> Option<Vec<String>> {
+    if let Some(hdrop) = payloads.iter().find(|p| p.format_id == CF_HDROP) {
+        return dropfiles_paths(&hdrop.data);
+    }
+    payloads
+        .iter()
+        .find(|p| p.format_name == URI_LIST)
+        .map(|p| formats::uri_list_to_paths(&String::from_utf8_lossy(&p.data)))
+}
+
+pub fn describe(paths: &[String], snapshotted: &HashSet<String>) -> Vec<FileEntry> {
+    paths
+        .iter()
+        .map(|path| {
+            let meta = fs::metadata(path).ok();
+            FileEntry {
+                path: path.clone(),
+                size: meta.as_ref().filter(|m| m.is_file()).map(|m| m.len()),
+                is_dir: meta.as_ref().is_some_and(|m| m.is_dir()),
+                exists: meta.is_some(),
+                snapshotted: snapshotted.contains(path),
+            }
+        })
+        .collect()
+}
+
+pub fn format_size(bytes: u64) -> String {
+    const UNITS: [&str; 4] = ["B", "KB", "MB", "GB"];
+    let mut value = bytes as f64;
+    let mut unit = 0;
+    while value >= 1024.0 && unit + 1 < UNITS.len() {
+        value /= 1024.0;
+        unit += 1;
+    }
+    if unit == 0 {
+        format!("{} B", bytes)
+    } else {
+        format!("{:.1} {}", value, UNITS[unit])
+    }
+}
+
+/// Contents of every regular file in `paths` no larger than `limit` bytes.
+pub fn read_snapshots(paths: &[String], limit: u64) -> Vec<(String, Vec<u8>)> {
+    paths
+        .iter()
+        .filter(|p| fs::metadata(p).is_ok_and(|m| m.is_file() && m.len() <= limit))
+        .filter_map(|p| match fs::read(p) {
+            Ok(data) => Some((p.clone(), data)),
+            Err(e) => {
+                eprintln!("snapshot {}: {}", p, e);
+                None
+            }
+        })
+        .collect()
+}
+
+/// Points the clip's file list at files that exist. Paths that are gone are
+/// replaced by their snapshot, written out under `restore_dir`, or dropped if
+/// there is none. CF_HDROP and the uri-list are rebuilt from the result.
+pub fn rebuild(
+    payloads: Vec<ClipboardPayload>,
+    snapshots: Vec<(String, Vec<u8>)>,
+    restore_dir: &Path,
+) -> Vec<ClipboardPayload> {
+    let Some(paths) = clip_paths(&payloads) else { return payloads };
+    let mut resolved = Vec::with_capacity(paths.len());
+    for (i, path) in paths.into_iter().enumerate() {
+        if Path::new(&path).exists() {
+            resolved.push(path);
+            continue;
+        }
+        match snapshots.iter().find(|(p, _)| *p == path) {
+            Some((_, data)) => match restore_snapshot(restore_dir, i, &path, data) {
+                Ok(restored) => resolved.push(restored.to_string_lossy().into_owned()),
+                Err(e) => eprintln!("restore {}: {}", path, e),
+            },
+            None => eprintln!("restore: {} no longer exists", path),
+        }
+    }
+
+    payloads
+        .into_iter()
+        .filter_map(|mut p| {
+            if p.format_id == CF_HDROP {
+                p.data = formats::build_dropfiles(&resolved);
+            } else if p.format_name == URI_LIST {
+                p.data = formats::paths_to_uri_list(&resolved).into_bytes();
+            } else {
+                return Some(p);
+            }
+            (!resolved.is_empty()).then_some(p)
+        })
+        .collect()
+}
+
+/// Each file gets its own numbered folder so two originals with the same name
+/// don't overwrite each other.
+fn restore_snapshot(restore_dir: &Path, index: usize, path: &str, data: &[u8]) -> std::io::Result<PathBuf> {
+    let name = path.rsplit(['\\', '/']).find(|s| !s.is_empty()).unwrap_or("file");
+    let dir = restore_dir.join(index.to_string());
+    fs::create_dir_all(&dir)?;
+    let target = dir.join(name);
+    fs::write(&target, data)?;
+    Ok(target)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hdrop(paths: &[String]) -> ClipboardPayload {
+        ClipboardPayload { format_id: CF_HDROP, format_name: "CF_HDROP".to_string(), data: formats::build_dropfiles(paths) }
+    }
+
+    #[test]
+    fn describes_paths() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path();
+        let file = dir.join("notes.txt");
+        fs::write(&file, b"hello").unwrap();
+        let paths = vec![
+            file.to_string_lossy().into_owned(),
+            dir.to_string_lossy().into_owned(),
+            dir.join("gone.png").to_string_lossy().into_owned(),
+        ];
+        let snapshotted = HashSet::from([paths[0].clone()]);
+        let entries = describe(&paths, &snapshotted);
+
+        assert_eq!((entries[0].size, entries[0].exists, entries[0].snapshotted), (Some(5), true, true));
+        assert_eq!((entries[0].name(), entries[0].icon()), ("notes.txt", "📝"));
+        assert!(entries[1].is_dir && entries[1].size.is_none());
+        assert_eq!(entries[1].icon(), "📁");
+        assert!(!entries[2].exists);
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn snapshots_respect_the_limit() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path();
+        let small = dir.join("small.bin");
+        let large = dir.join("large.bin");
+        fs::write(&small, [1u8; 10]).unwrap();
+        fs::write(&large, [2u8; 100]).unwrap();
+        let paths: Vec<String> = [small.as_path(), large.as_path(), dir].iter().map(|p| p.to_string_lossy().into_owned()).collect();
+        let snaps = read_snapshots(&paths, 50);
+        assert_eq!(snaps, vec![(paths[0].clone(), vec![1u8; 10])]);
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn rebuild_swaps_in_snapshots() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path();
+        let kept = dir.join("kept.txt");
+        fs::write(&kept, b"still here").unwrap();
+        let kept = kept.to_string_lossy().into_owned();
+        let moved = dir.join("moved.txt").to_string_lossy().into_owned();
+        let lost = dir.join("lost.txt").to_string_lossy().into_owned();
+        let paths = vec![kept.clone(), moved.clone(), lost];
+        let payloads = vec![
+            hdrop(&paths),
+            ClipboardPayload { format_id: 0, format_name: URI_LIST.to_string(), data: formats::paths_to_uri_list(&paths).into_bytes() },
+        ];
+
+        let restore_dir = dir.join("restored");
+        let rebuilt = rebuild(payloads, vec![(moved, b"snapshot".to_vec())], &restore_dir);
+        let restored = restore_dir.join("1").join("moved.txt");
+        assert_eq!(fs::read(&restored).unwrap(), b"snapshot");
+
+        let expected = vec![kept, restored.to_string_lossy().into_owned()];
+        assert_eq!(clip_paths(&rebuilt[..1]), Some(expected.clone()));
+        assert_eq!(formats::uri_list_to_paths(&String::from_utf8_lossy(&rebuilt[1].data)), expected);
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn rebuild_drops_empty_lists() {
+        let gone = vec!["/definitely/not/here.txt".to_string()];
+        let text = ClipboardPayload { format_id: 13, format_name: "CF_UNICODETEXT".to_string(), data: vec![0, 0] };
+        let rebuilt = rebuild(vec![hdrop(&gone), text], Vec::new(), Path::new("/unused"));
+        assert_eq!(rebuilt.len(), 1);
+        assert_eq!(rebuilt[0].format_id, 13);
+    }
+
+    #[test]
+    fn sizes() {
+        assert_eq!(format_size(512), "512 B");
+        assert_eq!(format_size(1536), "1.5 KB");
+        assert_eq!(format_size(5 << 20), "5.0 MB");
+    }
+}
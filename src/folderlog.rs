@@ -0,0 +1,623 @@
+//! Sync through a shared folder instead of a server, for a folder that
+//! Syncthing, Nextcloud or a network share already keeps the same on every
+//! device. Each device appends records to its own log under
+//! `<folder>/<device>/`, split into numbered segment files, and only ever
+//! reads the others'. Replaying every log into a local index gives the same
+//! change feed, blobs and keyring an openclip-sync server would.
+//!
+//! ```text
+//! <folder>/<device>/00000001.seg    record, record, ...
+//! record = u32 header length | JSON header | u64 body length | body | blake3(header, body)
+//! ```
+//!
+//! A log holds what a server would: opaque clip ids, sealed metas and blobs,
+//! the locked keyring, and times. A record that's cut short or doesn't check
+//! out, as when the sync tool is still copying the segment, ends that log for
+//! now and is read again next time. Once a later segment shows up the writer
+//! has moved on, so the damaged rest of the earlier one is skipped.
+//!
+//! Logs are replayed one after another, so an edit can turn up before the
+//! upload it applies to. Such edits wait in the index and are replayed right
+//! after the upload. The keyring can't be compared-and-swapped across logs:
+//! when two devices replace the same version while apart, the one with the
+//! larger device id wins everywhere. The other device notices on its next
+//! sync that its uploads were sealed with keys it no longer has and pushes
+//! them again.
+
+use rusqlite::{params, Connection, OptionalExtension};
+use serde::{Deserialize, Serialize};
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufReader, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+use openclip_sync::protocol::{self, Change, Changes};
+
+use crate::cloudstorage::{Remote, SyncError};
+
+/// A device starts a new segment once its current one is this big.
+const SEGMENT_SIZE: u64 = 16 << 20;
+/// Longest record header; anything longer is damage.
+const MAX_HEADER_SIZE: u32 = 1 << 20;
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum Op {
+    Upload,
+    Delete,
+    Meta,
+    Keyring,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct Header {
+    op: Op,
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    id: String,
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    meta: String,
+    /// Unix seconds when the record was written.
+    at: i64,
+    /// The keyring version a keyring record makes.
+    #[serde(default, skip_serializing_if = "is_zero")]
+    version: u64,
+}
+
+fn is_zero(n: &u64) -> bool {
+    *n == 0
+}
+
+/// A record read back from a segment; the body stays on disk.
+struct Record {
+    /// None for a record this version doesn't understand.
+    header: Option<Header>,
+    body_offset: u64,
+    body_len: u64,
+    body_hash: String,
+    end: u64,
+}
+
+/// A shared folder standing in for the server. `device` names the log this
+/// device writes; the device passed to each call is the same id.
+pub struct FolderRemote {
+    folder: PathBuf,
+    device: String,
+    conn: Connection,
+}
+
+impl FolderRemote {
+    /// `index` is a local file, outside the shared folder, that keeps what's
+    /// been read from the logs so far.
+    pub fn open(folder: &Path, index: &Path, device: &str) -> Result<Self, SyncError> {
+        let conn = Connection::open(index)?;
+        conn.execute_batch(
+            "CREATE TABLE IF NOT EXISTS settings (key TEXT PRIMARY KEY, value TEXT NOT NULL);
+            CREATE TABLE IF NOT EXISTS logs (
+                device TEXT PRIMARY KEY,
+                segment INTEGER NOT NULL,
+                offset INTEGER NOT NULL
+            );
+            CREATE TABLE IF NOT EXISTS clips (
+                id TEXT PRIMARY KEY,
+                seq INTEGER NOT NULL,
+                size INTEGER NOT NULL,
+                meta TEXT NOT NULL,
+                uploaded_at INTEGER NOT NULL,
+                device TEXT NOT NULL,
+                deleted INTEGER NOT NULL,
+                blob_device TEXT NOT NULL,
+                blob_segment INTEGER NOT NULL,
+                blob_offset INTEGER NOT NULL,
+                blob_hash TEXT NOT NULL
+            );
+            CREATE INDEX IF NOT EXISTS idx_clips_seq ON clips(seq);
+            CREATE TABLE IF NOT EXISTS waiting (
+                n INTEGER PRIMARY KEY AUTOINCREMENT,
+                id TEXT NOT NULL,
+                op TEXT NOT NULL,
+                meta TEXT NOT NULL,
+                at INTEGER NOT NULL,
+                device TEXT NOT NULL
+            );
+            CREATE TABLE IF NOT EXISTS keyring (
+                version INTEGER NOT NULL,
+                device TEXT NOT NULL,
+                data BLOB NOT NULL
+            );",
+        )?;
+        // Sequence numbers start at the time the index is made, so one built
+        // again from scratch carries on past the cursor cloud.db kept.
+        conn.execute(
+            "INSERT OR IGNORE INTO settings (key, value) VALUES ('seq', ?)",
+            [chrono::Utc::now().timestamp_millis().to_string()],
+        )?;
+        let folder_name = folder.to_string_lossy();
+        let indexed: Option<String> = conn
+            .query_row("SELECT value FROM settings WHERE key = 'folder'", [], |r| r.get(0))
+            .optional()?;
+        if indexed.as_deref() != Some(&folder_name) {
+            // Another folder, another set of logs: read them all again
+            conn.execute_batch("DELETE FROM logs; DELETE FROM clips; DELETE FROM waiting; DELETE FROM keyring;")?;
+            conn.execute("INSERT OR REPLACE INTO settings (key, value) VALUES ('folder', ?)", [&folder_name])?;
+        }
+        Ok(FolderRemote { folder: folder.to_path_buf(), device: device.to_string(), conn })
+    }
+
+    fn segment_path(&self, device: &str, segment: u64) -> PathBuf {
+        self.folder.join(device).join(format!("{:08}.seg", segment))
+    }
+
+    /// Reads whatever the devices added to their logs since last time.
+    fn refresh(&self) -> Result<(), SyncError> {
+        let entries = fs::read_dir(&self.folder).map_err(|e| unreachable(&self.folder, e))?;
+        let mut devices: Vec<String> = entries
+            .filter_map(|entry| entry.ok())
+            .filter(|entry| entry.path().is_dir())
+            .filter_map(|entry| entry.file_name().into_string().ok())
+            // Leaves out the sync tool's own folders, like .stfolder
+            .filter(|name| protocol::valid_id(name))
+            .collect();
+        devices.sort();
+        for device in devices {
+            self.read_log(&device)?;
+        }
+        Ok(())
+    }
+
+    /// Where reading `device`'s log left off: the segment, and the offset of its next record.
+    fn progress(&self, device: &str) -> Result<(u64, u64), SyncError> {
+        let progress = self
+            .conn
+            .query_row("SELECT segment, offset FROM logs WHERE device = ?", [device], |r| Ok((r.get(0)?, r.get(1)?)))
+            .optional()?;
+        Ok(progress.unwrap_or((0, 0)))
+    }
+
+    fn read_log(&self, device: &str) -> Result<(), SyncError> {
+        let (mut segment, mut offset) = self.progress(device)?;
+        for n in segments(&self.folder.join(device))? {
+            if n < segment {
+                continue;
+            }
+            if n > segment {
+                (segment, offset) = (n, 0);
+            }
+            let path = self.segment_path(device, segment);
+            let Ok(file) = File::open(&path) else { continue };
+            let mut reader = BufReader::new(file);
+            reader.seek(SeekFrom::Start(offset)).map_err(|e| unreachable(&path, e))?;
+            while let Some(record) = read_record(&mut reader, offset)? {
+                let tx = self.conn.unchecked_transaction()?;
+                if let Some(header) = &record.header {
+                    self.apply(device, segment, header, &record)?;
+                }
+                offset = record.end;
+                tx.execute(
+                    "INSERT INTO logs (device, segment, offset) VALUES (?1, ?2, ?3)
+                     ON CONFLICT(device) DO UPDATE SET segment = excluded.segment, offset = excluded.offset",
+                    params![device, segment, offset],
+                )?;
+                tx.commit()?;
+            }
+        }
+        Ok(())
+    }
+
+    fn next_seq(&self) -> Result<i64, SyncError> {
+        let seq = self
+            .conn
+            .query_row("UPDATE settings SET value = value + 1 WHERE key = 'seq' RETURNING CAST(value AS INTEGER)", [], |r| r.get(0))?;
+        Ok(seq)
+    }
+
+    fn apply(&self, device: &str, segment: u64, header: &Header, record: &Record) -> Result<(), SyncError> {
+        match header.op {
+            // Replaces a tombstone, bringing the clip back, like the server
+            Op::Upload => {
+                self.conn.execute(
+                    "INSERT INTO clips (id, seq, size, meta, uploaded_at, device, deleted, blob_device, blob_segment, blob_offset, blob_hash)
+                     VALUES (?1, ?2, ?3, ?4, ?5, ?6, 0, ?6, ?7, ?8, ?9)
+                     ON CONFLICT(id) DO UPDATE SET
+                        seq = excluded.seq, size = excluded.size, meta = excluded.meta, uploaded_at = excluded.uploaded_at,
+                        device = excluded.device, deleted = 0, blob_device = excluded.blob_device,
+                        blob_segment = excluded.blob_segment, blob_offset = excluded.blob_offset, blob_hash = excluded.blob_hash",
+                    params![
+                        header.id,
+                        self.next_seq()?,
+                        record.body_len,
+                        header.meta,
+                        header.at,
+                        device,
+                        segment,
+                        record.body_offset,
+                        record.body_hash
+                    ],
+                )?;
+                let waiting = {
+                    let mut stmt = self.conn.prepare("SELECT op, meta, at, device FROM waiting WHERE id = ? ORDER BY n")?;
+                    let rows = stmt.query_map([&header.id], |r| Ok((r.get::<_, String>(0)?, r.get(1)?, r.get(2)?, r.get::<_, String>(3)?)))?;
+                    rows.collect::<rusqlite::Result<Vec<(String, String, i64, String)>>>()?
+                };
+                self.conn.execute("DELETE FROM waiting WHERE id = ?", [&header.id])?;
+                for (op, meta, at, from) in waiting {
+                    let op = if op == "delete" { Op::Delete } else { Op::Meta };
+                    self.edit(&from, &Header { op, id: header.id.clone(), meta, at, version: 0 })?;
+                }
+            }
+            Op::Delete | Op::Meta => {
+                if !self.edit(device, header)? {
+                    let op = if header.op == Op::Delete { "delete" } else { "meta" };
+                    self.conn.execute(
+                        "INSERT INTO waiting (id, op, meta, at, device) VALUES (?, ?, ?, ?, ?)",
+                        params![header.id, op, header.meta, header.at, device],
+                    )?;
+                }
+            }
+            Op::Keyring if record.body_len <= protocol::MAX_KEYRING_SIZE => {
+                let current: Option<(u64, String)> = self
+                    .conn
+                    .query_row("SELECT version, device FROM keyring", [], |r| Ok((r.get(0)?, r.get(1)?)))
+                    .optional()?;
+                if current.is_none_or(|(version, from)| (header.version, device) > (version, from.as_str())) {
+                    let data = read_body(&self.segment_path(device, segment), record.body_offset, record.body_len)?;
+                    self.conn.execute("DELETE FROM keyring", [])?;
+                    self.conn.execute(
+                        "INSERT INTO keyring (version, device, data) VALUES (?, ?, ?)",
+                        params![header.version, device, data],
+                    )?;
+                }
+            }
+            Op::Keyring => {}
+        }
+        Ok(())
+    }
+
+    /// Applies a deletion or new meta. False when the clip hasn't been read yet.
+    fn edit(&self, device: &str, header: &Header) -> Result<bool, SyncError> {
+        let deleted: Option<bool> = self
+            .conn
+            .query_row("SELECT deleted FROM clips WHERE id = ?", [&header.id], |r| r.get(0))
+            .optional()?;
+        match (header.op, deleted) {
+            (_, None) => return Ok(false),
+            // Deleting a tombstone does nothing
+            (Op::Delete, Some(true)) => {}
+            (Op::Delete, Some(false)) => {
+                self.conn.execute(
+                    "UPDATE clips SET seq = ?, size = 0, meta = '', blob_hash = '', uploaded_at = ?, device = ?, deleted = 1 WHERE id = ?",
+                    params![self.next_seq()?, header.at, device, header.id],
+                )?;
+            }
+            _ => {
+                self.conn.execute(
+                    "UPDATE clips SET seq = ?, meta = ?, uploaded_at = ?, device = ? WHERE id = ?",
+                    params![self.next_seq()?, header.meta, header.at, device, header.id],
+                )?;
+            }
+        }
+        Ok(true)
+    }
+
+    /// Adds a record to the end of this device's log, and reads it back.
+    fn append(&self, header: &Header, body: &[u8]) -> Result<(), SyncError> {
+        self.read_log(&self.device)?;
+        let dir = self.folder.join(&self.device);
+        fs::create_dir_all(&dir).map_err(|e| unreachable(&dir, e))?;
+        let (read, offset) = self.progress(&self.device)?;
+        let last = segments(&dir)?.last().copied().unwrap_or(0);
+        let length = fs::metadata(self.segment_path(&self.device, last)).map_or(0, |m| m.len());
+        // A segment that's full, or ends in a write cut off by a crash, is done with
+        let segment = if last > 0 && last == read && length == offset && length < SEGMENT_SIZE { last } else { last + 1 };
+
+        let header = serde_json::to_vec(header).map_err(|e| SyncError::Corrupt(e.to_string()))?;
+        let mut record = Vec::with_capacity(header.len() + body.len() + 44);
+        record.extend_from_slice(&(header.len() as u32).to_le_bytes());
+        record.extend_from_slice(&header);
+        record.extend_from_slice(&(body.len() as u64).to_le_bytes());
+        record.extend_from_slice(body);
+        let mut check = blake3::Hasher::new();
+        check.update(&header).update(body);
+        record.extend_from_slice(check.finalize().as_bytes());
+
+        let path = self.segment_path(&self.device, segment);
+        let mut file = OpenOptions::new().create(true).append(true).open(&path).map_err(|e| unreachable(&path, e))?;
+        file.write_all(&record).and_then(|()| file.sync_all()).map_err(|e| unreachable(&path, e))?;
+        self.read_log(&self.device)
+    }
+}
+
+impl Remote for FolderRemote {
+    fn upload(&self, id: &str, meta: &str, blob: &[u8], _device: &str) -> Result<(), SyncError> {
+        if blob.len() as u64 > protocol::MAX_CLIP_SIZE {
+            return Err(SyncError::Server(413, format!("clip is larger than {} bytes", protocol::MAX_CLIP_SIZE)));
+        }
+        if meta.len() as u64 > protocol::MAX_META_SIZE {
+            return Err(SyncError::Server(413, format!("meta is limited to {} bytes", protocol::MAX_META_SIZE)));
+        }
+        self.append(&Header { op: Op::Upload, id: id.to_string(), meta: meta.to_string(), at: now(), version: 0 }, blob)
+    }
+
+    fn changes(&self, since: i64) -> Result<Changes, SyncError> {
+        self.refresh()?;
+        let limit = protocol::MAX_CHANGES;
+        let mut stmt = self
+            .conn
+            .prepare("SELECT seq, id, size, meta, uploaded_at, device, deleted FROM clips WHERE seq > ? ORDER BY seq LIMIT ?")?;
+        // One extra row tells whether there's another page
+        let mut changes = stmt
+            .query_map(params![since, limit as i64 + 1], |r| {
+                Ok(Change {
+                    seq: r.get(0)?,
+                    id: r.get(1)?,
+                    size: r.get(2)?,
+                    meta: r.get(3)?,
+                    uploaded_at: r.get(4)?,
+                    device: r.get(5)?,
+                    deleted: r.get(6)?,
+                })
+            })?
+            .collect::<rusqlite::Result<Vec<_>>>()?;
+        let more = changes.len() > limit;
+        changes.truncate(limit);
+        let next = changes.last().map_or(since, |c| c.seq);
+        Ok(Changes { changes, next, more })
+    }
+
+    fn download(&self, id: &str) -> Result<Vec<u8>, SyncError> {
+        let found: Option<(String, u64, u64, u64, String)> = self
+            .conn
+            .query_row(
+                "SELECT blob_device, blob_segment, blob_offset, size, blob_hash FROM clips WHERE id = ? AND deleted = 0",
+                [id],
+                |r| Ok((r.get(0)?, r.get(1)?, r.get(2)?, r.get(3)?, r.get(4)?)),
+            )
+            .optional()?;
+        let Some((device, segment, offset, size, hash)) = found else {
+            return Err(SyncError::Server(404, "not found".to_string()));
+        };
+        let blob = read_body(&self.segment_path(&device, segment), offset, size)?;
+        if blake3::hash(&blob).to_hex().as_str() != hash {
+            return Err(SyncError::Corrupt(format!("{}'s log changed under clip {}", device, id)));
+        }
+        Ok(blob)
+    }
+
+    fn delete(&self, id: &str, _device: &str) -> Result<(), SyncError> {
+        let live = self
+            .conn
+            .query_row("SELECT 1 FROM clips WHERE id = ? AND deleted = 0", [id], |_| Ok(()))
+            .optional()?;
+        // Deleting an unknown or already deleted clip does nothing
+        if live.is_none() {
+            return Ok(());
+        }
+        self.append(&Header { op: Op::Delete, id: id.to_string(), meta: String::new(), at: now(), version: 0 }, &[])
+    }
+
+    fn put_meta(&self, id: &str, meta: &str, _device: &str) -> Result<(), SyncError> {
+        if meta.len() as u64 > protocol::MAX_META_SIZE {
+            return Err(SyncError::Server(413, format!("meta is limited to {} bytes", protocol::MAX_META_SIZE)));
+        }
+        let known = self.conn.query_row("SELECT 1 FROM clips WHERE id = ?", [id], |_| Ok(())).optional()?;
+        if known.is_none() {
+            return Err(SyncError::Server(404, "not found".to_string()));
+        }
+        self.append(&Header { op: Op::Meta, id: id.to_string(), meta: meta.to_string(), at: now(), version: 0 }, &[])
+    }
+
+    fn keyring(&self) -> Result<Option<(u64, Vec<u8>)>, SyncError> {
+        self.refresh()?;
+        let keyring = self
+            .conn
+            .query_row("SELECT version, data FROM keyring", [], |r| Ok((r.get(0)?, r.get(1)?)))
+            .optional()?;
+        Ok(keyring)
+    }
+
+    fn put_keyring(&self, replaces: u64, keyring: &[u8]) -> Result<u64, SyncError> {
+        if keyring.len() as u64 > protocol::MAX_KEYRING_SIZE {
+            return Err(SyncError::Server(413, format!("keyring is larger than {} bytes", protocol::MAX_KEYRING_SIZE)));
+        }
+        self.refresh()?;
+        let current: u64 = self.conn.query_row("SELECT version FROM keyring", [], |r| r.get(0)).optional()?.unwrap_or(0);
+        if current != replaces {
+            return Err(SyncError::Server(409, format!("keyring is at version {}", current)));
+        }
+        let version = current + 1;
+        self.append(&Header { op: Op::Keyring, id: String::new(), meta: String::new(), at: now(), version }, keyring)?;
+        Ok(version)
+    }
+}
+
+/// A folder that can't be read is like a server that can't be reached:
+/// the share may be offline for now.
+fn unreachable(path: &Path, e: io::Error) -> SyncError {
+    SyncError::Offline(format!("{}: {}", path.display(), e))
+}
+
+/// The segment numbers in a device's directory, in order.
+fn segments(dir: &Path) -> Result<Vec<u64>, SyncError> {
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(unreachable(dir, e)),
+    };
+    // Skips temporary and conflict copies the sync tool leaves next to them
+    let mut segments: Vec<u64> = entries
+        .filter_map(|entry| entry.ok()?.file_name().into_string().ok())
+        .filter_map(|name| name.strip_suffix(".seg")?.parse().ok())
+        .collect();
+    segments.sort_unstable();
+    Ok(segments)
+}
+
+/// Fills `buf`, or returns false at the end of what's there so far.
+fn read_full(reader: &mut impl Read, buf: &mut [u8]) -> Result<bool, SyncError> {
+    match reader.read_exact(buf) {
+        Ok(()) => Ok(true),
+        Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => Ok(false),
+        Err(e) => Err(SyncError::Offline(e.to_string())),
+    }
+}
+
+/// The record at `offset`, or None if it's incomplete or damaged.
+fn read_record(reader: &mut impl Read, offset: u64) -> Result<Option<Record>, SyncError> {
+    let mut length = [0u8; 4];
+    if !read_full(reader, &mut length)? {
+        return Ok(None);
+    }
+    let header_len = u32::from_le_bytes(length);
+    if header_len > MAX_HEADER_SIZE {
+        return Ok(None);
+    }
+    let mut header = vec![0; header_len as usize];
+    let mut length = [0u8; 8];
+    if !read_full(reader, &mut header)? || !read_full(reader, &mut length)? {
+        return Ok(None);
+    }
+    let body_len = u64::from_le_bytes(length);
+    if body_len > protocol::MAX_CLIP_SIZE {
+        return Ok(None);
+    }
+
+    let mut check = blake3::Hasher::new();
+    check.update(&header);
+    let mut body_hash = blake3::Hasher::new();
+    let mut chunk = vec![0; 64 << 10];
+    let mut left = body_len;
+    while left > 0 {
+        let n = left.min(chunk.len() as u64) as usize;
+        if !read_full(reader, &mut chunk[..n])? {
+            return Ok(None);
+        }
+        check.update(&chunk[..n]);
+        body_hash.update(&chunk[..n]);
+        left -= n as u64;
+    }
+    let mut sum = [0u8; 32];
+    if !read_full(reader, &mut sum)? || check.finalize() != blake3::Hash::from(sum) {
+        return Ok(None);
+    }
+
+    let body_offset = offset + 4 + header_len as u64 + 8;
+    Ok(Some(Record {
+        header: serde_json::from_slice(&header).ok(),
+        body_offset,
+        body_len,
+        body_hash: body_hash.finalize().to_hex().to_string(),
+        end: body_offset + body_len + 32,
+    }))
+}
+
+fn read_body(path: &Path, offset: u64, len: u64) -> Result<Vec<u8>, SyncError> {
+    let mut file = File::open(path).map_err(|e| unreachable(path, e))?;
+    file.seek(SeekFrom::Start(offset)).map_err(|e| unreachable(path, e))?;
+    let mut body = vec![0; len as usize];
+    file.read_exact(&mut body).map_err(|e| unreachable(path, e))?;
+    Ok(body)
+}
+
+fn now() -> i64 {
+    chrono::Utc::now().timestamp()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn open(dir: &Path, device: &str) -> FolderRemote {
+        FolderRemote::open(&dir.join("shared"), &dir.join(format!("{}.db", device)), device).unwrap()
+    }
+
+    #[test]
+    fn feed_downloads_and_tombstones() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path();
+        fs::create_dir_all(dir.join("shared")).unwrap();
+        let (laptop, desktop) = (open(dir, "aa"), open(dir, "bb"));
+        laptop.upload("01", "meta 1", b"first", "aa").unwrap();
+        laptop.upload("02", "meta 2", b"second", "aa").unwrap();
+        desktop.delete("01", "bb").unwrap();
+        assert!(matches!(desktop.put_meta("01", "new", "bb"), Err(SyncError::Server(404, _))));
+
+        let feed = desktop.changes(0).unwrap();
+        assert_eq!(feed.changes.iter().map(|c| (c.id.as_str(), c.device.as_str())).collect::<Vec<_>>(), [("01", "aa"), ("02", "aa")]);
+        assert_eq!(desktop.download("02").unwrap(), b"second");
+        desktop.delete("01", "bb").unwrap();
+        desktop.put_meta("01", "state", "bb").unwrap();
+        let feed = desktop.changes(feed.next).unwrap();
+        assert_eq!(feed.changes.len(), 1);
+        assert!(feed.changes[0].deleted);
+        assert_eq!(feed.changes[0].meta, "state");
+        assert!(matches!(desktop.download("01"), Err(SyncError::Server(404, _))));
+
+        // The laptop reads the desktop's log, and uploading again brings the clip back
+        assert_eq!(laptop.changes(0).unwrap().changes.len(), 2);
+        assert!(laptop.changes(0).unwrap().changes.iter().any(|c| c.id == "01" && c.deleted));
+        laptop.upload("01", "meta 1", b"first", "aa").unwrap();
+        assert!(!desktop.changes(feed.next).unwrap().changes[0].deleted);
+        assert_eq!(desktop.download("01").unwrap(), b"first");
+    }
+
+    #[test]
+    fn edits_wait_for_their_upload() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path();
+        fs::create_dir_all(dir.join("shared")).unwrap();
+        let (aa, bb, cc) = (open(dir, "aa"), open(dir, "bb"), open(dir, "cc"));
+        bb.upload("01", "from bb", b"clip", "bb").unwrap();
+        aa.changes(0).unwrap();
+        aa.put_meta("01", "edited on aa", "aa").unwrap();
+        // A newcomer reads aa's log before bb's
+        let feed = cc.changes(0).unwrap();
+        assert_eq!(feed.changes.len(), 1);
+        assert_eq!((feed.changes[0].meta.as_str(), feed.changes[0].device.as_str()), ("edited on aa", "aa"));
+    }
+
+    #[test]
+    fn torn_records_wait() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path();
+        fs::create_dir_all(dir.join("shared")).unwrap();
+        let (laptop, desktop) = (open(dir, "aa"), open(dir, "bb"));
+        laptop.upload("01", "meta", b"first", "aa").unwrap();
+        laptop.upload("02", "meta", b"second", "aa").unwrap();
+        let segment = laptop.segment_path("aa", 1);
+        let whole = fs::read(&segment).unwrap();
+
+        // Half of the second record has arrived
+        fs::write(&segment, &whole[..whole.len() - 10]).unwrap();
+        assert_eq!(desktop.changes(0).unwrap().changes.len(), 1);
+        fs::write(&segment, &whole).unwrap();
+        assert_eq!(desktop.changes(0).unwrap().changes.len(), 2);
+
+        // A flipped bit is damage too
+        let mut damaged = whole.clone();
+        let last = damaged.len() - 40;
+        damaged[last] ^= 1;
+        fs::write(&segment, &damaged).unwrap();
+        let fresh = open(dir, "cc");
+        assert_eq!(fresh.changes(0).unwrap().changes.len(), 1);
+
+        // After a write cut off by a crash the laptop starts a new segment,
+        // and readers skip the rest of the old one
+        fs::write(&segment, &whole[..whole.len() - 10]).unwrap();
+        laptop.upload("03", "meta", b"third", "aa").unwrap();
+        let ids: Vec<String> = fresh.changes(0).unwrap().changes.into_iter().map(|c| c.id).collect();
+        assert_eq!(ids, ["01", "03"]);
+        assert!(laptop.segment_path("aa", 2).exists());
+    }
+
+    #[test]
+    fn keyring_versions() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path();
+        fs::create_dir_all(dir.join("shared")).unwrap();
+        let (aa, bb) = (open(dir, "aa"), open(dir, "bb"));
+        assert_eq!(aa.keyring().unwrap(), None);
+        assert_eq!(aa.put_keyring(0, b"one").unwrap(), 1);
+        assert!(matches!(bb.put_keyring(0, b"other"), Err(SyncError::Server(409, _))));
+        assert_eq!(bb.put_keyring(1, b"two").unwrap(), 2);
+        assert_eq!(aa.keyring().unwrap(), Some((2, b"two".to_vec())));
+        assert!(matches!(FolderRemote::open(&dir.join("missing"), &dir.join("x.db"), "aa").unwrap().keyring(), Err(SyncError::Offline(_))));
+    }
+}
@@ -0,0 +1,461 @@
+//! Registry of the formats a clip can travel in, mapping each Win32 clipboard
+//! format to the MIME type other platforms use for the same content.
+//!
+//! Clips are stored the way they were captured. Restoring on Windows fills in
+//! any Win32 format a clip only has as MIME, and pushing to cloud.db adds the
+//! MIME forms so other machines can read the clip.
+
+use std::io::Cursor;
+
+use image::{ImageFormat, RgbaImage};
+
+use crate::codecs::encode_bitmap;
+use crate::imaging::{decode_clip_image, CF_DIB};
+use crate::inspect::dropfiles_paths;
+use crate::models::ClipboardPayload;
+use crate::richtext::{self, Block};
+
+pub const CF_UNICODETEXT: u32 = 13;
+pub const CF_HDROP: u32 = 15;
+pub const HTML_FORMAT: &str = "HTML Format";
+pub const RTF_FORMAT: &str = "Rich Text Format";
+
+/// Content kinds that have a counterpart on both sides.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Canonical {
+    Text,
+    Html,
+    Image,
+    Files,
+}
+
+impl Canonical {
+    pub fn all() -> [Canonical; 4] {
+        [Canonical::Text, Canonical::Html, Canonical::Image, Canonical::Files]
+    }
+
+    pub fn mime(self) -> &'static str {
+        match self {
+            Canonical::Text => "text/plain;charset=utf-8",
+            Canonical::Html => "text/html",
+            Canonical::Image => "image/png",
+            Canonical::Files => "text/uri-list",
+        }
+    }
+
+    /// The Win32 format written on restore. Registered formats have no fixed
+    /// id, so they carry 0 and are resolved by name when set.
+    pub fn win32(self) -> (u32, &'static str) {
+        match self {
+            Canonical::Text => (CF_UNICODETEXT, "CF_UNICODETEXT"),
+            Canonical::Html => (0, HTML_FORMAT),
+            Canonical::Image => (CF_DIB, "CF_DIB"),
+            Canonical::Files => (CF_HDROP, "CF_HDROP"),
+        }
+    }
+
+    fn has_win32(self, payloads: &[ClipboardPayload]) -> bool {
+        payloads.iter().any(|p| match self {
+            Canonical::Text => matches!(p.format_id, 1 | CF_UNICODETEXT),
+            Canonical::Html => p.format_name == HTML_FORMAT,
+            Canonical::Image => matches!(p.format_id, 2 | 8 | 17) || p.format_name == "PNG",
+            Canonical::Files => p.format_id == CF_HDROP,
+        })
+    }
+
+    fn mime_payload(self, payloads: &[ClipboardPayload]) -> Option<&ClipboardPayload> {
+        payloads.iter().find(|p| match self {
+            // Charset parameters vary between toolkits
+            Canonical::Text => p.format_name.starts_with("text/plain"),
+            _ => p.format_name == self.mime(),
+        })
+    }
+
+    /// Converts the clip's Win32 form of this content to MIME bytes.
+    fn export(self, payloads: &[ClipboardPayload]) -> Option<Vec<u8>> {
+        let by_id = |id: u32| payloads.iter().find(|p| p.format_id == id);
+        match self {
+            Canonical::Text => by_id(CF_UNICODETEXT)
+                .map(|p| utf16_to_utf8(&p.data))
+                .or_else(|| by_id(1).map(|p| String::from_utf8_lossy(&p.data).trim_end_matches('\0').to_string()))
+                .map(String::into_bytes),
+            Canonical::Html => payloads
+                .iter()
+                .find(|p| p.format_name == HTML_FORMAT)
+                .and_then(|p| unwrap_cf_html(&p.data))
+                .map(String::into_bytes),
+            Canonical::Image => encode_png(&decode_clip_image(payloads)?),
+            Canonical::Files => by_id(CF_HDROP)
+                .and_then(|p| dropfiles_paths(&p.data))
+                .map(|paths| paths_to_uri_list(&paths).into_bytes()),
+        }
+    }
+
+    /// Converts MIME bytes of this content to the Win32 format.
+    fn import(self, data: &[u8]) -> Option<Vec<u8>> {
+        let text = || String::from_utf8_lossy(data).trim_end_matches('\0').to_string();
+        match self {
+            Canonical::Text => Some(utf8_to_utf16(&text())),
+            Canonical::Html => Some(wrap_cf_html(&text())),
+            Canonical::Image => png_to_dib(data),
+            Canonical::Files => {
+                let paths = uri_list_to_paths(&text());
+                (!paths.is_empty()).then(|| build_dropfiles(&paths))
+            }
+        }
+    }
+}
+
+/// MIME payloads for every canonical kind the clip has only in Win32 form.
+pub fn mime_extras(payloads: &[ClipboardPayload]) -> Vec<ClipboardPayload> {
+    Canonical::all()
+        .into_iter()
+        .filter(|kind| kind.mime_payload(payloads).is_none())
+        .filter_map(|kind| {
+            kind.export(payloads).map(|data| ClipboardPayload {
+                format_id: 0,
+                format_name: kind.mime().to_string(),
+                data,
+            })
+        })
+        .collect()
+}
+
+/// Win32 payloads for every canonical kind the clip has only in MIME form.
+pub fn win32_extras(payloads: &[ClipboardPayload]) -> Vec<ClipboardPayload> {
+    Canonical::all()
+        .into_iter()
+        .filter(|kind| !kind.has_win32(payloads))
+        .filter_map(|kind| {
+            let data = kind.import(&kind.mime_payload(payloads)?.data)?;
+            let (format_id, name) = kind.win32();
+            Some(ClipboardPayload { format_id, format_name: name.to_string(), data })
+        })
+        .collect()
+}
+
+/// Which of a clip's formats go back on the clipboard.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RestoreMode {
+    All,
+    /// Only unformatted text, derived from the HTML or RTF if the clip has no
+    /// plain text of its own.
+    PlainText,
+    /// Only HTML and RTF, so the target can't fall back to plain text.
+    RichText,
+}
+
+fn is_plain_text(p: &ClipboardPayload) -> bool {
+    matches!(p.format_id, 1 | 7 | CF_UNICODETEXT) || p.format_name.starts_with("text/plain")
+}
+
+pub fn is_rich_text(p: &ClipboardPayload) -> bool {
+    matches!(p.format_name.as_str(), HTML_FORMAT | "text/html" | RTF_FORMAT | "text/rtf")
+}
+
+/// The HTML or RTF of a clip as preview blocks, HTML first.
+pub fn rich_blocks(payloads: &[ClipboardPayload]) -> Option<Vec<Block>> {
+    let by_name = |name: &str| payloads.iter().find(|p| p.format_name == name);
+    if let Some(html) = by_name(HTML_FORMAT).and_then(|p| unwrap_cf_html(&p.data)) {
+        return Some(richtext::parse_html(&html));
+    }
+    if let Some(html) = by_name("text/html") {
+        return Some(richtext::parse_html(&String::from_utf8_lossy(&html.data)));
+    }
+    by_name(RTF_FORMAT)
+        .or_else(|| by_name("text/rtf"))
+        .map(|rtf| richtext::parse_rtf(&String::from_utf8_lossy(&rtf.data)))
+}
+
+pub fn for_restore(payloads: Vec<ClipboardPayload>, mode: RestoreMode) -> Vec<ClipboardPayload> {
+    match mode {
+        RestoreMode::All => payloads,
+        RestoreMode::PlainText => {
+            if payloads.iter().any(is_plain_text) {
+                return payloads.into_iter().filter(is_plain_text).collect();
+            }
+            match rich_blocks(&payloads) {
+                Some(blocks) => vec![ClipboardPayload {
+                    format_id: CF_UNICODETEXT,
+                    format_name: "CF_UNICODETEXT".to_string(),
+                    data: utf8_to_utf16(&richtext::plain_text(&blocks)),
+                }],
+                None => Vec::new(),
+            }
+        }
+        RestoreMode::RichText => payloads.into_iter().filter(is_rich_text).collect(),
+    }
+}
+
+/// CF_UNICODETEXT bytes (UTF-16LE, NUL-terminated) to a Rust string.
+pub fn utf16_to_utf8(bytes: &[u8]) -> String {
+    let units: Vec<u16> = bytes
+        .chunks_exact(2)
+        .map(|c| u16::from_le_bytes([c[0], c[1]]))
+        .take_while(|&u| u != 0)
+        .collect();
+    String::from_utf16_lossy(&units)
+}
+
+pub fn utf8_to_utf16(text: &str) -> Vec<u8> {
+    text.encode_utf16()
+        .chain(std::iter::once(0))
+        .flat_map(u16::to_le_bytes)
+        .collect()
+}
+
+const CF_HTML_HEADER: &str =
+    "Version:0.9\r\nStartHTML:{sh}\r\nEndHTML:{eh}\r\nStartFragment:{sf}\r\nEndFragment:{ef}\r\n";
+const FRAGMENT_PREFIX: &str = "<html><body>\r\n<!--StartFragment-->";
+const FRAGMENT_SUFFIX: &str = "<!--EndFragment-->\r\n</body></html>";
+
+/// Wraps raw HTML in the CF_HTML envelope, whose header gives byte offsets of
+/// the document and of the fragment inside it.
+pub fn wrap_cf_html(html: &str) -> Vec<u8> {
+    // Every offset is printed as ten digits, so the header length is fixed
+    let header_len = CF_HTML_HEADER.len() - 4 * "{xx}".len() + 4 * 10;
+    let start_fragment = header_len + FRAGMENT_PREFIX.len();
+    let end_fragment = start_fragment + html.len();
+    let end_html = end_fragment + FRAGMENT_SUFFIX.len();
+    let header = CF_HTML_HEADER
+        .replace("{sh}", &format!("{:010}", header_len))
+        .replace("{eh}", &format!("{:010}", end_html))
+        .replace("{sf}", &format!("{:010}", start_fragment))
+        .replace("{ef}", &format!("{:010}", end_fragment));
+    format!("{}{}{}{}", header, FRAGMENT_PREFIX, html, FRAGMENT_SUFFIX).into_bytes()
+}
+
+/// The HTML inside a CF_HTML envelope: the fragment when the header marks one,
+/// otherwise the whole document.
+pub fn unwrap_cf_html(data: &[u8]) -> Option<String> {
+    let text = String::from_utf8_lossy(data);
+    let offset = |key: &str| -> Option<usize> {
+        text.lines()
+            .take_while(|l| !l.trim_start().starts_with('<'))
+            .filter_map(|l| l.split_once(':'))
+            .find(|(k, _)| k.trim() == key)
+            .and_then(|(_, v)| v.trim().parse::<i64>().ok())
+            .and_then(|v| usize::try_from(v).ok())
+    };
+    let (start, end) = match (offset("StartFragment"), offset("EndFragment")) {
+        (Some(s), Some(e)) => (s, e),
+        _ => (offset("StartHTML")?, offset("EndHTML")?),
+    };
+    let body = data.get(start..end.min(data.len()))?;
+    Some(String::from_utf8_lossy(body).trim_end_matches('\0').to_string())
+}
+
+pub fn encode_png(img: &RgbaImage) -> Option<Vec<u8>> {
+    let mut png = Vec::new();
+    img.write_to(&mut Cursor::new(&mut png), ImageFormat::Png).ok()?;
+    Some(png)
+}
+
+/// PNG to a 32bpp bottom-up packed DIB, the layout CF_DIB readers expect.
+pub fn png_to_dib(png: &[u8]) -> Option<Vec<u8>> {
+    let img = image::load_from_memory_with_format(png, ImageFormat::Png).ok()?.to_rgba8();
+    let (width, height) = img.dimensions();
+    let mut pixels = Vec::with_capacity(width as usize * height as usize * 4);
+    for row in img.rows().rev() {
+        for p in row {
+            let [r, g, b, a] = p.0;
+            pixels.extend_from_slice(&[b, g, r, a]);
+        }
+    }
+    encode_bitmap(width as i32, height as i32, &pixels).ok()
+}
+
+/// `C:\dir\a b.txt` becomes `file:///C:/dir/a%20b.txt`; UNC paths keep their host.
+pub fn path_to_file_uri(path: &str) -> String {
+    let path = path.replace('\\', "/");
+    let (host, rest) = match path.strip_prefix("//") {
+        Some(unc) => unc.split_once('/').unwrap_or((unc, "")),
+        None => ("", path.trim_start_matches('/')),
+    };
+    let mut uri = format!("file://{}/", host);
+    for b in rest.bytes() {
+        if b.is_ascii_alphanumeric() || b"/-._~:".contains(&b) {
+            uri.push(b as char);
+        } else {
+            uri.push_str(&format!("%{:02X}", b));
+        }
+    }
+    uri
+}
+
+/// Inverse of [`path_to_file_uri`]; None for anything but a `file:` URI.
+pub fn file_uri_to_path(uri: &str) -> Option<String> {
+    let rest = uri.strip_prefix("file://")?;
+    let (host, path) = rest.split_once('/').unwrap_or((rest, ""));
+    let path = percent_decode(path)?;
+    let is_drive = path.as_bytes().get(1) == Some(&b':');
+    Some(match host {
+        "" | "localhost" if is_drive => path.replace('/', "\\"),
+        "" | "localhost" => format!("/{}", path),
+        host => format!("\\\\{}\\{}", host, path.replace('/', "\\")),
+    })
+}
+
+fn percent_decode(input: &str) -> Option<String> {
+    let bytes = input.as_bytes();
+    let mut out = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'%' {
+            let hex = input.get(i + 1..i + 3)?;
+            out.push(u8::from_str_radix(hex, 16).ok()?);
+            i += 3;
+        } else {
+            out.push(bytes[i]);
+            i += 1;
+        }
+    }
+    String::from_utf8(out).ok()
+}
+
+/// RFC 2483 list: one URI per CRLF-terminated line.
+pub fn paths_to_uri_list(paths: &[String]) -> String {
+    paths.iter().map(|p| path_to_file_uri(p) + "\r\n").collect()
+}
+
+/// Local paths named by a uri-list; comments and non-file URIs are skipped.
+pub fn uri_list_to_paths(list: &str) -> Vec<String> {
+    list.lines()
+        .map(str::trim)
+        .filter(|l| !l.is_empty() && !l.starts_with('#'))
+        .filter_map(file_uri_to_path)
+        .collect()
+}
+
+/// A DROPFILES header followed by the wide, double-NUL-terminated path list.
+pub fn build_dropfiles(paths: &[String]) -> Vec<u8> {
+    let mut data = Vec::new();
+    data.extend_from_slice(&20u32.to_le_bytes()); // pFiles
+    data.extend_from_slice(&[0; 12]); // pt, fNC
+    data.extend_from_slice(&1u32.to_le_bytes()); // fWide
+    for path in paths {
+        data.extend(path.encode_utf16().chain(std::iter::once(0)).flat_map(u16::to_le_bytes));
+    }
+    data.extend_from_slice(&[0, 0]);
+    data
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn payload(format_id: u32, name: &str, data: Vec<u8>) -> ClipboardPayload {
+        ClipboardPayload { format_id, format_name: name.to_string(), data }
+    }
+
+    #[test]
+    fn utf16_round_trip() {
+        let bytes = utf8_to_utf16("héllo ✂");
+        assert_eq!(&bytes[bytes.len() - 2..], &[0, 0]);
+        assert_eq!(utf16_to_utf8(&bytes), "héllo ✂");
+    }
+
+    #[test]
+    fn cf_html_round_trip() {
+        let html = "<b>bold</b> and <i>ünïcode</i>";
+        let wrapped = wrap_cf_html(html);
+        let text = String::from_utf8(wrapped.clone()).unwrap();
+        assert!(text.starts_with("Version:0.9\r\nStartHTML:"));
+        let start: usize = text.lines().nth(3).unwrap()["StartFragment:".len()..].parse().unwrap();
+        assert!(text[start..].starts_with("<b>bold</b>"));
+        assert_eq!(unwrap_cf_html(&wrapped).as_deref(), Some(html));
+    }
+
+    #[test]
+    fn cf_html_without_fragment_uses_document() {
+        let doc = "<html>x</html>";
+        let header_len = "Version:1.0\r\nStartHTML:0000000000\r\nEndHTML:0000000000\r\n".len();
+        let data = format!(
+            "Version:1.0\r\nStartHTML:{:010}\r\nEndHTML:{:010}\r\n{}",
+            header_len,
+            header_len + doc.len(),
+            doc
+        );
+        assert_eq!(unwrap_cf_html(data.as_bytes()).as_deref(), Some(doc));
+        assert_eq!(unwrap_cf_html(b"<html>no header</html>"), None);
+    }
+
+    #[test]
+    fn png_dib_round_trip() {
+        // BI_RGB has no defined alpha channel, so only opaque pixels survive intact
+        let mut img = RgbaImage::from_pixel(2, 2, image::Rgba([0, 0, 0, 255]));
+        img.put_pixel(0, 0, image::Rgba([255, 0, 0, 255]));
+        img.put_pixel(1, 1, image::Rgba([0, 0, 255, 255]));
+        let dib = png_to_dib(&encode_png(&img).unwrap()).unwrap();
+        // Bottom-up: the first stored row is the image's last
+        assert_eq!(&dib[40 + 4..40 + 8], &[255, 0, 0, 255]);
+        let back = crate::imaging::decode_dib(&dib).unwrap();
+        assert_eq!(back, img);
+    }
+
+    #[test]
+    fn file_uris() {
+        assert_eq!(path_to_file_uri(r"C:\dir\a b#.txt"), "file:///C:/dir/a%20b%23.txt");
+        assert_eq!(path_to_file_uri(r"\\server\share\x"), "file://server/share/x");
+        assert_eq!(path_to_file_uri("/home/me/ü"), "file:///home/me/%C3%BC");
+        for path in [r"C:\dir\a b#.txt", r"\\server\share\x", "/home/me/ü"] {
+            assert_eq!(file_uri_to_path(&path_to_file_uri(path)).as_deref(), Some(path));
+        }
+        assert_eq!(file_uri_to_path("https://example.com/"), None);
+        assert_eq!(file_uri_to_path("file:///C:/bad%zz"), None);
+    }
+
+    #[test]
+    fn hdrop_uri_list_round_trip() {
+        let paths = vec![r"C:\one.txt".to_string(), r"D:\two words\2.png".to_string()];
+        let list = paths_to_uri_list(&paths);
+        assert_eq!(list, "file:///C:/one.txt\r\nfile:///D:/two%20words/2.png\r\n");
+        assert_eq!(uri_list_to_paths(&format!("# comment\r\n{}", list)), paths);
+        assert_eq!(dropfiles_paths(&build_dropfiles(&paths)), Some(paths));
+    }
+
+    #[test]
+    fn extras_fill_the_other_side() {
+        let windows = vec![
+            payload(13, "CF_UNICODETEXT", utf8_to_utf16("hi")),
+            payload(0xC0A1, HTML_FORMAT, wrap_cf_html("<p>hi</p>")),
+            payload(15, "CF_HDROP", build_dropfiles(&[r"C:\a.txt".to_string()])),
+        ];
+        let mime = mime_extras(&windows);
+        let names: Vec<&str> = mime.iter().map(|p| p.format_name.as_str()).collect();
+        assert_eq!(names, ["text/plain;charset=utf-8", "text/html", "text/uri-list"]);
+        assert_eq!(mime[0].data, b"hi");
+        assert_eq!(mime[1].data, b"<p>hi</p>");
+        assert!(win32_extras(&windows).is_empty());
+
+        let back = win32_extras(&mime);
+        let ids: Vec<(u32, &str)> = back.iter().map(|p| (p.format_id, p.format_name.as_str())).collect();
+        assert_eq!(ids, [(13, "CF_UNICODETEXT"), (0, HTML_FORMAT), (15, "CF_HDROP")]);
+        assert_eq!(back[0].data, windows[0].data);
+        assert_eq!(unwrap_cf_html(&back[1].data).as_deref(), Some("<p>hi</p>"));
+        assert_eq!(back[2].data, windows[2].data);
+        assert!(mime_extras(&mime).is_empty());
+    }
+
+    #[test]
+    fn restore_modes() {
+        let clip = || {
+            vec![
+                payload(13, "CF_UNICODETEXT", utf8_to_utf16("bold text")),
+                payload(0xC0A1, HTML_FORMAT, wrap_cf_html("<b>bold</b> text")),
+                payload(0xC0A2, RTF_FORMAT, b"{\\rtf1 {\\b bold} text}".to_vec()),
+            ]
+        };
+        let plain = for_restore(clip(), RestoreMode::PlainText);
+        assert_eq!(plain.len(), 1);
+        assert_eq!(plain[0].format_id, 13);
+        let rich: Vec<String> = for_restore(clip(), RestoreMode::RichText).into_iter().map(|p| p.format_name).collect();
+        assert_eq!(rich, [HTML_FORMAT, RTF_FORMAT]);
+
+        // Without plain text of its own, the text comes from the markup
+        let html_only = vec![payload(0, "text/html", b"<ul><li>a</li></ul><p>b</p>".to_vec())];
+        let derived = for_restore(html_only, RestoreMode::PlainText);
+        assert_eq!(utf16_to_utf8(&derived[0].data), "• a\nb");
+        assert!(for_restore(vec![payload(8, "CF_DIB", vec![0; 40])], RestoreMode::PlainText).is_empty());
+    }
+}
@@ -0,0 +1,347 @@
+//! A small lexer for the code preview: keywords, types, strings, numbers and
+//! comments per language, split into lines for numbering. It only colours
+//! text, so a wrong guess at the syntax costs nothing worse than odd colours.
+
+use crate::classify::Language;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TokenKind {
+    Plain,
+    Keyword,
+    Type,
+    String,
+    Number,
+    Comment,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Token {
+    pub kind: TokenKind,
+    pub text: String,
+}
+
+struct Syntax {
+    line_comments: &'static [&'static str],
+    block_comment: Option<(&'static str, &'static str)>,
+    /// Longest first, so `"""` is tried before `"`.
+    quotes: &'static [&'static str],
+    keywords: &'static [&'static str],
+    types: &'static [&'static str],
+    /// Capitalised identifiers are type names by convention.
+    capitalised_types: bool,
+}
+
+const C_KEYWORDS: &[&str] = &[
+    "break", "case", "const", "continue", "default", "do", "else", "enum", "extern", "for", "goto", "if",
+    "inline", "return", "sizeof", "static", "struct", "switch", "typedef", "union", "volatile", "while",
+    "NULL", "true", "false",
+];
+const C_TYPES: &[&str] = &[
+    "char", "double", "float", "int", "long", "short", "signed", "unsigned", "void", "size_t", "bool",
+    "int8_t", "int16_t", "int32_t", "int64_t", "uint8_t", "uint16_t", "uint32_t", "uint64_t",
+];
+
+impl Syntax {
+    fn of(lang: Option<Language>) -> Syntax {
+        let c_like = Syntax {
+            line_comments: &["//"],
+            block_comment: Some(("/*", "*/")),
+            quotes: &["\"", "'"],
+            keywords: &[],
+            types: &[],
+            capitalised_types: true,
+        };
+        let Some(lang) = lang else {
+            return Syntax { line_comments: &["//", "#"], capitalised_types: false, ..c_like };
+        };
+        match lang {
+            Language::Rust => Syntax {
+                keywords: &[
+                    "as", "async", "await", "break", "const", "continue", "crate", "dyn", "else", "enum",
+                    "extern", "false", "fn", "for", "if", "impl", "in", "let", "loop", "match", "mod", "move",
+                    "mut", "pub", "ref", "return", "self", "Self", "static", "struct", "super", "trait", "true",
+                    "type", "unsafe", "use", "where", "while",
+                ],
+                types: &[
+                    "bool", "char", "str", "u8", "u16", "u32", "u64", "u128", "usize", "i8", "i16", "i32",
+                    "i64", "i128", "isize", "f32", "f64",
+                ],
+                ..c_like
+            },
+            Language::Python => Syntax {
+                line_comments: &["#"],
+                block_comment: None,
+                quotes: &["\"\"\"", "'''", "\"", "'"],
+                keywords: &[
+                    "and", "as", "assert", "async", "await", "break", "class", "continue", "def", "del", "elif",
+                    "else", "except", "False", "finally", "for", "from", "global", "if", "import", "in", "is",
+                    "lambda", "None", "nonlocal", "not", "or", "pass", "raise", "return", "self", "True", "try",
+                    "while", "with", "yield",
+                ],
+                types: &["bool", "bytes", "dict", "float", "int", "list", "object", "set", "str", "tuple"],
+                ..c_like
+            },
+            Language::JavaScript | Language::TypeScript => Syntax {
+                quotes: &["\"", "'", "`"],
+                keywords: &[
+                    "async", "await", "break", "case", "catch", "class", "const", "continue", "default", "delete",
+                    "do", "else", "export", "extends", "false", "finally", "for", "from", "function", "if",
+                    "import", "in", "instanceof", "interface", "let", "new", "null", "of", "return", "static",
+                    "super", "switch", "this", "throw", "true", "try", "type", "typeof", "undefined", "var",
+                    "void", "while", "yield",
+                ],
+                types: &["any", "boolean", "never", "number", "string", "unknown"],
+                ..c_like
+            },
+            Language::Java | Language::CSharp => Syntax {
+                keywords: &[
+                    "abstract", "async", "await", "break", "case", "catch", "class", "const", "continue",
+                    "default", "do", "else", "enum", "extends", "false", "final", "finally", "for", "foreach",
+                    "if", "implements", "import", "in", "interface", "internal", "namespace", "new", "null",
+                    "override", "package", "private", "protected", "public", "readonly", "return", "static",
+                    "super", "switch", "this", "throw", "throws", "true", "try", "using", "var", "virtual",
+                    "void", "while",
+                ],
+                types: &[
+                    "boolean", "bool", "byte", "char", "decimal", "double", "float", "int", "long", "object",
+                    "short", "string",
+                ],
+                ..c_like
+            },
+            Language::Cpp => Syntax {
+                keywords: &[
+                    "auto", "break", "case", "catch", "class", "const", "constexpr", "continue", "default",
+                    "delete", "do", "else", "enum", "explicit", "false", "for", "friend", "if", "inline",
+                    "namespace", "new", "nullptr", "operator", "private", "protected", "public", "return",
+                    "static", "struct", "switch", "template", "this", "throw", "true", "try", "typename",
+                    "using", "virtual", "while",
+                ],
+                types: C_TYPES,
+                ..c_like
+            },
+            Language::C => Syntax { keywords: C_KEYWORDS, types: C_TYPES, capitalised_types: false, ..c_like },
+            Language::Go => Syntax {
+                quotes: &["\"", "'", "`"],
+                keywords: &[
+                    "break", "case", "chan", "const", "continue", "default", "defer", "else", "false", "for",
+                    "func", "go", "if", "import", "interface", "map", "nil", "package", "range", "return",
+                    "select", "struct", "switch", "true", "type", "var",
+                ],
+                types: &[
+                    "bool", "byte", "error", "float32", "float64", "int", "int8", "int16", "int32", "int64",
+                    "rune", "string", "uint", "uint8", "uint16", "uint32", "uint64",
+                ],
+                capitalised_types: false,
+                ..c_like
+            },
+            Language::Ruby => Syntax {
+                line_comments: &["#"],
+                block_comment: None,
+                keywords: &[
+                    "begin", "class", "def", "do", "else", "elsif", "end", "ensure", "false", "if", "in",
+                    "module", "next", "nil", "require", "rescue", "return", "self", "then", "true", "unless",
+                    "until", "when", "while", "yield",
+                ],
+                ..c_like
+            },
+            Language::Php => Syntax {
+                line_comments: &["//", "#"],
+                keywords: &[
+                    "array", "as", "break", "case", "class", "const", "continue", "echo", "else", "elseif",
+                    "extends", "false", "foreach", "for", "function", "if", "implements", "namespace", "new",
+                    "null", "private", "protected", "public", "return", "static", "switch", "true", "use",
+                    "while",
+                ],
+                ..c_like
+            },
+            Language::Css => Syntax {
+                line_comments: &[],
+                keywords: &["important", "inherit", "initial", "none", "auto"],
+                capitalised_types: false,
+                ..c_like
+            },
+        }
+    }
+}
+
+fn is_ident(c: char) -> bool {
+    c.is_alphanumeric() || c == '_'
+}
+
+/// Lexes `text` and returns its tokens line by line, without the newlines.
+pub fn highlight(text: &str, lang: Option<Language>) -> Vec<Vec<Token>> {
+    let syntax = Syntax::of(lang);
+    let mut tokens: Vec<Token> = Vec::new();
+    let mut push = |kind: TokenKind, text: &str| match tokens.last_mut() {
+        Some(last) if last.kind == kind && kind == TokenKind::Plain => last.text.push_str(text),
+        _ => tokens.push(Token { kind, text: text.to_string() }),
+    };
+
+    let mut i = 0;
+    let mut prev: Option<char> = None;
+    while i < text.len() {
+        let rest = &text[i..];
+        let len = if syntax.line_comments.iter().any(|m| rest.starts_with(m)) {
+            let len = rest.find('\n').unwrap_or(rest.len());
+            push(TokenKind::Comment, &rest[..len]);
+            len
+        } else if let Some((open, close)) = syntax.block_comment.filter(|(open, _)| rest.starts_with(open)) {
+            let len = rest[open.len()..].find(close).map_or(rest.len(), |end| open.len() + end + close.len());
+            push(TokenKind::Comment, &rest[..len]);
+            len
+        } else if let Some(quote) = syntax.quotes.iter().find(|q| rest.starts_with(**q) && !is_lifetime(lang, rest)) {
+            let len = string_len(rest, quote);
+            push(TokenKind::String, &rest[..len]);
+            len
+        } else {
+            let c = rest.chars().next().unwrap_or_default();
+            if c.is_ascii_digit() && !prev.is_some_and(is_ident) {
+                let len = number_len(rest);
+                push(TokenKind::Number, &rest[..len]);
+                len
+            } else if is_ident(c) {
+                let len = rest.find(|c: char| !is_ident(c)).unwrap_or(rest.len());
+                let word = &rest[..len];
+                let kind = if syntax.keywords.contains(&word) {
+                    TokenKind::Keyword
+                } else if syntax.types.contains(&word)
+                    || (syntax.capitalised_types && word.starts_with(char::is_uppercase) && word.chars().any(char::is_lowercase))
+                {
+                    TokenKind::Type
+                } else {
+                    TokenKind::Plain
+                };
+                push(kind, word);
+                len
+            } else {
+                push(TokenKind::Plain, &rest[..c.len_utf8()]);
+                c.len_utf8()
+            }
+        };
+        prev = text[..i + len].chars().next_back();
+        i += len;
+    }
+    split_lines(tokens)
+}
+
+/// A Rust `'a` lifetime or label, as opposed to a `'x'` char literal.
+fn is_lifetime(lang: Option<Language>, rest: &str) -> bool {
+    if lang != Some(Language::Rust) || !rest.starts_with('\'') {
+        return false;
+    }
+    let mut chars = rest.chars().skip(1);
+    match (chars.next(), chars.next()) {
+        (Some('\\'), _) | (_, Some('\'')) => false,
+        (Some(c), _) => is_ident(c),
+        (None, _) => false,
+    }
+}
+
+/// Through the closing quote, skipping escapes. Single-character quotes other
+/// than backticks stop at the end of the line if left open.
+fn string_len(rest: &str, quote: &str) -> usize {
+    let multiline = quote.len() > 1 || quote == "`";
+    let mut iter = rest.char_indices().skip(quote.chars().count());
+    while let Some((i, c)) = iter.next() {
+        match c {
+            '\\' => {
+                iter.next();
+            }
+            '\n' if !multiline => return i,
+            _ if rest[i..].starts_with(quote) => return i + quote.len(),
+            _ => {}
+        }
+    }
+    rest.len()
+}
+
+/// Digits with radix prefixes, separators, exponents and type suffixes; a
+/// `..` range ends the number.
+fn number_len(rest: &str) -> usize {
+    let bytes = rest.as_bytes();
+    let mut len = 0;
+    while len < bytes.len() {
+        let b = bytes[len];
+        let fraction = b == b'.' && bytes.get(len + 1).is_some_and(u8::is_ascii_digit);
+        if !(b.is_ascii_alphanumeric() || b == b'_' || fraction) {
+            break;
+        }
+        len += 1;
+    }
+    len
+}
+
+fn split_lines(tokens: Vec<Token>) -> Vec<Vec<Token>> {
+    let mut lines = vec![Vec::new()];
+    for token in tokens {
+        for (n, piece) in token.text.split('\n').enumerate() {
+            if n > 0 {
+                lines.push(Vec::new());
+            }
+            let piece = piece.strip_suffix('\r').unwrap_or(piece);
+            if !piece.is_empty() {
+                lines.last_mut().expect("never empty").push(Token { kind: token.kind, text: piece.to_string() });
+            }
+        }
+    }
+    lines
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn kinds(line: &[Token]) -> Vec<(TokenKind, &str)> {
+        line.iter().filter(|t| !t.text.trim().is_empty()).map(|t| (t.kind, t.text.trim())).collect()
+    }
+
+    #[test]
+    fn rust() {
+        let lines = highlight("fn main() -> u8 {\n    let s: &'static str = \"a\\\"b\"; // hi\n    0x1F\n}", Some(Language::Rust));
+        assert_eq!(lines.len(), 4);
+        use TokenKind::*;
+        assert_eq!(kinds(&lines[0]), vec![(Keyword, "fn"), (Plain, "main() ->"), (Type, "u8"), (Plain, "{")]);
+        assert_eq!(
+            kinds(&lines[1]),
+            vec![
+                (Keyword, "let"),
+                (Plain, "s: &'"),
+                (Keyword, "static"),
+                (Type, "str"),
+                (Plain, "="),
+                (String, "\"a\\\"b\""),
+                (Plain, ";"),
+                (Comment, "// hi"),
+            ]
+        );
+        assert_eq!(kinds(&lines[2]), vec![(Number, "0x1F")]);
+    }
+
+    #[test]
+    fn multiline_tokens_split_per_line() {
+        let lines = highlight("x = \"\"\"one\ntwo\"\"\"  # done\n", Some(Language::Python));
+        assert_eq!(lines.len(), 3);
+        assert_eq!(lines[0].last().unwrap(), &Token { kind: TokenKind::String, text: "\"\"\"one".into() });
+        assert_eq!(lines[1][0], Token { kind: TokenKind::String, text: "two\"\"\"".into() });
+        assert_eq!(lines[1].last().unwrap().kind, TokenKind::Comment);
+        assert!(lines[2].is_empty());
+
+        let lines = highlight("/* a\r\nb */ int x;", Some(Language::C));
+        assert_eq!(lines[0], vec![Token { kind: TokenKind::Comment, text: "/* a".into() }]);
+        assert_eq!(lines[1][0].text, "b */");
+    }
+
+    #[test]
+    fn open_quotes_stop_at_the_line_end() {
+        let lines = highlight("it's\nfn x", Some(Language::Go));
+        assert_eq!(lines[0][1].kind, TokenKind::String);
+        assert_eq!(lines[1][0].text, "fn x");
+    }
+
+    #[test]
+    fn numbers_and_ranges() {
+        let line = &highlight("for i in 0..10 { v1 = 2.5e3 }", Some(Language::Rust))[0];
+        let numbers: Vec<&str> = line.iter().filter(|t| t.kind == TokenKind::Number).map(|t| t.text.as_str()).collect();
+        assert_eq!(numbers, vec!["0", "10", "2.5e3"]);
+    }
+}
@@ -0,0 +1,232 @@
+use std::collections::BTreeMap;
+use std::fmt;
+
+pub const MOD_ALT: u32 = 0x0001;
+pub const MOD_CONTROL: u32 = 0x0002;
+pub const MOD_SHIFT: u32 = 0x0004;
+pub const MOD_WIN: u32 = 0x0008;
+
+/// Something a global hotkey can trigger.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum HotkeyAction {
+    ShowHistory,
+    PastePrevious,
+    PastePlain,
+    OpenSearch,
+    SecureCopy,
+    PastePinned(u8),
+    ClearClipboard,
+    /// Runs the named transform on the newest clip and pastes the result.
+    Transform(String),
+}
+
+impl HotkeyAction {
+    pub fn all() -> Vec<HotkeyAction> {
+        let mut actions = vec![
+            HotkeyAction::ShowHistory,
+            HotkeyAction::OpenSearch,
+            HotkeyAction::PastePrevious,
+            HotkeyAction::PastePlain,
+            HotkeyAction::SecureCopy,
+            HotkeyAction::ClearClipboard,
+        ];
+        actions.extend((1..=9).map(HotkeyAction::PastePinned));
+        actions
+    }
+
+    /// Parses the `[hotkeys]` key naming the action, e.g. `paste_pinned_3` or
+    /// `transform:trim`. Whether the transform exists is checked with the config.
+    pub fn from_name(name: &str) -> Option<Self> {
+        Some(match name {
+            "show_history" | "toggle" => HotkeyAction::ShowHistory,
+            "paste_previous" => HotkeyAction::PastePrevious,
+            "paste_plain" => HotkeyAction::PastePlain,
+            "open_search" => HotkeyAction::OpenSearch,
+            "secure_copy" => HotkeyAction::SecureCopy,
+            "clear_clipboard" => HotkeyAction::ClearClipboard,
+            _ => {
+                if let Some(transform) = name.strip_prefix("transform:") {
+                    let transform = transform.trim();
+                    return (!transform.is_empty()).then(|| HotkeyAction::Transform(transform.to_string()));
+                }
+                let slot: u8 = name.strip_prefix("paste_pinned_")?.parse().ok()?;
+                if !(1..=9).contains(&slot) {
+                    return None;
+                }
+                HotkeyAction::PastePinned(slot)
+            }
+        })
+    }
+}
+
+impl fmt::Display for HotkeyAction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            HotkeyAction::ShowHistory => write!(f, "show_history"),
+            HotkeyAction::PastePrevious => write!(f, "paste_previous"),
+            HotkeyAction::PastePlain => write!(f, "paste_plain"),
+            HotkeyAction::OpenSearch => write!(f, "open_search"),
+            HotkeyAction::SecureCopy => write!(f, "secure_copy"),
+            HotkeyAction::PastePinned(slot) => write!(f, "paste_pinned_{}", slot),
+            HotkeyAction::ClearClipboard => write!(f, "clear_clipboard"),
+            HotkeyAction::Transform(name) => write!(f, "transform:{}", name),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Binding {
+    pub action: HotkeyAction,
+    pub combo: String,
+    pub modifiers: u32,
+    pub vk: u32,
+}
+
+pub fn default_bindings() -> BTreeMap<String, String> {
+    BTreeMap::from([
+        ("show_history".to_string(), "Ctrl+Alt+C".to_string()),
+        ("open_search".to_string(), "Ctrl+Alt+F".to_string()),
+        ("paste_previous".to_string(), "Ctrl+Alt+V".to_string()),
+        ("paste_plain".to_string(), "Ctrl+Alt+Shift+V".to_string()),
+    ])
+}
+
+/// Turns the `[hotkeys]` table into bindings, rejecting unknown actions,
+/// malformed combos and two actions sharing the same combo.
+pub fn parse_bindings(table: &BTreeMap<String, String>) -> Result<Vec<Binding>, String> {
+    let mut bindings: Vec<Binding> = Vec::new();
+    for (name, combo) in table {
+        let action = HotkeyAction::from_name(name)
+            .ok_or_else(|| format!("unknown hotkey action '{}'", name))?;
+        let (modifiers, vk) = parse_hotkey(combo).map_err(|e| format!("{}: {}", name, e))?;
+        if let Some(other) = bindings.iter().find(|b| b.modifiers == modifiers && b.vk == vk) {
+            return Err(format!(
+                "'{}' is bound to both {} and {}",
+                combo, other.action, action
+            ));
+        }
+        if bindings.iter().any(|b| b.action == action) {
+            return Err(format!("{} is bound more than once", action));
+        }
+        bindings.push(Binding { action, combo: combo.clone(), modifiers, vk });
+    }
+    Ok(bindings)
+}
+
+/// Parses a combo like `Ctrl+Alt+C` into RegisterHotKey modifiers and a virtual-key code.
+pub fn parse_hotkey(combo: &str) -> Result<(u32, u32), String> {
+    let mut modifiers = 0;
+    let mut key = None;
+    for part in combo.split('+').map(str::trim) {
+        match part.to_ascii_lowercase().as_str() {
+            "ctrl" | "control" => modifiers |= MOD_CONTROL,
+            "alt" => modifiers |= MOD_ALT,
+            "shift" => modifiers |= MOD_SHIFT,
+            "win" | "super" | "meta" => modifiers |= MOD_WIN,
+            "" => return Err(format!("empty key in '{}'", combo)),
+            name => {
+                if key.is_some() {
+                    return Err(format!("more than one key in '{}'", combo));
+                }
+                key = Some(virtual_key(name).ok_or_else(|| format!("unknown key '{}'", part))?);
+            }
+        }
+    }
+    let key = key.ok_or_else(|| format!("no key in '{}'", combo))?;
+    if modifiers == 0 {
+        return Err(format!("'{}' needs at least one modifier", combo));
+    }
+    Ok((modifiers, key))
+}
+
+fn virtual_key(name: &str) -> Option<u32> {
+    let bytes = name.as_bytes();
+    if bytes.len() == 1 && bytes[0].is_ascii_alphanumeric() {
+        return Some(bytes[0].to_ascii_uppercase() as u32);
+    }
+    if let Some(n) = name.strip_prefix('f').and_then(|n| n.parse::<u32>().ok()) {
+        if (1..=24).contains(&n) {
+            return Some(0x70 + n - 1);
+        }
+    }
+    Some(match name {
+        "space" => 0x20,
+        "enter" | "return" => 0x0D,
+        "tab" => 0x09,
+        "esc" | "escape" => 0x1B,
+        "backspace" => 0x08,
+        "delete" | "del" => 0x2E,
+        "insert" | "ins" => 0x2D,
+        "home" => 0x24,
+        "end" => 0x23,
+        "pageup" => 0x21,
+        "pagedown" => 0x22,
+        "left" => 0x25,
+        "up" => 0x26,
+        "right" => 0x27,
+        "down" => 0x28,
+        _ => return None,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn table(entries: &[(&str, &str)]) -> BTreeMap<String, String> {
+        entries.iter().map(|(k, v)| (k.to_string(), v.to_string())).collect()
+    }
+
+    #[test]
+    fn parses_combos() {
+        assert_eq!(parse_hotkey("Ctrl+Alt+C"), Ok((MOD_CONTROL | MOD_ALT, 'C' as u32)));
+        assert_eq!(parse_hotkey(" control + SHIFT + v "), Ok((MOD_CONTROL | MOD_SHIFT, 'V' as u32)));
+        assert_eq!(parse_hotkey("Super+1"), Ok((MOD_WIN, '1' as u32)));
+        assert_eq!(parse_hotkey("Meta+Win+Space"), Ok((MOD_WIN, 0x20)));
+        assert_eq!(parse_hotkey("Alt+F1"), Ok((MOD_ALT, 0x70)));
+        assert_eq!(parse_hotkey("Alt+F24"), Ok((MOD_ALT, 0x87)));
+        assert_eq!(parse_hotkey("Ctrl+Return"), parse_hotkey("Ctrl+Enter"));
+        assert_eq!(parse_hotkey("Ctrl+Del"), Ok((MOD_CONTROL, 0x2E)));
+        assert_eq!(parse_hotkey("Ctrl+PageDown"), Ok((MOD_CONTROL, 0x22)));
+    }
+
+    #[test]
+    fn rejects_bad_combos() {
+        let error = |combo: &str| parse_hotkey(combo).unwrap_err();
+        assert!(error("C").contains("needs at least one modifier"));
+        assert!(error("Ctrl+Alt").contains("no key in"));
+        assert!(error("Ctrl++C").contains("empty key in"));
+        assert!(error("Ctrl+").contains("empty key in"));
+        assert!(error("Ctrl+C+D").contains("more than one key in"));
+        assert!(error("Ctrl+F25").contains("unknown key 'F25'"));
+        assert!(error("Ctrl+F0").contains("unknown key"));
+        assert!(error("Ctrl+Hyper").contains("unknown key 'Hyper'"));
+    }
+
+    #[test]
+    fn action_names_round_trip() {
+        let mut actions = HotkeyAction::all();
+        actions.push(HotkeyAction::Transform("trim".to_string()));
+        for action in actions {
+            assert_eq!(HotkeyAction::from_name(&action.to_string()), Some(action));
+        }
+        assert_eq!(HotkeyAction::from_name("toggle"), Some(HotkeyAction::ShowHistory));
+        assert_eq!(HotkeyAction::from_name("transform: upper "), Some(HotkeyAction::Transform("upper".to_string())));
+        for bad in ["transform:", "paste_pinned_0", "paste_pinned_10", "paste_pinned_x", "paste", ""] {
+            assert_eq!(HotkeyAction::from_name(bad), None, "{}", bad);
+        }
+    }
+
+    #[test]
+    fn bindings() {
+        let bindings = parse_bindings(&default_bindings()).unwrap();
+        assert_eq!(bindings.len(), 4);
+        assert!(bindings.iter().any(|b| b.action == HotkeyAction::PastePlain && b.modifiers == MOD_CONTROL | MOD_ALT | MOD_SHIFT));
+
+        let error = |entries: &[(&str, &str)]| parse_bindings(&table(entries)).unwrap_err();
+        assert!(error(&[("launch", "Ctrl+L")]).contains("unknown hotkey action 'launch'"));
+        assert!(error(&[("open_search", "F")]).starts_with("open_search: "));
+        assert!(error(&[("open_search", "Ctrl+F"), ("paste_plain", "control+f")]).contains("is bound to both open_search and paste_plain"));
+        assert!(error(&[("show_history", "Ctrl+H"), ("toggle", "Ctrl+J")]).contains("show_history is bound more than once"));
+    }
+}
@@ -0,0 +1,206 @@
+use image::{imageops::FilterType, ImageFormat, RgbaImage};
+use std::io::Cursor;
+
+use crate::models::ClipboardPayload;
+
+pub const CF_BITMAP: u32 = 2;
+pub const CF_DIB: u32 = 8;
+pub const CF_DIBV5: u32 = 17;
+pub const THUMBNAIL_SIZE: u32 = 96;
+
+/// A small PNG rendition of an image clip, stored alongside it.
+pub struct Thumbnail {
+    pub width: u32,
+    pub height: u32,
+    pub png: Vec<u8>,
+}
+
+/// Whether `payload` carries an image we know how to decode.
+pub fn is_image_format(payload: &ClipboardPayload) -> bool {
+    matches!(payload.format_id, CF_BITMAP | CF_DIB | CF_DIBV5)
+        || matches!(payload.format_name.as_str(), "PNG" | "image/png")
+}
+
+/// Decodes the best image representation in a clip: PNG first, since it keeps
+/// alpha reliably, then CF_DIBV5, then CF_DIB, then CF_BITMAP (which the
+/// codecs store as a packed DIB).
+pub fn decode_clip_image(payloads: &[ClipboardPayload]) -> Option<RgbaImage> {
+    let by_name = |name: &str| payloads.iter().find(|p| p.format_name == name);
+    let by_id = |id: u32| payloads.iter().find(|p| p.format_id == id);
+
+    if let Some(png) = by_name("PNG").or_else(|| by_name("image/png")) {
+        if let Ok(img) = image::load_from_memory_with_format(&png.data, ImageFormat::Png) {
+            return Some(img.to_rgba8());
+        }
+    }
+    by_id(CF_DIBV5)
+        .or_else(|| by_id(CF_DIB))
+        .or_else(|| by_id(CF_BITMAP))
+        .and_then(|dib| decode_dib(&dib.data))
+}
+
+/// Decodes a packed DIB (BITMAPINFOHEADER or a later header, optional masks and
+/// color table, then pixels) by giving it the file header a .bmp would have.
+pub fn decode_dib(dib: &[u8]) -> Option<RgbaImage> {
+    let pixel_offset = dib_pixel_offset(dib)?;
+    let mut bmp = Vec::with_capacity(14 + dib.len());
+    bmp.extend_from_slice(b"BM");
+    bmp.extend_from_slice(&((14 + dib.len()) as u32).to_le_bytes());
+    bmp.extend_from_slice(&[0; 4]);
+    bmp.extend_from_slice(&((14 + pixel_offset) as u32).to_le_bytes());
+    bmp.extend_from_slice(dib);
+
+    let mut img = image::load_from_memory_with_format(&bmp, ImageFormat::Bmp).ok()?.to_rgba8();
+    // 32bpp BI_RGB clipboard bitmaps usually leave the alpha byte at zero
+    if img.pixels().all(|p| p.0[3] == 0) {
+        img.pixels_mut().for_each(|p| p.0[3] = 255);
+    }
+    Some(img)
+}
+
+/// Offset of the pixel array inside a packed DIB.
+pub fn dib_pixel_offset(dib: &[u8]) -> Option<usize> {
+    let u32_at = |at: usize| dib.get(at..at + 4).map(|b| u32::from_le_bytes([b[0], b[1], b[2], b[3]]));
+    let header_size = u32_at(0)? as usize;
+    let bit_count = u16::from_le_bytes([*dib.get(14)?, *dib.get(15)?]);
+    let compression = u32_at(16)?;
+    let colors_used = u32_at(32)? as usize;
+
+    const BI_BITFIELDS: u32 = 3;
+    // A plain BITMAPINFOHEADER is followed by the three masks; V4/V5 carry them inline
+    let masks = if header_size == 40 && compression == BI_BITFIELDS { 12 } else { 0 };
+    let palette = match (colors_used, bit_count) {
+        (0, 1 | 4 | 8) => 1usize << bit_count,
+        (n, _) => n,
+    };
+    let offset = header_size + masks + palette * 4;
+    (header_size >= 40 && offset <= dib.len()).then_some(offset)
+}
+
+pub fn make_thumbnail(img: &RgbaImage) -> Option<Thumbnail> {
+    let longest = img.width().max(img.height()).max(1);
+    let scale = (THUMBNAIL_SIZE as f32 / longest as f32).min(1.0);
+    let width = ((img.width() as f32 * scale).round() as u32).max(1);
+    let height = ((img.height() as f32 * scale).round() as u32).max(1);
+    let thumb = image::imageops::resize(img, width, height, FilterType::Triangle);
+    let mut png = Vec::new();
+    thumb.write_to(&mut Cursor::new(&mut png), ImageFormat::Png).ok()?;
+    Some(Thumbnail { width: thumb.width(), height: thumb.height(), png })
+}
+
+/// Thumbnail for a freshly captured clip, if it holds an image.
+pub fn thumbnail_for(payloads: &[ClipboardPayload]) -> Option<Thumbnail> {
+    if !payloads.iter().any(is_image_format) {
+        return None;
+    }
+    make_thumbnail(&decode_clip_image(payloads)?)
+}
+
+pub fn decode_png(png: &[u8]) -> Option<RgbaImage> {
+    image::load_from_memory_with_format(png, ImageFormat::Png).ok().map(|i| i.to_rgba8())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const BI_RGB: u32 = 0;
+    const BI_BITFIELDS: u32 = 3;
+
+    /// A packed DIB with a BITMAPINFOHEADER, then `extra` (masks or palette), then `pixels`.
+    fn dib(width: i32, height: i32, bit_count: u16, compression: u32, extra: &[u8], pixels: &[u8]) -> Vec<u8> {
+        let mut dib = Vec::new();
+        dib.extend_from_slice(&40u32.to_le_bytes());
+        dib.extend_from_slice(&width.to_le_bytes());
+        dib.extend_from_slice(&height.to_le_bytes());
+        dib.extend_from_slice(&1u16.to_le_bytes());
+        dib.extend_from_slice(&bit_count.to_le_bytes());
+        dib.extend_from_slice(&compression.to_le_bytes());
+        dib.extend_from_slice(&(pixels.len() as u32).to_le_bytes());
+        dib.extend_from_slice(&[0; 16]);
+        dib.extend_from_slice(extra);
+        dib.extend_from_slice(pixels);
+        dib
+    }
+
+    // Two rows of one red and one blue pixel over one green and one white, as 24bpp BGR
+    // rows padded to four bytes
+    const TOP_ROW: [u8; 8] = [0, 0, 255, 255, 0, 0, 0, 0];
+    const BOTTOM_ROW: [u8; 8] = [0, 255, 0, 255, 255, 255, 0, 0];
+
+    fn rgb(img: &RgbaImage, x: u32, y: u32) -> [u8; 4] {
+        img.get_pixel(x, y).0
+    }
+
+    #[test]
+    fn decodes_bottom_up_and_top_down() {
+        let bottom_up = decode_dib(&dib(2, 2, 24, BI_RGB, &[], &[BOTTOM_ROW, TOP_ROW].concat())).unwrap();
+        let top_down = decode_dib(&dib(2, -2, 24, BI_RGB, &[], &[TOP_ROW, BOTTOM_ROW].concat())).unwrap();
+        for img in [bottom_up, top_down] {
+            assert_eq!(img.dimensions(), (2, 2));
+            assert_eq!(rgb(&img, 0, 0), [255, 0, 0, 255]);
+            assert_eq!(rgb(&img, 1, 0), [0, 0, 255, 255]);
+            assert_eq!(rgb(&img, 0, 1), [0, 255, 0, 255]);
+            assert_eq!(rgb(&img, 1, 1), [255, 255, 255, 255]);
+        }
+    }
+
+    #[test]
+    fn decodes_32bpp() {
+        // BI_RGB with the alpha byte left at zero reads as opaque
+        let pixel = [10, 20, 30, 0];
+        let img = decode_dib(&dib(1, 1, 32, BI_RGB, &[], &pixel)).unwrap();
+        assert_eq!(rgb(&img, 0, 0), [30, 20, 10, 255]);
+
+        // BI_BITFIELDS puts three masks after a plain BITMAPINFOHEADER
+        let masks = [0x00FF0000u32, 0x0000FF00, 0x000000FF].map(u32::to_le_bytes).concat();
+        let bitfields = dib(1, 1, 32, BI_BITFIELDS, &masks, &pixel);
+        assert_eq!(dib_pixel_offset(&bitfields), Some(52));
+        assert_eq!(&rgb(&decode_dib(&bitfields).unwrap(), 0, 0)[..3], [30, 20, 10]);
+    }
+
+    #[test]
+    fn skips_the_palette() {
+        let mut palette = vec![0; 256 * 4];
+        palette[4..8].copy_from_slice(&[0, 0, 255, 0]);
+        let paletted = dib(1, 1, 8, BI_RGB, &palette, &[1, 0, 0, 0]);
+        assert_eq!(dib_pixel_offset(&paletted), Some(40 + 256 * 4));
+        assert_eq!(rgb(&decode_dib(&paletted).unwrap(), 0, 0), [255, 0, 0, 255]);
+    }
+
+    #[test]
+    fn rejects_malformed_dibs() {
+        let valid = dib(2, 2, 24, BI_RGB, &[], &[BOTTOM_ROW, TOP_ROW].concat());
+        // Truncated inside the header
+        for len in [0, 4, 15, 35] {
+            assert_eq!(dib_pixel_offset(&valid[..len]), None, "{}", len);
+            assert!(decode_dib(&valid[..len]).is_none());
+        }
+        // A header size smaller than BITMAPINFOHEADER
+        let mut small_header = valid.clone();
+        small_header[..4].copy_from_slice(&12u32.to_le_bytes());
+        assert_eq!(dib_pixel_offset(&small_header), None);
+        // A palette running past the end
+        assert_eq!(dib_pixel_offset(&dib(1, 1, 8, BI_RGB, &[0; 16], &[0; 4])), None);
+        // Missing pixel rows
+        assert!(decode_dib(&valid[..valid.len() - 8]).is_none());
+    }
+
+    #[test]
+    fn rejects_oversized_dimensions() {
+        for (width, height) in [(1 << 20, 1 << 20), (i32::MAX, 1), (1, i32::MIN), (-4, 4), (0, 0)] {
+            assert!(decode_dib(&dib(width, height, 24, BI_RGB, &[], &[0; 64])).is_none(), "{}x{}", width, height);
+        }
+    }
+
+    #[test]
+    fn thumbnails_fit_the_box() {
+        let wide = RgbaImage::new(400, 100);
+        let thumb = make_thumbnail(&wide).unwrap();
+        assert_eq!((thumb.width, thumb.height), (THUMBNAIL_SIZE, THUMBNAIL_SIZE / 4));
+        assert_eq!(decode_png(&thumb.png).unwrap().dimensions(), (thumb.width, thumb.height));
+        // Small images aren't blown up
+        let small = make_thumbnail(&RgbaImage::new(10, 5)).unwrap();
+        assert_eq!((small.width, small.height), (10, 5));
+    }
+}
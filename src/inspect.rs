@@ -0,0 +1,390 @@
+//! Helpers for the binary inspector: hex dump lines, byte search and decoders
+//! for the clipboard structures worth reading field by field.
+
+pub const BYTES_PER_ROW: usize = 16;
+
+/// One `offset  hex bytes  |ascii|` line starting at `offset`.
+pub fn hex_line(data: &[u8], offset: usize) -> String {
+    let end = (offset + BYTES_PER_ROW).min(data.len());
+    let row = &data[offset.min(end)..end];
+    let mut line = format!("{:08X}  ", offset);
+    for i in 0..BYTES_PER_ROW {
+        match row.get(i) {
+            Some(b) => line.push_str(&format!("{:02X} ", b)),
+            None => line.push_str("   "),
+        }
+        if i == 7 {
+            line.push(' ');
+        }
+    }
+    line.push_str(" |");
+    line.extend(row.iter().map(|&b| if b.is_ascii_graphic() || b == b' ' { b as char } else { '.' }));
+    line.push('|');
+    line
+}
+
+/// Parses a hex (`0x1F0`) or decimal offset.
+pub fn parse_offset(input: &str) -> Option<usize> {
+    let input = input.trim();
+    match input.strip_prefix("0x").or_else(|| input.strip_prefix("0X")) {
+        Some(hex) => usize::from_str_radix(hex, 16).ok(),
+        None => input.parse().ok(),
+    }
+}
+
+/// Search input is either `"quoted text"` or hex bytes like `de ad be ef` / `deadbeef`.
+pub fn parse_needle(input: &str) -> Result<Vec<u8>, String> {
+    let input = input.trim();
+    if let Some(text) = input.strip_prefix('"') {
+        let text = text.strip_suffix('"').unwrap_or(text);
+        return if text.is_empty() { Err("empty search".to_string()) } else { Ok(text.as_bytes().to_vec()) };
+    }
+    let digits: String = input.chars().filter(|c| !c.is_whitespace()).collect();
+    if digits.is_empty() || !digits.len().is_multiple_of(2) {
+        return Err("hex search needs whole bytes, e.g. 4D 5A".to_string());
+    }
+    (0..digits.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&digits[i..i + 2], 16).map_err(|_| format!("'{}' is not hex", &digits[i..i + 2])))
+        .collect()
+}
+
+/// Next occurrence of `needle` at or after `from`, wrapping around to the start.
+pub fn find_bytes(data: &[u8], needle: &[u8], from: usize) -> Option<usize> {
+    if needle.is_empty() || needle.len() > data.len() {
+        return None;
+    }
+    let find_in = |start: usize, end: usize| {
+        data[start..end]
+            .windows(needle.len())
+            .position(|w| w == needle)
+            .map(|p| p + start)
+    };
+    let from = from.min(data.len());
+    find_in(from, data.len()).or_else(|| find_in(0, (from + needle.len() - 1).min(data.len())))
+}
+
+fn u16_at(data: &[u8], at: usize) -> Option<u16> {
+    data.get(at..at + 2).map(|b| u16::from_le_bytes([b[0], b[1]]))
+}
+
+fn u32_at(data: &[u8], at: usize) -> Option<u32> {
+    data.get(at..at + 4).map(|b| u32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+}
+
+fn i32_at(data: &[u8], at: usize) -> Option<i32> {
+    u32_at(data, at).map(|v| v as i32)
+}
+
+pub type Fields = Vec<(String, String)>;
+
+/// Field-by-field view of the formats we understand, or None for anything else.
+pub fn decode_structure(format_id: u32, format_name: &str, data: &[u8]) -> Option<Fields> {
+    match (format_id, format_name) {
+        (8 | 17, _) => decode_bitmap_header(data),
+        (15, _) => decode_dropfiles(data),
+        (_, "HTML Format") => decode_cf_html(data),
+        (_, "Rich Text Format") => decode_rtf_header(data),
+        _ => None,
+    }
+}
+
+fn decode_bitmap_header(data: &[u8]) -> Option<Fields> {
+    let size = u32_at(data, 0)?;
+    let compression = u32_at(data, 16)?;
+    let compression_name = match compression {
+        0 => "BI_RGB",
+        1 => "BI_RLE8",
+        2 => "BI_RLE4",
+        3 => "BI_BITFIELDS",
+        4 => "BI_JPEG",
+        5 => "BI_PNG",
+        _ => "unknown",
+    };
+    let height = i32_at(data, 8)?;
+    let mut fields = vec![
+        ("biSize".to_string(), format!("{} ({})", size, match size {
+            40 => "BITMAPINFOHEADER",
+            108 => "BITMAPV4HEADER",
+            124 => "BITMAPV5HEADER",
+            _ => "unknown",
+        })),
+        ("biWidth".to_string(), i32_at(data, 4)?.to_string()),
+        ("biHeight".to_string(), format!("{} ({})", height, if height < 0 { "top-down" } else { "bottom-up" })),
+        ("biPlanes".to_string(), u16_at(data, 12)?.to_string()),
+        ("biBitCount".to_string(), u16_at(data, 14)?.to_string()),
+        ("biCompression".to_string(), format!("{} ({})", compression, compression_name)),
+        ("biSizeImage".to_string(), u32_at(data, 20)?.to_string()),
+        ("biXPelsPerMeter".to_string(), i32_at(data, 24)?.to_string()),
+        ("biYPelsPerMeter".to_string(), i32_at(data, 28)?.to_string()),
+        ("biClrUsed".to_string(), u32_at(data, 32)?.to_string()),
+        ("biClrImportant".to_string(), u32_at(data, 36)?.to_string()),
+    ];
+    if size >= 108 {
+        for (name, at) in [("RedMask", 40), ("GreenMask", 44), ("BlueMask", 48), ("AlphaMask", 52)] {
+            fields.push((name.to_string(), format!("{:#010X}", u32_at(data, at)?)));
+        }
+        let cs_type = u32_at(data, 56)?;
+        let cs_name = String::from_utf8_lossy(&cs_type.to_be_bytes()).into_owned();
+        fields.push(("CSType".to_string(), format!("{:#010X} ('{}')", cs_type, cs_name)));
+    }
+    Some(fields)
+}
+
+fn decode_dropfiles(data: &[u8]) -> Option<Fields> {
+    let files_offset = u32_at(data, 0)?;
+    let wide = u32_at(data, 16)? != 0;
+    let mut fields = vec![
+        ("pFiles".to_string(), files_offset.to_string()),
+        ("pt".to_string(), format!("({}, {})", i32_at(data, 4)?, i32_at(data, 8)?)),
+        ("fNC".to_string(), (u32_at(data, 12)? != 0).to_string()),
+        ("fWide".to_string(), wide.to_string()),
+    ];
+    for (i, path) in dropfiles_paths(data)?.into_iter().enumerate() {
+        fields.push((format!("file[{}]", i), path));
+    }
+    Some(fields)
+}
+
+/// The double-NUL-terminated path list that follows a DROPFILES header.
+pub fn dropfiles_paths(data: &[u8]) -> Option<Vec<String>> {
+    let start = u32_at(data, 0)? as usize;
+    let wide = u32_at(data, 16)? != 0;
+    let list = data.get(start..)?;
+    let text = if wide {
+        let units: Vec<u16> = list.chunks_exact(2).map(|c| u16::from_le_bytes([c[0], c[1]])).collect();
+        String::from_utf16_lossy(&units)
+    } else {
+        String::from_utf8_lossy(list).into_owned()
+    };
+    Some(
+        text.split('\0')
+            .take_while(|p| !p.is_empty())
+            .map(str::to_string)
+            .collect(),
+    )
+}
+
+/// The `Key:Value` lines CF_HTML puts before the markup.
+fn decode_cf_html(data: &[u8]) -> Option<Fields> {
+    let text = String::from_utf8_lossy(data);
+    let mut fields: Fields = text
+        .lines()
+        .take_while(|l| !l.trim_start().starts_with('<'))
+        .filter_map(|l| l.split_once(':'))
+        .map(|(k, v)| (k.trim().to_string(), v.trim().to_string()))
+        .collect();
+    if fields.is_empty() {
+        return None;
+    }
+    let offset = |key: &str| {
+        fields
+            .iter()
+            .find(|(k, _)| k == key)
+            .and_then(|(_, v)| v.parse::<usize>().ok())
+    };
+    if let (Some(start), Some(end)) = (offset("StartFragment"), offset("EndFragment")) {
+        if let Some(fragment) = data.get(start..end.min(data.len())) {
+            let fragment = String::from_utf8_lossy(fragment);
+            let preview: String = fragment.chars().take(200).collect();
+            fields.push(("fragment".to_string(), preview));
+        }
+    }
+    Some(fields)
+}
+
+/// Control words from the RTF preamble: version, charset, code page, default font, generator.
+fn decode_rtf_header(data: &[u8]) -> Option<Fields> {
+    let head = String::from_utf8_lossy(&data[..data.len().min(4096)]).into_owned();
+    let rest = head.strip_prefix("{\\rtf")?;
+    let version: String = rest.chars().take_while(|c| c.is_ascii_digit()).collect();
+    let mut fields = vec![("version".to_string(), version)];
+
+    let word_value = |word: &str| {
+        head.match_indices(word).find_map(|(at, _)| {
+            let tail = &head[at + word.len()..];
+            let n: String = tail.chars().take_while(|c| c.is_ascii_digit() || *c == '-').collect();
+            (!n.is_empty()).then_some(n)
+        })
+    };
+    for charset in ["\\ansi", "\\mac", "\\pca", "\\pc"] {
+        if head.contains(&format!("{}\\", charset)) || head.contains(&format!("{} ", charset)) {
+            fields.push(("charset".to_string(), charset[1..].to_string()));
+            break;
+        }
+    }
+    if let Some(cp) = word_value("\\ansicpg") {
+        fields.push(("ansicpg".to_string(), cp));
+    }
+    if let Some(font) = word_value("\\deff") {
+        fields.push(("deff".to_string(), font));
+    }
+    if let Some(at) = head.find("{\\*\\generator") {
+        let tail = &head[at + "{\\*\\generator".len()..];
+        let generator = tail.split('}').next().unwrap_or("").trim().trim_end_matches(';');
+        fields.push(("generator".to_string(), generator.to_string()));
+    }
+    Some(fields)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn field<'a>(fields: &'a Fields, name: &str) -> &'a str {
+        fields.iter().find(|(k, _)| k == name).map(|(_, v)| v.as_str()).unwrap_or_else(|| panic!("no {}", name))
+    }
+
+    fn bitmap_header(size: u32, height: i32, compression: u32) -> Vec<u8> {
+        let mut data = Vec::new();
+        data.extend_from_slice(&size.to_le_bytes());
+        data.extend_from_slice(&640i32.to_le_bytes());
+        data.extend_from_slice(&height.to_le_bytes());
+        data.extend_from_slice(&1u16.to_le_bytes());
+        data.extend_from_slice(&32u16.to_le_bytes());
+        data.extend_from_slice(&compression.to_le_bytes());
+        data.resize(size as usize, 0);
+        data
+    }
+
+    fn dropfiles(wide: bool, paths: &[&str]) -> Vec<u8> {
+        let mut data = Vec::new();
+        data.extend_from_slice(&20u32.to_le_bytes());
+        data.extend_from_slice(&5i32.to_le_bytes());
+        data.extend_from_slice(&(-7i32).to_le_bytes());
+        data.extend_from_slice(&0u32.to_le_bytes());
+        data.extend_from_slice(&(wide as u32).to_le_bytes());
+        let list: String = paths.iter().map(|p| format!("{}\0", p)).chain(["\0".to_string()]).collect();
+        if wide {
+            data.extend(list.encode_utf16().flat_map(u16::to_le_bytes));
+        } else {
+            data.extend_from_slice(list.as_bytes());
+        }
+        data
+    }
+
+    #[test]
+    fn hex_lines_and_search() {
+        let data: Vec<u8> = (0x41..0x41 + 20).collect();
+        assert_eq!(
+            hex_line(&data, 0),
+            "00000000  41 42 43 44 45 46 47 48  49 4A 4B 4C 4D 4E 4F 50  |ABCDEFGHIJKLMNOP|"
+        );
+        // A short last row is padded so the ASCII column lines up
+        assert_eq!(hex_line(&data, 16).len(), hex_line(&data, 0).len() - 12);
+        assert!(hex_line(&data, 16).starts_with("00000010  51 52 53 54    "));
+        assert!(hex_line(&data, 16).ends_with("|QRST|"));
+        assert_eq!(parse_offset("0x1F0"), Some(0x1F0));
+        assert_eq!(parse_offset(" 42 "), Some(42));
+        assert_eq!(parse_offset("0xZZ"), None);
+        assert_eq!(parse_needle("de ad BE ef"), Ok(vec![0xDE, 0xAD, 0xBE, 0xEF]));
+        assert_eq!(parse_needle("\"MZ\""), Ok(b"MZ".to_vec()));
+        assert!(parse_needle("abc").is_err());
+        assert!(parse_needle("zz").unwrap_err().contains("'zz' is not hex"));
+        assert!(parse_needle("\"\"").is_err());
+        assert_eq!(find_bytes(b"abcabc", b"bc", 0), Some(1));
+        assert_eq!(find_bytes(b"abcabc", b"bc", 2), Some(4));
+        // Wraps around past the end
+        assert_eq!(find_bytes(b"abcabc", b"ab", 4), Some(0));
+        assert_eq!(find_bytes(b"ab", b"abc", 0), None);
+    }
+
+    #[test]
+    fn bitmap_headers() {
+        let fields = decode_structure(8, "CF_DIB", &bitmap_header(40, -480, 3)).unwrap();
+        assert_eq!(field(&fields, "biSize"), "40 (BITMAPINFOHEADER)");
+        assert_eq!(field(&fields, "biWidth"), "640");
+        assert_eq!(field(&fields, "biHeight"), "-480 (top-down)");
+        assert_eq!(field(&fields, "biBitCount"), "32");
+        assert_eq!(field(&fields, "biCompression"), "3 (BI_BITFIELDS)");
+        assert!(!fields.iter().any(|(k, _)| k == "RedMask"));
+
+        let mut v5 = bitmap_header(124, 480, 0);
+        v5[40..44].copy_from_slice(&0x00FF0000u32.to_le_bytes());
+        v5[56..60].copy_from_slice(&u32::from_be_bytes(*b"sRGB").to_le_bytes());
+        let fields = decode_structure(17, "CF_DIBV5", &v5).unwrap();
+        assert_eq!(field(&fields, "biSize"), "124 (BITMAPV5HEADER)");
+        assert_eq!(field(&fields, "biHeight"), "480 (bottom-up)");
+        assert_eq!(field(&fields, "RedMask"), "0x00FF0000");
+        assert_eq!(field(&fields, "CSType"), "0x73524742 ('sRGB')");
+
+        // Truncated headers decode to nothing rather than reading past the end
+        assert!(decode_structure(8, "CF_DIB", &bitmap_header(40, 1, 0)[..39]).is_none());
+        assert!(decode_structure(17, "CF_DIBV5", &v5[..50]).is_none());
+        assert!(decode_structure(8, "CF_DIB", &[]).is_none());
+    }
+
+    #[test]
+    fn dropfiles_lists() {
+        let paths = [r"C:\Users\me\ünïcode.txt", r"D:\b.png"];
+        for wide in [true, false] {
+            let data = dropfiles(wide, &paths);
+            assert_eq!(dropfiles_paths(&data).unwrap(), paths);
+            let fields = decode_structure(15, "CF_HDROP", &data).unwrap();
+            assert_eq!(field(&fields, "pFiles"), "20");
+            assert_eq!(field(&fields, "pt"), "(5, -7)");
+            assert_eq!(field(&fields, "fWide"), wide.to_string());
+            assert_eq!(field(&fields, "file[1]"), r"D:\b.png");
+        }
+
+        // A list cut off before its terminator still yields what's there
+        let data = dropfiles(true, &paths);
+        assert_eq!(dropfiles_paths(&data[..data.len() - 5]).unwrap().len(), 2);
+        // An odd trailing byte in a wide list is dropped
+        let mut odd = dropfiles(true, &["a"]);
+        odd.push(0x41);
+        assert_eq!(dropfiles_paths(&odd).unwrap(), ["a"]);
+        // pFiles past the end, or a header too short to hold fWide
+        let mut bad_offset = data.clone();
+        bad_offset[..4].copy_from_slice(&4096u32.to_le_bytes());
+        assert!(dropfiles_paths(&bad_offset).is_none());
+        assert!(decode_structure(15, "CF_HDROP", &data[..12]).is_none());
+    }
+
+    #[test]
+    fn cf_html_headers() {
+        let markup = "<html><body><!--StartFragment--><b>hi</b><!--EndFragment--></body></html>";
+        let header = "Version:0.9\r\nStartHTML:0000000105\r\nEndHTML:0000000000\r\nStartFragment:0000000000\r\nEndFragment:0000000000\r\n";
+        let start = header.len() + markup.find("<b>").unwrap();
+        let end = header.len() + markup.find("<!--End").unwrap();
+        let header = header
+            .replace("StartFragment:0000000000", &format!("StartFragment:{:010}", start))
+            .replace("EndFragment:0000000000", &format!("EndFragment:{:010}", end));
+        let data = format!("{}{}", header, markup).into_bytes();
+        let fields = decode_structure(0xC0DE, "HTML Format", &data).unwrap();
+        assert_eq!(field(&fields, "Version"), "0.9");
+        assert_eq!(field(&fields, "StartHTML"), "0000000105");
+        assert_eq!(field(&fields, "fragment"), "<b>hi</b>");
+
+        // Offsets past the end or reversed leave the fragment out instead of panicking
+        let broken = "Version:0.9\r\nStartFragment:500\r\nEndFragment:20\r\n<b>x</b>";
+        let fields = decode_structure(0xC0DE, "HTML Format", broken.as_bytes()).unwrap();
+        assert!(!fields.iter().any(|(k, _)| k == "fragment"));
+        let past_end = "StartFragment:10\r\nEndFragment:9999\r\n<b>x</b>";
+        let fields = decode_structure(0xC0DE, "HTML Format", past_end.as_bytes()).unwrap();
+        assert!(field(&fields, "fragment").ends_with("<b>x</b>"));
+        // No header at all
+        assert!(decode_structure(0xC0DE, "HTML Format", b"<b>plain</b>").is_none());
+    }
+
+    #[test]
+    fn rtf_preamble() {
+        let rtf = br"{\rtf1\ansi\ansicpg1252\deff0{\fonttbl{\f0 Calibri;}}{\*\generator Riched20 10.0.19041;}hello}";
+        let fields = decode_structure(0xC0DF, "Rich Text Format", rtf).unwrap();
+        assert_eq!(field(&fields, "version"), "1");
+        assert_eq!(field(&fields, "charset"), "ansi");
+        assert_eq!(field(&fields, "ansicpg"), "1252");
+        assert_eq!(field(&fields, "deff"), "0");
+        assert_eq!(field(&fields, "generator"), "Riched20 10.0.19041");
+
+        // Just the opening group: a version and nothing else
+        let fields = decode_structure(0xC0DF, "Rich Text Format", br"{\rtf").unwrap();
+        assert_eq!(fields, vec![("version".to_string(), String::new())]);
+        // Invalid UTF-8 and a cut-off generator group don't panic
+        let fields = decode_structure(0xC0DF, "Rich Text Format", b"{\\rtf1\\mac \xff\xfe{\\*\\generator Word").unwrap();
+        assert_eq!(field(&fields, "charset"), "mac");
+        assert_eq!(field(&fields, "generator"), "Word");
+        assert!(decode_structure(0xC0DF, "Rich Text Format", b"plain text").is_none());
+        assert!(decode_structure(1, "CF_TEXT", b"{\\rtf1}").is_none());
+    }
+}
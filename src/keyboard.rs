@@ -0,0 +1,61 @@
+use windows::Win32::UI::Input::KeyboardAndMouse::{
+    GetAsyncKeyState, SendInput, INPUT, INPUT_0, INPUT_KEYBOARD, KEYBDINPUT, KEYBD_EVENT_FLAGS,
+    KEYEVENTF_KEYUP, VIRTUAL_KEY, VK_C, VK_CONTROL, VK_LWIN, VK_MENU, VK_RWIN, VK_SHIFT,
+};
+
+fn key_input(vk: VIRTUAL_KEY, up: bool) -> INPUT {
+    INPUT {
+        r#type: INPUT_KEYBOARD,
+        Anonymous: INPUT_0 {
+            ki: KEYBDINPUT {
+                wVk: vk,
+                wScan: 0,
+                dwFlags: if up { KEYEVENTF_KEYUP } else { KEYBD_EVENT_FLAGS(0) },
+                time: 0,
+                dwExtraInfo: 0,
+            },
+        },
+    }
+}
+
+/// Lifts any modifier the user is still holding from the hotkey that got us here,
+/// otherwise a synthesized Ctrl+V arrives as Ctrl+Alt+V.
+unsafe fn release_modifiers() {
+    let held: Vec<INPUT> = [VK_CONTROL, VK_MENU, VK_SHIFT, VK_LWIN, VK_RWIN]
+        .into_iter()
+        .filter(|vk| GetAsyncKeyState(vk.0 as i32) as u16 & 0x8000 != 0)
+        .map(|vk| key_input(vk, true))
+        .collect();
+    if !held.is_empty() {
+        SendInput(&held, std::mem::size_of::<INPUT>() as i32);
+    }
+}
+
+/// Presses `modifiers` + `key` and releases them in reverse order.
+pub fn send_chord(modifiers: &[VIRTUAL_KEY], key: VIRTUAL_KEY) {
+    let mut inputs: Vec<INPUT> = modifiers.iter().map(|&vk| key_input(vk, false)).collect();
+    inputs.push(key_input(key, false));
+    inputs.push(key_input(key, true));
+    inputs.extend(modifiers.iter().rev().map(|&vk| key_input(vk, true)));
+    unsafe {
+        release_modifiers();
+        SendInput(&inputs, std::mem::size_of::<INPUT>() as i32);
+    }
+}
+
+/// Sends a chord given as RegisterHotKey-style modifier bits plus a virtual-key
+/// code. X11 has its own in `x11keyboard`.
+#[cfg(not(all(unix, not(target_os = "macos"))))]
+pub fn send_combo(modifiers: u32, vk: u32) {
+    use crate::hotkeys::{MOD_ALT, MOD_CONTROL, MOD_SHIFT, MOD_WIN};
+    let mut held = Vec::new();
+    if modifiers & MOD_CONTROL != 0 { held.push(VK_CONTROL); }
+    if modifiers & MOD_ALT != 0 { held.push(VK_MENU); }
+    if modifiers & MOD_SHIFT != 0 { held.push(VK_SHIFT); }
+    if modifiers & MOD_WIN != 0 { held.push(VK_LWIN); }
+    send_chord(&held, VIRTUAL_KEY(vk as u16));
+}
+
+pub fn send_copy() {
+    send_chord(&[VK_CONTROL], VK_C);
+}
@@ -0,0 +1,764 @@
+//! Sending clips straight to another openclip on the local network.
+//!
+//! Instances advertise themselves over mDNS as `_openclip._tcp`. Two of them
+//! pair once: one shows a short code, the other types it in, and a SPAKE2
+//! exchange over that code (see `pairing`) lets them swap long-term X25519
+//! keys without trusting the network. After that either can offer the other
+//! a clip, and the receiver's user accepts or declines it.
+//!
+//! A connection is a short exchange of frames, each a `u32` length and a
+//! JSON header followed by a `u64` length and a body, big-endian. The
+//! listening side speaks first, with a hello carrying a fresh nonce. A clip
+//! travels as its `encode_payloads` blob sealed with XChaCha20-Poly1305,
+//! under a key from both devices' static keys, an ephemeral key of the sender
+//! and that nonce: only the paired sender can have made it, and it can't be
+//! replayed on a later connection.
+//!
+//! Two paired devices can also link their clipboards. A live clip skips the
+//! question and is taken straight away, but only from the one peer this
+//! device has linked to; everyone else is refused. Whether a clip is live is
+//! sealed along with it, so an offer can't be passed off as one.
+
+use std::collections::HashMap;
+use std::fmt;
+use std::io::{self, Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::mpsc::{channel, Sender};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use chacha20poly1305::aead::rand_core::RngCore;
+use chacha20poly1305::aead::OsRng;
+use mdns_sd::{ServiceDaemon, ServiceEvent, ServiceInfo};
+use serde::{Deserialize, Serialize};
+use x25519_dalek::{EphemeralSecret, PublicKey};
+
+use crate::cloudcrypto::{from_hex, open_with, seal_with, to_hex, DeviceKey};
+use crate::cloudstorage::{decode_payloads, encode_payloads};
+use crate::models::{ClipboardPayload, Peer};
+use crate::pairing::{self, Pairing, Role};
+
+pub const SERVICE_TYPE: &str = "_openclip._tcp.local.";
+/// How long a shown pairing code stays usable.
+pub const PAIRING_WINDOW: Duration = Duration::from_secs(120);
+/// How long an offer waits for the receiving user before it counts as declined.
+pub const OFFER_TIMEOUT: Duration = Duration::from_secs(120);
+/// Largest clip, encoded, that a peer may send.
+pub const MAX_CLIP_SIZE: u64 = 64 << 20;
+const MAX_HEADER_SIZE: u32 = 64 << 10;
+/// Longest wait for the other side in the middle of an exchange.
+const IO_TIMEOUT: Duration = Duration::from_secs(30);
+
+#[derive(Debug)]
+pub enum LanError {
+    Io(io::Error),
+    /// The other side broke the protocol or sent something that didn't open.
+    Protocol(String),
+    /// Turned away, and why.
+    Refused(String),
+    WrongCode,
+    NotPaired,
+    TooLarge,
+    Discovery(String),
+}
+
+impl fmt::Display for LanError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LanError::Io(e) => write!(f, "{}", e),
+            LanError::Protocol(msg) => write!(f, "bad reply from the other device: {}", msg),
+            LanError::Refused(msg) => write!(f, "the other device refused: {}", msg),
+            LanError::WrongCode => write!(f, "wrong pairing code"),
+            LanError::NotPaired => write!(f, "not paired with that device"),
+            LanError::TooLarge => write!(f, "clip is larger than {} MiB", MAX_CLIP_SIZE >> 20),
+            LanError::Discovery(e) => write!(f, "mDNS: {}", e),
+        }
+    }
+}
+
+impl From<io::Error> for LanError {
+    fn from(e: io::Error) -> Self {
+        LanError::Io(e)
+    }
+}
+
+/// Things the user should hear about.
+pub enum LanEvent {
+    Paired(Peer),
+    Offer(Offer),
+    /// From the linked peer, already taken.
+    Live(Peer, SentClip),
+}
+
+/// A clip a peer wants to hand over, waiting on `Node::answer`.
+pub struct Offer {
+    pub id: u64,
+    pub from: Peer,
+    pub clip: SentClip,
+}
+
+pub struct SentClip {
+    /// The sender's content hash, which one of the payloads hashes to.
+    pub hash: String,
+    pub owner: String,
+    pub title: String,
+    pub payloads: Vec<ClipboardPayload>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct ClipInfo {
+    /// Missing from older senders, whose first payload is the hashed one.
+    #[serde(default)]
+    hash: String,
+    owner: String,
+    title: String,
+}
+
+/// A clip on its way, with the payloads in the frame body.
+#[derive(Serialize, Deserialize)]
+struct SealedClip {
+    device: String,
+    /// Hex keys, and `ClipInfo` sealed and hex.
+    ephemeral: String,
+    info: String,
+    /// For the linked clipboard rather than the user. Part of the associated
+    /// data of both sealed parts, so it can't be flipped on the way.
+    #[serde(default)]
+    live: bool,
+}
+
+/// Keys and points are hex; `identity` is sealed JSON, hex.
+#[derive(Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum Frame {
+    Hello { device: String, nonce: String },
+    Pair { device: String, message: String },
+    PairReply { message: String, confirm: String },
+    PairConfirm { confirm: String, identity: String },
+    PairDone { identity: String },
+    Offer(SealedClip),
+    Answer { accepted: bool },
+    Error { message: String },
+}
+
+/// This device's end: listens for pairings and offers, and makes its own.
+pub struct Node {
+    shared: Arc<Shared>,
+    addr: SocketAddr,
+}
+
+struct Shared {
+    key: DeviceKey,
+    name: String,
+    peers: Mutex<Vec<Peer>>,
+    /// The code on screen and when it stops working.
+    pairing: Mutex<Option<(String, Instant)>>,
+    /// The peer whose live clips are taken.
+    linked: Mutex<Option<String>>,
+    answers: Mutex<HashMap<u64, Sender<bool>>>,
+    next_offer: AtomicU64,
+    events: Sender<LanEvent>,
+}
+
+impl Node {
+    /// Listens on `addr` (port 0 picks a free one) and reports to `events`.
+    pub fn start(addr: &str, key: DeviceKey, name: &str, peers: Vec<Peer>, events: Sender<LanEvent>) -> io::Result<Node> {
+        let listener = TcpListener::bind(addr)?;
+        let addr = listener.local_addr()?;
+        let shared = Arc::new(Shared {
+            key,
+            name: name.to_string(),
+            peers: Mutex::new(peers),
+            pairing: Mutex::new(None),
+            linked: Mutex::new(None),
+            answers: Mutex::new(HashMap::new()),
+            next_offer: AtomicU64::new(1),
+            events,
+        });
+        let accepting = shared.clone();
+        std::thread::spawn(move || {
+            for stream in listener.incoming().flatten() {
+                let shared = accepting.clone();
+                std::thread::spawn(move || shared.serve(stream));
+            }
+        });
+        Ok(Node { shared, addr })
+    }
+
+    pub fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    pub fn id(&self) -> &str {
+        &self.shared.key.id
+    }
+
+    pub fn peers(&self) -> Vec<Peer> {
+        self.shared.peers.lock().unwrap().clone()
+    }
+
+    /// Accepts one pairing attempt with a fresh code for the user to read out.
+    pub fn show_code(&self) -> String {
+        let code = pairing::new_code();
+        *self.shared.pairing.lock().unwrap() = Some((code.clone(), Instant::now() + PAIRING_WINDOW));
+        code
+    }
+
+    /// The code on screen, until it's used or runs out.
+    pub fn shown_code(&self) -> Option<String> {
+        let mut pairing = self.shared.pairing.lock().unwrap();
+        if pairing.as_ref().is_some_and(|(_, until)| Instant::now() >= *until) {
+            *pairing = None;
+        }
+        pairing.as_ref().map(|(code, _)| code.clone())
+    }
+
+    pub fn hide_code(&self) {
+        *self.shared.pairing.lock().unwrap() = None;
+    }
+
+    pub fn forget(&self, id: &str) {
+        self.shared.peers.lock().unwrap().retain(|p| p.id != id);
+        let mut linked = self.shared.linked.lock().unwrap();
+        if linked.as_deref() == Some(id) {
+            *linked = None;
+        }
+    }
+
+    /// Takes live clips from this peer only, or from nobody.
+    pub fn link(&self, id: Option<&str>) {
+        *self.shared.linked.lock().unwrap() = id.map(str::to_string);
+    }
+
+    pub fn linked(&self) -> Option<String> {
+        self.shared.linked.lock().unwrap().clone()
+    }
+
+    /// Answers an offer. False if it already timed out.
+    pub fn answer(&self, offer: u64, accept: bool) -> bool {
+        match self.shared.answers.lock().unwrap().remove(&offer) {
+            Some(tx) => tx.send(accept).is_ok(),
+            None => false,
+        }
+    }
+
+    /// Pairs with the device at `addr` using the code it shows.
+    pub fn pair(&self, addr: SocketAddr, code: &str) -> Result<Peer, LanError> {
+        let mut stream = connect(addr)?;
+        let Frame::Hello { device, .. } = read_frame(&mut stream)?.0 else { return Err(unexpected()) };
+        let pairing = Pairing::start(Role::Initiator, code);
+        let message = to_hex(&pairing.message());
+        write_frame(&mut stream, &Frame::Pair { device: self.shared.key.id.clone(), message }, &[])?;
+        let Frame::PairReply { message, confirm } = read_frame(&mut stream)?.0 else { return Err(unexpected()) };
+        let key = pairing.finish(&decode_key(&message)?).ok_or_else(|| LanError::Protocol("bad pairing message".to_string()))?;
+        if parse_hash(&confirm) != Some(pairing::confirmation(&key, Role::Responder)) {
+            let _ = write_frame(&mut stream, &Frame::Error { message: LanError::WrongCode.to_string() }, &[]);
+            return Err(LanError::WrongCode);
+        }
+        let confirm = pairing::confirmation(&key, Role::Initiator).to_hex().to_string();
+        let identity = seal_identity(&key, Role::Initiator, &self.shared.identity());
+        write_frame(&mut stream, &Frame::PairConfirm { confirm, identity }, &[])?;
+        let Frame::PairDone { identity } = read_frame(&mut stream)?.0 else { return Err(unexpected()) };
+        let peer = open_identity(&key, Role::Responder, &identity)?;
+        if peer.id != device {
+            return Err(LanError::Protocol("identity doesn't match the device".to_string()));
+        }
+        self.shared.paired(peer.clone());
+        Ok(peer)
+    }
+
+    /// Offers `clip` to a paired device at `addr`. True once its user
+    /// accepts, false if they decline or don't answer in time.
+    pub fn send(&self, peer: &Peer, addr: SocketAddr, clip: &SentClip) -> Result<bool, LanError> {
+        self.deliver(peer, addr, clip, false)
+    }
+
+    /// Hands `clip` to the linked clipboard of a device at `addr`. Refused
+    /// unless that device has linked back to this one.
+    pub fn push(&self, peer: &Peer, addr: SocketAddr, clip: &SentClip) -> Result<(), LanError> {
+        match self.deliver(peer, addr, clip, true)? {
+            true => Ok(()),
+            false => Err(unexpected()),
+        }
+    }
+
+    fn deliver(&self, peer: &Peer, addr: SocketAddr, clip: &SentClip, live: bool) -> Result<bool, LanError> {
+        let blob = encode_payloads(&clip.payloads);
+        if blob.len() as u64 > MAX_CLIP_SIZE {
+            return Err(LanError::TooLarge);
+        }
+        let public = decode_key(&peer.public_key)?;
+        let mut stream = connect(addr)?;
+        let Frame::Hello { device, nonce } = read_frame(&mut stream)?.0 else { return Err(unexpected()) };
+        if device != peer.id {
+            return Err(LanError::Protocol(format!("{} answered instead of {}", device, peer.name)));
+        }
+        let nonce = from_hex(&nonce).ok_or_else(unexpected)?;
+        let ephemeral = EphemeralSecret::random_from_rng(OsRng);
+        let ephemeral_public = PublicKey::from(&ephemeral).to_bytes();
+        let key = clip_key(
+            self.shared.key.agree(public),
+            ephemeral.diffie_hellman(&PublicKey::from(public)).to_bytes(),
+            &ephemeral_public,
+            &nonce,
+            &self.shared.key.id,
+            &peer.id,
+        );
+        let info = serde_json::to_vec(&ClipInfo { hash: clip.hash.clone(), owner: clip.owner.clone(), title: clip.title.clone() }).expect("clip info serializes");
+        let offer = Frame::Offer(SealedClip {
+            device: self.shared.key.id.clone(),
+            ephemeral: to_hex(&ephemeral_public),
+            info: to_hex(&seal_with(&key, &clip_aad("info", live), &info)),
+            live,
+        });
+        write_frame(&mut stream, &offer, &seal_with(&key, &clip_aad("clip", live), &blob))?;
+        if !live {
+            stream.set_read_timeout(Some(OFFER_TIMEOUT + IO_TIMEOUT))?;
+        }
+        let Frame::Answer { accepted } = read_frame(&mut stream)?.0 else { return Err(unexpected()) };
+        Ok(accepted)
+    }
+}
+
+impl Shared {
+    fn identity(&self) -> Peer {
+        Peer { id: self.key.id.clone(), name: self.name.clone(), public_key: self.key.public_key() }
+    }
+
+    fn paired(&self, peer: Peer) {
+        let mut peers = self.peers.lock().unwrap();
+        peers.retain(|p| p.id != peer.id);
+        peers.push(peer.clone());
+        drop(peers);
+        let _ = self.events.send(LanEvent::Paired(peer));
+    }
+
+    fn serve(&self, mut stream: TcpStream) {
+        if let Err(e) = self.exchange(&mut stream) {
+            let message = match &e {
+                // Turned away on purpose, nothing to log
+                LanError::Refused(message) => message.clone(),
+                LanError::WrongCode | LanError::NotPaired => e.to_string(),
+                e => {
+                    eprintln!("LAN connection from {:?}: {}", stream.peer_addr().ok(), e);
+                    e.to_string()
+                }
+            };
+            let _ = write_frame(&mut stream, &Frame::Error { message }, &[]);
+        }
+    }
+
+    fn exchange(&self, stream: &mut TcpStream) -> Result<(), LanError> {
+        stream.set_read_timeout(Some(IO_TIMEOUT))?;
+        let mut nonce = [0u8; 32];
+        OsRng.fill_bytes(&mut nonce);
+        write_frame(stream, &Frame::Hello { device: self.key.id.clone(), nonce: to_hex(&nonce) }, &[])?;
+        match read_frame(stream)? {
+            (Frame::Pair { device, message }, _) => self.accept_pairing(stream, &device, &message),
+            (Frame::Offer(sealed), body) => self.receive(stream, &nonce, sealed, &body),
+            _ => Err(unexpected()),
+        }
+    }
+
+    fn accept_pairing(&self, stream: &mut TcpStream, device: &str, message: &str) -> Result<(), LanError> {
+        // One guess per code: after a wrong one the user has to show a new code
+        let code = match self.pairing.lock().unwrap().take() {
+            Some((code, until)) if Instant::now() < until => code,
+            _ => return Err(LanError::Refused("not pairing; show a code on this device first".to_string())),
+        };
+        let pairing = Pairing::start(Role::Responder, &code);
+        let ours = to_hex(&pairing.message());
+        let key = pairing.finish(&decode_key(message)?).ok_or_else(|| LanError::Protocol("bad pairing message".to_string()))?;
+        let confirm = pairing::confirmation(&key, Role::Responder).to_hex().to_string();
+        write_frame(stream, &Frame::PairReply { message: ours, confirm }, &[])?;
+        let Frame::PairConfirm { confirm, identity } = read_frame(stream)?.0 else { return Err(unexpected()) };
+        if parse_hash(&confirm) != Some(pairing::confirmation(&key, Role::Initiator)) {
+            return Err(LanError::WrongCode);
+        }
+        let peer = open_identity(&key, Role::Initiator, &identity)?;
+        if peer.id != device {
+            return Err(LanError::Protocol("identity doesn't match the device".to_string()));
+        }
+        write_frame(stream, &Frame::PairDone { identity: seal_identity(&key, Role::Responder, &self.identity()) }, &[])?;
+        self.paired(peer);
+        Ok(())
+    }
+
+    fn receive(&self, stream: &mut TcpStream, nonce: &[u8], sealed: SealedClip, body: &[u8]) -> Result<(), LanError> {
+        let from = self.peers.lock().unwrap().iter().find(|p| p.id == sealed.device).cloned().ok_or(LanError::NotPaired)?;
+        if sealed.live && self.linked.lock().unwrap().as_deref() != Some(from.id.as_str()) {
+            return Err(LanError::Refused(format!("{} hasn't linked its clipboard with this device", self.name)));
+        }
+        let ephemeral = decode_key(&sealed.ephemeral)?;
+        let key = clip_key(
+            self.key.agree(decode_key(&from.public_key)?),
+            self.key.agree(ephemeral),
+            &ephemeral,
+            nonce,
+            &sealed.device,
+            &self.key.id,
+        );
+        let garbled = |_| LanError::Protocol("the clip didn't decrypt".to_string());
+        let info = open_with(&key, &clip_aad("info", sealed.live), &from_hex(&sealed.info).ok_or_else(unexpected)?).map_err(garbled)?;
+        let info: ClipInfo = serde_json::from_slice(&info).map_err(|e| LanError::Protocol(e.to_string()))?;
+        let blob = open_with(&key, &clip_aad("clip", sealed.live), body).map_err(garbled)?;
+        let payloads = decode_payloads(&blob).map_err(|e| LanError::Protocol(e.to_string()))?;
+        if payloads.is_empty() {
+            return Err(LanError::Protocol("empty clip".to_string()));
+        }
+        let hash = match info.hash {
+            hash if hash.is_empty() => blake3::hash(&payloads[0].data).to_hex().to_string(),
+            hash if payloads.iter().any(|p| blake3::hash(&p.data).to_hex().as_str() == hash) => hash,
+            _ => return Err(LanError::Protocol("the clip doesn't match its hash".to_string())),
+        };
+
+        let clip = SentClip { hash, owner: info.owner, title: info.title, payloads };
+        if sealed.live {
+            let _ = self.events.send(LanEvent::Live(from, clip));
+            write_frame(stream, &Frame::Answer { accepted: true }, &[])?;
+            return Ok(());
+        }
+
+        let id = self.next_offer.fetch_add(1, Ordering::Relaxed);
+        let (tx, rx) = channel();
+        self.answers.lock().unwrap().insert(id, tx);
+        let _ = self.events.send(LanEvent::Offer(Offer { id, from, clip }));
+        let accepted = rx.recv_timeout(OFFER_TIMEOUT).unwrap_or(false);
+        self.answers.lock().unwrap().remove(&id);
+        write_frame(stream, &Frame::Answer { accepted }, &[])?;
+        Ok(())
+    }
+}
+
+/// Another openclip seen on the network.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Nearby {
+    pub id: String,
+    pub name: String,
+    pub addr: SocketAddr,
+}
+
+/// Advertises this device over mDNS and keeps track of the others.
+pub struct Discovery {
+    daemon: ServiceDaemon,
+    /// By mDNS instance name, which is what removals carry.
+    nearby: Arc<Mutex<HashMap<String, Nearby>>>,
+}
+
+impl Discovery {
+    /// `changed` runs on the browse thread whenever the list changes.
+    pub fn start(id: &str, name: &str, port: u16, changed: impl Fn() + Send + 'static) -> Result<Discovery, LanError> {
+        let mdns = |e: mdns_sd::Error| LanError::Discovery(e.to_string());
+        let daemon = ServiceDaemon::new().map_err(mdns)?;
+        let host = format!("openclip-{}.local.", id);
+        let properties = [("id", id), ("name", name)];
+        let service = ServiceInfo::new(SERVICE_TYPE, id, &host, "", port, &properties[..]).map_err(mdns)?.enable_addr_auto();
+        daemon.register(service).map_err(mdns)?;
+        let events = daemon.browse(SERVICE_TYPE).map_err(mdns)?;
+
+        let nearby = Arc::new(Mutex::new(HashMap::new()));
+        let found = nearby.clone();
+        let own = id.to_string();
+        std::thread::spawn(move || {
+            while let Ok(event) = events.recv() {
+                match event {
+                    ServiceEvent::ServiceResolved(info) => {
+                        let Some(id) = info.get_property_val_str("id").filter(|id| *id != own) else { continue };
+                        // Prefer IPv4, which doesn't need a scope id to connect
+                        let Some(ip) = info.get_addresses().iter().min_by_key(|ip| ip.is_ipv6()) else { continue };
+                        let device = Nearby {
+                            id: id.to_string(),
+                            name: info.get_property_val_str("name").unwrap_or(id).to_string(),
+                            addr: SocketAddr::new(*ip, info.get_port()),
+                        };
+                        found.lock().unwrap().insert(info.get_fullname().to_string(), device);
+                    }
+                    ServiceEvent::ServiceRemoved(_, fullname) => {
+                        found.lock().unwrap().remove(&fullname);
+                    }
+                    _ => continue,
+                }
+                changed();
+            }
+        });
+        Ok(Discovery { daemon, nearby })
+    }
+
+    /// By name, one entry per device.
+    pub fn nearby(&self) -> Vec<Nearby> {
+        let mut nearby: Vec<Nearby> = self.nearby.lock().unwrap().values().cloned().collect();
+        nearby.sort_by(|a, b| a.name.cmp(&b.name).then(a.id.cmp(&b.id)));
+        nearby.dedup_by(|a, b| a.id == b.id);
+        nearby
+    }
+}
+
+impl Drop for Discovery {
+    fn drop(&mut self) {
+        let _ = self.daemon.shutdown();
+    }
+}
+
+fn clip_key(static_shared: [u8; 32], ephemeral_shared: [u8; 32], ephemeral: &[u8; 32], nonce: &[u8], from: &str, to: &str) -> [u8; 32] {
+    let context = [&static_shared[..], &ephemeral_shared, ephemeral, nonce, from.as_bytes(), b"\0", to.as_bytes()].concat();
+    blake3::derive_key("openclip lan clip v1", &context)
+}
+
+/// Associated data for one part of a sealed clip.
+fn clip_aad(part: &str, live: bool) -> Vec<u8> {
+    [part.as_bytes(), if live { b" live" } else { b" offer" }].concat()
+}
+
+fn seal_identity(key: &[u8; 32], role: Role, peer: &Peer) -> String {
+    let json = serde_json::to_vec(peer).expect("peers serialize");
+    to_hex(&seal_with(key, format!("{:?} identity", role).as_bytes(), &json))
+}
+
+fn open_identity(key: &[u8; 32], role: Role, sealed: &str) -> Result<Peer, LanError> {
+    let sealed = from_hex(sealed).ok_or_else(unexpected)?;
+    let json = open_with(key, format!("{:?} identity", role).as_bytes(), &sealed)
+        .map_err(|_| LanError::Protocol("identity didn't decrypt".to_string()))?;
+    let peer: Peer = serde_json::from_slice(&json).map_err(|e| LanError::Protocol(e.to_string()))?;
+    decode_key(&peer.public_key)?;
+    Ok(peer)
+}
+
+fn decode_key(hex: &str) -> Result<[u8; 32], LanError> {
+    from_hex(hex).and_then(|k| k.try_into().ok()).ok_or_else(|| LanError::Protocol("bad key".to_string()))
+}
+
+fn parse_hash(hex: &str) -> Option<blake3::Hash> {
+    blake3::Hash::from_hex(hex).ok()
+}
+
+fn unexpected() -> LanError {
+    LanError::Protocol("unexpected message".to_string())
+}
+
+fn connect(addr: SocketAddr) -> io::Result<TcpStream> {
+    let stream = TcpStream::connect_timeout(&addr, IO_TIMEOUT)?;
+    stream.set_read_timeout(Some(IO_TIMEOUT))?;
+    Ok(stream)
+}
+
+fn write_frame(stream: &mut TcpStream, frame: &Frame, body: &[u8]) -> io::Result<()> {
+    let header = serde_json::to_vec(frame).expect("frames serialize");
+    let mut out = Vec::with_capacity(12 + header.len() + body.len());
+    out.extend_from_slice(&(header.len() as u32).to_be_bytes());
+    out.extend_from_slice(&header);
+    out.extend_from_slice(&(body.len() as u64).to_be_bytes());
+    out.extend_from_slice(body);
+    stream.write_all(&out)
+}
+
+/// An error frame comes back as `LanError::Refused`.
+fn read_frame(stream: &mut TcpStream) -> Result<(Frame, Vec<u8>), LanError> {
+    let mut len = [0u8; 4];
+    stream.read_exact(&mut len)?;
+    let len = u32::from_be_bytes(len);
+    if len > MAX_HEADER_SIZE {
+        return Err(LanError::Protocol("header too large".to_string()));
+    }
+    let mut header = vec![0u8; len as usize];
+    stream.read_exact(&mut header)?;
+    let frame: Frame = serde_json::from_slice(&header).map_err(|e| LanError::Protocol(e.to_string()))?;
+
+    let mut len = [0u8; 8];
+    stream.read_exact(&mut len)?;
+    let len = u64::from_be_bytes(len);
+    // Room for the nonce and tag around the largest clip
+    if len > MAX_CLIP_SIZE + 64 {
+        return Err(LanError::TooLarge);
+    }
+    let mut body = Vec::new();
+    stream.take(len).read_to_end(&mut body)?;
+    if body.len() as u64 != len {
+        return Err(LanError::Protocol("connection closed mid-frame".to_string()));
+    }
+    match frame {
+        Frame::Error { message } => Err(LanError::Refused(message)),
+        frame => Ok((frame, body)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::mpsc::Receiver;
+
+    fn node(name: &str) -> (Node, Receiver<LanEvent>) {
+        let (tx, rx) = channel();
+        let node = Node::start("127.0.0.1:0", DeviceKey::generate(name), name, Vec::new(), tx).unwrap();
+        (node, rx)
+    }
+
+    fn next_event(events: &Receiver<LanEvent>) -> LanEvent {
+        events.recv_timeout(Duration::from_secs(10)).expect("an event")
+    }
+
+    fn text_clip(text: &str) -> SentClip {
+        SentClip {
+            hash: blake3::hash(text.as_bytes()).to_hex().to_string(),
+            owner: "notepad.exe".to_string(),
+            title: "notes.txt".to_string(),
+            payloads: vec![ClipboardPayload { format_id: 13, format_name: "CF_UNICODETEXT".to_string(), data: text.as_bytes().to_vec() }],
+        }
+    }
+
+    fn paired() -> ((Node, Receiver<LanEvent>), (Node, Receiver<LanEvent>)) {
+        let (laptop, laptop_events) = node("laptop");
+        let (desktop, desktop_events) = node("desktop");
+        let code = desktop.show_code();
+        laptop.pair(desktop.addr(), &code).unwrap();
+        assert!(matches!(next_event(&laptop_events), LanEvent::Paired(_)));
+        assert!(matches!(next_event(&desktop_events), LanEvent::Paired(_)));
+        ((laptop, laptop_events), (desktop, desktop_events))
+    }
+
+    #[test]
+    fn pairing_with_a_code() {
+        let (laptop, _laptop_events) = node("laptop");
+        let (desktop, desktop_events) = node("desktop");
+
+        // Nothing to pair with until a code is shown
+        assert!(matches!(laptop.pair(desktop.addr(), "123456"), Err(LanError::Refused(_))));
+
+        let code = desktop.show_code();
+        let wrong = if code == "000000" { "000001" } else { "000000" };
+        assert!(matches!(laptop.pair(desktop.addr(), wrong), Err(LanError::WrongCode)));
+        // A wrong guess used the code up
+        assert_eq!(desktop.shown_code(), None);
+        assert!(laptop.pair(desktop.addr(), &code).is_err());
+        assert!(laptop.peers().is_empty() && desktop.peers().is_empty());
+
+        let code = desktop.show_code();
+        let peer = laptop.pair(desktop.addr(), &code).unwrap();
+        assert_eq!(peer.id, "desktop");
+        assert_eq!(peer.public_key, desktop.shared.key.public_key());
+        let LanEvent::Paired(peer) = next_event(&desktop_events) else { panic!("expected a pairing") };
+        assert_eq!((peer.id.as_str(), peer.name.as_str()), ("laptop", "laptop"));
+        assert_eq!(peer.public_key, laptop.shared.key.public_key());
+        assert_eq!(desktop.peers(), vec![peer]);
+        assert_eq!(desktop.shown_code(), None);
+    }
+
+    #[test]
+    fn accepted_and_declined_offers() {
+        let ((laptop, _), (desktop, desktop_events)) = paired();
+        let to_desktop = laptop.peers().pop().unwrap();
+        let addr = desktop.addr();
+
+        let sending = std::thread::spawn(move || {
+            let first = laptop.send(&to_desktop, addr, &text_clip("hello")).unwrap();
+            let second = laptop.send(&to_desktop, addr, &text_clip("again")).unwrap();
+            (first, second)
+        });
+        let LanEvent::Offer(offer) = next_event(&desktop_events) else { panic!("expected an offer") };
+        assert_eq!(offer.from.id, "laptop");
+        assert_eq!((offer.clip.owner.as_str(), offer.clip.title.as_str()), ("notepad.exe", "notes.txt"));
+        assert_eq!(offer.clip.payloads[0].format_name, "CF_UNICODETEXT");
+        assert_eq!(offer.clip.payloads[0].data, b"hello");
+        assert!(desktop.answer(offer.id, true));
+        // Answered offers are gone
+        assert!(!desktop.answer(offer.id, true));
+
+        let LanEvent::Offer(offer) = next_event(&desktop_events) else { panic!("expected an offer") };
+        assert_eq!(offer.clip.payloads[0].data, b"again");
+        assert!(desktop.answer(offer.id, false));
+        assert_eq!(sending.join().unwrap(), (true, false));
+    }
+
+    #[test]
+    fn clips_arrive_under_the_senders_hash() {
+        let ((laptop, _), (desktop, desktop_events)) = paired();
+        let to_desktop = laptop.peers().pop().unwrap();
+        let addr = desktop.addr();
+
+        // Hashed from CF_UNICODETEXT, which isn't the first payload
+        let mut clip = text_clip("unicode");
+        clip.payloads.insert(0, ClipboardPayload { format_id: 1, format_name: "CF_TEXT".to_string(), data: b"ansi".to_vec() });
+        let hash = clip.hash.clone();
+        let mut forged = text_clip("other");
+        forged.hash = blake3::hash(b"something else").to_hex().to_string();
+        let sending = std::thread::spawn(move || (laptop.send(&to_desktop, addr, &clip).unwrap(), laptop.send(&to_desktop, addr, &forged)));
+        let LanEvent::Offer(offer) = next_event(&desktop_events) else { panic!("expected an offer") };
+        assert_eq!(offer.clip.hash, hash);
+        assert!(desktop.answer(offer.id, true));
+        let (accepted, forged) = sending.join().unwrap();
+        assert!(accepted);
+        assert!(matches!(forged, Err(LanError::Refused(_))));
+        assert!(desktop_events.try_recv().is_err());
+    }
+
+    #[test]
+    fn live_clips_need_a_link_both_ways() {
+        let ((laptop, laptop_events), (desktop, desktop_events)) = paired();
+        let to_desktop = laptop.peers().pop().unwrap();
+        let to_laptop = desktop.peers().pop().unwrap();
+
+        // Linking on the sending side alone isn't enough
+        laptop.link(Some("desktop"));
+        assert!(matches!(laptop.push(&to_desktop, desktop.addr(), &text_clip("hi")), Err(LanError::Refused(_))));
+        assert!(desktop_events.try_recv().is_err());
+
+        desktop.link(Some("laptop"));
+        laptop.push(&to_desktop, desktop.addr(), &text_clip("one")).unwrap();
+        let LanEvent::Live(from, clip) = next_event(&desktop_events) else { panic!("expected a live clip") };
+        assert_eq!(from.id, "laptop");
+        assert_eq!(clip.payloads[0].data, b"one");
+        desktop.push(&to_laptop, laptop.addr(), &text_clip("two")).unwrap();
+        let LanEvent::Live(_, clip) = next_event(&laptop_events) else { panic!("expected a live clip") };
+        assert_eq!(clip.payloads[0].data, b"two");
+
+        // Forgetting a peer drops the link with it
+        desktop.forget("laptop");
+        assert_eq!(desktop.linked(), None);
+        assert!(laptop.push(&to_desktop, desktop.addr(), &text_clip("three")).is_err());
+        assert!(desktop_events.try_recv().is_err());
+    }
+
+    #[test]
+    fn the_live_flag_cant_be_flipped() {
+        let ((laptop, _), (desktop, desktop_events)) = paired();
+        desktop.link(Some("laptop"));
+        let to_desktop = laptop.peers().pop().unwrap();
+
+        // Someone on the path turns an offer into a live clip
+        let relay = TcpListener::bind("127.0.0.1:0").unwrap();
+        let relay_addr = relay.local_addr().unwrap();
+        let desktop_addr = desktop.addr();
+        std::thread::spawn(move || {
+            let (mut client, _) = relay.accept().unwrap();
+            let mut upstream = connect(desktop_addr).unwrap();
+            let (hello, _) = read_frame(&mut upstream).unwrap();
+            write_frame(&mut client, &hello, &[]).unwrap();
+            let (Frame::Offer(mut sealed), body) = read_frame(&mut client).unwrap() else { panic!("expected an offer") };
+            sealed.live = true;
+            write_frame(&mut upstream, &Frame::Offer(sealed), &body).unwrap();
+            let reply = match read_frame(&mut upstream) {
+                Ok((frame, _)) => frame,
+                Err(e) => Frame::Error { message: e.to_string() },
+            };
+            write_frame(&mut client, &reply, &[]).unwrap();
+        });
+        assert!(matches!(laptop.send(&to_desktop, relay_addr, &text_clip("hi")), Err(LanError::Refused(_))));
+        assert!(desktop_events.try_recv().is_err());
+    }
+
+    #[test]
+    fn strangers_and_forgotten_peers_are_refused() {
+        let ((laptop, _), (desktop, desktop_events)) = paired();
+        let to_desktop = laptop.peers().pop().unwrap();
+        let (stranger, _) = node("laptop");
+        assert!(matches!(stranger.send(&to_desktop, desktop.addr(), &text_clip("hi")), Err(LanError::Refused(_))));
+
+        desktop.forget("laptop");
+        assert!(matches!(laptop.send(&to_desktop, desktop.addr(), &text_clip("hi")), Err(LanError::Refused(_))));
+        assert!(desktop_events.try_recv().is_err());
+
+        // Sending to the wrong address is caught before anything is sealed
+        let (other, _) = node("other");
+        assert!(matches!(laptop.send(&to_desktop, other.addr(), &text_clip("hi")), Err(LanError::Protocol(_))));
+    }
+}
@@ -0,0 +1,281 @@
+//! LAN sharing in the app: runs the `lan` node and its mDNS discovery, keeps
+//! paired devices in cloud.db and holds incoming offers until the user
+//! answers them. With a linked peer, it also passes fresh copies across as
+//! they're captured and puts the peer's on the clipboard.
+
+use std::net::SocketAddr;
+use std::sync::mpsc::{channel, Sender};
+use std::sync::{Arc, Mutex};
+
+use crate::autosync::refresh_ui;
+use crate::cloudstorage::CloudDatabase;
+use crate::config::Config;
+use crate::lan::{Discovery, LanEvent, Nearby, Node, Offer, SentClip};
+use crate::models::{Device, Peer};
+use crate::storage::Database;
+use crate::{classify, imaging};
+
+pub struct LanState {
+    pub node: Node,
+    /// None when mDNS couldn't start; the reason is in `status`.
+    pub discovery: Option<Discovery>,
+    /// Waiting for the user, oldest first.
+    pub offers: Mutex<Vec<Offer>>,
+    /// Outcome of the last pairing or send.
+    pub status: Mutex<Option<String>>,
+    /// Hashes of copies for the linked peer.
+    live: Sender<String>,
+}
+
+impl LanState {
+    fn set_status(&self, status: String) {
+        *self.status.lock().unwrap() = Some(status);
+        refresh_ui();
+    }
+}
+
+/// Starts listening and advertising this device on the local network.
+pub fn start(config: &Config) -> Result<Arc<LanState>, String> {
+    let cloud = CloudDatabase::new(&config.cloud_database_path(), &config.storage.key).map_err(|e| e.to_string())?;
+    let key = cloud.device_key().map_err(|e| e.to_string())?;
+    let peers = cloud.peers().map_err(|e| e.to_string())?;
+    let linked = cloud.linked_peer().map_err(|e| e.to_string())?;
+    let name = config.sync.device_name();
+    let (tx, rx) = channel();
+    let node = Node::start(&format!("0.0.0.0:{}", config.lan.port), key, &name, peers, tx)
+        .map_err(|e| format!("port {}: {}", config.lan.port, e))?;
+    node.link(linked.as_deref());
+    let (discovery, status) = match Discovery::start(node.id(), &name, node.addr().port(), refresh_ui) {
+        Ok(discovery) => (Some(discovery), None),
+        Err(e) => {
+            eprintln!("lan: {}", e);
+            (None, Some(format!("Can't look for nearby devices: {}", e)))
+        }
+    };
+    let (live, copies) = channel::<String>();
+    let state = Arc::new(LanState { node, discovery, offers: Mutex::new(Vec::new()), status: Mutex::new(status), live });
+
+    let linked = state.clone();
+    std::thread::spawn(move || {
+        while let Ok(mut hash) = copies.recv() {
+            // Only the newest copy matters once a send falls behind
+            while let Ok(newer) = copies.try_recv() {
+                hash = newer;
+            }
+            push_live(&linked, &hash);
+        }
+    });
+
+    let events = state.clone();
+    std::thread::spawn(move || {
+        while let Ok(event) = rx.recv() {
+            let config = crate::current_config();
+            match event {
+                LanEvent::Paired(peer) => {
+                    let saved = CloudDatabase::new(&config.cloud_database_path(), &config.storage.key)
+                        .and_then(|cloud| cloud.save_peer(&peer));
+                    if let Err(e) = saved {
+                        eprintln!("lan: saving {}: {}", peer.name, e);
+                    }
+                    events.set_status(format!("Paired with {}", peer.name));
+                }
+                // Turning sharing off takes a restart to stop listening, but no one gets through meanwhile
+                LanEvent::Offer(offer) if !config.lan.enabled => {
+                    events.node.answer(offer.id, false);
+                }
+                LanEvent::Offer(offer) => {
+                    events.offers.lock().unwrap().push(offer);
+                    unsafe { crate::set_main_window_visible(true) };
+                    refresh_ui();
+                }
+                LanEvent::Live(_, _) if !config.lan.enabled => {}
+                LanEvent::Live(from, clip) => {
+                    // write_clipboard marks it as ours, so it isn't captured and sent back
+                    crate::write_clipboard(&clip.payloads);
+                    if let Err(e) = keep(&config, &from, clip) {
+                        eprintln!("lan: saving the clip from {}: {}", from.name, e);
+                    }
+                    refresh_ui();
+                }
+            }
+        }
+    });
+    Ok(state)
+}
+
+/// Paired devices seen on the network, and where.
+pub fn reachable(state: &LanState) -> Vec<(Peer, SocketAddr)> {
+    let nearby = state.discovery.as_ref().map(|d| d.nearby()).unwrap_or_default();
+    state
+        .node
+        .peers()
+        .into_iter()
+        .filter_map(|peer| {
+            let addr = nearby.iter().find(|d| d.id == peer.id)?.addr;
+            Some((peer, addr))
+        })
+        .collect()
+}
+
+/// Pairs with `device` in the background, using the code it shows.
+pub fn pair(state: Arc<LanState>, device: Nearby, code: String) {
+    state.set_status(format!("Pairing with {}…", device.name));
+    std::thread::spawn(move || {
+        // Success comes back as a `Paired` event
+        if let Err(e) = state.node.pair(device.addr, &code) {
+            state.set_status(format!("Pairing with {} failed: {}", device.name, e));
+        }
+    });
+}
+
+/// Offers a history clip to `peer` in the background.
+pub fn send(state: Arc<LanState>, peer: Peer, addr: SocketAddr, hash: String) {
+    state.set_status(format!("Waiting for {} to accept…", peer.name));
+    std::thread::spawn(move || {
+        let sent = load_clip(&crate::current_config(), &hash).and_then(|clip| state.node.send(&peer, addr, &clip).map_err(|e| e.to_string()));
+        state.set_status(match sent {
+            Ok(true) => format!("{} accepted", peer.name),
+            Ok(false) => format!("{} declined", peer.name),
+            Err(e) => format!("Sending to {} failed: {}", peer.name, e),
+        });
+    });
+}
+
+/// An accepted clip goes on the clipboard and into the history.
+pub fn answer(state: &LanState, id: u64, accept: bool) {
+    let offer = {
+        let mut offers = state.offers.lock().unwrap();
+        let Some(i) = offers.iter().position(|o| o.id == id) else { return };
+        offers.remove(i)
+    };
+    if !state.node.answer(id, accept) {
+        if accept {
+            state.set_status(format!("{} stopped waiting for an answer", offer.from.name));
+        }
+        return;
+    }
+    if accept {
+        crate::write_clipboard(&offer.clip.payloads);
+        if let Err(e) = keep(&crate::current_config(), &offer.from, offer.clip) {
+            eprintln!("lan: saving the clip from {}: {}", offer.from.name, e);
+        }
+        refresh_ui();
+    }
+}
+
+/// Links the clipboard with `peer`, or unlinks it.
+pub fn link(state: &LanState, peer: Option<&Peer>) {
+    state.node.link(peer.map(|p| p.id.as_str()));
+    let config = crate::current_config();
+    let saved = CloudDatabase::new(&config.cloud_database_path(), &config.storage.key)
+        .and_then(|cloud| cloud.set_linked_peer(peer.map(|p| p.id.as_str())));
+    if let Err(e) = saved {
+        eprintln!("lan: saving the link: {}", e);
+    }
+    state.set_status(match peer {
+        Some(peer) => format!("Clipboard linked with {}; it has to link back too", peer.name),
+        None => "Clipboard unlinked".to_string(),
+    });
+}
+
+/// Passes a fresh copy to the linked peer when `[[lan.link]]` lets it
+/// through. Called for every copy that isn't sensitive.
+pub fn share_live(db: &Database, hash: &str) {
+    let Some(state) = crate::lan() else { return };
+    let config = crate::current_config();
+    if !config.lan.enabled || state.node.linked().is_none() {
+        return;
+    }
+    let facts = match db.get_clip_facts(hash) {
+        Ok(facts) => facts,
+        Err(e) => {
+            eprintln!("linked clipboard {}: {}", hash, e);
+            return;
+        }
+    };
+    let rules = &config.lan.link;
+    if !facts.sensitive && (rules.is_empty() || rules.iter().any(|rule| rule.matches(&facts))) {
+        let _ = state.live.send(hash.to_string());
+    }
+}
+
+fn push_live(state: &LanState, hash: &str) {
+    let Some(id) = state.node.linked() else { return };
+    let Some((peer, addr)) = reachable(state).into_iter().find(|(peer, _)| peer.id == id) else { return };
+    let pushed = load_clip(&crate::current_config(), hash).and_then(|clip| state.node.push(&peer, addr, &clip).map_err(|e| e.to_string()));
+    if let Err(e) = pushed {
+        eprintln!("linked clipboard: {}", e);
+        state.set_status(format!("Linked clipboard: {}", e));
+    }
+}
+
+pub fn forget(state: &LanState, peer: &Peer) {
+    state.node.forget(&peer.id);
+    let config = crate::current_config();
+    let forgotten = CloudDatabase::new(&config.cloud_database_path(), &config.storage.key).and_then(|cloud| cloud.forget_peer(&peer.id));
+    match forgotten {
+        Ok(()) => state.set_status(format!("Forgot {}", peer.name)),
+        Err(e) => eprintln!("lan: forgetting {}: {}", peer.name, e),
+    }
+}
+
+fn load_clip(config: &Config, hash: &str) -> Result<SentClip, String> {
+    let db = Database::new(&config.database_path(), &config.storage.key).map_err(|e| e.to_string())?;
+    let facts = db.get_clip_facts(hash).map_err(|e| e.to_string())?;
+    if facts.sensitive {
+        return Err("secure copies stay on this device".to_string());
+    }
+    let payloads = db.get_clip_payloads(hash).map_err(|e| e.to_string())?;
+    Ok(SentClip { hash: hash.to_string(), owner: facts.owner, title: facts.title, payloads })
+}
+
+fn keep(config: &Config, from: &Peer, clip: SentClip) -> rusqlite::Result<()> {
+    let db = Database::new(&config.database_path(), &config.storage.key)?;
+    let hash = clip.hash;
+    let kind = classify::classify(&clip.payloads, &clip.title);
+    let thumbnail = imaging::thumbnail_for(&clip.payloads);
+    db.save_snapshot(&clip.owner, &clip.title, "UnknownPath", &hash, &clip.payloads, false)?;
+    if let Some(kind) = kind {
+        db.set_kind(&hash, &kind.to_string())?;
+    }
+    if let Some(thumb) = thumbnail {
+        db.save_thumbnail(&hash, &thumb)?;
+    }
+    let device = Device { id: from.id.clone(), name: from.name.clone(), public_key: from.public_key.clone(), revoked: false };
+    db.save_devices(&[device])?;
+    db.set_device(&hash, &from.id)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::ClipboardPayload;
+
+    #[test]
+    fn received_clips_keep_the_senders_hash() {
+        let tmp = tempfile::tempdir().unwrap();
+        let config = |name: &str| {
+            let mut config = Config::default();
+            config.storage.database = tmp.path().join(name);
+            config
+        };
+        let (laptop, desktop) = (config("laptop.db"), config("desktop.db"));
+
+        // Captured CF_UNICODETEXT first, as Windows lists it
+        let text: Vec<u8> = "shared".encode_utf16().flat_map(u16::to_le_bytes).collect();
+        let hash = blake3::hash(&text).to_hex().to_string();
+        let payloads = vec![
+            ClipboardPayload { format_id: 13, format_name: "CF_UNICODETEXT".to_string(), data: text },
+            ClipboardPayload { format_id: 1, format_name: "CF_TEXT".to_string(), data: b"shared".to_vec() },
+        ];
+        let history = Database::new(&laptop.database_path(), &laptop.storage.key).unwrap();
+        history.save_snapshot("notepad.exe", "Notes", "", &hash, &payloads, false).unwrap();
+
+        let clip = load_clip(&laptop, &hash).unwrap();
+        let from = Peer { id: "laptop".to_string(), name: "Laptop".to_string(), public_key: String::new() };
+        keep(&desktop, &from, clip).unwrap();
+        let received = Database::new(&desktop.database_path(), &desktop.storage.key).unwrap();
+        assert_eq!(received.get_clip_payloads(&hash).unwrap().len(), 2);
+        assert_eq!(received.get_clip_device(&hash).unwrap().as_deref(), Some("laptop"));
+    }
+}
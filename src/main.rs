@@ -1,11 +1,42 @@
 mod storage;
 mod cloudstorage;
+mod cloudcrypto;
+mod crdt;
+mod pairing;
+mod lan;
+mod lanshare;
+mod folderlog;
+mod autosync;
 mod models;
 mod app;
+mod config;
+mod hotkeys;
+#[cfg(all(unix, not(target_os = "macos")))]
+mod x11hotkeys;
+mod keyboard;
+#[cfg(all(unix, not(target_os = "macos")))]
+mod x11keyboard;
+mod query;
+mod imaging;
+mod inspect;
+mod codecs;
+mod formats;
+mod richtext;
+mod files;
+mod classify;
+mod highlight;
+mod transform;
+mod scripts;
 
 use storage::Database;
+use config::{Config, CliOverrides};
+use hotkeys::{Binding, HotkeyAction};
 use models::{ClipboardPayload, ClipboardMsg};
+use formats::RestoreMode;
+use transform::Action;
 use app::App;
+use autosync::{SyncState, SyncTask};
+use lanshare::LanState;
 
 use windows::{
     core::*,
@@ -13,33 +44,60 @@ use windows::{
     Win32::System::LibraryLoader::*,
     Win32::UI::{
         WindowsAndMessaging::*,
-        Input::KeyboardAndMouse::{RegisterHotKey, MOD_ALT, MOD_CONTROL, VK_C},
+        Input::KeyboardAndMouse::{RegisterHotKey, UnregisterHotKey, HOT_KEY_MODIFIERS},
     },
     Win32::System::Threading::*,
     Win32::System::ProcessStatus::*,
     Win32::System::DataExchange::*,
-    Win32::System::Memory::*,
 };
 
-use std::sync::{OnceLock, Arc};
+use std::sync::{OnceLock, Arc, Mutex, RwLock};
 use std::sync::mpsc::{channel, Sender};
 use std::thread;
-use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant, SystemTime};
+use std::sync::atomic::{AtomicBool, AtomicIsize, Ordering};
 
 use eframe::egui;
 
 static TX: OnceLock<Sender<ClipboardMsg>> = OnceLock::new();
 static RESTORING: AtomicBool = AtomicBool::new(false);
+/// Hash of what `write_clipboard` last wrote and until when its update
+/// counts as an echo. RESTORING only covers the write itself, and the
+/// update can reach the listener after it's cleared.
+static LAST_WRITTEN: Mutex<Option<(String, Instant)>> = Mutex::new(None);
 static VISIBLE: OnceLock<Arc<AtomicBool>> = OnceLock::new();
 static NEEDS_REFRESH: OnceLock<Arc<AtomicBool>> = OnceLock::new();
-const HOTKEY_ID: i32 = 1;
+static FOCUS_SEARCH: OnceLock<Arc<AtomicBool>> = OnceLock::new();
 static EGUI_CTX: OnceLock<egui::Context> = OnceLock::new();
+static WINDOW_TITLE: OnceLock<String> = OnceLock::new();
+static CONFIG: OnceLock<RwLock<Config>> = OnceLock::new();
+static CLI: OnceLock<CliOverrides> = OnceLock::new();
+static SYNC: OnceLock<Sender<SyncTask>> = OnceLock::new();
+static SYNC_STATE: OnceLock<Arc<SyncState>> = OnceLock::new();
+static LAN: OnceLock<Arc<LanState>> = OnceLock::new();
+static HIDDEN_HWND: OnceLock<HWND> = OnceLock::new();
+// Bindings registered on the hidden window; the hotkey id is the index + 1
+static BINDINGS: Mutex<Vec<Binding>> = Mutex::new(Vec::new());
+static PENDING_BINDINGS: Mutex<Option<Vec<Binding>>> = Mutex::new(None);
+static HOTKEY_ERRORS: Mutex<Vec<String>> = Mutex::new(Vec::new());
+#[cfg(all(unix, not(target_os = "macos")))]
+static X11_GRABBER: Mutex<Option<x11hotkeys::Grabber>> = Mutex::new(None);
+// Window that had focus when a hotkey fired; paste targets go back to it
+static PREVIOUS_FOREGROUND: AtomicIsize = AtomicIsize::new(0);
+// Set by the secure-copy hotkey; the next capture before the deadline is stored as sensitive
+static SECURE_CAPTURE_UNTIL: Mutex<Option<Instant>> = Mutex::new(None);
+const WM_APP_RELOAD_HOTKEYS: u32 = WM_APP + 1;
 
 unsafe fn get_clipboard_source() -> String {
     let owner_hwnd = GetClipboardOwner();
     if owner_hwnd.0 == 0 { return "Unknown".to_string(); }
+    process_name(owner_hwnd)
+}
+
+/// Executable name of the process that owns `hwnd`, e.g. `chrome.exe`.
+unsafe fn process_name(hwnd: HWND) -> String {
     let mut pid = 0u32;
-    GetWindowThreadProcessId(owner_hwnd, Some(&mut pid));
+    GetWindowThreadProcessId(hwnd, Some(&mut pid));
     let process_handle = OpenProcess(
         PROCESS_QUERY_INFORMATION | PROCESS_VM_READ,
         false,
@@ -64,10 +122,446 @@ pub fn is_restoring() -> bool {
     RESTORING.load(Ordering::Relaxed)
 }
 
+/// Whether a clipboard update carrying `hash` is our own write coming back.
+/// Each write excuses one update.
+fn is_echo(hash: &str) -> bool {
+    let mut last = LAST_WRITTEN.lock().unwrap();
+    match last.take() {
+        Some((written, until)) if written == hash && Instant::now() <= until => true,
+        other => {
+            *last = other.filter(|(_, until)| Instant::now() <= *until);
+            false
+        }
+    }
+}
+
+pub fn current_config() -> Config {
+    CONFIG.get().expect("config set in main").read().unwrap().clone()
+}
+
+/// What the command line set; it wins over the file until a restart.
+pub fn cli_overrides() -> &'static CliOverrides {
+    CLI.get().expect("command line parsed in main")
+}
+
+pub fn set_config(config: Config) {
+    if let Some(lock) = CONFIG.get() {
+        *lock.write().unwrap() = config;
+    }
+}
+
+/// Hands `task` to the background sync thread.
+pub fn request_sync(task: SyncTask) {
+    if let Some(tx) = SYNC.get() {
+        let _ = tx.send(task);
+    }
+}
+
+pub fn sync_state() -> Arc<SyncState> {
+    SYNC_STATE.get().cloned().unwrap_or_default()
+}
+
+/// Starts LAN sharing unless it's already running.
+pub fn start_lan(config: &Config) {
+    if LAN.get().is_some() {
+        return;
+    }
+    match lanshare::start(config) {
+        Ok(state) => {
+            let _ = LAN.set(state);
+        }
+        Err(e) => eprintln!("LAN sharing failed to start: {}", e),
+    }
+}
+
+pub fn lan() -> Option<Arc<LanState>> {
+    LAN.get().cloned()
+}
+
+/// Replaces the registered global hotkeys. Registration happens on the hidden
+/// window's thread, since RegisterHotKey binds to the calling thread's window;
+/// on X11 the grabs are made on a thread of their own.
+pub fn reload_hotkeys(bindings: Vec<Binding>) {
+    #[cfg(all(unix, not(target_os = "macos")))]
+    grab_x11_hotkeys(bindings);
+    #[cfg(not(all(unix, not(target_os = "macos"))))]
+    {
+        *PENDING_BINDINGS.lock().unwrap() = Some(bindings);
+        if let Some(hwnd) = HIDDEN_HWND.get() {
+            unsafe {
+                let _ = PostMessageW(*hwnd, WM_APP_RELOAD_HOTKEYS, WPARAM(0), LPARAM(0));
+            }
+        }
+    }
+}
+
+/// Hotkeys that could not be registered, usually because another application owns them.
+pub fn hotkey_errors() -> Vec<String> {
+    HOTKEY_ERRORS.lock().unwrap().clone()
+}
+
+/// X11 has no hidden window to register on; a grabber thread of its own
+/// holds the keys instead, replaced wholesale on reload.
+#[cfg(all(unix, not(target_os = "macos")))]
+fn grab_x11_hotkeys(bindings: Vec<Binding>) {
+    let mut grabber = X11_GRABBER.lock().unwrap();
+    // Release the old grabs before taking the new ones
+    *grabber = None;
+    let errors = match x11hotkeys::Grabber::start(bindings, |action| unsafe {
+        remember_foreground();
+        run_hotkey_action(action)
+    }) {
+        Ok((started, errors)) => {
+            *grabber = Some(started);
+            errors
+        }
+        Err(e) => vec![format!("global hotkeys are unavailable: {}", e)],
+    };
+    for e in &errors {
+        eprintln!("failed to register hotkey {}", e);
+    }
+    *HOTKEY_ERRORS.lock().unwrap() = errors;
+    if let Some(ctx) = EGUI_CTX.get() {
+        ctx.request_repaint();
+    }
+}
+
+unsafe fn register_hotkeys(hwnd: HWND, bindings: Vec<Binding>) {
+    let mut registered = BINDINGS.lock().unwrap();
+    for id in 1..=registered.len() {
+        let _ = UnregisterHotKey(hwnd, id as i32);
+    }
+
+    let mut errors = Vec::new();
+    for (i, binding) in bindings.iter().enumerate() {
+        if let Err(e) = RegisterHotKey(hwnd, i as i32 + 1, HOT_KEY_MODIFIERS(binding.modifiers), binding.vk) {
+            let msg = format!("{} ({}) is unavailable: {}", binding.combo, binding.action, e.message());
+            eprintln!("failed to register hotkey {}", msg);
+            errors.push(msg);
+        }
+    }
+    *registered = bindings;
+    *HOTKEY_ERRORS.lock().unwrap() = errors;
+    if let Some(ctx) = EGUI_CTX.get() {
+        ctx.request_repaint();
+    }
+}
+
+/// Registered format ids differ between sessions and machines, and MIME
+/// payloads have none, so anything outside the predefined range is looked up
+/// by name.
+unsafe fn clipboard_format_id(payload: &ClipboardPayload) -> u32 {
+    if payload.format_id != 0 && payload.format_id < 0xC000 {
+        return payload.format_id;
+    }
+    let name: Vec<u16> = payload.format_name.encode_utf16().chain(std::iter::once(0)).collect();
+    RegisterClipboardFormatW(PCWSTR(name.as_ptr()))
+}
+
+/// Puts `payloads` on the system clipboard without recording them as a new capture.
+pub fn write_clipboard(payloads: &[ClipboardPayload]) -> bool {
+    set_restoring(true);
+    if let Some(primary) = payloads.first() {
+        let hash = blake3::hash(&primary.data).to_hex().to_string();
+        *LAST_WRITTEN.lock().unwrap() = Some((hash, Instant::now() + Duration::from_secs(2)));
+    }
+    let mut ok = false;
+    unsafe {
+        if OpenClipboard(HWND(0)).is_ok() {
+            let _ = EmptyClipboard();
+            let extras = formats::win32_extras(payloads);
+            for payload in payloads.iter().chain(&extras) {
+                let format = clipboard_format_id(payload);
+                match codecs::write_handle(format, &payload.data) {
+                    Ok(handle) => {
+                        let _ = SetClipboardData(format, handle);
+                    }
+                    Err(e) => eprintln!("Skipping {} on restore: {}", payload.format_name, e),
+                }
+            }
+            let _ = CloseClipboard();
+            ok = true;
+        }
+    }
+    set_restoring(false);
+    ok
+}
+
+/// Restores the clip with `hash` from the history database, either whole or
+/// reduced to its plain or rich formats.
+pub fn restore_hash(hash: &str, mode: RestoreMode) -> bool {
+    let config = current_config();
+    let db = match Database::new(&config.database_path(), &config.storage.key) {
+        Ok(db) => db,
+        Err(_) => return false,
+    };
+    match db.get_clip_payloads(hash) {
+        Ok(payloads) => {
+            let mut payloads = formats::for_restore(payloads, mode);
+            if files::clip_paths(&payloads).is_some() {
+                let snapshots = db.get_file_snapshots(hash).unwrap_or_default();
+                let restore_dir = config::data_dir().join("restored").join(&hash[..12.min(hash.len())]);
+                payloads = files::rebuild(payloads, snapshots, &restore_dir);
+            }
+            if payloads.is_empty() {
+                println!("restore: {} has nothing to restore as {:?}", hash, mode);
+                return false;
+            }
+            write_clipboard(&payloads)
+        }
+        Err(_) => false,
+    }
+}
+
+/// Re-activates the window that had focus when the last hotkey fired and
+/// sends it that application's paste keystroke. Does nothing if no hotkey has
+/// recorded a target yet.
+pub fn paste_into_previous_window() {
+    #[cfg(all(unix, not(target_os = "macos")))]
+    {
+        let target = PREVIOUS_FOREGROUND.load(Ordering::Relaxed) as std::os::raw::c_ulong;
+        if target == 0 {
+            return;
+        }
+        let paste = current_config().paste;
+        thread::spawn(move || {
+            let (modifiers, vk) = paste.combo_for(&x11keyboard::process_name(target));
+            thread::sleep(Duration::from_millis(paste.delay_ms));
+            if let Err(e) = x11keyboard::paste_into(target, modifiers, vk) {
+                eprintln!("paste: {}", e);
+            }
+        });
+    }
+    #[cfg(not(all(unix, not(target_os = "macos"))))]
+    paste_into_win32_window();
+}
+
+#[cfg(not(all(unix, not(target_os = "macos"))))]
+fn paste_into_win32_window() {
+    let target = HWND(PREVIOUS_FOREGROUND.load(Ordering::Relaxed));
+    if target.0 == 0 {
+        return;
+    }
+    let paste = current_config().paste;
+    thread::spawn(move || unsafe {
+        if !IsWindow(target).as_bool() {
+            return;
+        }
+        let (modifiers, vk) = paste.combo_for(&process_name(target));
+        thread::sleep(Duration::from_millis(paste.delay_ms));
+
+        let _ = SetForegroundWindow(target);
+        for _ in 0..50 {
+            if GetForegroundWindow() == target {
+                break;
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+        if GetForegroundWindow() != target {
+            eprintln!("paste: could not re-activate the previous window");
+            return;
+        }
+        keyboard::send_combo(modifiers, vk);
+    });
+}
+
+/// Remembers the focused window as the paste target, unless it's our own UI.
+unsafe fn remember_foreground() {
+    #[cfg(all(unix, not(target_os = "macos")))]
+    if let Some(window) = x11keyboard::active_window() {
+        PREVIOUS_FOREGROUND.store(window as isize, Ordering::Relaxed);
+    }
+    #[cfg(not(all(unix, not(target_os = "macos"))))]
+    remember_win32_foreground();
+}
+
+#[cfg(not(all(unix, not(target_os = "macos"))))]
+unsafe fn remember_win32_foreground() {
+    let fg = GetForegroundWindow();
+    let title: Vec<u16> = WINDOW_TITLE.get().map(String::as_str).unwrap_or("Clip")
+        .encode_utf16().chain(std::iter::once(0)).collect();
+    let main_hwnd = FindWindowW(None, PCWSTR(title.as_ptr()));
+    if fg.0 != 0 && fg != main_hwnd {
+        PREVIOUS_FOREGROUND.store(fg.0, Ordering::Relaxed);
+    }
+}
+
+/// Empties the clipboard. The update that follows carries no payloads and is
+/// never captured; `hash` names content that was just taken off it, so the
+/// same content showing up again within the echo window isn't recorded as
+/// an ordinary copy.
+unsafe fn clear_clipboard(hash: Option<String>) {
+    if let Some(hash) = hash {
+        *LAST_WRITTEN.lock().unwrap() = Some((hash, Instant::now() + Duration::from_secs(2)));
+    }
+    if OpenClipboard(HWND(0)).is_ok() {
+        let _ = EmptyClipboard();
+        let _ = CloseClipboard();
+    }
+}
+
+unsafe fn set_main_window_visible(show: bool) {
+    if let Some(visible) = VISIBLE.get() {
+        visible.store(show, Ordering::Relaxed);
+
+        let title: Vec<u16> = WINDOW_TITLE.get().map(String::as_str).unwrap_or("Clip")
+            .encode_utf16().chain(std::iter::once(0)).collect();
+        let main_hwnd = FindWindowW(None, PCWSTR(title.as_ptr()));
+        if main_hwnd.0 != 0 {
+            if show {
+                ShowWindow(main_hwnd, SW_SHOW);
+                SetForegroundWindow(main_hwnd);
+            } else {
+                ShowWindow(main_hwnd, SW_HIDE);
+            }
+        }
+
+        if let Some(ctx) = EGUI_CTX.get() {
+            ctx.request_repaint();
+        }
+    }
+}
+
+/// Restores a clip from history and pastes it into whatever has focus.
+/// Runs off the message thread since opening the database runs the KDF.
+fn paste_from_history(
+    mode: RestoreMode,
+    lookup: impl FnOnce(&Database) -> rusqlite::Result<Option<String>> + Send + 'static,
+) {
+    thread::spawn(move || {
+        let config = current_config();
+        let hash = match Database::new(&config.database_path(), &config.storage.key) {
+            Ok(db) => lookup(&db),
+            Err(e) => { eprintln!("paste: DB open failed: {}", e); return; }
+        };
+        match hash {
+            Ok(Some(hash)) => {
+                if restore_hash(&hash, mode) {
+                    paste_into_previous_window();
+                }
+            }
+            Ok(None) => println!("paste: nothing to paste"),
+            Err(e) => eprintln!("paste: lookup failed: {}", e),
+        }
+    });
+}
+
+/// Transforms the newest clip and pastes the result into the previous window.
+fn transform_and_paste(name: String) {
+    thread::spawn(move || {
+        let config = current_config();
+        let Some(action) = Action::resolve(&name, &config) else {
+            eprintln!("transform: unknown transform '{}'", name);
+            return;
+        };
+        let db = match Database::new(&config.database_path(), &config.storage.key) {
+            Ok(db) => db,
+            Err(e) => { eprintln!("transform: DB open failed: {}", e); return; }
+        };
+        let derived = match db.get_recent_hash(0) {
+            Ok(Some(hash)) => transform::derive(&db, &hash, &action),
+            Ok(None) => return,
+            Err(e) => Err(e.to_string()),
+        };
+        match derived {
+            Ok(derived) => {
+                if restore_hash(&derived, RestoreMode::All) {
+                    paste_into_previous_window();
+                }
+            }
+            Err(e) => eprintln!("transform {}: {}", name, e),
+        }
+    });
+}
+
+/// Runs the `[[rules]]` matching a newly captured clip. Results go into
+/// history; a rule with `replace` also puts its result on the clipboard.
+/// Returns the clip a `replace` rule put on the clipboard, if any.
+fn apply_capture_rules(db: &Database, hash: &str, owner: &str, title: &str, kind: Option<&str>) -> Option<String> {
+    let config = current_config();
+    let mut replaced = None;
+    for rule in config.rules.iter().filter(|r| r.matches(owner, title, kind)) {
+        let Some(action) = Action::resolve(&rule.transform, &config) else { continue };
+        match transform::derive(db, hash, &action) {
+            Ok(derived) => {
+                if rule.replace && restore_hash(&derived, RestoreMode::All) {
+                    replaced = Some(derived);
+                }
+                if let Some(flag) = NEEDS_REFRESH.get() {
+                    flag.store(true, Ordering::Relaxed);
+                }
+                if let Some(ctx) = EGUI_CTX.get() {
+                    ctx.request_repaint();
+                }
+            }
+            Err(e) => eprintln!("rule {}: {}", rule.transform, e),
+        }
+    }
+    replaced
+}
+
+/// A fresh clip whose `[[rules]]` still have to run.
+struct RuleJob {
+    hash: String,
+    owner: String,
+    title: String,
+    kind: Option<String>,
+}
+
+/// Runs capture rules off the capture thread, since a rule can be an external
+/// command or a WASM module. Shares whichever clip the rules leave on the
+/// clipboard once they're done.
+fn spawn_rule_worker(db_path: String, db_key: String) -> Sender<RuleJob> {
+    let (tx, rx) = channel::<RuleJob>();
+    thread::spawn(move || {
+        let db = match Database::new(&db_path, &db_key) {
+            Ok(db) => db,
+            Err(e) => { eprintln!("rules: DB open failed: {}", e); return; }
+        };
+        while let Ok(job) = rx.recv() {
+            let on_clipboard = apply_capture_rules(&db, &job.hash, &job.owner, &job.title, job.kind.as_deref())
+                .unwrap_or(job.hash);
+            lanshare::share_live(&db, &on_clipboard);
+        }
+    });
+    tx
+}
+
+unsafe fn run_hotkey_action(action: HotkeyAction) {
+    match action {
+        HotkeyAction::ShowHistory => {
+            let currently_visible = VISIBLE.get().is_some_and(|v| v.load(Ordering::Relaxed));
+            set_main_window_visible(!currently_visible);
+        }
+        HotkeyAction::OpenSearch => {
+            if let Some(flag) = FOCUS_SEARCH.get() {
+                flag.store(true, Ordering::Relaxed);
+            }
+            set_main_window_visible(true);
+        }
+        // The newest clip is what's on the clipboard right now, so "previous" is one below it
+        HotkeyAction::PastePrevious => paste_from_history(RestoreMode::All, |db| db.get_recent_hash(1)),
+        HotkeyAction::PastePlain => paste_from_history(RestoreMode::PlainText, |db| db.get_recent_hash(0)),
+        HotkeyAction::PastePinned(slot) => paste_from_history(RestoreMode::All, move |db| db.get_pinned_hash(slot)),
+        HotkeyAction::SecureCopy => {
+            *SECURE_CAPTURE_UNTIL.lock().unwrap() = Some(Instant::now() + Duration::from_secs(1));
+            keyboard::send_copy();
+        }
+        HotkeyAction::ClearClipboard => clear_clipboard(None),
+        HotkeyAction::Transform(name) => transform_and_paste(name),
+    }
+}
+
 unsafe fn process_clipboard_update(hwnd: HWND) {
     if RESTORING.load(Ordering::Relaxed) { return; }
     if OpenClipboard(hwnd).is_err() { return; }
 
+    let is_sensitive = SECURE_CAPTURE_UNTIL
+        .lock()
+        .unwrap()
+        .take()
+        .is_some_and(|deadline| Instant::now() <= deadline);
+
     let source_app = get_clipboard_source();
 
     let mut title_buffer = [0u16; 512];
@@ -80,14 +574,7 @@ unsafe fn process_clipboard_update(hwnd: HWND) {
 
     while format != 0 {
         if let Ok(handle) = GetClipboardData(format) {
-            let hglobal = HGLOBAL(handle.0 as *mut _);
-            let size = GlobalSize(hglobal);
-            let ptr = GlobalLock(hglobal);
-
-            if !ptr.is_null() && size > 0 {
-                let slice = std::slice::from_raw_parts(ptr as *const u8, size);
-                let data = slice.to_vec();
-
+            if let Some(data) = codecs::read_handle(format, handle) {
                 let mut name_buf = [0u16; 256];
                 let name_len = GetClipboardFormatNameW(format, &mut name_buf);
                 let format_name = if name_len > 0 {
@@ -96,14 +583,15 @@ unsafe fn process_clipboard_update(hwnd: HWND) {
                     match format {
                         1  => "CF_TEXT".to_string(),
                         2  => "CF_BITMAP".to_string(),
+                        9  => "CF_PALETTE".to_string(),
                         13 => "CF_UNICODETEXT".to_string(),
+                        14 => "CF_ENHMETAFILE".to_string(),
                         15 => "CF_HDROP".to_string(),
                         _  => format!("ID_{}", format),
                     }
                 };
 
                 payloads.push(ClipboardPayload { format_id: format, format_name, data });
-                let _ = GlobalUnlock(hglobal);
             }
         }
         format = EnumClipboardFormats(format);
@@ -111,21 +599,30 @@ unsafe fn process_clipboard_update(hwnd: HWND) {
 
     let _ = CloseClipboard();
 
-    if let Some(primary) = payloads.first() {
-        // blake3 gives a stable, collision-resistant hash
-        let hash = blake3::hash(&primary.data).to_hex().to_string();
+    // blake3 gives a stable, collision-resistant hash
+    let Some(hash) = payloads.first().map(|p| blake3::hash(&p.data).to_hex().to_string()) else { return };
+    // A restored or linked clip must not come back as a copy, or two linked
+    // devices would bounce it between them
+    if is_echo(&hash) {
+        return;
+    }
 
-        let msg = ClipboardMsg {
-            owner: source_app,
-            fg_title,
-            exe_path: "UnknownPath".to_string(),
-            hash,
-            payloads,
-        };
+    if is_sensitive {
+        // Secure copy keeps the data in the encrypted store only
+        clear_clipboard(Some(hash.clone()));
+    }
 
-        if let Some(tx) = TX.get() {
-            let _ = tx.send(msg);
-        }
+    let msg = ClipboardMsg {
+        owner: source_app,
+        fg_title,
+        exe_path: "UnknownPath".to_string(),
+        hash,
+        payloads,
+        is_sensitive,
+    };
+
+    if let Some(tx) = TX.get() {
+        let _ = tx.send(msg);
     }
 }
 
@@ -139,26 +636,20 @@ unsafe extern "system" fn wnd_proc(
 ) -> LRESULT {
     match msg {
         WM_HOTKEY => {
-            if wparam.0 == HOTKEY_ID as usize {
-                if let Some(visible) = VISIBLE.get() {
-                    let currently_visible = visible.load(Ordering::Relaxed);
-                    visible.store(!currently_visible, Ordering::Relaxed);
-
-                    let title: Vec<u16> = "Clip".encode_utf16().chain(std::iter::once(0)).collect();
-                    let main_hwnd = FindWindowW(None, PCWSTR(title.as_ptr()));
-                    if main_hwnd.0 != 0 {
-                        if !currently_visible {
-                            ShowWindow(main_hwnd, SW_SHOW);
-                            SetForegroundWindow(main_hwnd);
-                        } else {
-                            ShowWindow(main_hwnd, SW_HIDE);
-                        }
-                    }
-
-                    if let Some(ctx) = EGUI_CTX.get() {
-                        ctx.request_repaint();
-                    }
-                }
+            let action = BINDINGS
+                .lock()
+                .unwrap()
+                .get(wparam.0.wrapping_sub(1))
+                .map(|b| b.action.clone());
+            if let Some(action) = action {
+                remember_foreground();
+                run_hotkey_action(action);
+            }
+            LRESULT(0)
+        }
+        WM_APP_RELOAD_HOTKEYS => {
+            if let Some(bindings) = PENDING_BINDINGS.lock().unwrap().take() {
+                register_hotkeys(hwnd, bindings);
             }
             LRESULT(0)
         }
@@ -181,7 +672,30 @@ unsafe extern "system" fn wnd_proc(
     }
 }
 
+fn modified_time(path: &std::path::Path) -> Option<SystemTime> {
+    std::fs::metadata(path).and_then(|m| m.modified()).ok()
+}
+
 fn main() -> Result<()> {
+    let cli = match CliOverrides::parse(std::env::args().skip(1)) {
+        Ok(cli) => cli,
+        Err(e) => {
+            eprintln!("{}", e);
+            std::process::exit(2);
+        }
+    };
+    let config_path = cli.config_path();
+    let config = match Config::load(&config_path, &cli) {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+    };
+    WINDOW_TITLE.set(config.ui.window_title.clone()).unwrap();
+    CONFIG.set(RwLock::new(config.clone())).unwrap();
+    CLI.set(cli.clone()).unwrap();
+
     let (tx, rx) = channel::<ClipboardMsg>();
     TX.set(tx).map_err(|_| Error::from(HRESULT(0))).unwrap();
 
@@ -191,21 +705,105 @@ fn main() -> Result<()> {
     let needs_refresh = Arc::new(AtomicBool::new(false));
     NEEDS_REFRESH.set(needs_refresh.clone()).unwrap();
 
+    let focus_search = Arc::new(AtomicBool::new(false));
+    FOCUS_SEARCH.set(focus_search.clone()).unwrap();
+
+    let config_changed = Arc::new(AtomicBool::new(false));
+    {
+        // Poll the config file so edits made outside the app are picked up live
+        let config_changed = config_changed.clone();
+        let config_path = config_path.clone();
+        thread::spawn(move || {
+            let mut last = modified_time(&config_path);
+            loop {
+                thread::sleep(Duration::from_secs(1));
+                let current = modified_time(&config_path);
+                if current != last {
+                    last = current;
+                    config_changed.store(true, Ordering::Relaxed);
+                    if let Some(ctx) = EGUI_CTX.get() {
+                        ctx.request_repaint();
+                    }
+                }
+            }
+        });
+    }
+
+    // Before the first capture, so it's stored with this device's id
+    autosync::register_this_device(&config);
+    let sync_state = Arc::new(SyncState::default());
+    SYNC_STATE.set(sync_state.clone()).unwrap();
+    SYNC.set(autosync::spawn(sync_state)).unwrap();
+    if config.lan.enabled {
+        start_lan(&config);
+    }
+
+    let db_path = config.database_path();
+    let db_key = config.storage.key.clone();
+    let rules = spawn_rule_worker(db_path.clone(), db_key.clone());
     thread::spawn(move || {
-        let db = Database::new("clipboard.db", "pwd").expect("Failed to init DB");
+        let db = Database::new(&db_path, &db_key).expect("Failed to init DB");
         while let Ok(msg) = rx.recv() {
-            let _ = db.save_snapshot(
-                &msg.owner,
-                &msg.fg_title,
-                &msg.exe_path,
-                &msg.hash,
-                msg.payloads,
+            let kind = if msg.is_sensitive { None } else { classify::classify(&msg.payloads, &msg.fg_title) };
+            let is_new = matches!(
+                db.save_snapshot(
+                    &msg.owner,
+                    &msg.fg_title,
+                    &msg.exe_path,
+                    &msg.hash,
+                    &msg.payloads,
+                    msg.is_sensitive,
+                ),
+                Ok(true)
             );
+            // Decode once here so the UI never has to touch full-size bitmaps for the list;
+            // a duplicate already got its thumbnail the first time
+            let thumbnail = if is_new && !msg.is_sensitive { imaging::thumbnail_for(&msg.payloads) } else { None };
+            if let Some(thumb) = thumbnail {
+                if let Err(e) = db.save_thumbnail(&msg.hash, &thumb) {
+                    eprintln!("thumbnail save failed: {}", e);
+                }
+            }
+            if let Some(kind) = kind {
+                if let Err(e) = db.set_kind(&msg.hash, &kind.to_string()) {
+                    eprintln!("kind save failed: {}", e);
+                }
+            }
+            // A repeat copy of the same files keeps the snapshots taken the first time
+            let files_config = current_config().files;
+            let snapshots = match files::clip_paths(&msg.payloads) {
+                Some(paths) if is_new && files_config.snapshot && !msg.is_sensitive => {
+                    files::read_snapshots(&paths, files_config.snapshot_max_kb * 1024)
+                }
+                _ => Vec::new(),
+            };
+            if !snapshots.is_empty() {
+                if let Err(e) = db.save_file_snapshots(&msg.hash, &snapshots) {
+                    eprintln!("file snapshot save failed: {}", e);
+                }
+            }
+            if is_new && !msg.is_sensitive {
+                autosync::push_if_matching(&db, &msg.hash);
+            }
+            // Only fresh clips, so a rule's own result coming back through the clipboard doesn't loop
+            let kind = kind.map(|k| k.to_string());
+            let has_rules = is_new
+                && !msg.is_sensitive
+                && current_config().rules.iter().any(|r| r.matches(&msg.owner, &msg.fg_title, kind.as_deref()));
+            if has_rules {
+                // The worker shares the clip once the rules have had their say
+                let _ = rules.send(RuleJob { hash: msg.hash.clone(), owner: msg.owner.clone(), title: msg.fg_title.clone(), kind });
+            } else if !msg.is_sensitive {
+                lanshare::share_live(&db, &msg.hash);
+            }
             println!("Saved clip from: {}", msg.owner);
         }
     });
 
-    thread::spawn(|| {
+    let bindings = hotkeys::parse_bindings(&config.hotkeys).expect("hotkeys validated on load");
+    #[cfg(all(unix, not(target_os = "macos")))]
+    grab_x11_hotkeys(bindings.clone());
+    thread::spawn(move || {
         unsafe {
             let hinstance = GetModuleHandleW(None).expect("Failed gmhw");
             let class_name: Vec<u16> = CLASS_NAME.encode_utf16().chain(std::iter::once(0)).collect();
@@ -232,8 +830,8 @@ fn main() -> Result<()> {
             );
 
             AddClipboardFormatListener(_hwnd).expect("failed to add clipboard listener");
-            RegisterHotKey(_hwnd, HOTKEY_ID, MOD_CONTROL | MOD_ALT, VK_C.0 as u32)
-                .expect("failed to register hotkey");
+            let _ = HIDDEN_HWND.set(_hwnd);
+            register_hotkeys(_hwnd, bindings);
 
             let mut msg = MSG::default();
             while GetMessageW(&mut msg, HWND(0), 0, 0).into() {
@@ -244,10 +842,11 @@ fn main() -> Result<()> {
     });
 
     let native_options = eframe::NativeOptions::default();
+    let title = config.ui.window_title.clone();
     eframe::run_native(
-        "Clip",
+        &title,
         native_options,
-        Box::new(|cc| Box::new(App::new(cc, visible, needs_refresh))),
+        Box::new(move |cc| Box::new(App::new(cc, visible, needs_refresh, focus_search, config_changed, config_path, cli))),
     ).expect("eframe failure");
 
     Ok(())
@@ -1,3 +1,5 @@
+use serde::{Deserialize, Serialize};
+
 pub struct ClipboardPayload {
     pub format_id: u32,
     pub format_name: String,
@@ -10,6 +12,7 @@ pub struct ClipboardMsg {
     pub exe_path: String,
     pub hash: String,
     pub payloads: Vec<ClipboardPayload>,
+    pub is_sensitive: bool,
 }
 
 pub struct ClipSummary {
@@ -18,4 +21,79 @@ pub struct ClipSummary {
     pub fg_title: String,
     pub preview: String,
     pub hash: String,
-}
\ No newline at end of file
+    pub pin_slot: Option<u8>,
+    pub is_sensitive: bool,
+    pub has_thumbnail: bool,
+    /// Carries HTML or RTF that the rich preview can render.
+    pub has_rich: bool,
+    /// Carries a CF_HDROP or uri-list file list.
+    pub has_files: bool,
+    /// Stored `classify::Kind`, e.g. `url` or `code:rust`.
+    pub kind: Option<String>,
+    /// Clip this one was transformed from.
+    pub parent_hash: Option<String>,
+    pub tags: Vec<String>,
+    /// Name of the device it was copied on, when that's another device.
+    pub device: Option<String>,
+    pub note: Option<String>,
+}
+
+/// The parts of a history clip that `[[sync.push]]` rules match on.
+pub struct ClipFacts {
+    pub owner: String,
+    pub title: String,
+    pub kind: Option<String>,
+    pub pinned: bool,
+    pub sensitive: bool,
+    pub tags: Vec<String>,
+}
+
+/// A clip in `cloud.db`, as listed by the cloud browser.
+pub struct CloudClip {
+    pub hash: String,
+    pub timestamp: String,
+    pub owner: String,
+    pub fg_title: String,
+    pub preview: String,
+    pub size: usize,
+    /// Where it was copied: the device's name, or its id before the registry names it.
+    pub device: Option<String>,
+    /// Pushed from or to a sync server, rather than waiting for the next sync.
+    pub push: PushState,
+}
+
+/// One stored format of a clip, as the inspector lists it.
+pub struct FormatInfo {
+    pub format_id: u32,
+    pub format_name: String,
+    pub size: usize,
+}
+
+/// An entry in the sync set's device registry.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Device {
+    pub id: String,
+    pub name: String,
+    /// X25519, hex.
+    pub public_key: String,
+    #[serde(default)]
+    pub revoked: bool,
+}
+
+/// A device paired for sending clips over the local network.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Peer {
+    pub id: String,
+    pub name: String,
+    /// X25519, hex.
+    pub public_key: String,
+}
+
+/// Where a cloud clip is on its way to the sync server.
+#[derive(Clone, Debug, PartialEq)]
+pub enum PushState {
+    Pending,
+    /// Given up after repeated refusals, with the last error; a retry re-queues it.
+    Failed(String),
+    Uploaded,
+}
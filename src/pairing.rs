@@ -0,0 +1,158 @@
+//! Pairing code exchange: SPAKE2 over Ristretto255. Both devices blind a
+//! Diffie-Hellman share with the code the user typed, so they only end up
+//! with the same key if the codes match. Someone in the middle gets a single
+//! guess per attempt rather than a transcript to test every code against.
+
+use chacha20poly1305::aead::rand_core::RngCore;
+use chacha20poly1305::aead::OsRng;
+use curve25519_dalek::constants::RISTRETTO_BASEPOINT_POINT;
+use curve25519_dalek::ristretto::{CompressedRistretto, RistrettoPoint};
+use curve25519_dalek::scalar::Scalar;
+
+/// Digits in a pairing code.
+pub const CODE_DIGITS: usize = 6;
+
+/// Each side blinds with its own point, so a message can't be reflected back.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Role {
+    Initiator,
+    Responder,
+}
+
+impl Role {
+    fn blinding(self) -> RistrettoPoint {
+        match self {
+            Role::Initiator => point("openclip pairing M"),
+            Role::Responder => point("openclip pairing N"),
+        }
+    }
+
+    fn other(self) -> Role {
+        match self {
+            Role::Initiator => Role::Responder,
+            Role::Responder => Role::Initiator,
+        }
+    }
+}
+
+/// One side of an exchange in progress.
+pub struct Pairing {
+    role: Role,
+    secret: Scalar,
+    password: Scalar,
+    message: [u8; 32],
+}
+
+impl Pairing {
+    pub fn start(role: Role, code: &str) -> Pairing {
+        let mut random = [0u8; 64];
+        OsRng.fill_bytes(&mut random);
+        let secret = Scalar::from_bytes_mod_order_wide(&random);
+        let password = Scalar::from_bytes_mod_order_wide(&wide("openclip pairing code v1", normalize_code(code).as_bytes()));
+        let message = (RISTRETTO_BASEPOINT_POINT * secret + role.blinding() * password).compress().to_bytes();
+        Pairing { role, secret, password, message }
+    }
+
+    /// What to send the other device.
+    pub fn message(&self) -> [u8; 32] {
+        self.message
+    }
+
+    /// The shared key, or None if `theirs` isn't a valid point.
+    pub fn finish(self, theirs: &[u8; 32]) -> Option<[u8; 32]> {
+        let their_point = CompressedRistretto(*theirs).decompress()?;
+        let shared = (their_point - self.role.other().blinding() * self.password) * self.secret;
+        let (initiator, responder) = match self.role {
+            Role::Initiator => (self.message, *theirs),
+            Role::Responder => (*theirs, self.message),
+        };
+        let transcript = [&initiator[..], &responder, shared.compress().as_bytes(), self.password.as_bytes()].concat();
+        Some(blake3::derive_key("openclip pairing key v1", &transcript))
+    }
+}
+
+/// Proves to the other side that `role` derived `key`. Comparing two of
+/// these is constant-time.
+pub fn confirmation(key: &[u8; 32], role: Role) -> blake3::Hash {
+    let label: &[u8] = match role {
+        Role::Initiator => b"initiator",
+        Role::Responder => b"responder",
+    };
+    blake3::keyed_hash(key, label)
+}
+
+/// A fresh random code, zero-padded.
+pub fn new_code() -> String {
+    let range = 10u64.pow(CODE_DIGITS as u32);
+    // Rejection sampling keeps every code equally likely
+    let limit = u64::MAX - u64::MAX % range;
+    loop {
+        let n = OsRng.next_u64();
+        if n < limit {
+            return format!("{:0width$}", n % range, width = CODE_DIGITS);
+        }
+    }
+}
+
+/// Drops the spaces and dashes people type between digit groups.
+pub fn normalize_code(code: &str) -> String {
+    code.chars().filter(|c| !c.is_whitespace() && *c != '-').collect()
+}
+
+fn wide(context: &str, input: &[u8]) -> [u8; 64] {
+    let mut out = [0u8; 64];
+    blake3::Hasher::new_derive_key(context).update(input).finalize_xof().fill(&mut out);
+    out
+}
+
+/// A point nobody knows the discrete log of.
+fn point(label: &str) -> RistrettoPoint {
+    RistrettoPoint::from_uniform_bytes(&wide("openclip pairing points v1", label.as_bytes()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn exchange(initiator_code: &str, responder_code: &str) -> ([u8; 32], [u8; 32]) {
+        let initiator = Pairing::start(Role::Initiator, initiator_code);
+        let responder = Pairing::start(Role::Responder, responder_code);
+        let (to_responder, to_initiator) = (initiator.message(), responder.message());
+        (initiator.finish(&to_initiator).unwrap(), responder.finish(&to_responder).unwrap())
+    }
+
+    #[test]
+    fn same_code_same_key() {
+        let (a, b) = exchange("123456", "123-456");
+        assert_eq!(a, b);
+        assert_eq!(confirmation(&a, Role::Initiator), confirmation(&b, Role::Initiator));
+        assert_ne!(confirmation(&a, Role::Initiator), confirmation(&a, Role::Responder));
+        // Every run gets a fresh key
+        assert_ne!(exchange("123456", "123456").0, a);
+    }
+
+    #[test]
+    fn wrong_code_different_key() {
+        let (a, b) = exchange("123456", "123457");
+        assert_ne!(a, b);
+        assert_ne!(confirmation(&a, Role::Responder), confirmation(&b, Role::Responder));
+    }
+
+    #[test]
+    fn reflected_message_fails() {
+        let initiator = Pairing::start(Role::Initiator, "123456");
+        let own = initiator.message();
+        let responder = Pairing::start(Role::Responder, "123456");
+        let key = Pairing::start(Role::Responder, "123456").finish(&own).unwrap();
+        assert_ne!(initiator.finish(&own), Some(key));
+        assert_eq!(responder.finish(&[0xff; 32]), None);
+    }
+
+    #[test]
+    fn codes() {
+        let code = new_code();
+        assert_eq!(code.len(), CODE_DIGITS);
+        assert!(code.bytes().all(|b| b.is_ascii_digit()));
+        assert_eq!(normalize_code(" 12-34 56 "), "123456");
+    }
+}
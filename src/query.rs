@@ -0,0 +1,445 @@
+use chrono::NaiveDate;
+use rusqlite::types::Value;
+
+use crate::classify::{Kind, Language};
+
+/// Clip kinds `type:` can select on, matched against the stored format ids and names.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ClipType {
+    Text,
+    Image,
+    Html,
+    Rtf,
+    Files,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Flag {
+    Pinned,
+    Sensitive,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Cmp {
+    Lt,
+    Le,
+    Eq,
+    Ge,
+    Gt,
+}
+
+/// One search term. A query is the conjunction of its filters.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Filter {
+    App(String),
+    Title(String),
+    Type(ClipType),
+    /// Stored classifier kind; `code` also matches every `code:<language>`.
+    Kind(String),
+    After(NaiveDate),
+    Before(NaiveDate),
+    Is(Flag),
+    Tag(String),
+    /// Part of a device's name, or its whole id.
+    Device(String),
+    Size(Cmp, u64),
+    /// Bare words, fuzzy-matched against the clip text, app name and window title.
+    Fuzzy(String),
+    Not(Box<Filter>),
+}
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Query {
+    pub filters: Vec<Filter>,
+}
+
+/// Parses the search box syntax:
+///
+/// ```text
+/// app:chrome  title:"Pull Request"  type:image|text|html|rtf|files
+/// kind:url|json|code|rust|...  after:2026-10-01  before:2026-10-18
+/// is:pinned  is:sensitive  tag:share  device:laptop
+/// size:>1mb  size:<=512kb  -is:pinned  fuzzy words
+/// ```
+pub fn parse(input: &str) -> Result<Query, String> {
+    let mut filters = Vec::new();
+    for token in tokenize(input)? {
+        let (negated, token) = match token.strip_prefix('-') {
+            Some(rest) if !rest.is_empty() => (true, rest.to_string()),
+            _ => (false, token),
+        };
+        let filter = match token.split_once(':') {
+            Some((key, value)) if is_known_key(key) => parse_field(key, value)?,
+            _ => Filter::Fuzzy(token),
+        };
+        filters.push(if negated { Filter::Not(Box::new(filter)) } else { filter });
+    }
+    Ok(Query { filters })
+}
+
+fn is_known_key(key: &str) -> bool {
+    matches!(key, "app" | "title" | "type" | "kind" | "after" | "before" | "is" | "tag" | "device" | "size")
+}
+
+fn parse_field(key: &str, value: &str) -> Result<Filter, String> {
+    if value.is_empty() {
+        return Err(format!("{}: needs a value", key));
+    }
+    Ok(match key {
+        "app" => Filter::App(value.to_string()),
+        "title" => Filter::Title(value.to_string()),
+        "type" => Filter::Type(match value.to_ascii_lowercase().as_str() {
+            "text" => ClipType::Text,
+            "image" | "img" => ClipType::Image,
+            "html" => ClipType::Html,
+            "rtf" => ClipType::Rtf,
+            "files" | "file" => ClipType::Files,
+            other => return Err(format!("type: unknown type '{}'", other)),
+        }),
+        "kind" => Filter::Kind(parse_kind(value)?),
+        "after" => Filter::After(parse_date(value)?),
+        "before" => Filter::Before(parse_date(value)?),
+        "is" => Filter::Is(match value.to_ascii_lowercase().as_str() {
+            "pinned" => Flag::Pinned,
+            "sensitive" => Flag::Sensitive,
+            other => return Err(format!("is: unknown flag '{}'", other)),
+        }),
+        "tag" => Filter::Tag(value.trim_start_matches('#').to_lowercase()),
+        "device" => Filter::Device(value.to_string()),
+        "size" => {
+            let (cmp, rest) = parse_cmp(value);
+            Filter::Size(cmp, parse_size(rest)?)
+        }
+        _ => unreachable!("checked by is_known_key"),
+    })
+}
+
+/// A kind name, a language name (`rust` means `code:rust`) or `code:<language>`.
+pub fn parse_kind(value: &str) -> Result<String, String> {
+    let lower = value.to_ascii_lowercase();
+    if Kind::NAMES.contains(&lower.as_str()) {
+        return Ok(lower);
+    }
+    let lang = lower.strip_prefix("code:").unwrap_or(&lower);
+    Language::from_name(lang)
+        .map(|l| Kind::Code(Some(l)).to_string())
+        .ok_or_else(|| format!("kind: unknown kind '{}'", value))
+}
+
+fn parse_date(value: &str) -> Result<NaiveDate, String> {
+    NaiveDate::parse_from_str(value, "%Y-%m-%d")
+        .map_err(|_| format!("'{}' is not a YYYY-MM-DD date", value))
+}
+
+fn parse_cmp(value: &str) -> (Cmp, &str) {
+    for (prefix, cmp) in [(">=", Cmp::Ge), ("<=", Cmp::Le), (">", Cmp::Gt), ("<", Cmp::Lt), ("=", Cmp::Eq)] {
+        if let Some(rest) = value.strip_prefix(prefix) {
+            return (cmp, rest);
+        }
+    }
+    // A bare size reads as "at least"
+    (Cmp::Ge, value)
+}
+
+fn parse_size(value: &str) -> Result<u64, String> {
+    let lower = value.to_ascii_lowercase();
+    let split = lower.find(|c: char| !c.is_ascii_digit() && c != '.').unwrap_or(lower.len());
+    let (number, unit) = lower.split_at(split);
+    let number: f64 = number.parse().map_err(|_| format!("'{}' is not a size", value))?;
+    let multiplier = match unit {
+        "" | "b" => 1u64,
+        "k" | "kb" => 1 << 10,
+        "m" | "mb" => 1 << 20,
+        "g" | "gb" => 1 << 30,
+        _ => return Err(format!("'{}' has an unknown size unit", value)),
+    };
+    Ok((number * multiplier as f64) as u64)
+}
+
+/// Splits on whitespace, keeping `"quoted phrases"` (also after `key:`) together.
+fn tokenize(input: &str) -> Result<Vec<String>, String> {
+    let mut tokens = Vec::new();
+    let mut current = String::new();
+    let mut in_quotes = false;
+    for c in input.chars() {
+        match c {
+            '"' => in_quotes = !in_quotes,
+            c if c.is_whitespace() && !in_quotes => {
+                if !current.is_empty() {
+                    tokens.push(std::mem::take(&mut current));
+                }
+            }
+            c => current.push(c),
+        }
+    }
+    if in_quotes {
+        return Err("unterminated quote".to_string());
+    }
+    if !current.is_empty() {
+        tokens.push(current);
+    }
+    Ok(tokens)
+}
+
+impl Query {
+    /// Positive fuzzy terms, used to rank the rows the SQL filter lets through.
+    pub fn fuzzy_terms(&self) -> Vec<&str> {
+        self.filters
+            .iter()
+            .filter_map(|f| match f {
+                Filter::Fuzzy(term) => Some(term.as_str()),
+                _ => None,
+            })
+            .collect()
+    }
+
+    /// Compiles the filters into a `WHERE` clause over `clips` plus its parameters.
+    pub fn to_sql(&self) -> (String, Vec<Value>) {
+        let mut params = Vec::new();
+        if self.filters.is_empty() {
+            return ("1".to_string(), params);
+        }
+        let clauses: Vec<String> = self
+            .filters
+            .iter()
+            .map(|f| filter_sql(f, &mut params))
+            .collect();
+        (clauses.join(" AND "), params)
+    }
+}
+
+fn filter_sql(filter: &Filter, params: &mut Vec<Value>) -> String {
+    match filter {
+        Filter::App(s) => {
+            params.push(Value::Text(s.clone()));
+            "instr(lower(coalesce(owner_process_name, '')), lower(?)) > 0".to_string()
+        }
+        Filter::Title(s) => {
+            params.push(Value::Text(s.clone()));
+            "instr(lower(coalesce(foreground_window_title, '')), lower(?)) > 0".to_string()
+        }
+        Filter::Type(t) => {
+            let cond = match t {
+                ClipType::Text => "f.format_id IN (1, 7, 13) OR f.format_name LIKE 'text/plain%'",
+                ClipType::Image => "f.format_id IN (2, 8, 17) OR f.format_name IN ('PNG', 'image/png', 'image/bmp', 'image/jpeg')",
+                ClipType::Html => "f.format_name IN ('HTML Format', 'text/html')",
+                ClipType::Rtf => "f.format_name IN ('Rich Text Format', 'text/rtf')",
+                ClipType::Files => "f.format_id = 15 OR f.format_name = 'text/uri-list'",
+            };
+            format!("EXISTS (SELECT 1 FROM formats f WHERE f.clip_id = clips.id AND ({}))", cond)
+        }
+        Filter::Kind(kind) => {
+            params.push(Value::Text(kind.clone()));
+            params.push(Value::Text(format!("{}:%", kind)));
+            "(kind = ? OR kind LIKE ?)".to_string()
+        }
+        Filter::After(d) => {
+            params.push(Value::Text(d.format("%Y-%m-%d").to_string()));
+            "date(timestamp) > ?".to_string()
+        }
+        Filter::Before(d) => {
+            params.push(Value::Text(d.format("%Y-%m-%d").to_string()));
+            "date(timestamp) < ?".to_string()
+        }
+        Filter::Is(Flag::Pinned) => "pin_slot IS NOT NULL".to_string(),
+        Filter::Is(Flag::Sensitive) => "is_sensitive = 1".to_string(),
+        Filter::Tag(tag) => {
+            params.push(Value::Text(tag.clone()));
+            "EXISTS (SELECT 1 FROM tags WHERE tags.clip_id = clips.id AND tags.tag = ?)".to_string()
+        }
+        Filter::Device(s) => {
+            params.push(Value::Text(s.clone()));
+            params.push(Value::Text(s.clone()));
+            "device IN (SELECT id FROM devices WHERE instr(lower(coalesce(name, '')), lower(?)) > 0 OR id = ?)".to_string()
+        }
+        Filter::Size(cmp, bytes) => {
+            params.push(Value::Integer(*bytes as i64));
+            let op = match cmp {
+                Cmp::Lt => "<",
+                Cmp::Le => "<=",
+                Cmp::Eq => "=",
+                Cmp::Ge => ">=",
+                Cmp::Gt => ">",
+            };
+            format!(
+                "(SELECT coalesce(sum(length(data)), 0) FROM formats WHERE clip_id = clips.id) {} ?",
+                op
+            )
+        }
+        Filter::Fuzzy(term) => {
+            // Subsequence match: "prq" becomes '%p%r%q%'
+            let mut pattern = String::from("%");
+            for c in term.chars() {
+                if matches!(c, '%' | '_' | '\\') {
+                    pattern.push('\\');
+                }
+                pattern.push(c);
+                pattern.push('%');
+            }
+            for _ in 0..3 {
+                params.push(Value::Text(pattern.clone()));
+            }
+            "(coalesce(plain_text, '') LIKE ? ESCAPE '\\'
+              OR coalesce(owner_process_name, '') LIKE ? ESCAPE '\\'
+              OR coalesce(foreground_window_title, '') LIKE ? ESCAPE '\\')"
+                .to_string()
+        }
+        Filter::Not(inner) => format!("NOT ({})", filter_sql(inner, params)),
+    }
+}
+
+/// Scores `needle` as a case-insensitive subsequence of `haystack`; higher is better.
+/// Consecutive runs and matches at word starts earn bonuses, gaps cost a little.
+pub fn fuzzy_score(needle: &str, haystack: &str) -> Option<i64> {
+    let needle: Vec<char> = needle.to_lowercase().chars().collect();
+    if needle.is_empty() {
+        return Some(0);
+    }
+    let hay: Vec<char> = haystack.to_lowercase().chars().collect();
+
+    let mut score = 0i64;
+    let mut ni = 0;
+    let mut last_match: Option<usize> = None;
+    for (hi, &c) in hay.iter().enumerate() {
+        if ni == needle.len() {
+            break;
+        }
+        if c != needle[ni] {
+            continue;
+        }
+        score += 10;
+        match last_match {
+            Some(prev) if prev + 1 == hi => score += 15,
+            Some(prev) => score -= (hi - prev - 1).min(10) as i64,
+            None => score -= hi.min(20) as i64,
+        }
+        if hi == 0 || !hay[hi - 1].is_alphanumeric() {
+            score += 10;
+        }
+        last_match = Some(hi);
+        ni += 1;
+    }
+    (ni == needle.len()).then_some(score)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::ClipboardPayload;
+    use crate::storage::Database;
+
+    fn filters(input: &str) -> Vec<Filter> {
+        parse(input).unwrap().filters
+    }
+
+    fn date(s: &str) -> NaiveDate {
+        NaiveDate::parse_from_str(s, "%Y-%m-%d").unwrap()
+    }
+
+    #[test]
+    fn parses_filters_and_words() {
+        assert_eq!(parse("").unwrap(), Query::default());
+        assert_eq!(
+            filters("app:chrome is:Pinned type:img tag:#Share after:2026-10-01 hello"),
+            vec![
+                Filter::App("chrome".to_string()),
+                Filter::Is(Flag::Pinned),
+                Filter::Type(ClipType::Image),
+                Filter::Tag("share".to_string()),
+                Filter::After(date("2026-10-01")),
+                Filter::Fuzzy("hello".to_string()),
+            ]
+        );
+        assert_eq!(filters("kind:rust"), vec![Filter::Kind("code:rust".to_string())]);
+        assert_eq!(filters("kind:URL"), vec![Filter::Kind("url".to_string())]);
+        // Unknown keys are just words, colon and all
+        assert_eq!(filters("http://x foo:bar"), vec![Filter::Fuzzy("http://x".to_string()), Filter::Fuzzy("foo:bar".to_string())]);
+    }
+
+    #[test]
+    fn quoting_and_negation() {
+        assert_eq!(
+            filters(r#"title:"Pull Request"  "two words" -is:sensitive -draft"#),
+            vec![
+                Filter::Title("Pull Request".to_string()),
+                Filter::Fuzzy("two words".to_string()),
+                Filter::Not(Box::new(Filter::Is(Flag::Sensitive))),
+                Filter::Not(Box::new(Filter::Fuzzy("draft".to_string()))),
+            ]
+        );
+        // A lone dash is a word, not an empty negation
+        assert_eq!(filters("-"), vec![Filter::Fuzzy("-".to_string())]);
+        assert_eq!(parse(r#"title:"open"#).unwrap_err(), "unterminated quote");
+    }
+
+    #[test]
+    fn sizes() {
+        assert_eq!(filters("size:>1mb"), vec![Filter::Size(Cmp::Gt, 1 << 20)]);
+        assert_eq!(filters("size:<=512KB"), vec![Filter::Size(Cmp::Le, 512 << 10)]);
+        assert_eq!(filters("size:=10"), vec![Filter::Size(Cmp::Eq, 10)]);
+        assert_eq!(filters("size:<1.5k"), vec![Filter::Size(Cmp::Lt, 1536)]);
+        assert_eq!(filters("size:2g"), vec![Filter::Size(Cmp::Ge, 2 << 30)]);
+        assert!(parse("size:>").unwrap_err().contains("is not a size"));
+        assert!(parse("size:10tb").unwrap_err().contains("unknown size unit"));
+    }
+
+    #[test]
+    fn rejects_bad_values() {
+        assert_eq!(parse("after:2026-13-01").unwrap_err(), "'2026-13-01' is not a YYYY-MM-DD date");
+        assert!(parse("before:yesterday").unwrap_err().contains("is not a YYYY-MM-DD date"));
+        assert_eq!(parse("app:").unwrap_err(), "app: needs a value");
+        assert!(parse("type:video").unwrap_err().contains("unknown type 'video'"));
+        assert!(parse("is:starred").unwrap_err().contains("unknown flag 'starred'"));
+        assert!(parse("kind:cobol").unwrap_err().contains("unknown kind 'cobol'"));
+    }
+
+    #[test]
+    fn compiles_to_sql() {
+        assert_eq!(Query::default().to_sql(), ("1".to_string(), vec![]));
+
+        let (sql, params) = parse("app:chrome -size:<1kb").unwrap().to_sql();
+        assert!(sql.starts_with("instr(lower(coalesce(owner_process_name, '')), lower(?)) > 0 AND NOT ("));
+        assert!(sql.ends_with("FROM formats WHERE clip_id = clips.id) < ?)"));
+        assert_eq!(params, vec![Value::Text("chrome".to_string()), Value::Integer(1024)]);
+
+        // LIKE wildcards in a word are escaped, not matched
+        let (_, params) = parse("5%_off").unwrap().to_sql();
+        assert_eq!(params[0], Value::Text(r"%5%\%%\_%o%f%f%".to_string()));
+        assert_eq!(params.len(), 3);
+    }
+
+    #[test]
+    fn fuzzy_scores() {
+        assert_eq!(fuzzy_score("", "anything"), Some(0));
+        assert_eq!(fuzzy_score("abc", "ab"), None);
+        assert_eq!(fuzzy_score("ba", "ab"), None);
+        assert!(fuzzy_score("PRQ", "pull request queue").is_some());
+        // A run beats scattered letters, and a word start beats the middle of one
+        assert!(fuzzy_score("req", "request") > fuzzy_score("req", "r_e_q"));
+        assert!(fuzzy_score("pr", "pull request") < fuzzy_score("pr", "pr review"));
+        assert!(fuzzy_score("main", "main.rs") > fuzzy_score("main", "domain.rs"));
+    }
+
+    #[test]
+    fn bare_words_match_app_and_title() {
+        let dir = tempfile::tempdir().unwrap();
+        let db = Database::new(&dir.path().join("clipboard.db").to_string_lossy(), "pwd").unwrap();
+        let text = |s: &str| {
+            let data = s.encode_utf16().flat_map(u16::to_le_bytes).collect();
+            vec![ClipboardPayload { format_id: 13, format_name: "CF_UNICODETEXT".to_string(), data }]
+        };
+        db.save_snapshot("chrome.exe", "Pull Request #12", "", "a", &text("first"), false).unwrap();
+        db.save_snapshot("notepad.exe", "todo.txt", "", "b", &text("second"), false).unwrap();
+
+        let found = |input: &str| -> Vec<String> {
+            let query = parse(input).unwrap();
+            db.get_latest_clips(&query, 10, 0, 20).unwrap().into_iter().map(|c| c.preview).collect()
+        };
+        assert_eq!(found("chrome"), ["first"]);
+        assert_eq!(found("pull request"), ["first"]);
+        assert_eq!(found("todo"), ["second"]);
+        assert_eq!(found("second"), ["second"]);
+        assert_eq!(found("-notepad"), ["first"]);
+        assert!(found("firefox").is_empty());
+    }
+}
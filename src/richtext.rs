@@ -0,0 +1,774 @@
+//! Turns HTML and RTF payloads into styled blocks the preview can draw: bold,
+//! italics, underline, strikethrough, inline code, links, headings, list items,
+//! quotes and code blocks. Anything else is reduced to its text.
+
+use std::collections::HashMap;
+
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Style {
+    pub bold: bool,
+    pub italic: bool,
+    pub underline: bool,
+    pub strike: bool,
+    pub code: bool,
+    pub link: Option<String>,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Span {
+    pub text: String,
+    pub style: Style,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum BlockKind {
+    Paragraph,
+    Heading(u8),
+    /// `marker` is the bullet or number shown before the item.
+    ListItem { marker: String, depth: usize },
+    Quote,
+    Code,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Block {
+    pub kind: BlockKind,
+    pub spans: Vec<Span>,
+}
+
+/// Collects spans into blocks, merging neighbours that share a style.
+struct Builder {
+    blocks: Vec<Block>,
+    kind: BlockKind,
+    spans: Vec<Span>,
+}
+
+impl Builder {
+    fn new() -> Self {
+        Builder { blocks: Vec::new(), kind: BlockKind::Paragraph, spans: Vec::new() }
+    }
+
+    fn push(&mut self, text: &str, style: &Style) {
+        if text.is_empty() {
+            return;
+        }
+        match self.spans.last_mut() {
+            Some(last) if last.style == *style => last.text.push_str(text),
+            _ => self.spans.push(Span { text: text.to_string(), style: style.clone() }),
+        }
+    }
+
+    fn ends_with_space(&self) -> bool {
+        self.spans.last().is_none_or(|s| s.text.ends_with([' ', '\n']))
+    }
+
+    /// Ends the current block and starts one of `kind`. Blocks with no visible
+    /// text are dropped.
+    fn start(&mut self, kind: BlockKind) {
+        if let Some(last) = self.spans.last_mut() {
+            if self.kind != BlockKind::Code {
+                let trimmed = last.text.trim_end().len();
+                last.text.truncate(trimmed);
+            }
+        }
+        self.spans.retain(|s| !s.text.is_empty());
+        if self.spans.iter().any(|s| !s.text.trim().is_empty()) {
+            self.blocks.push(Block { kind: self.kind.clone(), spans: std::mem::take(&mut self.spans) });
+        }
+        self.spans.clear();
+        self.kind = kind;
+    }
+
+    fn finish(mut self) -> Vec<Block> {
+        self.start(BlockKind::Paragraph);
+        self.blocks
+    }
+}
+
+/// Text of the blocks with one line per block, for restoring as plain text.
+pub fn plain_text(blocks: &[Block]) -> String {
+    blocks
+        .iter()
+        .map(|b| {
+            let text: String = b.spans.iter().map(|s| s.text.as_str()).collect();
+            match &b.kind {
+                BlockKind::ListItem { marker, depth } => format!("{}{} {}", "  ".repeat(*depth), marker, text),
+                _ => text,
+            }
+        })
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+// ---------------------------------------------------------------------------
+// HTML
+
+fn decode_entity(entity: &str) -> Option<char> {
+    Some(match entity {
+        "amp" => '&',
+        "lt" => '<',
+        "gt" => '>',
+        "quot" => '"',
+        "apos" => '\'',
+        "nbsp" => '\u{a0}',
+        "ndash" => '–',
+        "mdash" => '—',
+        "hellip" => '…',
+        "bull" => '•',
+        "copy" => '©',
+        "reg" => '®',
+        "trade" => '™',
+        "lsquo" => '‘',
+        "rsquo" => '’',
+        "ldquo" => '“',
+        "rdquo" => '”',
+        _ => {
+            let num = entity.strip_prefix('#')?;
+            let code = match num.strip_prefix(['x', 'X']) {
+                Some(hex) => u32::from_str_radix(hex, 16).ok()?,
+                None => num.parse().ok()?,
+            };
+            char::from_u32(code)?
+        }
+    })
+}
+
+fn decode_entities(text: &str) -> String {
+    let mut out = String::with_capacity(text.len());
+    let mut rest = text;
+    while let Some(at) = rest.find('&') {
+        out.push_str(&rest[..at]);
+        rest = &rest[at..];
+        let decoded = rest
+            .find(';')
+            .filter(|&end| end <= 10)
+            .and_then(|end| decode_entity(&rest[1..end]).map(|c| (c, end)));
+        match decoded {
+            Some((c, end)) => {
+                out.push(c);
+                rest = &rest[end + 1..];
+            }
+            None => {
+                out.push('&');
+                rest = &rest[1..];
+            }
+        }
+    }
+    out.push_str(rest);
+    out
+}
+
+/// Value of `name="..."` (or single-quoted, or bare) inside a tag.
+fn attribute(tag: &str, name: &str) -> Option<String> {
+    let lower = tag.to_ascii_lowercase();
+    let mut search = 0;
+    while let Some(found) = lower[search..].find(name) {
+        let at = search + found;
+        search = at + name.len();
+        let before_ok = lower[..at].ends_with(|c: char| c.is_whitespace());
+        let after = lower[search..].trim_start();
+        if !before_ok || !after.starts_with('=') {
+            continue;
+        }
+        let value_start = tag.len() - after.len() + 1;
+        let value = tag[value_start..].trim_start();
+        let (value, _) = match value.chars().next()? {
+            q @ ('"' | '\'') => value[1..].split_once(q)?,
+            _ => value.split_once(|c: char| c.is_whitespace() || c == '>').unwrap_or((value, "")),
+        };
+        return Some(decode_entities(value));
+    }
+    None
+}
+
+/// Inline CSS that Word, Google Docs and browsers use instead of tags.
+fn apply_css(style: &mut Style, css: &str) {
+    for decl in css.split(';') {
+        let Some((prop, value)) = decl.split_once(':') else { continue };
+        let value = value.trim().to_ascii_lowercase();
+        match prop.trim().to_ascii_lowercase().as_str() {
+            "font-weight" => style.bold = value == "bold" || value.parse::<u32>().is_ok_and(|w| w >= 600),
+            "font-style" => style.italic = value == "italic" || value == "oblique",
+            "text-decoration" | "text-decoration-line" => {
+                style.underline |= value.contains("underline");
+                style.strike |= value.contains("line-through");
+            }
+            "font-family" => {
+                style.code |= ["monospace", "courier", "consolas", "menlo"].iter().any(|m| value.contains(m));
+            }
+            _ => {}
+        }
+    }
+}
+
+pub fn parse_html(html: &str) -> Vec<Block> {
+    let mut out = Builder::new();
+    // Open elements and the style each one established
+    let mut stack: Vec<(String, Style)> = Vec::new();
+    // One entry per open list: None for bullets, Some(next number) for ordered
+    let mut lists: Vec<Option<u32>> = Vec::new();
+    let mut pre_depth = 0usize;
+    let mut skip_depth = 0usize;
+    let mut rest = html;
+
+    while !rest.is_empty() {
+        let Some(lt) = rest.find('<') else {
+            if skip_depth == 0 {
+                push_html_text(&mut out, rest, stack.last().map(|(_, s)| s), pre_depth > 0);
+            }
+            break;
+        };
+        if lt > 0 && skip_depth == 0 {
+            push_html_text(&mut out, &rest[..lt], stack.last().map(|(_, s)| s), pre_depth > 0);
+        }
+        rest = &rest[lt..];
+
+        if let Some(comment) = rest.strip_prefix("<!--") {
+            rest = comment.find("-->").map_or("", |end| &comment[end + 3..]);
+            continue;
+        }
+        let Some(gt) = rest.find('>') else { break };
+        let tag = &rest[1..gt];
+        rest = &rest[gt + 1..];
+
+        let closing = tag.starts_with('/');
+        let name: String = tag
+            .trim_start_matches('/')
+            .chars()
+            .take_while(|c| c.is_ascii_alphanumeric())
+            .collect::<String>()
+            .to_ascii_lowercase();
+        if name.is_empty() {
+            continue;
+        }
+
+        if matches!(name.as_str(), "script" | "style" | "head" | "title") {
+            if closing {
+                skip_depth = skip_depth.saturating_sub(1);
+            } else if !tag.ends_with('/') {
+                skip_depth += 1;
+            }
+            continue;
+        }
+        if skip_depth > 0 {
+            continue;
+        }
+
+        if closing {
+            if let Some(pos) = stack.iter().rposition(|(n, _)| *n == name) {
+                stack.truncate(pos);
+            }
+            match name.as_str() {
+                "ul" | "ol" => {
+                    lists.pop();
+                    out.start(BlockKind::Paragraph);
+                }
+                "pre" => {
+                    pre_depth = pre_depth.saturating_sub(1);
+                    out.start(BlockKind::Paragraph);
+                }
+                "p" | "div" | "li" | "blockquote" | "h1" | "h2" | "h3" | "h4" | "h5" | "h6" | "tr" | "table" => {
+                    out.start(BlockKind::Paragraph)
+                }
+                _ => {}
+            }
+            continue;
+        }
+
+        let mut style = stack.last().map(|(_, s)| s.clone()).unwrap_or_default();
+        match name.as_str() {
+            "br" => {
+                out.push("\n", &style);
+                continue;
+            }
+            "hr" | "img" | "meta" | "link" | "input" | "col" | "wbr" => continue,
+            "p" | "div" | "tr" | "table" => out.start(BlockKind::Paragraph),
+            "td" | "th" if !out.ends_with_space() => out.push("\t", &style),
+            "h1" | "h2" | "h3" | "h4" | "h5" | "h6" => {
+                out.start(BlockKind::Heading(name.as_bytes()[1] - b'0'));
+            }
+            "blockquote" => out.start(BlockKind::Quote),
+            "pre" => {
+                pre_depth += 1;
+                out.start(BlockKind::Code);
+            }
+            "ul" => lists.push(None),
+            "ol" => {
+                let start = attribute(tag, "start").and_then(|s| s.parse().ok()).unwrap_or(1);
+                lists.push(Some(start));
+            }
+            "li" => {
+                let marker = match lists.last_mut() {
+                    Some(Some(n)) => {
+                        let marker = format!("{}.", n);
+                        *n = n.saturating_add(1);
+                        marker
+                    }
+                    _ => "•".to_string(),
+                };
+                out.start(BlockKind::ListItem { marker, depth: lists.len().saturating_sub(1) });
+            }
+            "b" | "strong" => style.bold = true,
+            "i" | "em" | "cite" | "var" => style.italic = true,
+            "u" | "ins" => style.underline = true,
+            "s" | "strike" | "del" => style.strike = true,
+            "code" | "tt" | "kbd" | "samp" => style.code = true,
+            "a" => style.link = attribute(tag, "href"),
+            _ => {}
+        }
+        if let Some(css) = attribute(tag, "style") {
+            apply_css(&mut style, &css);
+        }
+        if !tag.ends_with('/') {
+            stack.push((name, style));
+        }
+    }
+    out.finish()
+}
+
+fn push_html_text(out: &mut Builder, raw: &str, style: Option<&Style>, preformatted: bool) {
+    let default = Style::default();
+    let style = style.unwrap_or(&default);
+    let text = decode_entities(raw);
+    if preformatted {
+        out.push(&text, style);
+        return;
+    }
+    let mut collapsed = String::with_capacity(text.len());
+    let mut space = out.ends_with_space();
+    for c in text.chars() {
+        if c.is_whitespace() && c != '\u{a0}' {
+            if !space {
+                collapsed.push(' ');
+                space = true;
+            }
+        } else {
+            collapsed.push(if c == '\u{a0}' { ' ' } else { c });
+            space = false;
+        }
+    }
+    out.push(&collapsed, style);
+}
+
+// ---------------------------------------------------------------------------
+// RTF
+
+#[derive(Clone, Default)]
+struct RtfState {
+    style: Style,
+    /// Destination whose text is not part of the document.
+    skip: bool,
+    font_table: bool,
+    /// Inside `\fldinst`, whose text names the field (e.g. `HYPERLINK "..."`).
+    field_instruction: bool,
+    list_text: bool,
+    /// Characters to skip after `\uN`.
+    uc: usize,
+}
+
+const RTF_SKIPPED: &[&str] = &[
+    "colortbl", "stylesheet", "info", "pict", "header", "footer", "headerl", "headerr", "footerl",
+    "footerr", "listtable", "listoverridetable", "rsidtbl", "themedata", "colorschememapping",
+    "latentstyles", "datastore", "xmlnstbl", "generator", "object", "shp", "nonshppict",
+];
+
+/// Windows-1252 for the bytes `\'hh` escapes; Latin-1 covers the rest.
+fn cp1252(byte: u8) -> char {
+    const HIGH: [char; 32] = [
+        '€', '\u{81}', '‚', 'ƒ', '„', '…', '†', '‡', 'ˆ', '‰', 'Š', '‹', 'Œ', '\u{8d}', 'Ž', '\u{8f}',
+        '\u{90}', '‘', '’', '“', '”', '•', '–', '—', '˜', '™', 'š', '›', 'œ', '\u{9d}', 'ž', 'Ÿ',
+    ];
+    match byte {
+        0x80..=0x9F => HIGH[(byte - 0x80) as usize],
+        b => b as char,
+    }
+}
+
+/// Parser state that outlives a single group.
+struct RtfReader {
+    out: Builder,
+    state: RtfState,
+    stack: Vec<RtfState>,
+    text: String,
+    /// Font number -> monospace, filled from `\fonttbl`.
+    mono_fonts: HashMap<i32, bool>,
+    font_entry: Option<(i32, bool, String)>,
+    instruction: String,
+    list_marker: String,
+    list_depth: usize,
+    /// A `\listtext` group just closed, so the next text opens a list item.
+    in_list: bool,
+}
+
+impl RtfReader {
+    /// Sends the pending text to wherever the current destination collects it.
+    fn flush(&mut self) {
+        if self.text.is_empty() {
+            return;
+        }
+        let text = std::mem::take(&mut self.text);
+        if self.state.field_instruction {
+            self.instruction.push_str(&text);
+        } else if self.state.list_text {
+            self.list_marker.push_str(&text);
+        } else if self.state.font_table {
+            if let Some((_, _, name)) = &mut self.font_entry {
+                name.push_str(&text);
+            }
+        } else if !self.state.skip {
+            if self.in_list {
+                let marker = match self.list_marker.trim() {
+                    "" | "·" | "o" | "§" | "\u{f0b7}" => "•".to_string(),
+                    m => m.to_string(),
+                };
+                self.out.start(BlockKind::ListItem { marker, depth: self.list_depth });
+                self.in_list = false;
+            }
+            self.out.push(&text, &self.state.style);
+        }
+    }
+
+    fn finish_font(&mut self) {
+        if let Some((n, fixed, name)) = self.font_entry.take() {
+            let name = name.trim_end_matches(';').to_ascii_lowercase();
+            let mono = fixed
+                || ["courier", "consolas", "mono", "menlo", "lucida console"]
+                    .iter()
+                    .any(|m| name.contains(m));
+            self.mono_fonts.insert(n, mono);
+        }
+    }
+
+    fn paragraph(&mut self) {
+        self.flush();
+        self.out.start(BlockKind::Paragraph);
+        self.in_list = false;
+    }
+
+    fn control_word(&mut self, word: &str, param: Option<i32>) -> usize {
+        let on = param != Some(0);
+        match word {
+            "par" | "sect" | "page" | "row" => self.paragraph(),
+            "line" => self.text.push('\n'),
+            "tab" | "cell" => self.text.push('\t'),
+            "bullet" => self.text.push('•'),
+            "emdash" => self.text.push('—'),
+            "endash" => self.text.push('–'),
+            "lquote" => self.text.push('‘'),
+            "rquote" => self.text.push('’'),
+            "ldblquote" => self.text.push('“'),
+            "rdblquote" => self.text.push('”'),
+            "u" => {
+                if let Some(n) = param {
+                    // Negative values are UTF-16 units above 0x7FFF
+                    if let Some(c) = char::from_u32(n as i16 as u16 as u32) {
+                        self.text.push(c);
+                    }
+                    return self.state.uc;
+                }
+            }
+            "uc" => self.state.uc = param.unwrap_or(1).max(0) as usize,
+            _ => {
+                self.flush();
+                let state = &mut self.state;
+                match word {
+                    "b" => state.style.bold = on,
+                    "i" => state.style.italic = on,
+                    "ul" => state.style.underline = on,
+                    "ulnone" => state.style.underline = false,
+                    "strike" => state.style.strike = on,
+                    "plain" => {
+                        let link = state.style.link.take();
+                        state.style = Style { link, ..Default::default() };
+                    }
+                    "pard" => self.list_depth = 0,
+                    "ilvl" => self.list_depth = param.unwrap_or(0).max(0) as usize,
+                    "fonttbl" => state.font_table = true,
+                    "f" if state.font_table => {
+                        self.finish_font();
+                        self.font_entry = Some((param.unwrap_or(0), false, String::new()));
+                    }
+                    "fmodern" if state.font_table => {
+                        if let Some((_, fixed, _)) = &mut self.font_entry {
+                            *fixed = true;
+                        }
+                    }
+                    "f" => state.style.code = self.mono_fonts.get(&param.unwrap_or(0)).copied().unwrap_or(false),
+                    "fldinst" => {
+                        state.field_instruction = true;
+                        self.instruction.clear();
+                    }
+                    "fldrslt" => state.style.link = hyperlink_target(&self.instruction),
+                    "listtext" | "pntext" => {
+                        state.list_text = true;
+                        self.list_marker.clear();
+                    }
+                    w if RTF_SKIPPED.contains(&w) => state.skip = true,
+                    _ => {}
+                }
+            }
+        }
+        0
+    }
+
+    fn close_group(&mut self) {
+        self.flush();
+        if self.state.font_table {
+            self.finish_font();
+        }
+        let parent = self.stack.pop().unwrap_or_default();
+        if self.state.list_text && !parent.list_text {
+            self.in_list = true;
+        }
+        self.state = parent;
+    }
+}
+
+pub fn parse_rtf(rtf: &str) -> Vec<Block> {
+    let mut reader = RtfReader {
+        out: Builder::new(),
+        state: RtfState { uc: 1, ..Default::default() },
+        stack: Vec::new(),
+        text: String::new(),
+        mono_fonts: HashMap::new(),
+        font_entry: None,
+        instruction: String::new(),
+        list_marker: String::new(),
+        list_depth: 0,
+        in_list: false,
+    };
+    let bytes = rtf.as_bytes();
+    let mut i = 0;
+    // Fallback characters still to drop after `\uN`
+    let mut pending_skip = 0usize;
+
+    while i < bytes.len() {
+        let c = bytes[i];
+        if pending_skip > 0 && c != b'{' && c != b'}' {
+            // A \'hh escape counts as one character, as does a multi-byte one
+            pending_skip -= 1;
+            i += match c {
+                b'\\' if bytes.get(i + 1) == Some(&b'\'') => if hex_escape(rtf, i).is_some() { 4 } else { 2 },
+                _ => rtf[i..].chars().next().map_or(1, char::len_utf8),
+            };
+            continue;
+        }
+        match c {
+            b'{' => {
+                reader.flush();
+                reader.stack.push(reader.state.clone());
+                i += 1;
+            }
+            b'}' => {
+                reader.close_group();
+                i += 1;
+            }
+            b'\\' => {
+                let Some(&next) = bytes.get(i + 1) else { break };
+                if next == b'\'' {
+                    // A malformed escape drops just the \' and reads on from there,
+                    // which keeps `i` on a character boundary
+                    match hex_escape(rtf, i) {
+                        Some(b) => {
+                            reader.text.push(cp1252(b));
+                            i += 4;
+                        }
+                        None => i += 2,
+                    }
+                    continue;
+                }
+                if !next.is_ascii_alphabetic() {
+                    match next {
+                        b'\\' | b'{' | b'}' => reader.text.push(next as char),
+                        b'~' => reader.text.push(' '),
+                        b'_' => reader.text.push('-'),
+                        b'*' => reader.state.skip = true,
+                        b'\n' | b'\r' => reader.paragraph(),
+                        _ => {}
+                    }
+                    // Step over the whole symbol, which may be a multi-byte character
+                    i += 1 + rtf[i + 1..].chars().next().map_or(1, char::len_utf8);
+                    continue;
+                }
+
+                let mut end = i + 1;
+                while end < bytes.len() && bytes[end].is_ascii_alphabetic() {
+                    end += 1;
+                }
+                let word = &rtf[i + 1..end];
+                let num_start = end;
+                if bytes.get(end) == Some(&b'-') {
+                    end += 1;
+                }
+                while end < bytes.len() && bytes[end].is_ascii_digit() {
+                    end += 1;
+                }
+                let param = rtf[num_start..end].parse().ok();
+                // One space after a control word is its delimiter, not text
+                if bytes.get(end) == Some(&b' ') {
+                    end += 1;
+                }
+                i = end;
+                pending_skip = reader.control_word(word, param);
+            }
+            b'\r' | b'\n' => i += 1,
+            _ => {
+                let run_end = bytes[i..]
+                    .iter()
+                    .position(|b| matches!(b, b'\\' | b'{' | b'}' | b'\r' | b'\n'))
+                    .map_or(bytes.len(), |p| i + p);
+                reader.text.push_str(&rtf[i..run_end]);
+                i = run_end;
+            }
+        }
+    }
+    reader.flush();
+    reader.out.finish()
+}
+
+/// The byte of the `\'hh` escape starting at `i`, if both digits are hex.
+fn hex_escape(rtf: &str, i: usize) -> Option<u8> {
+    let digits = rtf.get(i + 2..i + 4)?;
+    if !digits.bytes().all(|b| b.is_ascii_hexdigit()) {
+        return None;
+    }
+    u8::from_str_radix(digits, 16).ok()
+}
+
+/// The URL in a field instruction like `HYPERLINK "https://example.com"`.
+fn hyperlink_target(instruction: &str) -> Option<String> {
+    let rest = instruction.trim().strip_prefix("HYPERLINK")?.trim();
+    let url = match rest.strip_prefix('"') {
+        Some(quoted) => quoted.split('"').next()?,
+        None => rest.split_whitespace().next()?,
+    };
+    (!url.is_empty()).then(|| url.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use proptest::prelude::*;
+
+    fn texts(block: &Block) -> Vec<(&str, &Style)> {
+        block.spans.iter().map(|s| (s.text.as_str(), &s.style)).collect()
+    }
+
+    #[test]
+    fn html_inline_styles_and_links() {
+        let blocks = parse_html(
+            "<p>Plain <b>bold <i>both</i></b> <a href=\"https://x.test/?a=1&amp;b=2\">link</a> &lt;tag&gt;</p>",
+        );
+        assert_eq!(blocks.len(), 1);
+        let spans = texts(&blocks[0]);
+        assert_eq!(spans[0].0, "Plain ");
+        assert_eq!(spans[1], ("bold ", &Style { bold: true, ..Default::default() }));
+        assert_eq!(spans[2], ("both", &Style { bold: true, italic: true, ..Default::default() }));
+        assert_eq!(spans[4].1.link.as_deref(), Some("https://x.test/?a=1&b=2"));
+        assert_eq!(spans[5].0, " <tag>");
+    }
+
+    #[test]
+    fn html_blocks() {
+        let html = "<html><head><style>p{}</style></head><body><!--StartFragment-->\
+            <h2>Title</h2><ul><li>one</li><li>two<ol start=\"3\"><li>three</li></ol></li></ul>\
+            <pre>let x  = 1;\n  x</pre><blockquote>quoted</blockquote>\
+            <span style=\"font-weight:700\">heavy</span><!--EndFragment--></body></html>";
+        let blocks = parse_html(html);
+        let kinds: Vec<&BlockKind> = blocks.iter().map(|b| &b.kind).collect();
+        assert_eq!(
+            kinds,
+            [
+                &BlockKind::Heading(2),
+                &BlockKind::ListItem { marker: "•".into(), depth: 0 },
+                &BlockKind::ListItem { marker: "•".into(), depth: 0 },
+                &BlockKind::ListItem { marker: "3.".into(), depth: 1 },
+                &BlockKind::Code,
+                &BlockKind::Quote,
+                &BlockKind::Paragraph,
+            ]
+        );
+        assert_eq!(blocks[4].spans[0].text, "let x  = 1;\n  x");
+        assert!(blocks[6].spans[0].style.bold);
+        assert_eq!(
+            plain_text(&blocks),
+            "Title\n• one\n• two\n  3. three\nlet x  = 1;\n  x\nquoted\nheavy"
+        );
+    }
+
+    #[test]
+    fn html_whitespace_collapses() {
+        let blocks = parse_html("<div>  a \n\n b&nbsp;&nbsp;c<br>d </div>");
+        assert_eq!(plain_text(&blocks), "a b  c\nd");
+    }
+
+    #[test]
+    fn rtf_styles_and_escapes() {
+        let rtf = r#"{\rtf1\ansi\deff0{\fonttbl{\f0\fswiss Arial;}{\f1\fmodern Courier New;}}
+{\colortbl;\red255\green0\blue0;}
+\pard Hello \b bold\b0  \i it\i0  \ul under\ulnone  caf\'e9 \u8364? \f1 mono\f0\par
+{\field{\*\fldinst HYPERLINK "https://example.com"}{\fldrslt link}}\par}"#;
+        let blocks = parse_rtf(rtf);
+        assert_eq!(plain_text(&blocks), "Hello bold it under café € mono\nlink");
+        let spans = texts(&blocks[0]);
+        assert_eq!(spans[1], ("bold", &Style { bold: true, ..Default::default() }));
+        assert!(spans.iter().any(|(t, s)| *t == "it" && s.italic));
+        assert!(spans.iter().any(|(t, s)| *t == "under" && s.underline));
+        assert!(spans.iter().any(|(t, s)| *t == "mono" && s.code));
+        assert_eq!(blocks[1].spans[0].style.link.as_deref(), Some("https://example.com"));
+    }
+
+    #[test]
+    fn rtf_lists() {
+        let rtf = r"{\rtf1\ansi{\fonttbl{\f0 Calibri;}{\f1 Symbol;}}
+{\listtext\f1 \'b7\tab}\pard\ls1\ilvl0 first\par
+{\listtext 2.\tab}\pard\ls2\ilvl1 second\par
+after\par}";
+        let blocks = parse_rtf(rtf);
+        assert_eq!(blocks[0].kind, BlockKind::ListItem { marker: "•".into(), depth: 0 });
+        assert_eq!(blocks[1].kind, BlockKind::ListItem { marker: "2.".into(), depth: 1 });
+        assert_eq!(blocks[2].kind, BlockKind::Paragraph);
+        assert_eq!(plain_text(&blocks), "• first\n  2. second\nafter");
+    }
+
+    #[test]
+    fn rtf_multibyte_text_after_escapes() {
+        // The \u fallback is the multi-byte é itself
+        assert_eq!(plain_text(&parse_rtf(r"{\rtf1 \u233 é rest}")), "é rest");
+        assert_eq!(plain_text(&parse_rtf(r"{\rtf1 \uc2\u8364 €€x}")), "€x");
+        assert_eq!(plain_text(&parse_rtf(r"{\rtf1 \u8364 \'€x}")), "€€x");
+        // A \' escape without two hex digits
+        assert_eq!(plain_text(&parse_rtf(r"{\rtf1 \'€x}")), "€x");
+        assert_eq!(plain_text(&parse_rtf(r"{\rtf1 \'e€x}")), "e€x");
+        assert_eq!(plain_text(&parse_rtf(r"{\rtf1 a\'+fb}")), "a+fb");
+        assert_eq!(plain_text(&parse_rtf(r"{\rtf1 end\'")), "end");
+        // An unknown control symbol that isn't ASCII
+        assert_eq!(plain_text(&parse_rtf(r"{\rtf1 a\😀b}")), "ab");
+    }
+
+    proptest! {
+        // Control words, escapes and groups mixed with multi-byte text
+        #[test]
+        fn rtf_never_panics(rtf in r"\{\\rtf1 ([\\{}'a-f0-9 éż€😀-]|\\u-?[0-9]{1,6} ?|\\uc[0-9]|\\'[0-9a-fé€]{0,2}){0,40}") {
+            parse_rtf(&rtf);
+        }
+
+        #[test]
+        fn html_never_panics(html in r"(<ol start=[0-9]{1,12}>|<li>|</ol>|<ul>|[a-zé€ ]|&[a-z#0-9]{0,6};?){0,40}") {
+            parse_html(&html);
+        }
+    }
+
+    #[test]
+    fn html_list_start_at_the_limit() {
+        let blocks = parse_html("<ol start=4294967295><li>a</li><li>b</li></ol>");
+        let markers: Vec<&BlockKind> = blocks.iter().map(|b| &b.kind).collect();
+        assert_eq!(
+            markers,
+            [
+                &BlockKind::ListItem { marker: "4294967295.".into(), depth: 0 },
+                &BlockKind::ListItem { marker: "4294967295.".into(), depth: 0 },
+            ]
+        );
+    }
+}
@@ -0,0 +1,215 @@
+//! Runs the user transforms declared under `[[transforms]]`: external commands
+//! that read the clip text on stdin and write the result to stdout, and
+//! WebAssembly modules run in a wasmi sandbox.
+//!
+//! A module gets no imports, so it can't reach files, the network or the
+//! clock, and it runs with capped fuel and memory. It must export:
+//!
+//! ```text
+//! memory
+//! alloc(len: i32) -> i32
+//! transform(ptr: i32, len: i32, format_ptr: i32, format_len: i32) -> i64
+//! ```
+//!
+//! `alloc` is called for the input bytes and then for the format name.
+//! `transform` returns its result as `ptr << 32 | len`, UTF-8 text in its own
+//! memory; trapping (e.g. `unreachable`) reports failure.
+
+use std::io::{Read, Write};
+use std::path::Path;
+use std::process::{Command, Stdio};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use wasmi::{Engine, Linker, Module, Store, StoreLimits, StoreLimitsBuilder};
+
+/// Roughly a few seconds of work, far more than any text transform needs.
+const WASM_FUEL: u64 = 2_000_000_000;
+const WASM_MAX_MEMORY: usize = 64 << 20;
+
+pub fn run_command(command: &[String], input: &str, timeout: Duration) -> Result<String, String> {
+    let (program, args) = command.split_first().ok_or("empty command")?;
+    let mut cmd = Command::new(program);
+    cmd.args(args).stdin(Stdio::piped()).stdout(Stdio::piped()).stderr(Stdio::piped());
+    #[cfg(windows)]
+    {
+        use std::os::windows::process::CommandExt;
+        const CREATE_NO_WINDOW: u32 = 0x0800_0000;
+        cmd.creation_flags(CREATE_NO_WINDOW);
+    }
+    let mut child = cmd.spawn().map_err(|e| format!("{}: {}", program, e))?;
+
+    // Feed and drain on their own threads so a chatty child can't deadlock on a full pipe
+    let mut stdin = child.stdin.take().expect("stdin is piped");
+    let data = input.as_bytes().to_vec();
+    thread::spawn(move || {
+        let _ = stdin.write_all(&data);
+    });
+    let drain = |mut pipe: Box<dyn Read + Send>| {
+        thread::spawn(move || {
+            let mut buf = Vec::new();
+            let _ = pipe.read_to_end(&mut buf);
+            buf
+        })
+    };
+    let stdout = drain(Box::new(child.stdout.take().expect("stdout is piped")));
+    let stderr = drain(Box::new(child.stderr.take().expect("stderr is piped")));
+
+    let deadline = Instant::now() + timeout;
+    let status = loop {
+        if let Some(status) = child.try_wait().map_err(|e| format!("{}: {}", program, e))? {
+            break status;
+        }
+        if Instant::now() >= deadline {
+            let _ = child.kill();
+            let _ = child.wait();
+            return Err(format!("{} timed out after {} ms", program, timeout.as_millis()));
+        }
+        thread::sleep(Duration::from_millis(10));
+    };
+    let out = stdout.join().unwrap_or_default();
+    if !status.success() {
+        let err = String::from_utf8_lossy(&stderr.join().unwrap_or_default()).trim().to_string();
+        let reason = err.lines().next().unwrap_or("no output on stderr").to_string();
+        return Err(format!("{} failed ({}): {}", program, status, reason));
+    }
+
+    let mut text = String::from_utf8(out).map_err(|_| format!("{} wrote output that isn't UTF-8", program))?;
+    // Most tools end with a newline the clip didn't have
+    if !input.ends_with('\n') && text.ends_with('\n') {
+        text.pop();
+        if text.ends_with('\r') {
+            text.pop();
+        }
+    }
+    Ok(text)
+}
+
+pub fn run_wasm(path: &Path, input: &[u8], format: &str) -> Result<String, String> {
+    let wasm = std::fs::read(path).map_err(|e| format!("{}: {}", path.display(), e))?;
+    run_module(&wasm, input, format, WASM_FUEL)
+}
+
+fn run_module(wasm: &[u8], input: &[u8], format: &str, fuel: u64) -> Result<String, String> {
+    let mut config = wasmi::Config::default();
+    config.consume_fuel(true);
+    let engine = Engine::new(&config);
+    let module = Module::new(&engine, wasm).map_err(|e| format!("invalid module: {}", e))?;
+
+    let mut store = Store::new(&engine, StoreLimitsBuilder::new().memory_size(WASM_MAX_MEMORY).build());
+    store.limiter(|limits: &mut StoreLimits| limits);
+    store.set_fuel(fuel).map_err(|e| e.to_string())?;
+    // An empty linker is the sandbox: any import fails to resolve
+    let instance = Linker::new(&engine)
+        .instantiate_and_start(&mut store, &module)
+        .map_err(|e| format!("instantiate: {}", e))?;
+
+    let memory = instance.get_memory(&store, "memory").ok_or("module exports no memory")?;
+    let alloc = instance
+        .get_typed_func::<i32, i32>(&store, "alloc")
+        .map_err(|e| format!("alloc: {}", e))?;
+    let transform = instance
+        .get_typed_func::<(i32, i32, i32, i32), i64>(&store, "transform")
+        .map_err(|e| format!("transform: {}", e))?;
+
+    let pass = |store: &mut Store<StoreLimits>, bytes: &[u8]| -> Result<(i32, i32), String> {
+        let len = i32::try_from(bytes.len()).map_err(|_| "input too large")?;
+        let ptr = alloc.call(&mut *store, len).map_err(|e| format!("alloc: {}", e))?;
+        memory
+            .write(&mut *store, ptr as u32 as usize, bytes)
+            .map_err(|e| format!("alloc returned a bad pointer: {}", e))?;
+        Ok((ptr, len))
+    };
+    let (ptr, len) = pass(&mut store, input)?;
+    let (format_ptr, format_len) = pass(&mut store, format.as_bytes())?;
+
+    let packed = transform
+        .call(&mut store, (ptr, len, format_ptr, format_len))
+        .map_err(|e| format!("transform: {}", e))?;
+    let (start, len) = ((packed >> 32) as u32 as usize, packed as u32 as usize);
+    let out = memory
+        .data(&store)
+        .get(start..start + len)
+        .ok_or("transform returned a range outside its memory")?;
+    String::from_utf8(out.to_vec()).map_err(|_| "transform returned text that isn't UTF-8".to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TEST_FUEL: u64 = 1_000_000;
+
+    /// Bump allocator plus an in-place ASCII upper-caser.
+    const UPPER: &str = r#"
+        (module
+          (memory (export "memory") 1)
+          (global $heap (mut i32) (i32.const 1024))
+          (func (export "alloc") (param $len i32) (result i32)
+            (local $ptr i32)
+            (local.set $ptr (global.get $heap))
+            (global.set $heap (i32.add (global.get $heap) (local.get $len)))
+            (local.get $ptr))
+          (func (export "transform") (param $ptr i32) (param $len i32) (param $fptr i32) (param $flen i32) (result i64)
+            (local $i i32) (local $c i32)
+            (block $done
+              (loop $next
+                (br_if $done (i32.ge_u (local.get $i) (local.get $len)))
+                (local.set $c (i32.load8_u (i32.add (local.get $ptr) (local.get $i))))
+                (if (i32.and (i32.ge_u (local.get $c) (i32.const 97)) (i32.le_u (local.get $c) (i32.const 122)))
+                  (then (i32.store8 (i32.add (local.get $ptr) (local.get $i)) (i32.sub (local.get $c) (i32.const 32)))))
+                (local.set $i (i32.add (local.get $i) (i32.const 1)))
+                (br $next)))
+            (i64.or (i64.shl (i64.extend_i32_u (local.get $ptr)) (i64.const 32)) (i64.extend_i32_u (local.get $len)))))
+    "#;
+
+    #[test]
+    fn wasm_transforms_text() {
+        assert_eq!(run_module(UPPER.as_bytes(), b"hello, wasm", "text/plain", TEST_FUEL).unwrap(), "HELLO, WASM");
+    }
+
+    #[test]
+    fn wasm_sees_the_format_name() {
+        // Returns the format name instead of the input
+        let echo_format = UPPER.replace(
+            "(i64.extend_i32_u (local.get $ptr)) (i64.const 32)) (i64.extend_i32_u (local.get $len))",
+            "(i64.extend_i32_u (local.get $fptr)) (i64.const 32)) (i64.extend_i32_u (local.get $flen))",
+        );
+        assert_eq!(run_module(echo_format.as_bytes(), b"x", "HTML Format", TEST_FUEL).unwrap(), "HTML Format");
+    }
+
+    #[test]
+    fn wasm_is_sandboxed() {
+        let spin = r#"(module (memory (export "memory") 1)
+            (func (export "alloc") (param i32) (result i32) (i32.const 0))
+            (func (export "transform") (param i32 i32 i32 i32) (result i64) (loop $l (br $l)) (i64.const 0)))"#;
+        assert!(run_module(spin.as_bytes(), b"", "text/plain", TEST_FUEL).unwrap_err().starts_with("transform:"));
+
+        let imports = r#"(module (import "env" "open" (func)) (memory (export "memory") 1))"#;
+        assert!(run_module(imports.as_bytes(), b"", "text/plain", TEST_FUEL).unwrap_err().starts_with("instantiate:"));
+
+        let grow = r#"(module (memory (export "memory") 1)
+            (func (export "alloc") (param i32) (result i32) (drop (memory.grow (i32.const 2000))) (i32.const 0))
+            (func (export "transform") (param i32 i32 i32 i32) (result i64) (i64.extend_i32_u (memory.size))))"#;
+        // transform reports the page count as the result length: the 125 MiB grow
+        // is refused, leaving the single starting page
+        assert_eq!(run_module(grow.as_bytes(), b"", "text/plain", TEST_FUEL).unwrap().len(), 1);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn commands() {
+        let sh = |script: &str| vec!["sh".to_string(), "-c".to_string(), script.to_string()];
+        let second = Duration::from_secs(5);
+        assert_eq!(run_command(&sh("tr a-z A-Z"), "abc", second).unwrap(), "ABC");
+        // The trailing newline from echo is dropped because the input had none
+        assert_eq!(run_command(&sh("cat; echo"), "abc", second).unwrap(), "abc");
+        assert_eq!(run_command(&sh("cat"), "abc\n", second).unwrap(), "abc\n");
+        assert_eq!(
+            run_command(&sh("echo nope >&2; exit 3"), "", second).unwrap_err(),
+            "sh failed (exit status: 3): nope"
+        );
+        assert!(run_command(&sh("sleep 5"), "", Duration::from_millis(50)).unwrap_err().contains("timed out"));
+        assert!(run_command(&["/no/such/program".to_string()], "", second).is_err());
+    }
+}
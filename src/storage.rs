@@ -1,5 +1,10 @@
-use rusqlite::{params, Connection, Result};
-use crate::models::{ClipboardPayload, ClipSummary};
+use rusqlite::{params, params_from_iter, types::Value, Connection, Result};
+use crate::models::{ClipboardPayload, ClipFacts, ClipSummary, Device, FormatInfo};
+use crate::query::{fuzzy_score, Query};
+use crate::imaging::{self, Thumbnail};
+use crate::files;
+use crate::classify;
+use std::collections::HashMap;
 
 pub struct Database {
     conn: Connection,
@@ -16,6 +21,46 @@ fn apply_cipher_pragmas(conn: &Connection, password: &str) -> Result<()> {
     Ok(())
 }
 
+/// Adds `column` to `table` unless it's already there. Returns whether it was added.
+pub(crate) fn add_column_if_missing(conn: &Connection, table: &str, column: &str, decl: &str) -> Result<bool> {
+    let mut stmt = conn.prepare(&format!("PRAGMA table_info({})", table))?;
+    let exists = stmt
+        .query_map([], |r| r.get::<_, String>(1))?
+        .collect::<Result<Vec<_>>>()?
+        .iter()
+        .any(|name| name == column);
+    if !exists {
+        conn.execute(&format!("ALTER TABLE {} ADD COLUMN {} {}", table, column, decl), [])?;
+    }
+    Ok(!exists)
+}
+
+/// Lowercased, deduplicated tags from free text like "share, Work".
+pub fn parse_tags(text: &str) -> Vec<String> {
+    let mut tags: Vec<String> = text
+        .split(|c: char| c == ',' || c.is_whitespace())
+        .map(|t| t.trim_start_matches('#').to_lowercase())
+        .filter(|t| !t.is_empty())
+        .collect();
+    tags.sort();
+    tags.dedup();
+    tags
+}
+
+/// Best-effort text from a CF_UNICODETEXT (UTF-16LE) or CF_TEXT payload, minus the trailing NUL.
+pub fn decode_text(bytes: &[u8]) -> String {
+    let text = if bytes.len() >= 2 && bytes.len().is_multiple_of(2) {
+        let utf16: Vec<u16> = bytes
+            .chunks_exact(2)
+            .map(|c| u16::from_le_bytes([c[0], c[1]]))
+            .collect();
+        String::from_utf16(&utf16).unwrap_or_else(|_| String::from_utf8_lossy(bytes).into_owned())
+    } else {
+        String::from_utf8_lossy(bytes).into_owned()
+    };
+    text.trim_end_matches('\0').to_string()
+}
+
 impl Database {
     pub fn new(path: &str, password: &str) -> Result<Self> {
         let conn = Connection::open(path)?;
@@ -49,17 +94,136 @@ impl Database {
             )",
             [],
         )?;
+        let had_thumbnails: bool = self.conn.query_row(
+            "SELECT COUNT(1) FROM sqlite_master WHERE type = 'table' AND name = 'thumbnails'",
+            [],
+            |r| r.get(0),
+        )?;
+        self.conn.execute(
+            "CREATE TABLE IF NOT EXISTS thumbnails (
+                clip_id INTEGER PRIMARY KEY,
+                width INTEGER,
+                height INTEGER,
+                png BLOB,
+                FOREIGN KEY(clip_id) REFERENCES clips(id) ON DELETE CASCADE
+            )",
+            [],
+        )?;
+        self.conn.execute(
+            "CREATE TABLE IF NOT EXISTS tags (
+                clip_id INTEGER,
+                tag TEXT,
+                PRIMARY KEY(clip_id, tag),
+                FOREIGN KEY(clip_id) REFERENCES clips(id) ON DELETE CASCADE
+            )",
+            [],
+        )?;
+        // Names for the ids in `clips.device`, copied from the sync registry
+        self.conn.execute(
+            "CREATE TABLE IF NOT EXISTS devices (
+                id TEXT PRIMARY KEY,
+                name TEXT,
+                revoked INTEGER DEFAULT 0,
+                this_device INTEGER DEFAULT 0
+            )",
+            [],
+        )?;
+        self.conn.execute(
+            "CREATE TABLE IF NOT EXISTS file_snapshots (
+                id INTEGER PRIMARY KEY AUTOINCREMENT,
+                clip_id INTEGER,
+                path TEXT,
+                data BLOB,
+                FOREIGN KEY(clip_id) REFERENCES clips(id) ON DELETE CASCADE,
+                UNIQUE(clip_id, path)
+            )",
+            [],
+        )?;
+        self.migrate()?;
+        if !had_thumbnails {
+            self.backfill_thumbnails()?;
+        }
+        Ok(())
+    }
+
+    /// Generates thumbnails for image clips captured before the table existed.
+    fn backfill_thumbnails(&self) -> Result<()> {
+        let mut stmt = self.conn.prepare(
+            "SELECT DISTINCT clips.content_hash FROM clips JOIN formats ON formats.clip_id = clips.id
+             WHERE clips.is_sensitive = 0
+               AND (formats.format_id IN (8, 17) OR formats.format_name IN ('PNG', 'image/png'))",
+        )?;
+        let hashes = stmt.query_map([], |r| r.get::<_, String>(0))?.collect::<Result<Vec<_>>>()?;
+        for hash in hashes {
+            let payloads = self.get_clip_payloads(&hash)?;
+            if let Some(thumb) = imaging::thumbnail_for(&payloads) {
+                self.save_thumbnail(&hash, &thumb)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn migrate(&self) -> Result<()> {
+        add_column_if_missing(&self.conn, "clips", "pin_slot", "INTEGER")?;
+        if add_column_if_missing(&self.conn, "clips", "plain_text", "TEXT")? {
+            // Backfill searchable text for clips captured before the column existed
+            let mut stmt = self.conn.prepare(
+                "SELECT clips.id, formats.data FROM clips JOIN formats ON formats.clip_id = clips.id
+                 WHERE clips.is_sensitive = 0 AND formats.format_id IN (13, 1)
+                 ORDER BY formats.format_id DESC",
+            )?;
+            let rows = stmt
+                .query_map([], |r| Ok((r.get::<_, i64>(0)?, r.get::<_, Vec<u8>>(1)?)))?
+                .collect::<Result<Vec<_>>>()?;
+            for (id, data) in rows {
+                self.conn.execute(
+                    "UPDATE clips SET plain_text = ? WHERE id = ? AND plain_text IS NULL",
+                    params![decode_text(&data), id],
+                )?;
+            }
+        }
+        if add_column_if_missing(&self.conn, "clips", "kind", "TEXT")? {
+            self.backfill_kinds()?;
+        }
+        add_column_if_missing(&self.conn, "clips", "parent_hash", "TEXT")?;
+        // Filled in by `set_this_device`, since only cloud.db knows the id
+        add_column_if_missing(&self.conn, "clips", "device", "TEXT")?;
+        add_column_if_missing(&self.conn, "clips", "note", "TEXT")?;
+        // Tables from before UNIQUE(clip_id, path) may hold a path more than once
+        self.conn.execute_batch(
+            "DELETE FROM file_snapshots WHERE id NOT IN (SELECT min(id) FROM file_snapshots GROUP BY clip_id, path);
+             CREATE UNIQUE INDEX IF NOT EXISTS file_snapshots_clip_path ON file_snapshots (clip_id, path);",
+        )?;
+        Ok(())
+    }
+
+    /// Classifies clips captured before the `kind` column existed.
+    fn backfill_kinds(&self) -> Result<()> {
+        let mut stmt = self.conn.prepare(
+            "SELECT content_hash, coalesce(foreground_window_title, '') FROM clips WHERE is_sensitive = 0",
+        )?;
+        let clips = stmt
+            .query_map([], |r| Ok((r.get::<_, String>(0)?, r.get::<_, String>(1)?)))?
+            .collect::<Result<Vec<_>>>()?;
+        for (hash, title) in clips {
+            if let Some(kind) = classify::classify(&self.get_clip_payloads(&hash)?, &title) {
+                self.set_kind(&hash, &kind.to_string())?;
+            }
+        }
         Ok(())
     }
 
+    /// Stores a captured clip. Returns false when a clip with this hash was
+    /// already stored, in which case nothing is written.
     pub fn save_snapshot(
         &self,
         owner_name: &str,
         fg_title: &str,
         exe_path: &str,
         hash: &str,
-        payloads: Vec<ClipboardPayload>,
-    ) -> Result<()> {
+        payloads: &[ClipboardPayload],
+        is_sensitive: bool,
+    ) -> Result<bool> {
         let tx = self.conn.unchecked_transaction()?;
 
         let exists: u32 = tx.query_row(
@@ -68,13 +232,26 @@ impl Database {
             |r| r.get(0),
         )?;
         if exists > 0 {
-            return Ok(());
+            return Ok(false);
         }
 
+        // Sensitive clips stay out of the search index
+        let plain_text = if is_sensitive {
+            None
+        } else {
+            payloads
+                .iter()
+                .find(|p| p.format_id == 13)
+                .or_else(|| payloads.iter().find(|p| p.format_id == 1))
+                .map(|p| decode_text(&p.data))
+                // File lists are searchable by their paths
+                .or_else(|| files::clip_paths(payloads).map(|paths| paths.join("\n")))
+        };
+
         tx.execute(
-            "INSERT INTO clips (owner_process_name, foreground_window_title, exe_path, content_hash)
-             VALUES (?, ?, ?, ?)",
-            params![owner_name, fg_title, exe_path, hash],
+            "INSERT INTO clips (owner_process_name, foreground_window_title, exe_path, content_hash, is_sensitive, plain_text, device)
+             VALUES (?, ?, ?, ?, ?, ?, (SELECT id FROM devices WHERE this_device = 1))",
+            params![owner_name, fg_title, exe_path, hash, is_sensitive, plain_text],
         )?;
         let clip_id = tx.last_insert_rowid();
 
@@ -86,62 +263,216 @@ impl Database {
         }
 
         tx.commit()?;
-        Ok(())
+        Ok(true)
     }
 
-    pub fn get_latest_clips(&self, limit: i32, offset: i32) -> Result<Vec<ClipSummary>> {
-        let mut stmt = self.conn.prepare(
-            "SELECT id, timestamp, owner_process_name, foreground_window_title, content_hash,
-             (SELECT data FROM formats WHERE clip_id = clips.id AND (format_id = 13 OR format_id = 1) LIMIT 1) as preview
-             FROM clips ORDER BY timestamp DESC LIMIT ? OFFSET ?"
-        )?;
+    /// One page of history matching `query`. Without fuzzy terms this is newest
+    /// first; with them, rows are ranked by how well their text matches.
+    pub fn get_latest_clips(&self, query: &Query, limit: i32, offset: i32, preview_len: usize) -> Result<Vec<ClipSummary>> {
+        let (where_sql, mut args) = query.to_sql();
+        let terms = query.fuzzy_terms();
+        let columns = "SELECT id, timestamp, owner_process_name, foreground_window_title, content_hash,
+             (SELECT data FROM formats WHERE clip_id = clips.id AND (format_id = 13 OR format_id = 1) LIMIT 1) as preview,
+             pin_slot, is_sensitive,
+             EXISTS (SELECT 1 FROM thumbnails WHERE clip_id = clips.id) as has_thumbnail,
+             EXISTS (SELECT 1 FROM formats WHERE clip_id = clips.id
+                     AND format_name IN ('HTML Format', 'text/html', 'Rich Text Format', 'text/rtf')) as has_rich,
+             EXISTS (SELECT 1 FROM formats WHERE clip_id = clips.id
+                     AND (format_id = 15 OR format_name = 'text/uri-list')) as has_files,
+             kind, parent_hash,
+             (SELECT group_concat(tag, ' ') FROM (SELECT tag FROM tags WHERE clip_id = clips.id ORDER BY tag)) as tags,
+             (SELECT coalesce(name, id) FROM devices WHERE devices.id = clips.device AND this_device = 0) as device,
+             note
+             FROM clips";
 
-        let rows = stmt.query_map([limit, offset], |row| {
+        let map_row = |row: &rusqlite::Row| -> Result<(i64, ClipSummary)> {
             let raw_data: Option<Vec<u8>> = row.get(5)?;
+            let is_sensitive: bool = row.get(7)?;
+            let has_thumbnail: bool = row.get(8)?;
+            let has_files: bool = row.get(10)?;
             let preview = match raw_data {
-                Some(bytes) => {
-                    if bytes.len() >= 2 {
-                        let utf16: Vec<u16> = bytes
-                            .chunks_exact(2)
-                            .map(|c| u16::from_le_bytes([c[0], c[1]]))
-                            .collect();
-                        if let Ok(s) = String::from_utf16(&utf16) {
-                            s.chars().take(80).collect()
-                        } else {
-                            String::from_utf8_lossy(&bytes).chars().take(80).collect()
-                        }
-                    } else {
-                        String::from_utf8_lossy(&bytes).chars().take(80).collect()
-                    }
-                }
+                _ if is_sensitive => "[ sensitive ]".to_string(),
+                Some(bytes) => decode_text(&bytes).chars().take(preview_len).collect(),
+                None if has_thumbnail => "[ image ]".to_string(),
+                None if has_files => "[ files ]".to_string(),
                 None => "[ binary ]".to_string(),
             };
-            Ok(ClipSummary {
-                timestamp: row.get(1)?,
-                owner: row.get(2)?,
-                fg_title: row.get(3)?,
-                hash: row.get(4)?,
-                preview,
+            Ok((
+                row.get(0)?,
+                ClipSummary {
+                    timestamp: row.get(1)?,
+                    owner: row.get(2)?,
+                    fg_title: row.get(3)?,
+                    hash: row.get(4)?,
+                    preview,
+                    pin_slot: row.get(6)?,
+                    is_sensitive,
+                    has_thumbnail,
+                    has_rich: row.get(9)?,
+                    has_files,
+                    kind: row.get(11)?,
+                    parent_hash: row.get(12)?,
+                    tags: row
+                        .get::<_, Option<String>>(13)?
+                        .map(|tags| tags.split(' ').map(str::to_string).collect())
+                        .unwrap_or_default(),
+                    device: row.get(14)?,
+                    note: row.get::<_, Option<String>>(15)?.filter(|note| !note.is_empty()),
+                },
+            ))
+        };
+
+        if terms.is_empty() {
+            args.push(Value::Integer(limit as i64));
+            args.push(Value::Integer(offset as i64));
+            let mut stmt = self.conn.prepare(&format!("{} WHERE {} ORDER BY timestamp DESC, id DESC LIMIT ? OFFSET ?", columns, where_sql))?;
+            let rows = stmt.query_map(params_from_iter(args), map_row)?;
+            return rows.map(|r| r.map(|(_, clip)| clip)).collect();
+        }
+
+        // Ranking needs every candidate's text, so page after sorting. Only
+        // what's ranked on is read for that; the page is read in full after.
+        // The stable sort keeps newest-first among equal scores.
+        let mut stmt = self.conn.prepare(&format!(
+            "SELECT id, owner_process_name, foreground_window_title, plain_text FROM clips WHERE {} ORDER BY timestamp DESC, id DESC",
+            where_sql
+        ))?;
+        let mut ranked: Vec<(i64, i64)> = stmt
+            .query_map(params_from_iter(args), |r| {
+                Ok((r.get::<_, i64>(0)?, [r.get::<_, Option<String>>(1)?, r.get(2)?, r.get(3)?]))
+            })?
+            .collect::<Result<Vec<_>>>()?
+            .into_iter()
+            .filter_map(|(id, [owner, title, text])| {
+                // A term counts by whichever of text, app and title it matches best
+                let fields = [text.unwrap_or_default(), owner.unwrap_or_default(), title.unwrap_or_default()];
+                let score = terms
+                    .iter()
+                    .map(|t| fields.iter().filter_map(|field| fuzzy_score(t, field)).max())
+                    .sum::<Option<i64>>()?;
+                Some((score, id))
             })
-        })?;
+            .collect();
+        ranked.sort_by_key(|(score, _)| std::cmp::Reverse(*score));
+        let page: Vec<i64> = ranked.into_iter().skip(offset.max(0) as usize).take(limit.max(0) as usize).map(|(_, id)| id).collect();
+        if page.is_empty() {
+            return Ok(Vec::new());
+        }
+
+        let placeholders = vec!["?"; page.len()].join(", ");
+        let mut stmt = self.conn.prepare(&format!("{} WHERE id IN ({})", columns, placeholders))?;
+        let mut clips: HashMap<i64, ClipSummary> = stmt.query_map(params_from_iter(&page), map_row)?.collect::<Result<_>>()?;
+        Ok(page.iter().filter_map(|id| clips.remove(id)).collect())
+    }
+
+    pub fn get_total_count(&self, query: &Query) -> Result<i32> {
+        let (where_sql, args) = query.to_sql();
+        self.conn.query_row(
+            &format!("SELECT COUNT(*) FROM clips WHERE {}", where_sql),
+            params_from_iter(args),
+            |r| r.get(0),
+        )
+    }
 
-        let mut clips = Vec::new();
-        for clip in rows {
-            clips.push(clip?);
+    /// Hash of the clip `skip` places below the newest one.
+    pub fn get_recent_hash(&self, skip: i32) -> Result<Option<String>> {
+        let mut stmt = self.conn.prepare(
+            "SELECT content_hash FROM clips ORDER BY timestamp DESC, id DESC LIMIT 1 OFFSET ?",
+        )?;
+        let mut rows = stmt.query_map([skip], |r| r.get(0))?;
+        rows.next().transpose()
+    }
+
+    /// Pins `hash` to `slot` (1–9), taking the slot away from whichever clip held it.
+    /// `None` unpins.
+    pub fn set_pin_slot(&self, hash: &str, slot: Option<u8>) -> Result<()> {
+        let tx = self.conn.unchecked_transaction()?;
+        if let Some(slot) = slot {
+            tx.execute("UPDATE clips SET pin_slot = NULL WHERE pin_slot = ?", [slot])?;
+        }
+        tx.execute("UPDATE clips SET pin_slot = ? WHERE content_hash = ?", params![slot, hash])?;
+        tx.commit()
+    }
+
+    /// Replaces the clip's tags. Tags are lowercased, and split on spaces and commas.
+    pub fn set_tags(&self, hash: &str, tags: &str) -> Result<()> {
+        let tx = self.conn.unchecked_transaction()?;
+        tx.execute("DELETE FROM tags WHERE clip_id IN (SELECT id FROM clips WHERE content_hash = ?)", [hash])?;
+        for tag in parse_tags(tags) {
+            tx.execute(
+                "INSERT OR IGNORE INTO tags (clip_id, tag) SELECT id, ? FROM clips WHERE content_hash = ?",
+                params![tag, hash],
+            )?;
         }
-        Ok(clips)
+        tx.commit()
+    }
+
+    /// An empty note clears it.
+    pub fn set_note(&self, hash: &str, note: &str) -> Result<()> {
+        self.conn.execute("UPDATE clips SET note = ? WHERE content_hash = ?", params![note.trim(), hash])?;
+        Ok(())
+    }
+
+    /// Pin slot, tags and note: what synced clip metadata carries.
+    pub fn get_clip_metadata(&self, hash: &str) -> Result<(Option<u8>, Vec<String>, String)> {
+        self.conn.query_row(
+            "SELECT pin_slot, (SELECT group_concat(tag, ' ') FROM tags WHERE clip_id = clips.id), coalesce(note, '')
+             FROM clips WHERE content_hash = ? LIMIT 1",
+            [hash],
+            |r| Ok((r.get(0)?, r.get::<_, Option<String>>(1)?.map(|t| parse_tags(&t)).unwrap_or_default(), r.get(2)?)),
+        )
+    }
+
+    /// What the `[[sync.push]]` rules look at.
+    pub fn get_clip_facts(&self, hash: &str) -> Result<ClipFacts> {
+        self.conn.query_row(
+            "SELECT coalesce(owner_process_name, ''), coalesce(foreground_window_title, ''), kind,
+             pin_slot IS NOT NULL, is_sensitive,
+             (SELECT group_concat(tag, ' ') FROM tags WHERE clip_id = clips.id)
+             FROM clips WHERE content_hash = ? LIMIT 1",
+            [hash],
+            |r| {
+                Ok(ClipFacts {
+                    owner: r.get(0)?,
+                    title: r.get(1)?,
+                    kind: r.get(2)?,
+                    pinned: r.get(3)?,
+                    sensitive: r.get(4)?,
+                    tags: r.get::<_, Option<String>>(5)?.map(|t| parse_tags(&t)).unwrap_or_default(),
+                })
+            },
+        )
+    }
+
+    pub fn set_kind(&self, hash: &str, kind: &str) -> Result<()> {
+        self.conn.execute("UPDATE clips SET kind = ? WHERE content_hash = ?", params![kind, hash])?;
+        Ok(())
     }
 
-    pub fn get_total_count(&self) -> Result<i32> {
-        self.conn.query_row("SELECT COUNT(*) FROM clips", [], |r| r.get(0))
+    /// Links a derived clip to the clip it was made from. A clip that already
+    /// existed keeps its original lineage.
+    pub fn set_parent(&self, hash: &str, parent: &str) -> Result<()> {
+        self.conn.execute(
+            "UPDATE clips SET parent_hash = ? WHERE content_hash = ? AND parent_hash IS NULL",
+            params![parent, hash],
+        )?;
+        Ok(())
     }
 
+    pub fn get_pinned_hash(&self, slot: u8) -> Result<Option<String>> {
+        let mut stmt = self.conn.prepare("SELECT content_hash FROM clips WHERE pin_slot = ? LIMIT 1")?;
+        let mut rows = stmt.query_map([slot], |r| r.get(0))?;
+        rows.next().transpose()
+    }
+
+    /// In the order they were captured, so the first is the format the
+    /// content hash was taken of.
     pub fn get_clip_payloads(&self, hash: &str) -> Result<Vec<ClipboardPayload>> {
         let mut stmt = self.conn.prepare(
             "SELECT format_id, format_name, data
              FROM formats
              WHERE clip_id = (SELECT id FROM clips WHERE content_hash = ?)
-             ORDER BY format_id",
+             ORDER BY id",
         )?;
         let payloads = stmt
             .query_map([hash], |row| {
@@ -155,6 +486,91 @@ impl Database {
         Ok(payloads)
     }
 
+    pub fn get_clip_formats(&self, hash: &str) -> Result<Vec<FormatInfo>> {
+        let mut stmt = self.conn.prepare(
+            "SELECT format_id, format_name, length(data)
+             FROM formats
+             WHERE clip_id = (SELECT id FROM clips WHERE content_hash = ?)
+             ORDER BY format_id",
+        )?;
+        let formats = stmt
+            .query_map([hash], |row| {
+                Ok(FormatInfo {
+                    format_id: row.get(0)?,
+                    format_name: row.get(1)?,
+                    size: row.get::<_, Option<i64>>(2)?.unwrap_or(0) as usize,
+                })
+            })?
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(formats)
+    }
+
+    pub fn get_format_data(&self, hash: &str, format_id: u32) -> Result<Vec<u8>> {
+        self.conn.query_row(
+            "SELECT data FROM formats
+             WHERE clip_id = (SELECT id FROM clips WHERE content_hash = ?) AND format_id = ?",
+            params![hash, format_id],
+            |r| r.get(0),
+        )
+    }
+
+    pub fn save_thumbnail(&self, hash: &str, thumb: &Thumbnail) -> Result<()> {
+        self.conn.execute(
+            "INSERT OR REPLACE INTO thumbnails (clip_id, width, height, png)
+             SELECT id, ?, ?, ? FROM clips WHERE content_hash = ?",
+            params![thumb.width, thumb.height, thumb.png, hash],
+        )?;
+        Ok(())
+    }
+
+    pub fn get_thumbnail(&self, hash: &str) -> Result<Option<Vec<u8>>> {
+        let mut stmt = self.conn.prepare(
+            "SELECT png FROM thumbnails WHERE clip_id = (SELECT id FROM clips WHERE content_hash = ?)",
+        )?;
+        let mut rows = stmt.query_map([hash], |r| r.get(0))?;
+        rows.next().transpose()
+    }
+
+    pub fn save_file_snapshots(&self, hash: &str, snapshots: &[(String, Vec<u8>)]) -> Result<()> {
+        let tx = self.conn.unchecked_transaction()?;
+        for (path, data) in snapshots {
+            tx.execute(
+                "INSERT OR IGNORE INTO file_snapshots (clip_id, path, data)
+                 SELECT id, ?, ? FROM clips WHERE content_hash = ?",
+                params![path, data, hash],
+            )?;
+        }
+        tx.commit()
+    }
+
+    pub fn get_file_snapshots(&self, hash: &str) -> Result<Vec<(String, Vec<u8>)>> {
+        let mut stmt = self.conn.prepare(
+            "SELECT path, data FROM file_snapshots
+             WHERE clip_id = (SELECT id FROM clips WHERE content_hash = ?)",
+        )?;
+        let rows = stmt.query_map([hash], |r| Ok((r.get(0)?, r.get(1)?)))?;
+        rows.collect()
+    }
+
+    /// Paths that have a snapshot, without loading the contents.
+    pub fn get_snapshot_paths(&self, hash: &str) -> Result<Vec<String>> {
+        let mut stmt = self.conn.prepare(
+            "SELECT path FROM file_snapshots
+             WHERE clip_id = (SELECT id FROM clips WHERE content_hash = ?)",
+        )?;
+        let rows = stmt.query_map([hash], |r| r.get(0))?;
+        rows.collect()
+    }
+
+    pub fn has_clip(&self, hash: &str) -> Result<bool> {
+        let exists: u32 = self.conn.query_row(
+            "SELECT COUNT(1) FROM clips WHERE content_hash = ?",
+            [hash],
+            |r| r.get(0),
+        )?;
+        Ok(exists > 0)
+    }
+
     pub fn get_clip_meta(&self, hash: &str) -> Result<(String, String, String)> {
         self.conn.query_row(
             "SELECT owner_process_name, foreground_window_title, exe_path FROM clips WHERE content_hash = ?",
@@ -163,6 +579,43 @@ impl Database {
         )
     }
 
+    pub fn get_clip_device(&self, hash: &str) -> Result<Option<String>> {
+        self.conn.query_row("SELECT device FROM clips WHERE content_hash = ? LIMIT 1", [hash], |r| r.get(0))
+    }
+
+    pub fn set_device(&self, hash: &str, device: &str) -> Result<()> {
+        self.conn.execute("INSERT OR IGNORE INTO devices (id) VALUES (?)", [device])?;
+        self.conn.execute("UPDATE clips SET device = ? WHERE content_hash = ?", params![device, hash])?;
+        Ok(())
+    }
+
+    /// Records which device new captures come from. Clips captured before
+    /// the device column existed are taken to be from here too.
+    pub fn set_this_device(&self, id: &str, name: &str) -> Result<()> {
+        let tx = self.conn.unchecked_transaction()?;
+        tx.execute("UPDATE devices SET this_device = 0 WHERE id != ?", [id])?;
+        tx.execute(
+            "INSERT INTO devices (id, name, this_device) VALUES (?, ?, 1)
+             ON CONFLICT(id) DO UPDATE SET name = excluded.name, this_device = 1",
+            params![id, name],
+        )?;
+        tx.execute("UPDATE clips SET device = ? WHERE device IS NULL", [id])?;
+        tx.commit()
+    }
+
+    /// Takes names and revocations from the sync registry.
+    pub fn save_devices(&self, devices: &[Device]) -> Result<()> {
+        let tx = self.conn.unchecked_transaction()?;
+        for d in devices {
+            tx.execute(
+                "INSERT INTO devices (id, name, revoked) VALUES (?, ?, ?)
+                 ON CONFLICT(id) DO UPDATE SET name = excluded.name, revoked = excluded.revoked",
+                params![d.id, d.name, d.revoked],
+            )?;
+        }
+        tx.commit()
+    }
+
     pub fn clear_all_clips(&self) -> Result<()> {
         self.conn.execute("DELETE FROM clips", [])?;
         self.conn.execute("DELETE FROM sqlite_sequence WHERE name='formats'", [])?;
@@ -174,4 +627,68 @@ impl Database {
         self.conn.execute("DELETE FROM clips WHERE content_hash = ?", [hash])?;
         Ok(())
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn open(dir: &std::path::Path) -> Database {
+        Database::new(&dir.join("clipboard.db").to_string_lossy(), "pwd").unwrap()
+    }
+
+    fn text(s: &str) -> Vec<ClipboardPayload> {
+        vec![ClipboardPayload { format_id: 1, format_name: "CF_TEXT".to_string(), data: s.as_bytes().to_vec() }]
+    }
+
+    #[test]
+    fn fuzzy_results_page_in_rank_order() {
+        let dir = tempfile::tempdir().unwrap();
+        let db = open(dir.path());
+        for (hash, owner, title) in [("a", "notepad.exe", "report.txt"), ("b", "excel.exe", "budget"), ("c", "chrome.exe", "Quarterly report"), ("d", "reporter.exe", "r e p o r t")] {
+            db.save_snapshot(owner, title, "", hash, &text(hash), false).unwrap();
+        }
+        db.set_tags("c", "work").unwrap();
+        let query = crate::query::parse("report").unwrap();
+        let ranked = db.get_latest_clips(&query, 10, 0, 20).unwrap();
+        let hashes: Vec<&str> = ranked.iter().map(|c| c.hash.as_str()).collect();
+        assert!(!hashes.contains(&"b") && hashes.len() == 3, "{:?}", hashes);
+
+        // Pages are slices of the same ranking, read in full
+        for (offset, clip) in ranked.iter().enumerate() {
+            let page = db.get_latest_clips(&query, 1, offset as i32, 20).unwrap();
+            assert_eq!(page.len(), 1);
+            assert_eq!((page[0].hash.as_str(), &page[0].tags, page[0].owner.as_str()), (clip.hash.as_str(), &clip.tags, clip.owner.as_str()));
+        }
+        assert_eq!(db.get_latest_clips(&query, 2, 1, 20).unwrap().len(), 2);
+        assert!(db.get_latest_clips(&query, 10, 3, 20).unwrap().is_empty());
+        assert!(db.get_latest_clips(&query, 0, 0, 20).unwrap().is_empty());
+        assert_eq!(ranked.iter().find(|c| c.hash == "c").unwrap().tags, ["work"]);
+    }
+
+    #[test]
+    fn file_snapshots_are_kept_once_per_path() {
+        let dir = tempfile::tempdir().unwrap();
+        let db = open(dir.path());
+        db.save_snapshot("explorer.exe", "Downloads", "", "h", &text("a.txt"), false).unwrap();
+        let snapshot = [("C:\\a.txt".to_string(), b"first".to_vec())];
+        db.save_file_snapshots("h", &snapshot).unwrap();
+        db.save_file_snapshots("h", &[("C:\\a.txt".to_string(), b"second".to_vec())]).unwrap();
+        assert_eq!(db.get_file_snapshots("h").unwrap(), snapshot);
+
+        // A table from before the constraint loses its repeats when opened
+        db.conn
+            .execute_batch(
+                "DROP TABLE file_snapshots;
+                 CREATE TABLE file_snapshots (id INTEGER PRIMARY KEY AUTOINCREMENT, clip_id INTEGER, path TEXT, data BLOB);
+                 INSERT INTO file_snapshots (clip_id, path, data) VALUES (1, 'C:\\a.txt', x'01'), (1, 'C:\\a.txt', x'02'), (1, 'C:\\b.txt', x'03');",
+            )
+            .unwrap();
+        drop(db);
+        let db = open(dir.path());
+        assert_eq!(db.get_snapshot_paths("h").unwrap(), ["C:\\a.txt", "C:\\b.txt"]);
+        assert_eq!(db.get_file_snapshots("h").unwrap()[0].1, [1]);
+        db.save_file_snapshots("h", &[("C:\\b.txt".to_string(), b"again".to_vec())]).unwrap();
+        assert_eq!(db.get_snapshot_paths("h").unwrap().len(), 2);
+    }
+}
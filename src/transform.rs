@@ -0,0 +1,503 @@
+//! Text transforms that turn a clip into a new, derived clip. The result is
+//! stored as plain text with `parent_hash` pointing back at the original.
+
+use std::time::Duration;
+
+use crate::classify;
+use crate::config::{Config, TransformConfig};
+use crate::formats::{self, CF_UNICODETEXT};
+use crate::inspect;
+use crate::models::ClipboardPayload;
+use crate::scripts;
+use crate::storage::Database;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Transform {
+    Trim,
+    NormalizeWhitespace,
+    Upper,
+    Lower,
+    Title,
+    JsonPretty,
+    JsonMinify,
+    XmlPretty,
+    XmlMinify,
+    Base64Encode,
+    Base64Decode,
+    UrlEncode,
+    UrlDecode,
+    EscapeJson,
+    EscapeRegex,
+    EscapeShell,
+    StripFormatting,
+    SortLines,
+    DedupeLines,
+    HexDump,
+}
+
+impl Transform {
+    pub fn all() -> [Transform; 20] {
+        use Transform::*;
+        [
+            Trim, NormalizeWhitespace, Upper, Lower, Title, JsonPretty, JsonMinify, XmlPretty, XmlMinify,
+            Base64Encode, Base64Decode, UrlEncode, UrlDecode, EscapeJson, EscapeRegex, EscapeShell,
+            StripFormatting, SortLines, DedupeLines, HexDump,
+        ]
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Transform::Trim => "trim",
+            Transform::NormalizeWhitespace => "normalize_whitespace",
+            Transform::Upper => "upper",
+            Transform::Lower => "lower",
+            Transform::Title => "title",
+            Transform::JsonPretty => "json_pretty",
+            Transform::JsonMinify => "json_minify",
+            Transform::XmlPretty => "xml_pretty",
+            Transform::XmlMinify => "xml_minify",
+            Transform::Base64Encode => "base64_encode",
+            Transform::Base64Decode => "base64_decode",
+            Transform::UrlEncode => "url_encode",
+            Transform::UrlDecode => "url_decode",
+            Transform::EscapeJson => "escape_json",
+            Transform::EscapeRegex => "escape_regex",
+            Transform::EscapeShell => "escape_shell",
+            Transform::StripFormatting => "strip_formatting",
+            Transform::SortLines => "sort_lines",
+            Transform::DedupeLines => "dedupe_lines",
+            Transform::HexDump => "hex_dump",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        Transform::all().into_iter().find(|t| t.name() == name)
+    }
+
+    pub fn label(self) -> &'static str {
+        match self {
+            Transform::Trim => "Trim",
+            Transform::NormalizeWhitespace => "Normalize whitespace",
+            Transform::Upper => "UPPER CASE",
+            Transform::Lower => "lower case",
+            Transform::Title => "Title Case",
+            Transform::JsonPretty => "JSON: pretty-print",
+            Transform::JsonMinify => "JSON: minify",
+            Transform::XmlPretty => "XML: pretty-print",
+            Transform::XmlMinify => "XML: minify",
+            Transform::Base64Encode => "Base64: encode",
+            Transform::Base64Decode => "Base64: decode",
+            Transform::UrlEncode => "URL: encode",
+            Transform::UrlDecode => "URL: decode",
+            Transform::EscapeJson => "Escape for JSON string",
+            Transform::EscapeRegex => "Escape for regex",
+            Transform::EscapeShell => "Quote for shell",
+            Transform::StripFormatting => "Strip formatting",
+            Transform::SortLines => "Sort lines",
+            Transform::DedupeLines => "Remove duplicate lines",
+            Transform::HexDump => "Hex dump",
+        }
+    }
+
+    pub fn apply(self, text: &str) -> Result<String, String> {
+        Ok(match self {
+            Transform::Trim => text.trim().to_string(),
+            Transform::NormalizeWhitespace => normalize_whitespace(text),
+            Transform::Upper => text.to_uppercase(),
+            Transform::Lower => text.to_lowercase(),
+            Transform::Title => title_case(text),
+            Transform::JsonPretty => serde_json::to_string_pretty(&parse_json(text)?).map_err(|e| e.to_string())?,
+            Transform::JsonMinify => parse_json(text)?.to_string(),
+            Transform::XmlPretty => xml_pretty(&xml_nodes(text)?),
+            Transform::XmlMinify => xml_nodes(text)?.concat(),
+            Transform::Base64Encode => base64_encode(text.as_bytes()),
+            Transform::Base64Decode => {
+                String::from_utf8(base64_decode(text)?).map_err(|_| "decoded bytes are not UTF-8 text".to_string())?
+            }
+            Transform::UrlEncode => url_encode(text),
+            Transform::UrlDecode => url_decode(text)?,
+            Transform::EscapeJson => {
+                let quoted = serde_json::to_string(text).map_err(|e| e.to_string())?;
+                quoted[1..quoted.len() - 1].to_string()
+            }
+            Transform::EscapeRegex => text
+                .chars()
+                .flat_map(|c| {
+                    let meta = "\\.^$|?*+()[]{}/".contains(c);
+                    meta.then_some('\\').into_iter().chain(std::iter::once(c))
+                })
+                .collect(),
+            Transform::EscapeShell => format!("'{}'", text.replace('\'', r"'\''")),
+            // The derived clip is plain text only, which is the whole job
+            Transform::StripFormatting => text.to_string(),
+            Transform::SortLines => {
+                let mut lines: Vec<&str> = text.lines().collect();
+                lines.sort_unstable();
+                lines.join("\n")
+            }
+            Transform::DedupeLines => {
+                let mut seen = std::collections::HashSet::new();
+                text.lines().filter(|l| seen.insert(*l)).collect::<Vec<_>>().join("\n")
+            }
+            Transform::HexDump => {
+                let bytes = text.as_bytes();
+                (0..bytes.len())
+                    .step_by(inspect::BYTES_PER_ROW)
+                    .map(|offset| inspect::hex_line(bytes, offset))
+                    .collect::<Vec<_>>()
+                    .join("\n")
+            }
+        })
+    }
+}
+
+/// A built-in transform or one declared under `[[transforms]]`.
+#[derive(Clone, Debug)]
+pub enum Action {
+    Builtin(Transform),
+    User(TransformConfig),
+}
+
+impl Action {
+    pub fn resolve(name: &str, config: &Config) -> Option<Action> {
+        Transform::from_name(name).map(Action::Builtin).or_else(|| {
+            config.transforms.iter().find(|t| t.name == name).cloned().map(Action::User)
+        })
+    }
+
+    pub fn label(&self) -> &str {
+        match self {
+            Action::Builtin(t) => t.label(),
+            Action::User(t) => &t.name,
+        }
+    }
+
+    pub fn run(&self, payloads: &[ClipboardPayload]) -> Result<String, String> {
+        let text = || source_text(payloads).ok_or("clip has no text");
+        match self {
+            Action::Builtin(t) => t.apply(&text()?),
+            Action::User(t) => match t.wasm_path() {
+                // Text goes in as UTF-8; any other format is handed over as stored
+                Some(path) if t.format == "text/plain" => scripts::run_wasm(&path, text()?.as_bytes(), &t.format),
+                Some(path) => {
+                    let payload = payloads
+                        .iter()
+                        .find(|p| p.format_name == t.format)
+                        .ok_or_else(|| format!("clip has no {}", t.format))?;
+                    scripts::run_wasm(&path, &payload.data, &t.format)
+                }
+                None => scripts::run_command(&t.command, &text()?, Duration::from_millis(t.timeout_ms)),
+            },
+        }
+    }
+}
+
+/// The text a transform works on: the clip's plain text, or the text of its
+/// HTML/RTF when it has none.
+pub fn source_text(payloads: &[ClipboardPayload]) -> Option<String> {
+    classify::clip_text(payloads)
+        .or_else(|| formats::rich_blocks(payloads).map(|blocks| crate::richtext::plain_text(&blocks)))
+}
+
+/// Applies `transform` to the clip `parent` and stores the result as a new
+/// clip linked back to it. Returns the new clip's hash. The result of a
+/// sensitive clip is sensitive too, so it stays out of the search index.
+pub fn derive(db: &Database, parent: &str, action: &Action) -> Result<String, String> {
+    let payloads = db.get_clip_payloads(parent).map_err(|e| e.to_string())?;
+    let sensitive = db.get_clip_facts(parent).map_err(|e| e.to_string())?.sensitive;
+    let output = action.run(&payloads)?;
+    let data = formats::utf8_to_utf16(&output);
+    let hash = blake3::hash(&data).to_hex().to_string();
+    let payloads = vec![ClipboardPayload { format_id: CF_UNICODETEXT, format_name: "CF_UNICODETEXT".to_string(), data }];
+    let kind = if sensitive { None } else { classify::classify(&payloads, "") };
+
+    let (owner, title, exe_path) = db.get_clip_meta(parent).map_err(|e| e.to_string())?;
+    db.save_snapshot(&owner, &title, &exe_path, &hash, &payloads, sensitive).map_err(|e| e.to_string())?;
+    if hash != parent {
+        db.set_parent(&hash, parent).map_err(|e| e.to_string())?;
+    }
+    if let Some(kind) = kind {
+        db.set_kind(&hash, &kind.to_string()).map_err(|e| e.to_string())?;
+    }
+    Ok(hash)
+}
+
+fn normalize_whitespace(text: &str) -> String {
+    let mut out: Vec<String> = Vec::new();
+    for line in text.lines() {
+        let line = line.split_whitespace().collect::<Vec<_>>().join(" ");
+        // Runs of blank lines collapse to one
+        if line.is_empty() && out.last().is_none_or(|l| l.is_empty()) {
+            continue;
+        }
+        out.push(line);
+    }
+    while out.last().is_some_and(|l| l.is_empty()) {
+        out.pop();
+    }
+    out.join("\n")
+}
+
+fn title_case(text: &str) -> String {
+    let mut out = String::with_capacity(text.len());
+    let mut word_start = true;
+    for c in text.chars() {
+        if word_start {
+            out.extend(c.to_uppercase());
+        } else {
+            out.extend(c.to_lowercase());
+        }
+        word_start = !(c.is_alphanumeric() || c == '\'');
+    }
+    out
+}
+
+fn parse_json(text: &str) -> Result<serde_json::Value, String> {
+    serde_json::from_str(text).map_err(|e| format!("not JSON: {}", e))
+}
+
+/// Splits XML into tags and trimmed text, dropping whitespace between tags.
+/// Only checks what pretty-printing needs: every `<` closes and end tags nest.
+fn xml_nodes(text: &str) -> Result<Vec<String>, String> {
+    let mut nodes = Vec::new();
+    let mut open: Vec<String> = Vec::new();
+    let mut rest = text.trim();
+    while !rest.is_empty() {
+        if !rest.starts_with('<') {
+            let end = rest.find('<').unwrap_or(rest.len());
+            let content = rest[..end].trim();
+            if !content.is_empty() {
+                nodes.push(content.to_string());
+            }
+            rest = &rest[end..];
+            continue;
+        }
+        let close = [("<!--", "-->"), ("<![CDATA[", "]]>"), ("<?", "?>")]
+            .iter()
+            .find(|(start, _)| rest.starts_with(start))
+            .map_or(">", |(_, end)| end);
+        let end = rest.find(close).ok_or("not XML: unterminated tag")? + close.len();
+        let tag = &rest[..end];
+        if let Some(name) = tag.strip_prefix("</") {
+            let name = name.trim_end_matches('>').trim();
+            if open.pop().as_deref() != Some(name) {
+                return Err(format!("not XML: unexpected </{}>", name));
+            }
+        } else if !tag.ends_with("/>") && close == ">" && !tag.starts_with("<!") {
+            let name = tag[1..tag.len() - 1].split(|c: char| c.is_whitespace() || c == '/').next().unwrap_or("");
+            open.push(name.to_string());
+        }
+        nodes.push(tag.to_string());
+        rest = &rest[end..];
+    }
+    match open.last() {
+        Some(name) => Err(format!("not XML: <{}> is never closed", name)),
+        None => Ok(nodes),
+    }
+}
+
+fn xml_pretty(nodes: &[String]) -> String {
+    let is_open = |n: &str| n.starts_with('<') && !n.starts_with("</") && !n.starts_with("<?") && !n.starts_with("<!") && !n.ends_with("/>");
+    let is_close = |n: &str| n.starts_with("</");
+    let mut lines = Vec::new();
+    let mut depth = 0usize;
+    let mut i = 0;
+    while i < nodes.len() {
+        let node = &nodes[i];
+        if is_close(node) {
+            depth = depth.saturating_sub(1);
+        }
+        let indent = "  ".repeat(depth);
+        // An element holding only text (or nothing) stays on one line
+        let inline = is_open(node)
+            .then(|| match (nodes.get(i + 1), nodes.get(i + 2)) {
+                (Some(next), _) if is_close(next) => Some(1),
+                (Some(text), Some(next)) if !text.starts_with('<') && is_close(next) => Some(2),
+                _ => None,
+            })
+            .flatten();
+        match inline {
+            Some(len) => {
+                lines.push(format!("{}{}", indent, nodes[i..=i + len].concat()));
+                i += len + 1;
+                continue;
+            }
+            None => lines.push(format!("{}{}", indent, node)),
+        }
+        if is_open(node) {
+            depth += 1;
+        }
+        i += 1;
+    }
+    lines.join("\n")
+}
+
+const BASE64: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+fn base64_encode(data: &[u8]) -> String {
+    let mut out = String::with_capacity(data.len().div_ceil(3) * 4);
+    for chunk in data.chunks(3) {
+        let n = chunk.iter().enumerate().fold(0u32, |n, (i, &b)| n | (b as u32) << (16 - 8 * i));
+        for i in 0..4 {
+            if i <= chunk.len() {
+                out.push(BASE64[(n >> (18 - 6 * i) & 63) as usize] as char);
+            } else {
+                out.push('=');
+            }
+        }
+    }
+    out
+}
+
+/// Accepts the URL-safe alphabet too, and ignores whitespace and padding.
+fn base64_decode(text: &str) -> Result<Vec<u8>, String> {
+    let mut out = Vec::with_capacity(text.len() / 4 * 3);
+    let mut buffer = 0u32;
+    let mut bits = 0;
+    for c in text.chars().filter(|c| !c.is_whitespace() && *c != '=') {
+        let value = match c {
+            'A'..='Z' => c as u32 - 'A' as u32,
+            'a'..='z' => c as u32 - 'a' as u32 + 26,
+            '0'..='9' => c as u32 - '0' as u32 + 52,
+            '+' | '-' => 62,
+            '/' | '_' => 63,
+            _ => return Err(format!("not base64: unexpected '{}'", c)),
+        };
+        buffer = buffer << 6 | value;
+        bits += 6;
+        if bits >= 8 {
+            bits -= 8;
+            out.push((buffer >> bits) as u8);
+        }
+    }
+    Ok(out)
+}
+
+/// Percent-encodes everything but RFC 3986 unreserved characters.
+fn url_encode(text: &str) -> String {
+    text.bytes()
+        .map(|b| match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => (b as char).to_string(),
+            _ => format!("%{:02X}", b),
+        })
+        .collect()
+}
+
+fn url_decode(text: &str) -> Result<String, String> {
+    let bytes = text.as_bytes();
+    let mut out = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'%' {
+            let hex = text.get(i + 1..i + 3).ok_or("not URL-encoded: truncated escape")?;
+            out.push(u8::from_str_radix(hex, 16).map_err(|_| format!("not URL-encoded: bad escape %{}", hex))?);
+            i += 3;
+        } else {
+            out.push(bytes[i]);
+            i += 1;
+        }
+    }
+    String::from_utf8(out).map_err(|_| "decoded bytes are not UTF-8 text".to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn apply(t: Transform, text: &str) -> String {
+        t.apply(text).unwrap()
+    }
+
+    #[test]
+    fn names_round_trip() {
+        for t in Transform::all() {
+            assert_eq!(Transform::from_name(t.name()), Some(t));
+        }
+        assert_eq!(Transform::from_name("nope"), None);
+    }
+
+    #[test]
+    fn whitespace_and_case() {
+        assert_eq!(apply(Transform::Trim, "  a b \n"), "a b");
+        assert_eq!(apply(Transform::NormalizeWhitespace, "\n a \t b \n\n\n c  \n\n"), "a b\n\nc");
+        assert_eq!(apply(Transform::Title, "hello wORLD, it's o'clock"), "Hello World, It's O'clock");
+        assert_eq!(apply(Transform::Upper, "straße"), "STRASSE");
+    }
+
+    #[test]
+    fn json() {
+        let text = r#"{"b": [1, 2], "a": {"c": null}}"#;
+        assert_eq!(apply(Transform::JsonMinify, text), r#"{"b":[1,2],"a":{"c":null}}"#);
+        assert_eq!(
+            apply(Transform::JsonPretty, text),
+            "{\n  \"b\": [\n    1,\n    2\n  ],\n  \"a\": {\n    \"c\": null\n  }\n}"
+        );
+        assert!(Transform::JsonPretty.apply("{oops").unwrap_err().starts_with("not JSON"));
+    }
+
+    #[test]
+    fn xml() {
+        let text = "<?xml version=\"1.0\"?>\n<a x=\"1\">  <b>hi</b>\n<c/><!-- note --><d></d></a>";
+        assert_eq!(
+            apply(Transform::XmlMinify, text),
+            "<?xml version=\"1.0\"?><a x=\"1\"><b>hi</b><c/><!-- note --><d></d></a>"
+        );
+        assert_eq!(
+            apply(Transform::XmlPretty, text),
+            "<?xml version=\"1.0\"?>\n<a x=\"1\">\n  <b>hi</b>\n  <c/>\n  <!-- note -->\n  <d></d>\n</a>"
+        );
+        assert!(Transform::XmlPretty.apply("<a><b></a>").is_err());
+        assert!(Transform::XmlPretty.apply("<a>").is_err());
+    }
+
+    #[test]
+    fn encodings() {
+        for text in ["", "f", "fo", "foo", "foob", "héllo wörld"] {
+            assert_eq!(apply(Transform::Base64Decode, &apply(Transform::Base64Encode, text)), text);
+        }
+        assert_eq!(apply(Transform::Base64Encode, "foob"), "Zm9vYg==");
+        assert_eq!(apply(Transform::Base64Decode, "Zm9v\nYg"), "foob");
+        assert!(Transform::Base64Decode.apply("!!").is_err());
+
+        assert_eq!(apply(Transform::UrlEncode, "a b/ü?"), "a%20b%2F%C3%BC%3F");
+        assert_eq!(apply(Transform::UrlDecode, "a%20b%2F%C3%BC%3F"), "a b/ü?");
+        assert!(Transform::UrlDecode.apply("%zz").is_err());
+        assert!(Transform::UrlDecode.apply("%4").is_err());
+    }
+
+    #[test]
+    fn escapes() {
+        assert_eq!(apply(Transform::EscapeJson, "say \"hi\"\n\\"), r#"say \"hi\"\n\\"#);
+        assert_eq!(apply(Transform::EscapeRegex, "a.b*(c)"), r"a\.b\*\(c\)");
+        assert_eq!(apply(Transform::EscapeShell, "it's"), r"'it'\''s'");
+    }
+
+    #[test]
+    fn lines() {
+        assert_eq!(apply(Transform::SortLines, "b\na\nc\na"), "a\na\nb\nc");
+        assert_eq!(apply(Transform::DedupeLines, "b\na\nb\na\nc"), "b\na\nc");
+        assert_eq!(
+            apply(Transform::HexDump, "AB"),
+            "00000000  41 42                                             |AB|"
+        );
+    }
+
+    #[test]
+    fn derived_secrets_stay_sensitive() {
+        let dir = tempfile::tempdir().unwrap();
+        let db = Database::new(&dir.path().join("clipboard.db").to_string_lossy(), "pwd").unwrap();
+        let text = |s: &str| vec![ClipboardPayload { format_id: CF_UNICODETEXT, format_name: "CF_UNICODETEXT".to_string(), data: formats::utf8_to_utf16(s) }];
+        db.save_snapshot("keepass.exe", "Vault", "", "secret", &text(" hunter2 "), true).unwrap();
+        db.save_snapshot("notepad.exe", "Notes", "", "plain", &text(" hello "), false).unwrap();
+
+        let upper = Action::Builtin(Transform::Upper);
+        let derived = derive(&db, "secret", &upper).unwrap();
+        assert!(db.get_clip_facts(&derived).unwrap().sensitive);
+        let derived = derive(&db, "plain", &upper).unwrap();
+        assert!(!db.get_clip_facts(&derived).unwrap().sensitive);
+
+        let found = |word: &str| db.get_total_count(&crate::query::parse(word).unwrap()).unwrap();
+        assert_eq!(found("HUNTER2"), 0);
+        assert_eq!(found("HELLO"), 2);
+    }
+}
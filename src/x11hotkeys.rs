@@ -0,0 +1,181 @@
+//! Global hotkeys on X11: the `[hotkeys]` bindings grabbed on the root window
+//! with XGrabKey, where Windows uses RegisterHotKey. libX11 is loaded at run
+//! time, so a session without an X server reports the hotkeys as unavailable
+//! rather than failing to start.
+
+use std::os::raw::{c_int, c_uint, c_ulong};
+use std::ptr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::channel;
+use std::sync::Arc;
+use std::thread::JoinHandle;
+use std::time::Duration;
+
+use x11_dl::keysym;
+use x11_dl::xlib::{self, Xlib};
+
+use crate::hotkeys::{Binding, HotkeyAction, MOD_ALT, MOD_CONTROL, MOD_SHIFT, MOD_WIN};
+
+/// How often the grab thread looks for key presses and for being stopped.
+const POLL_INTERVAL: Duration = Duration::from_millis(30);
+
+/// Set by the error handler when a grab fails because another client holds the key.
+static GRAB_REFUSED: AtomicBool = AtomicBool::new(false);
+
+/// Holds the grabs until dropped.
+pub struct Grabber {
+    stop: Arc<AtomicBool>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl Drop for Grabber {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::Relaxed);
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+impl Grabber {
+    /// Grabs `bindings` on a thread of its own, which calls `fire` with the
+    /// action of each one pressed. Also returns the bindings that couldn't be
+    /// grabbed, usually because another application owns them.
+    pub fn start(bindings: Vec<Binding>, fire: impl Fn(HotkeyAction) + Send + 'static) -> Result<(Grabber, Vec<String>), String> {
+        let stop = Arc::new(AtomicBool::new(false));
+        let (tx, rx) = channel();
+        let stopped = stop.clone();
+        let thread = std::thread::spawn(move || {
+            // The display is only ever touched from this thread
+            let xlib = match Xlib::open() {
+                Ok(xlib) => xlib,
+                Err(e) => return drop(tx.send(Err(format!("can't load libX11: {}", e)))),
+            };
+            unsafe {
+                let display = (xlib.XOpenDisplay)(ptr::null());
+                if display.is_null() {
+                    return drop(tx.send(Err("can't open the X display".to_string())));
+                }
+                let root = (xlib.XDefaultRootWindow)(display);
+                (xlib.XSetErrorHandler)(Some(on_error));
+                let mut grabbed = Vec::new();
+                let mut errors = Vec::new();
+                for binding in bindings {
+                    let Some(sym) = keysym_for(binding.vk) else {
+                        errors.push(format!("{} ({}) has no X11 key", binding.combo, binding.action));
+                        continue;
+                    };
+                    let keycode = (xlib.XKeysymToKeycode)(display, sym) as c_int;
+                    let mask = modifier_mask(binding.modifiers);
+                    GRAB_REFUSED.store(false, Ordering::Relaxed);
+                    // Caps Lock and Num Lock count as modifiers on X11; grab with and without them
+                    for locks in LOCK_VARIANTS {
+                        (xlib.XGrabKey)(display, keycode, mask | locks, root, xlib::False, xlib::GrabModeAsync, xlib::GrabModeAsync);
+                    }
+                    (xlib.XSync)(display, xlib::False);
+                    if keycode == 0 || GRAB_REFUSED.swap(false, Ordering::Relaxed) {
+                        for locks in LOCK_VARIANTS {
+                            (xlib.XUngrabKey)(display, keycode, mask | locks, root);
+                        }
+                        errors.push(format!("{} ({}) is unavailable", binding.combo, binding.action));
+                        continue;
+                    }
+                    grabbed.push((keycode as c_uint, mask, binding.action));
+                }
+                (xlib.XSelectInput)(display, root, xlib::KeyPressMask);
+                let _ = tx.send(Ok(errors));
+
+                let mut event: xlib::XEvent = std::mem::zeroed();
+                while !stopped.load(Ordering::Relaxed) {
+                    while (xlib.XPending)(display) > 0 {
+                        (xlib.XNextEvent)(display, &mut event);
+                        if event.get_type() != xlib::KeyPress {
+                            continue;
+                        }
+                        let key = event.key;
+                        let state = key.state & !(xlib::LockMask | xlib::Mod2Mask);
+                        if let Some((_, _, action)) = grabbed.iter().find(|(code, mask, _)| *code == key.keycode && *mask == state) {
+                            fire(action.clone());
+                        }
+                    }
+                    std::thread::sleep(POLL_INTERVAL);
+                }
+                // Closing the connection releases its grabs
+                (xlib.XCloseDisplay)(display);
+            }
+        });
+        match rx.recv() {
+            Ok(Ok(errors)) => Ok((Grabber { stop, thread: Some(thread) }, errors)),
+            Ok(Err(e)) => Err(e),
+            Err(_) => Err("the hotkey thread stopped".to_string()),
+        }
+    }
+}
+
+const LOCK_VARIANTS: [c_uint; 4] = [0, xlib::LockMask, xlib::Mod2Mask, xlib::LockMask | xlib::Mod2Mask];
+
+unsafe extern "C" fn on_error(_: *mut xlib::Display, event: *mut xlib::XErrorEvent) -> c_int {
+    if (*event).error_code == xlib::BadAccess {
+        GRAB_REFUSED.store(true, Ordering::Relaxed);
+    }
+    0
+}
+
+/// The X modifier mask for RegisterHotKey-style modifiers.
+fn modifier_mask(modifiers: u32) -> c_uint {
+    [(MOD_CONTROL, xlib::ControlMask), (MOD_SHIFT, xlib::ShiftMask), (MOD_ALT, xlib::Mod1Mask), (MOD_WIN, xlib::Mod4Mask)]
+        .into_iter()
+        .filter(|(modifier, _)| modifiers & modifier != 0)
+        .fold(0, |mask, (_, x)| mask | x)
+}
+
+/// The X keysym for a virtual-key code from `hotkeys::parse_hotkey`.
+pub fn keysym_for(vk: u32) -> Option<c_ulong> {
+    let sym = match vk {
+        0x41..=0x5A => keysym::XK_a + (vk - 0x41),
+        0x30..=0x39 => keysym::XK_0 + (vk - 0x30),
+        0x70..=0x87 => keysym::XK_F1 + (vk - 0x70),
+        0x20 => keysym::XK_space,
+        0x0D => keysym::XK_Return,
+        0x09 => keysym::XK_Tab,
+        0x1B => keysym::XK_Escape,
+        0x08 => keysym::XK_BackSpace,
+        0x2E => keysym::XK_Delete,
+        0x2D => keysym::XK_Insert,
+        0x24 => keysym::XK_Home,
+        0x23 => keysym::XK_End,
+        0x21 => keysym::XK_Prior,
+        0x22 => keysym::XK_Next,
+        0x25 => keysym::XK_Left,
+        0x26 => keysym::XK_Up,
+        0x27 => keysym::XK_Right,
+        0x28 => keysym::XK_Down,
+        _ => return None,
+    };
+    Some(sym as c_ulong)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::hotkeys::parse_hotkey;
+
+    fn x11(combo: &str) -> (c_uint, Option<c_ulong>) {
+        let (modifiers, vk) = parse_hotkey(combo).unwrap();
+        (modifier_mask(modifiers), keysym_for(vk))
+    }
+
+    #[test]
+    fn combos_map_to_x11() {
+        assert_eq!(x11("Ctrl+Alt+C"), (xlib::ControlMask | xlib::Mod1Mask, Some(keysym::XK_c as c_ulong)));
+        assert_eq!(x11("Win+Shift+7"), (xlib::Mod4Mask | xlib::ShiftMask, Some(keysym::XK_7 as c_ulong)));
+        assert_eq!(x11("Ctrl+F12"), (xlib::ControlMask, Some(keysym::XK_F12 as c_ulong)));
+        assert_eq!(x11("Ctrl+PageDown").1, Some(keysym::XK_Next as c_ulong));
+        assert_eq!(x11("Alt+Esc").1, Some(keysym::XK_Escape as c_ulong));
+        // Every key the parser knows has an X11 counterpart
+        let named = ["space", "enter", "tab", "esc", "backspace", "delete", "insert", "home", "end", "pageup", "pagedown", "left", "up", "right", "down", "f1", "f24", "a", "z", "0", "9"];
+        for key in named {
+            assert!(x11(&format!("Ctrl+{}", key)).1.is_some(), "{}", key);
+        }
+    }
+}
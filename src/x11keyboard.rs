@@ -0,0 +1,211 @@
+//! Paste keystrokes on X11, where Windows uses SendInput (see `keyboard`):
+//! the previous window is re-activated through the window manager and the
+//! chord is typed with the XTest extension. libX11 and libXtst are loaded at
+//! run time, like the hotkey grabs in `x11hotkeys`.
+
+use std::ffi::CStr;
+use std::os::raw::{c_int, c_long, c_uchar, c_ulong};
+use std::ptr;
+use std::time::Duration;
+
+use x11_dl::keysym;
+use x11_dl::xlib::{self, Xlib};
+use x11_dl::xtest::Xf86vmode as Xtst;
+
+use crate::hotkeys::{MOD_ALT, MOD_CONTROL, MOD_SHIFT, MOD_WIN};
+use crate::x11hotkeys::keysym_for;
+
+/// Both sides of every modifier, for lifting whatever the hotkey left held.
+const MODIFIER_KEYS: [c_ulong; 8] = [
+    keysym::XK_Control_L as c_ulong,
+    keysym::XK_Control_R as c_ulong,
+    keysym::XK_Shift_L as c_ulong,
+    keysym::XK_Shift_R as c_ulong,
+    keysym::XK_Alt_L as c_ulong,
+    keysym::XK_Alt_R as c_ulong,
+    keysym::XK_Super_L as c_ulong,
+    keysym::XK_Super_R as c_ulong,
+];
+
+/// A display connection, closed when dropped.
+struct Connection {
+    xlib: Xlib,
+    display: *mut xlib::Display,
+}
+
+impl Connection {
+    fn open() -> Result<Connection, String> {
+        let xlib = Xlib::open().map_err(|e| format!("can't load libX11: {}", e))?;
+        let display = unsafe { (xlib.XOpenDisplay)(ptr::null()) };
+        if display.is_null() {
+            return Err("can't open the X display".to_string());
+        }
+        Ok(Connection { xlib, display })
+    }
+
+    fn root(&self) -> c_ulong {
+        unsafe { (self.xlib.XDefaultRootWindow)(self.display) }
+    }
+
+    fn atom(&self, name: &CStr) -> c_ulong {
+        unsafe { (self.xlib.XInternAtom)(self.display, name.as_ptr(), xlib::False) }
+    }
+
+    /// The first 32-bit value of a window property, such as a window id or a pid.
+    fn property(&self, window: c_ulong, name: &CStr, kind: c_ulong) -> Option<c_ulong> {
+        let (mut actual_type, mut actual_format, mut items, mut after) = (0, 0, 0, 0);
+        let mut data: *mut c_uchar = ptr::null_mut();
+        unsafe {
+            let status = (self.xlib.XGetWindowProperty)(
+                self.display, window, self.atom(name), 0, 1, xlib::False, kind,
+                &mut actual_type, &mut actual_format, &mut items, &mut after, &mut data,
+            );
+            if data.is_null() {
+                return None;
+            }
+            // 32-bit items come back as longs
+            let value = (status == 0 && actual_format == 32 && items > 0).then(|| *(data as *const c_ulong));
+            (self.xlib.XFree)(data.cast());
+            value
+        }
+    }
+
+    /// The window the window manager calls active, or failing that the one with the input focus.
+    fn active_window(&self) -> Option<c_ulong> {
+        if let Some(window) = self.property(self.root(), c"_NET_ACTIVE_WINDOW", xlib::XA_WINDOW).filter(|w| *w != 0) {
+            return Some(window);
+        }
+        let (mut focus, mut revert) = (0, 0);
+        unsafe { (self.xlib.XGetInputFocus)(self.display, &mut focus, &mut revert) };
+        // 0 and 1 are None and PointerRoot
+        (focus > 1).then_some(focus)
+    }
+
+    fn pid(&self, window: c_ulong) -> Option<u32> {
+        self.property(window, c"_NET_WM_PID", xlib::XA_CARDINAL).map(|pid| pid as u32)
+    }
+
+    /// Asks the window manager to bring `window` forward, as a pager would,
+    /// and sets the focus directly too for sessions without one.
+    fn activate(&self, window: c_ulong) {
+        unsafe {
+            let mut event: xlib::XEvent = std::mem::zeroed();
+            event.client_message = xlib::XClientMessageEvent {
+                type_: xlib::ClientMessage,
+                serial: 0,
+                send_event: xlib::True,
+                display: self.display,
+                window,
+                message_type: self.atom(c"_NET_ACTIVE_WINDOW"),
+                format: 32,
+                data: xlib::ClientMessageData::from([2, xlib::CurrentTime as c_long, 0, 0, 0]),
+            };
+            let mask = xlib::SubstructureRedirectMask | xlib::SubstructureNotifyMask;
+            (self.xlib.XSendEvent)(self.display, self.root(), xlib::False, mask, &mut event);
+            (self.xlib.XSetInputFocus)(self.display, window, xlib::RevertToParent, xlib::CurrentTime);
+            (self.xlib.XSync)(self.display, xlib::False);
+        }
+    }
+
+    fn keycode(&self, sym: c_ulong) -> u8 {
+        unsafe { (self.xlib.XKeysymToKeycode)(self.display, sym) }
+    }
+}
+
+impl Drop for Connection {
+    fn drop(&mut self) {
+        unsafe { (self.xlib.XCloseDisplay)(self.display) };
+    }
+}
+
+/// The focused window, as the paste target, unless it's one of ours.
+pub fn active_window() -> Option<c_ulong> {
+    let connection = Connection::open().ok()?;
+    let window = connection.active_window()?;
+    (connection.pid(window) != Some(std::process::id())).then_some(window)
+}
+
+/// The name of the process behind `window`, e.g. `code`, for `[paste.overrides]`.
+pub fn process_name(window: c_ulong) -> String {
+    Connection::open()
+        .ok()
+        .and_then(|connection| connection.pid(window))
+        .and_then(|pid| std::fs::read_to_string(format!("/proc/{}/comm", pid)).ok())
+        .map(|name| name.trim_end().to_string())
+        .unwrap_or_else(|| "Unknown Process".to_string())
+}
+
+/// Re-activates `window` and types `modifiers` + `vk` into it, releasing any
+/// modifier the user is still holding from the hotkey first.
+pub fn paste_into(window: c_ulong, modifiers: u32, vk: u32) -> Result<(), String> {
+    let keys = chord(modifiers, vk).ok_or_else(|| format!("key {:#x} has no X11 keysym", vk))?;
+    let connection = Connection::open()?;
+    let xtst = Xtst::open().map_err(|e| format!("can't load libXtst: {}", e))?;
+
+    connection.activate(window);
+    let mut active = false;
+    for _ in 0..50 {
+        if connection.active_window() == Some(window) {
+            active = true;
+            break;
+        }
+        std::thread::sleep(Duration::from_millis(10));
+    }
+    if !active {
+        return Err("could not re-activate the previous window".to_string());
+    }
+
+    let fake = |keycode: u8, press: bool| unsafe {
+        (xtst.XTestFakeKeyEvent)(connection.display, keycode as _, press as c_int, 0);
+    };
+    let mut held = [0; 32];
+    unsafe { (connection.xlib.XQueryKeymap)(connection.display, held.as_mut_ptr()) };
+    for keycode in MODIFIER_KEYS.iter().map(|&sym| connection.keycode(sym)) {
+        if keycode != 0 && held[keycode as usize / 8] as u8 & (1 << (keycode % 8)) != 0 {
+            fake(keycode, false);
+        }
+    }
+    let keycodes: Vec<u8> = keys.iter().map(|&sym| connection.keycode(sym)).collect();
+    if keycodes.contains(&0) {
+        return Err("the paste keys aren't on this keyboard".to_string());
+    }
+    for &keycode in &keycodes {
+        fake(keycode, true);
+    }
+    for &keycode in keycodes.iter().rev() {
+        fake(keycode, false);
+    }
+    unsafe { (connection.xlib.XSync)(connection.display, xlib::False) };
+    Ok(())
+}
+
+/// Keysyms to press in order for RegisterHotKey-style modifiers plus a virtual-key code.
+fn chord(modifiers: u32, vk: u32) -> Option<Vec<c_ulong>> {
+    let mut keys: Vec<c_ulong> = [(MOD_CONTROL, keysym::XK_Control_L), (MOD_ALT, keysym::XK_Alt_L), (MOD_SHIFT, keysym::XK_Shift_L), (MOD_WIN, keysym::XK_Super_L)]
+        .into_iter()
+        .filter(|(modifier, _)| modifiers & modifier != 0)
+        .map(|(_, sym)| sym as c_ulong)
+        .collect();
+    keys.push(keysym_for(vk)?);
+    Some(keys)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::hotkeys::parse_hotkey;
+
+    #[test]
+    fn paste_chords() {
+        let keys = |combo: &str| {
+            let (modifiers, vk) = parse_hotkey(combo).unwrap();
+            chord(modifiers, vk)
+        };
+        assert_eq!(keys("Ctrl+V"), Some(vec![keysym::XK_Control_L as c_ulong, keysym::XK_v as c_ulong]));
+        assert_eq!(keys("Ctrl+Shift+V"), Some(vec![keysym::XK_Control_L as c_ulong, keysym::XK_Shift_L as c_ulong, keysym::XK_v as c_ulong]));
+        assert_eq!(keys("Shift+Insert"), Some(vec![keysym::XK_Shift_L as c_ulong, keysym::XK_Insert as c_ulong]));
+        // The default paste combo
+        assert_eq!(chord(MOD_CONTROL, 'V' as u32).unwrap().len(), 2);
+        assert_eq!(chord(0, 0xFF), None);
+    }
+}